@@ -1,10 +1,23 @@
 use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::de::{DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::config::RemoteEntry;
+use crate::config::{name_problem, slugify, RemoteEntry, MAX_NAME_LEN};
+use crate::error::{SyncError, SyncResult};
+use crate::schedule::BandwidthWindow;
+use crate::term::warn;
 
 pub type RemoteMap = HashMap<String, Vec<RemoteEntry>>;
 
@@ -14,6 +27,191 @@ pub struct VersionedCache {
     pub entries: RemoteMap,
 }
 
+// What history snapshots hold: the same as `VersionedCache`, but borrowing
+// the entries, with directories in a stable order
+#[derive(Serialize)]
+struct VersionedCacheRef<'a> {
+    version: &'a str,
+    entries: BTreeMap<&'a str, &'a [RemoteEntry]>,
+}
+
+// Each directory's remotes are kept in a file of their own under
+// `projects/`, named after a hash of the directory, so that saving one
+// project never rewrites another's and config synced between machines only
+// conflicts when the same project changed on both. `projects/index.json`
+// says which file holds which directory. `cache.json` is the single file
+// used before, read until the first save moves its directories over.
+#[derive(Debug)]
+struct ProjectFile {
+    dir: String,
+    remotes: Vec<RemoteEntry>,
+}
+
+// A project file as stored, before its remotes are upgraded
+#[derive(Deserialize)]
+struct RawProjectFile {
+    version: String,
+    dir: String,
+    remotes: Vec<Value>,
+}
+
+#[derive(Serialize)]
+struct ProjectFileRef<'a> {
+    version: &'a str,
+    dir: &'a str,
+    remotes: Vec<StampedEntry<'a>>,
+}
+
+// A remote as stored, with the version of sync-rs that wrote it
+#[derive(Serialize)]
+struct StampedEntry<'a> {
+    version: &'a str,
+    #[serde(flatten)]
+    entry: &'a RemoteEntry,
+}
+
+// `cache.json` as stored, before its remotes are upgraded
+#[derive(Deserialize)]
+struct RawCache {
+    version: String,
+    entries: HashMap<String, Vec<Value>>,
+}
+
+type EntryMigration = fn(&mut Map<String, Value>);
+
+// Changes to how a remote is stored, oldest first, with the version that
+// made each. Every remote records the version that wrote it (ones without
+// take their file's), so remotes from older releases or added by other
+// tools are upgraded one at a time, next to others that are already current.
+const ENTRY_MIGRATIONS: &[(&str, EntryMigration)] = &[("1.4.0", migrate_name)];
+
+// Names used to be made up from the host and directory, which can give
+// names later versions refuse, e.g. with spaces
+fn migrate_name(entry: &mut Map<String, Value>) {
+    if let Some(Value::String(name)) = entry.get_mut("name") {
+        *name = valid_name(std::mem::take(name));
+    }
+}
+
+fn valid_name(name: String) -> String {
+    if name_problem(&name).is_none() {
+        return name;
+    }
+    let mut name = slugify(&name);
+    name.truncate(MAX_NAME_LEN);
+    name
+}
+
+// Orders versions like `1.10.0` after `1.9.2`. Anything that isn't one, like
+// a test's, comes first.
+fn version_parts(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+// Remotes as stored in a file written by `file_version`, brought up to date
+fn upgrade_entries(
+    file_version: &str,
+    remotes: Vec<Value>,
+) -> serde_json::Result<Vec<RemoteEntry>> {
+    remotes
+        .into_iter()
+        .map(|remote| {
+            let Value::Object(mut entry) = remote else {
+                return serde_json::from_value(remote);
+            };
+            let written_by = match entry.remove("version") {
+                Some(Value::String(version)) => version_parts(&version),
+                _ => version_parts(file_version),
+            };
+            for (since, migrate) in ENTRY_MIGRATIONS {
+                if written_by < version_parts(since) {
+                    migrate(&mut entry);
+                }
+            }
+            serde_json::from_value(Value::Object(entry))
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct ProjectIndex {
+    version: String,
+    // Directory to file name
+    projects: BTreeMap<String, String>,
+}
+
+// Reads a versioned cache keeping only the remotes of one directory. The
+// others are skipped over without being built, which with hundreds of
+// directories saves most of the work of reading the cache.
+struct DirCacheSeed<'a>(&'a str);
+
+impl<'de> Visitor<'de> for DirCacheSeed<'_> {
+    // The cache version and the directory's remotes, as stored
+    type Value = (String, Vec<Value>);
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a versioned sync-rs cache")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut version = None;
+        let mut entries = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "version" => version = Some(map.next_value()?),
+                "entries" => entries = Some(map.next_value_seed(DirEntriesSeed(self.0))?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let version = version.ok_or_else(|| serde::de::Error::missing_field("version"))?;
+        let entries = entries.ok_or_else(|| serde::de::Error::missing_field("entries"))?;
+        Ok((version, entries))
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for DirCacheSeed<'_> {
+    type Value = (String, Vec<Value>);
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+struct DirEntriesSeed<'a>(&'a str);
+
+impl<'de> Visitor<'de> for DirEntriesSeed<'_> {
+    type Value = Vec<Value>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("remotes by directory")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut found = Vec::new();
+        while let Some(dir) = map.next_key::<String>()? {
+            if dir == self.0 {
+                found = map.next_value()?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for DirEntriesSeed<'_> {
+    type Value = Vec<Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
 // Legacy cache format for migration (v0)
 #[derive(Debug, Deserialize)]
 struct LegacyCacheEntry {
@@ -48,7 +246,7 @@ impl CacheMigrator for LegacyMigrator {
     }
 
     fn migrate(&self, data: &[u8], cache_path: &Path) -> Result<RemoteMap> {
-        println!("Migrating from legacy cache format...");
+        eprintln!("Migrating from legacy cache format...");
 
         let legacy_cache: LegacyCache =
             serde_json::from_slice(data).context("Failed to parse legacy cache")?;
@@ -59,7 +257,7 @@ impl CacheMigrator for LegacyMigrator {
         let backup_path = cache_path.with_extension("json.bak");
         fs::copy(cache_path, &backup_path).context("Failed to backup legacy cache file")?;
 
-        println!(
+        eprintln!(
             "Cache migration complete. Backup saved at {:?}",
             backup_path
         );
@@ -79,13 +277,12 @@ impl LegacyMigrator {
                 entry.remote_dir.replace('/', "_")
             );
             let remote_entry = RemoteEntry {
-                name,
+                name: valid_name(name),
                 remote_host: entry.remote_host,
                 remote_dir: entry.remote_dir,
                 override_paths: entry.override_paths,
                 post_sync_command: entry.post_sync_command,
-                preferred: false,
-                ignore_patterns: Vec::new(),
+                ..Default::default()
             };
 
             new_cache.insert(dir, vec![remote_entry]);
@@ -99,6 +296,10 @@ impl LegacyMigrator {
 pub struct MigrationManager {
     migrators: Vec<Box<dyn CacheMigrator>>,
     current_version: String,
+    // Each project as `read_cache` last found it, serialized, so that
+    // `save_cache` only writes the ones changed since and leaves the others
+    // to whichever process changes them
+    as_read: Mutex<HashMap<String, Vec<u8>>>,
 }
 
 impl MigrationManager {
@@ -106,6 +307,7 @@ impl MigrationManager {
         let mut manager = Self {
             migrators: Vec::new(),
             current_version,
+            as_read: Mutex::default(),
         };
 
         // Register all migrators in chronological order
@@ -118,7 +320,87 @@ impl MigrationManager {
         self.migrators.push(migrator);
     }
 
-    pub fn read_cache(&self, cache_path: &Path) -> Result<RemoteMap> {
+    pub fn read_cache(&self, cache_path: &Path) -> SyncResult<RemoteMap> {
+        let (mut cache, from_projects) = self.read_entries(cache_path)?;
+
+        // Directories read from `cache.json` are all written by the next save
+        let mut as_read = self.as_read.lock().unwrap();
+        as_read.clear();
+        if from_projects {
+            for (dir, remotes) in &cache {
+                as_read.insert(dir.clone(), self.project_data(dir, remotes)?);
+            }
+        }
+
+        for (dir, entries) in cache.iter_mut() {
+            if let Some(kept) = repair_preferred(entries) {
+                warn(format_args!(
+                    "{} had more than one preferred remote, keeping '{}'",
+                    dir, kept
+                ));
+            }
+        }
+        Ok(cache)
+    }
+
+    // The remotes of one directory, for commands that only look at those.
+    // Don't save the result with `save_cache`, as it lacks every other
+    // directory; `update_cache` reads the whole cache.
+    pub fn read_dir_cache(&self, cache_path: &Path, dir: &str) -> SyncResult<RemoteMap> {
+        let mut entries = if projects_dir(cache_path).is_dir() {
+            let index = read_index(cache_path)?;
+            eprintln!("Using cache version {}", index.version);
+            match find_project_file(cache_path, &index, dir) {
+                Some(file) => read_project(&projects_dir(cache_path).join(file))?
+                    .map(|project| project.remotes)
+                    .unwrap_or_default(),
+                None => Vec::new(),
+            }
+        } else {
+            if !cache_path.exists() {
+                return Ok(RemoteMap::new());
+            }
+            let data = fs::read(cache_path).context("Failed to read cache file")?;
+            let mut deserializer = serde_json::Deserializer::from_slice(&data);
+            match DirCacheSeed(dir).deserialize(&mut deserializer) {
+                Ok((version, entries)) => {
+                    eprintln!("Using cache version {}", version);
+                    upgrade_entries(&version, entries).map_err(|e| SyncError::CacheCorrupt {
+                        path: cache_path.to_path_buf(),
+                        message: e.to_string(),
+                    })?
+                }
+                // Older formats are migrated as a whole
+                Err(_) => self
+                    .read_cache_file(cache_path)?
+                    .remove(dir)
+                    .unwrap_or_default(),
+            }
+        };
+
+        if let Some(kept) = repair_preferred(&mut entries) {
+            warn(format_args!(
+                "{} had more than one preferred remote, keeping '{}'",
+                dir, kept
+            ));
+        }
+        if entries.is_empty() {
+            return Ok(RemoteMap::new());
+        }
+        Ok(RemoteMap::from([(dir.to_string(), entries)]))
+    }
+
+    // Every directory's remotes, and whether they came from `projects/`
+    // rather than `cache.json`
+    fn read_entries(&self, cache_path: &Path) -> Result<(RemoteMap, bool)> {
+        if projects_dir(cache_path).is_dir() {
+            eprintln!("Using cache version {}", read_index(cache_path)?.version);
+            return Ok((read_projects(cache_path)?, true));
+        }
+        Ok((self.read_cache_file(cache_path)?, false))
+    }
+
+    fn read_cache_file(&self, cache_path: &Path) -> Result<RemoteMap> {
         if !cache_path.exists() {
             return Ok(RemoteMap::new());
         }
@@ -127,51 +409,923 @@ impl MigrationManager {
         let data = fs::read(cache_path).context("Failed to read cache file")?;
 
         // Try parsing as versioned cache first
-        if let Ok(versioned_cache) = serde_json::from_slice::<VersionedCache>(&data) {
-            println!("Using cache version {}", versioned_cache.version);
-
-            // If already at current version, use as is
-            if versioned_cache.version == self.current_version {
-                return Ok(versioned_cache.entries);
+        if let Ok(versioned_cache) = serde_json::from_slice::<RawCache>(&data) {
+            eprintln!("Using cache version {}", versioned_cache.version);
+            if versioned_cache.version != self.current_version {
+                eprintln!(
+                    "Cache version {} migrated to {}",
+                    versioned_cache.version, self.current_version
+                );
             }
-
-            // Future: Add specific version-to-version migrations here
-            println!(
-                "Cache version {} migrated to {}",
-                versioned_cache.version, self.current_version
-            );
-            return Ok(versioned_cache.entries);
+            return upgrade_cache(versioned_cache).map_err(|e| {
+                SyncError::CacheCorrupt {
+                    path: cache_path.to_path_buf(),
+                    message: e.to_string(),
+                }
+                .into()
+            });
         }
 
         // Try each migrator in sequence
         for migrator in &self.migrators {
             if migrator.can_migrate(&data) {
-                println!("Found compatible migrator: {}", migrator.version());
+                eprintln!("Found compatible migrator: {}", migrator.version());
                 return migrator.migrate(&data, cache_path);
             }
         }
 
         // If no migrator works, log and return empty cache
-        eprintln!("Warning: Could not migrate cache, creating new one");
+        warn(format_args!("Could not migrate cache, creating new one"));
         Ok(RemoteMap::new())
     }
 
+    // Change the cache with `f` while holding a lock on it, so that processes
+    // adding remotes at the same time see each other's entries (and pick
+    // different names). `cache` is reloaded first and left as saved.
+    pub fn update_cache<T>(
+        &self,
+        cache_path: &Path,
+        cache: &mut RemoteMap,
+        f: impl FnOnce(&mut RemoteMap) -> Result<T>,
+    ) -> Result<T> {
+        let _lock = FileLock::acquire(cache_path)?;
+        *cache = self.read_cache(cache_path)?;
+        let result = f(cache)?;
+        self.save_cache(cache_path, cache)?;
+        Ok(result)
+    }
+
+    // Write the projects changed since `read_cache`. Directories without
+    // remotes aren't worth keeping, so theirs are removed.
     pub fn save_cache(&self, cache_path: &Path, entries: &RemoteMap) -> Result<()> {
-        let cache = VersionedCache {
-            version: self.current_version.clone(),
-            entries: entries.clone(),
+        let mut as_read = self.as_read.lock().unwrap();
+        let mut saved = HashMap::new();
+        let mut changed = BTreeMap::new();
+
+        for (dir, remotes) in entries.iter().filter(|(_, remotes)| !remotes.is_empty()) {
+            // Only the few with more than one preferred remote are copied to be repaired
+            let remotes = if remotes.iter().filter(|e| e.preferred).count() > 1 {
+                let mut remotes = remotes.clone();
+                repair_preferred(&mut remotes);
+                Cow::Owned(remotes)
+            } else {
+                Cow::Borrowed(remotes.as_slice())
+            };
+            let data = self.project_data(dir, &remotes)?;
+            if as_read.get(dir) != Some(&data) {
+                changed.insert(dir.as_str(), data.clone());
+            }
+            saved.insert(dir.clone(), data);
+        }
+        // Directories that weren't read may have been added by another
+        // process since, so only those that were can have been removed
+        let removed: Vec<&str> = as_read
+            .keys()
+            .filter(|dir| !saved.contains_key(*dir))
+            .map(String::as_str)
+            .collect();
+
+        write_projects(cache_path, &self.current_version, &changed, &removed, true)?;
+        *as_read = saved;
+        Ok(())
+    }
+
+    fn project_data(&self, dir: &str, remotes: &[RemoteEntry]) -> Result<Vec<u8>> {
+        let version = self.current_version.as_str();
+        let project = ProjectFileRef {
+            version,
+            dir,
+            remotes: remotes
+                .iter()
+                .map(|entry| StampedEntry { version, entry })
+                .collect(),
         };
+        serde_json::to_vec_pretty(&project).context("Failed to write cache file")
+    }
+}
+
+pub fn projects_dir(cache_path: &Path) -> PathBuf {
+    cache_path.with_file_name("projects")
+}
 
-        let file = File::create(cache_path).context("Failed to create cache file")?;
-        serde_json::to_writer_pretty(file, &cache).context("Failed to write cache file")
+fn index_path(cache_path: &Path) -> PathBuf {
+    projects_dir(cache_path).join("index.json")
+}
+
+// The index, or an empty one if it's missing. One left broken, e.g. by a
+// merge conflict, is rebuilt as projects are saved, as every file names its
+// directory.
+fn read_index(cache_path: &Path) -> Result<ProjectIndex> {
+    let path = index_path(cache_path);
+    if !path.exists() {
+        return Ok(ProjectIndex::default());
     }
+    let data = fs::read(&path).context("Failed to read project index")?;
+    Ok(serde_json::from_slice(&data).unwrap_or_else(|e| {
+        warn(format_args!("Ignoring {}: {}", path.display(), e));
+        ProjectIndex::default()
+    }))
 }
 
-pub fn get_cache_path() -> Result<PathBuf> {
+// A project file, or None if it's gone
+fn read_project(path: &Path) -> Result<Option<ProjectFile>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+    };
+    serde_json::from_slice(&data)
+        .and_then(|project: RawProjectFile| {
+            Ok(ProjectFile {
+                remotes: upgrade_entries(&project.version, project.remotes)?,
+                dir: project.dir,
+            })
+        })
+        .map(Some)
+        .map_err(|e| {
+            SyncError::CacheCorrupt {
+                path: path.to_path_buf(),
+                message: e.to_string(),
+            }
+            .into()
+        })
+}
+
+fn upgrade_cache(cache: RawCache) -> serde_json::Result<RemoteMap> {
+    cache
+        .entries
+        .into_iter()
+        .map(|(dir, remotes)| Ok((dir, upgrade_entries(&cache.version, remotes)?)))
+        .collect()
+}
+
+// Every project's remotes. The files are listed rather than looked up in
+// the index, so that projects added on two machines sharing their config
+// are all found even when the two indexes conflicted.
+fn read_projects(cache_path: &Path) -> Result<RemoteMap> {
+    let index = index_path(cache_path);
+    let mut cache = RemoteMap::new();
+    let files = fs::read_dir(projects_dir(cache_path)).context("Failed to read projects")?;
+    for file in files.flatten() {
+        let path = file.path();
+        if path == index || path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match read_project(&path) {
+            Ok(Some(project)) => {
+                cache.insert(project.dir, project.remotes);
+            }
+            Ok(None) => {}
+            Err(e) => warn(format_args!("Skipping {:#}", e)),
+        }
+    }
+    Ok(cache)
+}
+
+// File names a project's file may have: a hash of its directory, with a
+// number added in the unlikely case that another directory has the same hash
+fn project_file_names(dir: &str) -> impl Iterator<Item = String> {
+    // FNV-1a, which unlike std's hasher is the same on every machine and release
+    let hash = dir.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    (1..).map(move |n| match n {
+        1 => format!("{:016x}.json", hash),
+        n => format!("{:016x}-{}.json", hash, n),
+    })
+}
+
+// The file holding `dir`'s remotes, if there is one
+fn find_project_file(cache_path: &Path, index: &ProjectIndex, dir: &str) -> Option<String> {
+    let projects = projects_dir(cache_path);
+    if let Some(file) = index.projects.get(dir) {
+        if projects.join(file).exists() {
+            return Some(file.clone());
+        }
+    }
+    // Not in the index, or the index is out of date
+    project_file_names(dir)
+        .take_while(|file| projects.join(file).exists())
+        .find(|file| {
+            read_project(&projects.join(file))
+                .ok()
+                .flatten()
+                .is_some_and(|project| project.dir == dir)
+        })
+}
+
+// Replace a file in one step, so a concurrent reader never sees it half written
+fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp_path).context("Failed to create cache file")?;
+    file.write_all(data).context("Failed to write cache file")?;
+    fs::rename(&tmp_path, path).context("Failed to save cache file")
+}
+
+// Write the `changed` projects' files and remove the `removed` ones',
+// keeping the index up to date. A `cache.json` from before projects had
+// files of their own is moved over by the first write.
+fn write_projects(
+    cache_path: &Path,
+    version: &str,
+    changed: &BTreeMap<&str, Vec<u8>>,
+    removed: &[&str],
+    record_history: bool,
+) -> Result<()> {
+    let projects = projects_dir(cache_path);
+    let migrating = !projects.is_dir() && cache_path.exists();
+    fs::create_dir_all(&projects).context("Failed to create projects directory")?;
+
+    // The index is shared by every project, so it's changed under a lock
+    let _lock = FileLock::acquire(&index_path(cache_path))?;
+    let mut index = read_index(cache_path)?;
+    let old_index = serde_json::to_vec_pretty(&index).unwrap_or_default();
+
+    let mut writes = Vec::new();
+    for (&dir, data) in changed {
+        let file = match find_project_file(cache_path, &index, dir) {
+            // Nothing to do if another process made the same change
+            Some(file) if fs::read(projects.join(&file)).is_ok_and(|old| old == *data) => {
+                index.projects.insert(dir.to_string(), file);
+                continue;
+            }
+            Some(file) => file,
+            None => project_file_names(dir)
+                .find(|file| {
+                    !projects.join(file).exists() && !index.projects.values().any(|f| f == file)
+                })
+                .unwrap_or_default(),
+        };
+        index.projects.insert(dir.to_string(), file.clone());
+        writes.push((file, data));
+    }
+    let mut stale = Vec::new();
+    for &dir in removed {
+        if let Some(file) = find_project_file(cache_path, &index, dir) {
+            stale.push(file);
+        }
+        index.projects.remove(dir);
+    }
+    if writes.is_empty() && stale.is_empty() && !migrating {
+        return Ok(());
+    }
+
+    if record_history {
+        push_history(cache_path, migrating)?;
+    }
+    for (file, data) in writes {
+        write_file(&projects.join(file), data)?;
+    }
+    index.version = version.to_string();
+    let data = serde_json::to_vec_pretty(&index).context("Failed to write project index")?;
+    if data != old_index {
+        write_file(&index_path(cache_path), &data)?;
+    }
+    for file in stale {
+        fs::remove_file(projects.join(file)).context("Failed to remove project file")?;
+    }
+    if migrating {
+        fs::remove_file(cache_path).context("Failed to remove old cache file")?;
+    }
+    Ok(())
+}
+
+// An exclusive lock on a lock file next to the cache or state file,
+// released when dropped
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    pub(crate) fn acquire(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path.with_extension("lock")).context("Failed to create lock file")?;
+        // SAFETY: flock on a descriptor we own; closing it releases the lock
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("Failed to lock {}", path.display()));
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+pub fn get_config_dir() -> Result<PathBuf> {
     let config_dir = dirs::config_dir().context("Failed to find config directory")?;
     let cache_dir = config_dir.join("sync-rs");
     if !cache_dir.exists() {
         fs::create_dir_all(&cache_dir).context("Failed to create cache directory")?;
     }
-    Ok(cache_dir.join("cache.json"))
+    Ok(cache_dir)
+}
+
+pub fn get_cache_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("cache.json"))
+}
+
+// Key for a local path in the cache, state and other JSON files. Paths that
+// aren't valid UTF-8 can't be used as they are, so their key starts with `%`
+// and has the invalid bytes (and any `%`) percent-encoded. Absolute paths
+// never start with `%`, so the two kinds of keys can't clash.
+pub fn path_key(path: &Path) -> String {
+    if let Some(path) = path.to_str() {
+        return path.to_string();
+    }
+    let mut key = String::from("%");
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        key.push_str(&chunk.valid().replace('%', "%25"));
+        for byte in chunk.invalid() {
+            key.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    key
+}
+
+// The path a key from `path_key` stands for
+pub fn key_path(key: &str) -> PathBuf {
+    let Some(encoded) = key.strip_prefix('%') else {
+        return PathBuf::from(key);
+    };
+    let mut bytes = Vec::new();
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .filter(|_| byte == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(escaped) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+// Make `name` the preferred remote among `entries`, so that it's used
+// without asking. A directory has at most one preferred remote, so the flag
+// is taken off the others.
+pub fn set_preferred(entries: &mut [RemoteEntry], name: &str) {
+    for entry in entries {
+        entry.preferred = entry.name == name;
+    }
+}
+
+// Keep only the first of several preferred remotes, returning its name if
+// any others had to be cleared
+pub fn repair_preferred(entries: &mut [RemoteEntry]) -> Option<String> {
+    let mut preferred = entries.iter_mut().filter(|e| e.preferred);
+    let kept = preferred.next()?.name.clone();
+    let mut repaired = false;
+    for entry in preferred {
+        entry.preferred = false;
+        repaired = true;
+    }
+    repaired.then_some(kept)
+}
+
+// Check the cache file against its schema and the entries against the
+// filesystem, returning a description of every problem found
+pub fn validate_cache(cache_path: &Path) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let projects = projects_dir(cache_path);
+    let cache = if projects.is_dir() {
+        let index_path = index_path(cache_path);
+        let index = match fs::read(&index_path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                problems.push(format!("Not a valid project index: {}", e));
+                ProjectIndex::default()
+            }),
+            Err(_) => {
+                problems.push("The project index is missing".to_string());
+                ProjectIndex::default()
+            }
+        };
+
+        let mut cache = RemoteMap::new();
+        let mut files: Vec<PathBuf> = fs::read_dir(&projects)
+            .context("Failed to read projects")?
+            .flatten()
+            .map(|file| file.path())
+            .filter(|path| *path != index_path && path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        files.sort();
+        for path in files {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            match read_project(&path) {
+                Ok(Some(project)) => {
+                    if index.projects.get(&project.dir).map(String::as_str) != Some(&*name) {
+                        problems.push(format!("{}: not in the project index", project.dir));
+                    }
+                    cache.insert(project.dir, project.remotes);
+                }
+                Ok(None) => {}
+                Err(e) => problems.push(format!("Not a valid project file: {:#}", e)),
+            }
+        }
+        for (dir, file) in &index.projects {
+            if !projects.join(file).exists() {
+                problems.push(format!("{}: project file {} is missing", dir, file));
+            }
+        }
+        cache
+    } else if cache_path.exists() {
+        let data = fs::read(cache_path).context("Failed to read cache file")?;
+        match serde_json::from_slice::<RawCache>(&data).and_then(upgrade_cache) {
+            Ok(cache) => cache,
+            Err(e) => return Ok(vec![format!("Not a valid cache file: {}", e)]),
+        }
+    } else {
+        return Ok(Vec::new());
+    };
+
+    let mut dirs: Vec<&String> = cache.keys().collect();
+    dirs.sort();
+
+    for dir in dirs {
+        let entries = &cache[dir];
+        let dir_path = key_path(dir);
+        if !entries.is_empty() && !dir_path.is_dir() {
+            problems.push(format!("{}: directory no longer exists", dir));
+        }
+
+        if entries.iter().filter(|e| e.preferred).count() > 1 {
+            problems.push(format!("{}: more than one preferred remote", dir));
+        }
+
+        for (i, entry) in entries.iter().enumerate() {
+            let at = format!("{} [{}]", dir, entry.name);
+
+            if let Some(problem) = name_problem(&entry.name) {
+                problems.push(format!("{}: unusable remote name, {}", at, problem));
+            }
+            if entries[..i].iter().any(|e| e.name == entry.name) {
+                problems.push(format!("{}: duplicate remote name", at));
+            }
+            if entry.remote_host.is_empty() {
+                problems.push(format!("{}: remote host is empty", at));
+            }
+            if entry.remote_dir.is_empty() {
+                problems.push(format!("{}: remote directory is empty", at));
+            }
+
+            // Missing files only matter while the project itself is there
+            if dir_path.is_dir() {
+                for path in &entry.override_paths {
+                    if !dir_path.join(path).exists() {
+                        problems.push(format!("{}: override path '{}' does not exist", at, path));
+                    }
+                }
+                for template in &entry.templates {
+                    if !dir_path.join(&template.source).is_file() {
+                        problems.push(format!(
+                            "{}: template '{}' does not exist",
+                            at, template.source
+                        ));
+                    }
+                }
+            }
+
+            for window in &entry.bandwidth_windows {
+                if let Err(e) = BandwidthWindow::parse(window) {
+                    problems.push(format!("{}: {:#}", at, e));
+                }
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+// Number of previous cache states kept for `sync-rs undo`
+pub const UNDO_DEPTH: usize = 10;
+
+fn history_dir(cache_path: &Path) -> PathBuf {
+    cache_path.with_file_name("history")
+}
+
+// Previous cache states, oldest first
+fn history(cache_path: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(history_dir(cache_path))
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    files.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    files.sort();
+    files
+}
+
+// Keep a copy of every project's remotes as they are now, dropping the
+// oldest beyond UNDO_DEPTH
+fn push_history(cache_path: &Path, migrating: bool) -> Result<()> {
+    let snapshot = if migrating {
+        fs::read(cache_path).context("Failed to read cache file")?
+    } else {
+        let index = read_index(cache_path)?;
+        let cache = read_projects(cache_path)?;
+        serde_json::to_vec_pretty(&VersionedCacheRef {
+            version: &index.version,
+            entries: cache
+                .iter()
+                .map(|(dir, remotes)| (dir.as_str(), remotes.as_slice()))
+                .collect(),
+        })
+        .context("Failed to save cache history")?
+    };
+
+    let dir = history_dir(cache_path);
+    fs::create_dir_all(&dir).context("Failed to create cache history directory")?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    // Zero-padded so that names sort by time
+    fs::write(dir.join(format!("cache-{:016}.json", millis)), snapshot)
+        .context("Failed to save cache history")?;
+
+    let files = history(cache_path);
+    for old in &files[..files.len().saturating_sub(UNDO_DEPTH)] {
+        let _ = fs::remove_file(old);
+    }
+
+    Ok(())
+}
+
+// Put back the cache as it was before the most recent change. Returns false
+// if there is nothing left to undo.
+pub fn undo_last_change(cache_path: &Path) -> Result<bool> {
+    let Some(previous) = history(cache_path).pop() else {
+        return Ok(false);
+    };
+    let data = fs::read(&previous).context("Failed to read cache history")?;
+    let snapshot: VersionedCache =
+        serde_json::from_slice(&data).context("Failed to read cache history")?;
+
+    // Every project goes back to how it was, including those added since
+    let manager = MigrationManager::new(snapshot.version.clone());
+    let mut changed = BTreeMap::new();
+    for (dir, remotes) in &snapshot.entries {
+        changed.insert(dir.as_str(), manager.project_data(dir, remotes)?);
+    }
+    let current = if projects_dir(cache_path).is_dir() {
+        read_projects(cache_path)?
+    } else {
+        RemoteMap::new()
+    };
+    let removed: Vec<&str> = current
+        .keys()
+        .filter(|dir| !snapshot.entries.contains_key(*dir))
+        .map(String::as_str)
+        .collect();
+    write_projects(cache_path, &snapshot.version, &changed, &removed, false)?;
+
+    fs::remove_file(&previous).context("Failed to update cache history")?;
+    Ok(true)
+}
+
+// Human-readable list of the remotes that differ between two caches
+pub fn describe_changes(from: &RemoteMap, to: &RemoteMap) -> Vec<String> {
+    let dirs: BTreeSet<&String> = from.keys().chain(to.keys()).collect();
+    let mut changes = Vec::new();
+
+    for dir in dirs {
+        let before = from.get(dir).map(Vec::as_slice).unwrap_or_default();
+        let after = to.get(dir).map(Vec::as_slice).unwrap_or_default();
+        let names: BTreeSet<&String> = before.iter().chain(after).map(|e| &e.name).collect();
+
+        for name in names {
+            let old = before.iter().find(|e| &e.name == name);
+            let new = after.iter().find(|e| &e.name == name);
+            let change = match (old, new) {
+                (None, Some(_)) => "added",
+                (Some(_), None) => "removed",
+                (Some(old), Some(new))
+                    if serde_json::to_value(old).ok() != serde_json::to_value(new).ok() =>
+                {
+                    "changed"
+                }
+                _ => continue,
+            };
+            changes.push(format!("{} {} ({})", change, name, dir));
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::generate_unique_name;
+    use std::thread;
+
+    fn entries(preferred: &[bool]) -> Vec<RemoteEntry> {
+        preferred
+            .iter()
+            .enumerate()
+            .map(|(i, &preferred)| RemoteEntry {
+                name: format!("r{}", i),
+                preferred,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn preferred(entries: &[RemoteEntry]) -> Vec<bool> {
+        entries.iter().map(|e| e.preferred).collect()
+    }
+
+    #[test]
+    fn only_one_remote_is_preferred() {
+        let mut remotes = entries(&[true, false, false]);
+        set_preferred(&mut remotes, "r2");
+        assert_eq!(preferred(&remotes), [false, false, true]);
+
+        let mut remotes = entries(&[false, true, true]);
+        assert_eq!(repair_preferred(&mut remotes), Some("r1".to_string()));
+        assert_eq!(preferred(&remotes), [false, true, false]);
+        assert_eq!(repair_preferred(&mut remotes), None);
+    }
+
+    #[test]
+    fn non_utf8_paths_get_their_own_keys() {
+        assert_eq!(path_key(Path::new("/home/me/100%")), "/home/me/100%");
+        assert_eq!(key_path("/home/me/100%"), Path::new("/home/me/100%"));
+
+        // Latin-1 `café`, as left behind by old tools, next to a literal `%E9`
+        let latin1 = PathBuf::from(OsString::from_vec(b"/srv/caf\xe9/%E9".to_vec()));
+        let key = path_key(&latin1);
+        assert_eq!(key, "%/srv/caf%E9/%25E9");
+        assert_eq!(key_path(&key), latin1);
+        assert_ne!(key, path_key(Path::new("/srv/caf%E9/%E9")));
+    }
+
+    #[test]
+    fn one_directory_is_read_without_the_others() {
+        let dir =
+            std::env::temp_dir().join(format!("sync-rs-dir-cache-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("cache.json");
+        let manager = MigrationManager::new("test".to_string());
+        let cache = RemoteMap::from([
+            ("/a".to_string(), entries(&[true, true])),
+            ("/b".to_string(), entries(&[false])),
+        ]);
+        manager.save_cache(&cache_path, &cache).unwrap();
+
+        let read = manager.read_dir_cache(&cache_path, "/a").unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read["/a"].len(), 2);
+        assert!(read["/a"][0].preferred && !read["/a"][1].preferred);
+        assert!(manager
+            .read_dir_cache(&cache_path, "/none")
+            .unwrap()
+            .is_empty());
+
+        // Older formats are still migrated
+        fs::remove_dir_all(projects_dir(&cache_path)).unwrap();
+        fs::write(
+            &cache_path,
+            r#"{"/a": {"remote_host": "box", "remote_dir": "app"}}"#,
+        )
+        .unwrap();
+        let read = manager.read_dir_cache(&cache_path, "/a").unwrap();
+        assert_eq!(read["/a"][0].remote_host, "box");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remotes_are_upgraded_one_at_a_time() {
+        // One remote added by hand from an old release, next to a current one
+        let remotes = serde_json::json!([
+            {"version": "0.3.0", "name": "me@box_my notes", "remote_host": "me@box", "remote_dir": "my notes"},
+            {"version": "1.4.0", "name": "gpu", "remote_host": "gpu", "remote_dir": "app"},
+            {"name": "tpu box", "remote_host": "tpu", "remote_dir": "app"},
+        ]);
+        let Value::Array(remotes) = remotes else {
+            unreachable!()
+        };
+        let upgraded = upgrade_entries("1.4.0", remotes.clone()).unwrap();
+        let names: Vec<&str> = upgraded.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["me-box_my-notes", "gpu", "tpu box"]);
+
+        // Remotes without a version of their own are as old as their file
+        let upgraded = upgrade_entries("0.2.0", remotes[2..].to_vec()).unwrap();
+        assert_eq!(upgraded[0].name, "tpu-box");
+        assert!(version_parts("1.10.0") > version_parts("1.9.2"));
+    }
+
+    #[test]
+    fn saves_only_touch_the_projects_changed() {
+        let dir =
+            std::env::temp_dir().join(format!("sync-rs-projects-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("cache.json");
+        fs::write(
+            &cache_path,
+            r#"{"version": "old", "entries": {"/a": [{"name": "a", "remote_host": "box", "remote_dir": "a"}], "/b": []}}"#,
+        )
+        .unwrap();
+
+        // The first save moves the single file's directories over
+        let first = MigrationManager::new("test".to_string());
+        let mut cache = first.read_cache(&cache_path).unwrap();
+        first.save_cache(&cache_path, &cache).unwrap();
+        assert!(!cache_path.exists());
+        let index = read_index(&cache_path).unwrap();
+        assert_eq!(index.projects.keys().collect::<Vec<_>>(), ["/a"]);
+
+        // Another process adds /c while this one still has the old cache
+        let second = MigrationManager::new("test".to_string());
+        let mut other = second.read_cache(&cache_path).unwrap();
+        other.insert("/c".to_string(), entries(&[true]));
+        second.save_cache(&cache_path, &other).unwrap();
+
+        cache.get_mut("/a").unwrap()[0].remote_dir = "changed".to_string();
+        first.save_cache(&cache_path, &cache).unwrap();
+        let read = first.read_cache(&cache_path).unwrap();
+        assert_eq!(read["/a"][0].remote_dir, "changed");
+        assert_eq!(read["/c"].len(), 1);
+
+        // Removing every remote of a directory removes its file
+        let mut cache = read;
+        cache.remove("/c");
+        first.save_cache(&cache_path, &cache).unwrap();
+        assert_eq!(fs::read_dir(projects_dir(&cache_path)).unwrap().count(), 3);
+        assert!(first.read_dir_cache(&cache_path, "/c").unwrap().is_empty());
+
+        // Undo puts /c back
+        assert!(undo_last_change(&cache_path).unwrap());
+        assert_eq!(first.read_cache(&cache_path).unwrap()["/c"].len(), 1);
+        assert!(validate_cache(&cache_path)
+            .unwrap()
+            .iter()
+            .all(|problem| !problem.contains("index") && !problem.contains("project file")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/cache")
+    }
+
+    // The JSON files in `dir` by name
+    fn files(dir: &Path) -> BTreeMap<String, String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .map(|file| file.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                (name, fs::read_to_string(path).unwrap())
+            })
+            .collect()
+    }
+
+    // Every format the cache has had, each holding the same two projects, is
+    // moved over to the files in `tests/fixtures/cache/projects` exactly
+    #[test]
+    fn every_cache_version_upgrades_to_the_same_files() {
+        let fixtures = fixtures();
+        let expected = projects_dir(&fixtures.join("cache.json"));
+
+        for version in ["v0.1.0", "v0.2.0", "v1.4.0", "projects"] {
+            let dir = std::env::temp_dir().join(format!(
+                "sync-rs-fixture-test-{}-{}",
+                version,
+                std::process::id()
+            ));
+            let cache_path = dir.join("cache.json");
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(projects_dir(&cache_path)).unwrap();
+            if version == "projects" {
+                for (name, data) in files(&expected) {
+                    fs::write(projects_dir(&cache_path).join(name), data).unwrap();
+                }
+            } else {
+                fs::remove_dir(projects_dir(&cache_path)).unwrap();
+                fs::copy(fixtures.join(format!("{}.json", version)), &cache_path).unwrap();
+            }
+
+            let manager = MigrationManager::new("1.4.0".to_string());
+            let notes = manager
+                .read_dir_cache(&cache_path, "/home/me/notes")
+                .unwrap();
+            assert_eq!(
+                notes["/home/me/notes"][0].name, "me-box_my-notes",
+                "{}",
+                version
+            );
+            let cache = manager.read_cache(&cache_path).unwrap();
+            manager.save_cache(&cache_path, &cache).unwrap();
+
+            let upgraded = files(&projects_dir(&cache_path));
+            assert_eq!(upgraded, files(&expected), "{}", version);
+            assert!(!cache_path.exists(), "{}", version);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    // Each historical format on its own: legacy v0 (one remote per
+    // directory, no version), the early versioned one and the current one.
+    // All read as the remotes in `projects/`, without the file being changed,
+    // and upgrading what was saved again changes nothing.
+    #[test]
+    fn each_cache_version_reads_as_expected() {
+        let fixtures = fixtures();
+        let expected = read_projects(&fixtures.join("cache.json")).unwrap();
+        let expected = serde_json::to_value(&expected).unwrap();
+        assert_eq!(expected.as_object().unwrap().len(), 2);
+
+        // Only the legacy format is converted by a migrator, which backs it up
+        for (version, backed_up) in [("v0.1.0", true), ("v0.2.0", false), ("v1.4.0", false)] {
+            let dir = std::env::temp_dir().join(format!(
+                "sync-rs-fixture-read-test-{}-{}",
+                version,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let cache_path = dir.join("cache.json");
+            let fixture = fs::read(fixtures.join(format!("{}.json", version))).unwrap();
+            fs::write(&cache_path, &fixture).unwrap();
+
+            let manager = MigrationManager::new("1.4.0".to_string());
+            let cache = manager.read_cache(&cache_path).unwrap();
+            assert_eq!(
+                serde_json::to_value(&cache).unwrap(),
+                expected,
+                "{}",
+                version
+            );
+            assert_eq!(fs::read(&cache_path).unwrap(), fixture, "{}", version);
+            let backup = fs::read(cache_path.with_extension("json.bak")).ok();
+            assert_eq!(backup, backed_up.then(|| fixture.clone()), "{}", version);
+
+            manager.save_cache(&cache_path, &cache).unwrap();
+            let saved = files(&projects_dir(&cache_path));
+            let again = MigrationManager::new("1.4.0".to_string());
+            let cache = again.read_cache(&cache_path).unwrap();
+            assert_eq!(
+                serde_json::to_value(&cache).unwrap(),
+                expected,
+                "{}",
+                version
+            );
+            again.save_cache(&cache_path, &cache).unwrap();
+            assert_eq!(files(&projects_dir(&cache_path)), saved, "{}", version);
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn concurrent_adds_get_unique_names() {
+        let dir = std::env::temp_dir().join(format!("sync-rs-cache-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("cache.json");
+
+        let adders: Vec<_> = (0..8)
+            .map(|_| {
+                let cache_path = cache_path.clone();
+                thread::spawn(move || {
+                    let manager = MigrationManager::new("test".to_string());
+                    let mut cache = RemoteMap::new();
+                    for _ in 0..5 {
+                        manager
+                            .update_cache(&cache_path, &mut cache, |cache| {
+                                let entries = cache.entry("/project".to_string()).or_default();
+                                let name = generate_unique_name("box", "app", entries);
+                                entries.push(RemoteEntry {
+                                    name,
+                                    remote_host: "box".to_string(),
+                                    remote_dir: "app".to_string(),
+                                    ..Default::default()
+                                });
+                                Ok(())
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for adder in adders {
+            adder.join().unwrap();
+        }
+
+        let cache = MigrationManager::new("test".to_string())
+            .read_cache(&cache_path)
+            .unwrap();
+        let mut names: Vec<&str> = cache["/project"].iter().map(|e| e.name.as_str()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 40);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
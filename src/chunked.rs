@@ -0,0 +1,294 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::cache::get_config_dir;
+use crate::sync::{dir_contents, files_from_list, pending_files, sync_directory, RsyncOptions};
+use crate::term::warn;
+
+pub const DEFAULT_CHUNK_SIZE: u64 = 1 << 30;
+
+// Files still to be pushed to a destination, split into chunks. The manifest
+// is saved after every finished chunk so an interrupted transfer resumes
+// with the first unfinished one.
+#[derive(Debug, Serialize, Deserialize)]
+struct TransferManifest {
+    source: String,
+    destination: String,
+    chunks: Vec<Vec<String>>,
+    #[serde(default)]
+    completed: Vec<usize>,
+}
+
+// Push everything `source` would send to `destination` in chunks of about
+// `chunk_size` bytes, resuming a previously interrupted transfer if there is
+// one. Nothing is deleted; a regular sync afterwards takes care of that.
+pub fn chunked_sync(
+    source: &Path,
+    destination: &str,
+    options: &RsyncOptions,
+    chunk_size: u64,
+) -> Result<()> {
+    let source_dir = source.to_string_lossy().to_string();
+    let manifest_path = manifest_path(&source_dir, destination)?;
+    let list_path = manifest_path.with_extension("list");
+
+    let options = RsyncOptions {
+        delete: false,
+        ..options.clone()
+    };
+
+    let mut manifest = match read_manifest(&manifest_path)? {
+        Some(manifest) => {
+            println!(
+                "Resuming chunked transfer: {} of {} chunks already done",
+                manifest.completed.len(),
+                manifest.chunks.len()
+            );
+            manifest
+        }
+        None => {
+            println!("Building transfer manifest...");
+            let files = pending_files(&dir_contents(&source_dir), destination, &options)?;
+            let manifest = TransferManifest {
+                source: source_dir.clone(),
+                destination: destination.to_string(),
+                chunks: split_chunks(files, chunk_size),
+                completed: Vec::new(),
+            };
+            save_manifest(&manifest_path, &manifest)?;
+            manifest
+        }
+    };
+
+    let total = manifest.chunks.len();
+    for index in 0..total {
+        if manifest.completed.contains(&index) {
+            continue;
+        }
+
+        let files = &manifest.chunks[index];
+        println!(
+            "Transferring chunk {}/{} ({} files)",
+            index + 1,
+            total,
+            files.len()
+        );
+
+        fs::write(&list_path, files_from_list(files)).context("Failed to write chunk file list")?;
+        let mut chunk_options = options.clone();
+        chunk_options
+            .extra_args
+            .push(format!("--files-from={}", list_path.display()));
+        sync_directory(&dir_contents(&source_dir), destination, &chunk_options)?;
+
+        manifest.completed.push(index);
+        save_manifest(&manifest_path, &manifest)?;
+    }
+
+    fs::remove_file(&manifest_path).context("Failed to remove transfer manifest")?;
+    if list_path.exists() {
+        fs::remove_file(&list_path).context("Failed to remove chunk file list")?;
+    }
+
+    Ok(())
+}
+
+// Group files in order into chunks of at most `chunk_size` bytes, except where
+// a single file is larger than that
+fn split_chunks(files: Vec<(String, u64)>, chunk_size: u64) -> Vec<Vec<String>> {
+    let mut chunks = Vec::new();
+    let mut current = Vec::new();
+    let mut current_size = 0;
+
+    for (name, size) in files {
+        if !current.is_empty() && current_size + size > chunk_size {
+            chunks.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+        current.push(name);
+        current_size += size;
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+// Manifests live in the config directory, one per source and destination
+fn manifest_path(source: &str, destination: &str) -> Result<PathBuf> {
+    let dir = get_config_dir()?.join("transfers");
+    fs::create_dir_all(&dir).context("Failed to create transfers directory")?;
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in source.bytes().chain([0]).chain(destination.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    Ok(dir.join(format!("{:016x}.json", hash)))
+}
+
+fn read_manifest(path: &Path) -> Result<Option<TransferManifest>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let data = fs::read(path).context("Failed to read transfer manifest")?;
+    match serde_json::from_slice(&data) {
+        Ok(manifest) => Ok(Some(manifest)),
+        Err(_) => {
+            warn(format_args!(
+                "Could not parse transfer manifest, starting over"
+            ));
+            Ok(None)
+        }
+    }
+}
+
+fn save_manifest(path: &Path, manifest: &TransferManifest) -> Result<()> {
+    // Write to a temporary file first so an interruption never leaves a torn manifest
+    let tmp_path = path.with_extension("json.tmp");
+    let file = File::create(&tmp_path).context("Failed to create transfer manifest")?;
+    serde_json::to_writer(file, manifest).context("Failed to write transfer manifest")?;
+    fs::rename(&tmp_path, path).context("Failed to save transfer manifest")
+}
+
+// Bytes as a short human-readable size, e.g. `1.5G`
+pub fn format_size(bytes: u64) -> String {
+    let units = ["K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in units {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    if unit == "B" {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, unit)
+    }
+}
+
+// Parse a size such as `512M` or `10G` into bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => anyhow::bail!("Invalid size '{}'", s),
+    };
+    let number: u64 = number.parse().context(format!("Invalid size '{}'", s))?;
+
+    match number.checked_mul(multiplier) {
+        Some(size) if size > 0 => Ok(size),
+        _ => anyhow::bail!("Invalid size '{}'", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{with_runner, MockRunner};
+    use std::rc::Rc;
+
+    const RSYNC_VERSION: &str = "rsync  version 3.2.7  protocol version 31\n";
+
+    fn files(sizes: &[u64]) -> Vec<(String, u64)> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| (format!("f{}", i), *size))
+            .collect()
+    }
+
+    #[test]
+    fn files_are_split_in_order_by_size() {
+        assert!(split_chunks(Vec::new(), 10).is_empty());
+        assert_eq!(split_chunks(files(&[3, 3, 3]), 10), [["f0", "f1", "f2"]]);
+        assert_eq!(
+            split_chunks(files(&[6, 4, 1, 10, 0]), 10),
+            [vec!["f0", "f1"], vec!["f2"], vec!["f3", "f4"]]
+        );
+        // A file larger than a chunk gets one of its own
+        assert_eq!(
+            split_chunks(files(&[25, 1, 30]), 10),
+            [["f0"], ["f1"], ["f2"]]
+        );
+    }
+
+    #[test]
+    fn sizes_parse_and_format() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size(" 10gb ").unwrap(), 10 << 30);
+        assert_eq!(parse_size("1T").unwrap(), 1 << 40);
+        for invalid in ["", "0", "G", "1.5G", "10X", "-1", "99999999999T"] {
+            assert!(parse_size(invalid).is_err(), "{:?}", invalid);
+        }
+        assert_eq!(format_size(1000), "1000B");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(DEFAULT_CHUNK_SIZE), "1.0G");
+    }
+
+    #[test]
+    fn manifests_are_per_source_and_destination() {
+        let path = manifest_path("/src/a", "box:/dst").unwrap();
+        assert_eq!(path, manifest_path("/src/a", "box:/dst").unwrap());
+        assert_ne!(path, manifest_path("/src/b", "box:/dst").unwrap());
+        // The separator keeps shifted boundaries apart
+        assert_ne!(
+            manifest_path("/src/ab", "c").unwrap(),
+            manifest_path("/src/a", "bc").unwrap()
+        );
+    }
+
+    #[test]
+    fn chunks_are_sent_and_interrupted_transfers_resume() {
+        let source = Path::new("/tmp/sync-rs-chunked-source");
+        let destination = format!("box:/data-{}", std::process::id());
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, RSYNC_VERSION, "")
+                .reply(0, "600 a.bin\n500 b.bin\n100 c.bin\n", "")
+                .reply(0, RSYNC_VERSION, "")
+                .reply(0, "", "")
+                .reply(0, RSYNC_VERSION, "")
+                .reply(23, "", "connection lost"),
+        );
+        let interrupted = with_runner(runner.clone(), || {
+            chunked_sync(source, &destination, &RsyncOptions::default(), 1000)
+        });
+        assert!(interrupted.is_err());
+        let commands = runner.commands();
+        assert!(commands[1].contains("--dry-run"));
+        assert!(commands[3].contains("--files-from="));
+        let manifest_path = manifest_path(&source.to_string_lossy(), &destination).unwrap();
+        let manifest = read_manifest(&manifest_path).unwrap().unwrap();
+        assert_eq!(manifest.chunks, [vec!["a.bin"], vec!["b.bin", "c.bin"]]);
+        assert_eq!(manifest.completed, [0]);
+
+        // Resuming skips the manifest and the finished chunk
+        let runner = Rc::new(MockRunner::default().reply(0, RSYNC_VERSION, ""));
+        with_runner(runner.clone(), || {
+            chunked_sync(source, &destination, &RsyncOptions::default(), 1000)
+        })
+        .unwrap();
+        assert_eq!(runner.commands().len(), 2);
+        assert!(!runner.commands()[1].contains("--dry-run"));
+        assert!(!manifest_path.exists());
+        assert!(!manifest_path.with_extension("list").exists());
+    }
+}
@@ -1,55 +1,286 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::chunked::format_size;
+use crate::glob::glob_match;
+use crate::i18n::tr;
+use crate::nested::NestedRepos;
+use crate::net::SshHost;
+use crate::remote::RemoteTarget;
+use crate::state::RemoteState;
+use crate::statusline::last_sync;
+use crate::template::TemplateSpec;
+use crate::term::{bold, dim, green, require_interactive, warn, yellow};
+use crate::transport::TransportKind;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct RemoteEntry {
     pub name: String,
     pub remote_host: String,
     pub remote_dir: String,
+    // Free-form note shown next to the name, e.g. "A100 box, use for big runs"
+    #[serde(default)]
+    pub description: Option<String>,
+    // Other names the remote answers to, e.g. `g` for `gpu-box`
+    #[serde(default)]
+    pub aliases: Vec<String>,
     #[serde(default)]
     pub override_paths: Vec<String>,
     #[serde(default)]
     pub post_sync_command: Option<String>,
     #[serde(default)]
     pub preferred: bool,
+    // Free-form labels for grouping remotes, e.g. `staging`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Kept for later but never picked, e.g. while the server is down for maintenance
+    #[serde(default)]
+    pub disabled: bool,
     #[serde(default)]
     pub ignore_patterns: Vec<String>,
+    // Paths generated on the remote (checkpoints, logs) that syncs must never touch
+    #[serde(default)]
+    pub protected_paths: Vec<String>,
+    #[serde(default)]
+    pub templates: Vec<TemplateSpec>,
+    #[serde(default)]
+    pub template_vars: BTreeMap<String, String>,
+    // Extra ssh `-o KEY=VALUE` options, e.g. Port or IdentityFile
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+    // Command resolving the host at sync time, for machines with changing addresses
+    #[serde(default)]
+    pub host_command: Option<String>,
+    // Other addresses of the same machine, e.g. a LAN IP and a tailnet name
+    #[serde(default)]
+    pub alternate_hosts: Vec<String>,
+    #[serde(default)]
+    pub tailscale: bool,
+    // MAC address to wake the machine with when it's not reachable
+    #[serde(default)]
+    pub wol_mac: Option<String>,
+    // Seconds to wait for SSH after waking the machine
+    #[serde(default)]
+    pub wol_timeout: Option<u64>,
+    // Time-of-day bandwidth limits, see `schedule::BandwidthWindow`
+    #[serde(default)]
+    pub bandwidth_windows: Vec<String>,
+    // Files at least this large go through the remote's shared blob store
+    #[serde(default)]
+    pub dedup_min_size: Option<u64>,
+    // Send large batches of changed files as one tar stream before rsync
+    #[serde(default)]
+    pub tar_stream: bool,
+    // Keep rsync's defaults instead of adapting them to the measured link
+    #[serde(default)]
+    pub fixed_strategy: bool,
+    // Plugins (`sync-rs-<name>` on PATH) run as pre- and post-sync hooks
+    #[serde(default)]
+    pub plugins: Vec<String>,
+    // Refuse syncs that would delete more files than this on the remote
+    #[serde(default)]
+    pub max_deletes: Option<usize>,
+    // Command template used instead of ssh to reach the host, e.g. `tsh ssh {host}`
+    #[serde(default)]
+    pub connect_command: Option<String>,
+    // healthchecks.io-style URL pinged when syncs start, succeed and fail
+    #[serde(default)]
+    pub healthcheck_url: Option<String>,
+    // Patterns of files whose changes call for the post-sync command. Without
+    // any, any change does.
+    #[serde(default)]
+    pub post_command_triggers: Vec<String>,
+    // Run the post-sync command at most once per this many seconds
+    #[serde(default)]
+    pub post_command_cooldown: Option<u64>,
+    // When the cooldown holds the command back, run it once syncs have been
+    // quiet for a full cooldown instead of dropping it
+    #[serde(default)]
+    pub post_command_coalesce: bool,
+    // Create the remote directory (and its parents) when it's missing, for
+    // syncs as well as post-sync commands
+    #[serde(default)]
+    pub auto_create: bool,
+    // Group that synced files should belong to and be writable by, for
+    // project directories shared with teammates
+    #[serde(default)]
+    pub shared_group: Option<String>,
+    // Also give the group default ACLs on every directory, so that files
+    // teammates create are writable by the group too
+    #[serde(default)]
+    pub shared_acl: bool,
+    // Shell the post-sync command runs in, e.g. `bash` or `zsh -l`, instead of
+    // whatever the remote user's login shell makes of it
+    #[serde(default)]
+    pub remote_shell: Option<String>,
+    // SSH host the remote is reached through when it can't be reached
+    // directly, e.g. a bastion. Files are staged there and forwarded.
+    #[serde(default)]
+    pub relay: Option<String>,
+    // Remove the staged copy from the relay once it has been forwarded
+    #[serde(default)]
+    pub relay_cleanup: bool,
+    // Bytes a month that syncs and pulls may move before warnings start, for
+    // hosts with metered traffic
+    #[serde(default)]
+    pub monthly_budget: Option<u64>,
+    // Move files that syncs delete or overwrite on the remote into a dated
+    // directory under `.sync-rs-trash` instead of losing them
+    #[serde(default)]
+    pub trash: bool,
+    // rsync `--usermap`/`--groupmap` rules (`FROM:TO`) giving synced files
+    // other owners on the remote, e.g. a service user
+    #[serde(default)]
+    pub usermap: Vec<String>,
+    #[serde(default)]
+    pub groupmap: Vec<String>,
+    // Run rsync through `sudo` on the remote, which changing owners needs
+    // unless the login is root
+    #[serde(default)]
+    pub sudo_rsync: bool,
+    // What to do with git repositories inside the directory, like submodules
+    #[serde(default)]
+    pub nested_repos: NestedRepos,
+    // Only sync submodules that are initialized here
+    #[serde(default)]
+    pub initialized_submodules_only: bool,
+    // Compare the remote's `git submodule status` with the local one after
+    // syncing and warn about differences
+    #[serde(default)]
+    pub check_submodules: bool,
+    // How files get there: rsync, SFTP or tar, or picked by what the remote has
+    #[serde(default)]
+    pub transport: TransportKind,
+}
+
+impl RemoteEntry {
+    // Whether `name` is the remote's name or one of its aliases
+    pub fn is_called(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|alias| alias == name)
+    }
+}
+
+// Make sure the aliases of `entry` can be used as names: each must be valid,
+// not `reserved` and not the name or an alias of another remote in `entries`
+pub fn check_aliases(
+    entries: &[RemoteEntry],
+    entry: &RemoteEntry,
+    reserved: &[String],
+) -> Result<()> {
+    for alias in &entry.aliases {
+        if reserved.contains(alias) {
+            anyhow::bail!(
+                "'{}' can't be an alias, it is a sync-rs subcommand or option",
+                alias
+            );
+        }
+        if let Some(problem) = name_problem(alias) {
+            anyhow::bail!("'{}' can't be an alias: {}", alias, problem);
+        }
+        if *alias == entry.name {
+            anyhow::bail!("'{}' is already the remote's name", alias);
+        }
+        if let Some(other) = entries
+            .iter()
+            .find(|e| e.name != entry.name && e.is_called(alias))
+        {
+            anyhow::bail!("'{}' is already used by remote '{}'", alias, other.name);
+        }
+    }
+    Ok(())
 }
 
 pub fn prompt_remote_info() -> Result<(String, String)> {
+    require_interactive("Asking for the remote host")?;
     let mut remote_host = String::new();
-    let mut remote_dir = String::new();
 
-    print!("Enter remote host (e.g., user@host): ");
+    print!("{}", tr("prompt.remote_host", &[]));
     io::stdout().flush()?;
     io::stdin().read_line(&mut remote_host)?;
 
-    print!("Enter remote directory (relative to remote home): ");
+    Ok((remote_host.trim().to_string(), prompt_remote_dir()?))
+}
+
+pub fn prompt_remote_dir() -> Result<String> {
+    require_interactive("Asking for the remote directory")?;
+    let mut remote_dir = String::new();
+
+    print!("{}", tr("prompt.remote_dir", &[]));
     io::stdout().flush()?;
     io::stdin().read_line(&mut remote_dir)?;
 
-    Ok((
-        remote_host.trim().to_string(),
-        remote_dir.trim().to_string(),
+    Ok(normalize_remote_dir(&remote_dir))
+}
+
+// Canonical form of a remote directory as typed, so that `proj`, `proj/`,
+// `./proj` and `~/proj` all name the same place instead of creating separate
+// entries or nested copies. Relative paths stay relative to the remote home,
+// which is `.` on its own.
+pub fn normalize_remote_dir(remote_dir: &str) -> String {
+    let remote_dir = remote_dir.trim();
+    let (absolute, rest) = match remote_dir.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (
+            false,
+            match remote_dir {
+                "~" => "",
+                _ => remote_dir.strip_prefix("~/").unwrap_or(remote_dir),
+            },
+        ),
+    };
+
+    let parts: Vec<&str> = rest
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    match (absolute, parts.is_empty()) {
+        (true, _) => format!("/{}", parts.join("/")),
+        (false, true) => ".".to_string(),
+        (false, false) => parts.join("/"),
+    }
+}
+
+// Full path of an entry's directory on the remote, without a trailing slash
+pub fn remote_full_dir(remote_home: &str, remote_dir: &str) -> String {
+    let remote_dir = normalize_remote_dir(remote_dir);
+    if remote_dir.starts_with('/') {
+        remote_dir
+    } else if remote_dir == "." {
+        remote_home.to_string()
+    } else {
+        format!("{}/{}", remote_home.trim_end_matches('/'), remote_dir)
+    }
+}
+
+// Ask a yes/no question, with yes as the default
+pub fn confirm(question: &str) -> Result<bool> {
+    require_interactive(&format!("Confirming '{}'", question))?;
+    let mut answer = String::new();
+
+    print!("{} [Y/n]: ", question);
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(
+        answer.trim().to_lowercase().as_str(),
+        "" | "y" | "yes"
     ))
 }
 
-pub fn select_remote(entries: &[RemoteEntry]) -> Result<String> {
-    println!("Multiple remote configurations found. Please select one:");
+// Let the user pick one of several options, returning its index
+pub fn select_from(header: &str, options: &[String]) -> Result<usize> {
+    require_interactive("Selecting an option")?;
+    println!("{}", header);
 
-    for (i, entry) in entries.iter().enumerate() {
-        println!(
-            "{}: {} ({}:{})",
-            i + 1,
-            entry.name,
-            entry.remote_host,
-            entry.remote_dir
-        );
+    for (i, option) in options.iter().enumerate() {
+        println!("{}: {}", i + 1, option);
     }
 
     let mut selection = String::new();
-    print!("Enter selection (1-{}): ", entries.len());
+    print!("{}", tr("prompt.selection", &[&options.len()]));
     io::stdout().flush()?;
     io::stdin().read_line(&mut selection)?;
 
@@ -57,86 +288,824 @@ pub fn select_remote(entries: &[RemoteEntry]) -> Result<String> {
         .trim()
         .parse::<usize>()
         .context("Invalid selection")?
-        - 1;
+        .checked_sub(1)
+        .context("Selection out of range")?;
 
-    if index >= entries.len() {
+    if index >= options.len() {
         anyhow::bail!("Selection out of range");
     }
 
+    Ok(index)
+}
+
+// When each remote was last synced, e.g. `synced 2h ago ✓`, from the state of
+// the directory's remotes
+fn sync_note(entry: &RemoteEntry, states: Option<&HashMap<String, RemoteState>>) -> String {
+    match states
+        .and_then(|states| states.get(&entry.name))
+        .and_then(last_sync)
+    {
+        Some(ago) => tr("list.last_sync", &[&ago]),
+        None => tr("list.never_synced", &[]),
+    }
+}
+
+pub fn select_remote(
+    entries: &[RemoteEntry],
+    states: Option<&HashMap<String, RemoteState>>,
+) -> Result<String> {
+    let options: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let mut option = format!(
+                "{} ({}:{}) [{}]",
+                entry.name,
+                entry.remote_host,
+                entry.remote_dir,
+                sync_note(entry, states)
+            );
+            if let Some(description) = &entry.description {
+                option.push_str(&format!(" - {}", description));
+            }
+            option
+        })
+        .collect();
+
+    let index = select_from(&tr("prompt.select_remote", &[]), &options)?;
+
     Ok(entries[index].name.clone())
 }
 
-pub fn list_remotes(cache: &crate::cache::RemoteMap, current_dir: &str) -> Result<()> {
+pub fn list_remotes(
+    cache: &crate::cache::RemoteMap,
+    states: Option<&HashMap<String, RemoteState>>,
+    current_dir: &str,
+) -> Result<()> {
     let empty_vec: Vec<RemoteEntry> = Vec::new();
     let entries = cache.get(current_dir).unwrap_or(&empty_vec);
 
     if entries.is_empty() {
-        println!("No remote configurations found for this directory.");
+        println!("{}", tr("list.empty", &[]));
         return Ok(());
     }
 
-    println!("Remote configurations for this directory:");
+    println!("{}", tr("list.header", &[]));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
     for (i, entry) in entries.iter().enumerate() {
-        let preferred = if entry.preferred { " (preferred)" } else { "" };
+        let preferred = if entry.preferred {
+            green(&tr("list.preferred", &[]))
+        } else {
+            String::new()
+        };
+        let disabled = if entry.disabled {
+            yellow(&tr("list.disabled", &[]))
+        } else {
+            String::new()
+        };
+        let tags: String = entry
+            .tags
+            .iter()
+            .map(|tag| format!(" {}", dim(&format!("#{}", tag))))
+            .collect();
+        let aliases = if entry.aliases.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", entry.aliases.join(", "))
+        };
         println!(
-            "{}: {}{} ({}:{})",
+            "{}: {}{}{}{} {}{} {}",
             i + 1,
-            entry.name,
+            bold(&entry.name),
+            aliases,
             preferred,
-            entry.remote_host,
-            entry.remote_dir
+            disabled,
+            dim(&format!("({}:{})", entry.remote_host, entry.remote_dir)),
+            tags,
+            dim(&format!("- {}", sync_note(entry, states)))
         );
+        if let Some(description) = &entry.description {
+            println!("   {}", description);
+        }
+        if let Some(budget) = entry.monthly_budget {
+            let used = states
+                .and_then(|states| states.get(&entry.name))
+                .map(|state| state.transferred_in_month(now))
+                .unwrap_or_default();
+            let note = tr("list.budget", &[&format_size(used), &format_size(budget)]);
+            if used >= budget {
+                println!("   {}", yellow(&note));
+            } else {
+                println!("   {}", dim(&note));
+            }
+        }
+    }
+
+    for group in find_duplicates(entries) {
+        let names: Vec<&str> = group.iter().map(|&i| entries[i].name.as_str()).collect();
+        warn(format_args!(
+            "{} point at the same host and directory; run `sync-rs duplicates` to merge them",
+            names.join(", ")
+        ));
     }
 
     Ok(())
 }
 
-pub fn remove_remote(
+// Indices of the remotes named by `patterns`, each a name or a glob such as
+// `gpu*`. Every pattern has to match something, so that typos aren't ignored.
+pub fn match_remotes(entries: &[RemoteEntry], patterns: &[String]) -> Result<Vec<usize>> {
+    let mut indices = Vec::new();
+    for pattern in patterns {
+        let matched: Vec<usize> = (0..entries.len())
+            .filter(|&i| {
+                std::iter::once(&entries[i].name)
+                    .chain(&entries[i].aliases)
+                    .any(|name| glob_match(pattern, name))
+            })
+            .collect();
+        if matched.is_empty() {
+            anyhow::bail!("Remote with name '{}' not found", pattern);
+        }
+        indices.extend(matched);
+    }
+    indices.sort_unstable();
+    indices.dedup();
+    Ok(indices)
+}
+
+// Show the remotes a change applies to and ask before going ahead. A change to
+// a single remote goes ahead without asking, as does any with `yes`.
+fn confirm_group(
+    action: &str,
+    entries: &[RemoteEntry],
+    indices: &[usize],
+    yes: bool,
+) -> Result<bool> {
+    if indices.len() <= 1 || yes {
+        return Ok(true);
+    }
+
+    println!("{} {} remotes:", action, indices.len());
+    for &i in indices {
+        println!(
+            "  {} {}",
+            bold(&entries[i].name),
+            dim(&format!(
+                "({}:{})",
+                entries[i].remote_host, entries[i].remote_dir
+            ))
+        );
+    }
+    confirm("Go ahead?")
+}
+
+// Remove the remotes matching `patterns`, returning whether any were removed
+pub fn remove_remotes(
     cache: &mut crate::cache::RemoteMap,
     current_dir: &str,
-    name: &str,
-) -> Result<()> {
+    patterns: &[String],
+    yes: bool,
+) -> Result<bool> {
     let entries = cache
         .get_mut(current_dir)
         .context("No remotes found for this directory")?;
 
-    let initial_len = entries.len();
-    entries.retain(|e| e.name != name);
+    let indices = match_remotes(entries, patterns)?;
+    if !confirm_group("Removing", entries, &indices, yes)? {
+        return Ok(false);
+    }
 
-    if entries.len() == initial_len {
-        anyhow::bail!("Remote with name '{}' not found", name);
+    for &i in &indices {
+        println!("{}", tr("remove.done", &[&entries[i].name]));
     }
+    *entries = std::mem::take(entries)
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !indices.contains(i))
+        .map(|(_, e)| e)
+        .collect();
+    Ok(true)
+}
 
-    println!("Removed remote configuration '{}'", name);
+// Add `tag` to the remotes matching `patterns`, or take it off them,
+// returning whether anything changed
+pub fn tag_remotes(
+    cache: &mut crate::cache::RemoteMap,
+    current_dir: &str,
+    tag: &str,
+    patterns: &[String],
+    add: bool,
+    yes: bool,
+) -> Result<bool> {
+    let tag = tag.trim();
+    if tag.is_empty() || tag.contains(char::is_whitespace) {
+        anyhow::bail!("Tags must be non-empty and can't contain whitespace");
+    }
+
+    let entries = cache
+        .get_mut(current_dir)
+        .context("No remotes found for this directory")?;
+
+    let indices = match_remotes(entries, patterns)?;
+    let action = if add {
+        format!("Tagging '{}' on", tag)
+    } else {
+        format!("Removing tag '{}' from", tag)
+    };
+    if !confirm_group(&action, entries, &indices, yes)? {
+        return Ok(false);
+    }
+
+    for i in indices {
+        let entry = &mut entries[i];
+        let has_tag = entry.tags.iter().any(|t| t == tag);
+        if add && !has_tag {
+            entry.tags.push(tag.to_string());
+            println!("Tagged '{}' with '{}'", entry.name, tag);
+        } else if !add && has_tag {
+            entry.tags.retain(|t| t != tag);
+            println!("Removed tag '{}' from '{}'", tag, entry.name);
+        }
+    }
+    Ok(true)
+}
+
+// Rename the remote called `old` (by its name or an alias) and return its previous name
+pub fn rename_remote(
+    cache: &mut crate::cache::RemoteMap,
+    current_dir: &str,
+    old: &str,
+    new: &str,
+) -> Result<String> {
+    let entries = cache
+        .get_mut(current_dir)
+        .context("No remotes found for this directory")?;
+    if entries.iter().any(|e| e.is_called(new)) {
+        anyhow::bail!("A remote named '{}' already exists", new);
+    }
+
+    let entry = entries
+        .iter_mut()
+        .find(|e| e.is_called(old))
+        .with_context(|| format!("Remote with name '{}' not found", old))?;
+    Ok(std::mem::replace(&mut entry.name, new.to_string()))
+}
+
+// The remotes of a directory that `--all` commands go over: every one that
+// isn't disabled
+pub fn active_remotes<'a>(
+    cache: &'a crate::cache::RemoteMap,
+    current_dir: &str,
+) -> Vec<&'a RemoteEntry> {
+    cache
+        .get(current_dir)
+        .into_iter()
+        .flatten()
+        .filter(|entry| !entry.disabled)
+        .collect()
+}
+
+// Disable or re-enable a remote. Disabled remotes stay in the cache but
+// aren't offered or picked until they're enabled again.
+pub fn set_disabled(
+    cache: &mut crate::cache::RemoteMap,
+    current_dir: &str,
+    name: &str,
+    disabled: bool,
+) -> Result<()> {
+    let entry = cache
+        .get_mut(current_dir)
+        .context("No remotes found for this directory")?
+        .iter_mut()
+        .find(|e| e.is_called(name))
+        .with_context(|| format!("Remote with name '{}' not found", name))?;
+
+    entry.disabled = disabled;
+    if disabled {
+        println!("Disabled '{}'", entry.name);
+    } else {
+        println!("Enabled '{}'", entry.name);
+    }
     Ok(())
 }
 
 // Generate a unique name based on the host name
-pub fn generate_unique_name(
-    host: &str,
-    cache: &crate::cache::RemoteMap,
-    current_dir: &str,
-) -> String {
-    // Extract username@hostname or just hostname
-    let base_name = host.split(':').next().unwrap_or(host);
-
-    // If there are no entries for this directory or no entries with this base name, use it as is
-    if !cache.contains_key(current_dir) || !cache[current_dir].iter().any(|e| e.name == base_name) {
-        return base_name.to_string();
-    }
-
-    // Find the highest index used for this base name
-    let mut highest_index = 0;
-    for entry in &cache[current_dir] {
-        if entry.name == base_name {
-            highest_index = 1;
-        } else if entry.name.starts_with(&format!("{}_", base_name)) {
-            if let Ok(index) = entry.name[base_name.len() + 1..].parse::<usize>() {
-                highest_index = highest_index.max(index + 1);
+// A name for a new remote of a directory that has `entries`: `HOST-DIR`
+// (e.g. `gpu-box-project`), without any user or port, and with `-2`, `-3`, ...
+// (the lowest that's free) added if another remote has it already
+pub fn generate_unique_name(host: &str, remote_dir: &str, entries: &[RemoteEntry]) -> String {
+    let host = match RemoteTarget::parse(host) {
+        RemoteTarget::Ssh { .. } => SshHost::parse(host).host,
+        _ => host.to_string(),
+    };
+    let remote_dir = normalize_remote_dir(remote_dir);
+    let dir = remote_dir.rsplit('/').next().filter(|dir| *dir != ".");
+
+    let base_name = match dir.map(slugify).filter(|dir| !dir.is_empty()) {
+        Some(dir) => format!("{}-{}", slugify(&host), dir),
+        None => slugify(&host),
+    };
+    let base_name = base_name.trim_start_matches('-');
+    let base_name = if base_name.is_empty() {
+        "remote"
+    } else {
+        base_name
+    };
+    // Slugs are ASCII, so this cuts at characters. The name has to stay
+    // usable with the suffix added.
+    let with_suffix = |suffix: String| {
+        let base = &base_name[..base_name.len().min(MAX_NAME_LEN - suffix.len())];
+        format!("{}{}", base.trim_end_matches(['-', '.']), suffix)
+    };
+
+    let taken = |name: &str| entries.iter().any(|e| e.is_called(name));
+    let name = with_suffix(String::new());
+    if !taken(&name) {
+        return name;
+    }
+    (2..)
+        .map(|index| with_suffix(format!("-{}", index)))
+        .find(|name| !taken(name))
+        .unwrap_or(name)
+}
+
+// Longest name a remote may have
+pub const MAX_NAME_LEN: usize = 64;
+
+// Why a name can't be used as is, if it can't: it has to work as a CLI
+// argument and in file names
+pub fn name_problem(name: &str) -> Option<&'static str> {
+    if name.is_empty() {
+        Some("it is empty")
+    } else if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Some("it contains whitespace")
+    } else if name.contains('/') {
+        Some("it contains '/'")
+    } else if name.starts_with('-') {
+        Some("it starts with '-'")
+    } else if name.chars().count() > MAX_NAME_LEN {
+        Some("it is too long")
+    } else {
+        None
+    }
+}
+
+// The name to give a new remote: `name` if it is fine, or a slug of it
+// otherwise, saying so. Words in `reserved` are refused.
+pub fn checked_name(name: &str, reserved: &[String]) -> Result<String> {
+    if reserved.iter().any(|word| word == name) {
+        anyhow::bail!(
+            "'{}' can't be a remote name, it is a sync-rs subcommand or option",
+            name
+        );
+    }
+    let Some(problem) = name_problem(name) else {
+        return Ok(name.to_string());
+    };
+
+    let slug: String = slugify(name).chars().take(MAX_NAME_LEN).collect();
+    let slug = slug.trim_end_matches(['-', '.']).to_string();
+    if slug.is_empty() || reserved.contains(&slug) {
+        anyhow::bail!("'{}' can't be a remote name: {}", name, problem);
+    }
+    println!(
+        "Using '{}' as the remote name, as '{}' can't be used: {}",
+        slug, name, problem
+    );
+    Ok(slug)
+}
+
+// Lowercase letters, digits, `.` and `_`, with anything else turned into
+// single dashes, e.g. `My Project (v2)` -> `my-project-v2`
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches(['-', '.'])
+        .trim_start_matches(['-', '.'])
+        .to_string()
+}
+
+// Groups of entries (by index) that point at the same host and directory
+pub fn find_duplicates(entries: &[RemoteEntry]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let same_place = |other: &RemoteEntry| {
+            other.remote_host == entry.remote_host
+                && normalize_remote_dir(&other.remote_dir)
+                    == normalize_remote_dir(&entry.remote_dir)
+        };
+        match groups
+            .iter_mut()
+            .find(|group| same_place(&entries[group[0]]))
+        {
+            Some(group) => group.push(i),
+            None => groups.push(vec![i]),
+        }
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+// Fold duplicates into `keep`: list settings are unioned, and everything else
+// comes from `keep` unless it left it unset
+pub fn merge_entries(keep: &RemoteEntry, others: &[&RemoteEntry]) -> RemoteEntry {
+    fn union(into: &mut Vec<String>, from: &[String]) {
+        for item in from {
+            if !into.contains(item) {
+                into.push(item.clone());
+            }
+        }
+    }
+
+    let mut merged = keep.clone();
+    for other in others {
+        union(&mut merged.override_paths, &other.override_paths);
+        union(&mut merged.ignore_patterns, &other.ignore_patterns);
+        union(&mut merged.protected_paths, &other.protected_paths);
+        union(&mut merged.ssh_options, &other.ssh_options);
+        union(&mut merged.alternate_hosts, &other.alternate_hosts);
+        union(&mut merged.bandwidth_windows, &other.bandwidth_windows);
+        union(&mut merged.plugins, &other.plugins);
+        union(&mut merged.tags, &other.tags);
+        union(&mut merged.aliases, &other.aliases);
+        union(&mut merged.usermap, &other.usermap);
+        union(&mut merged.groupmap, &other.groupmap);
+        union(
+            &mut merged.post_command_triggers,
+            &other.post_command_triggers,
+        );
+        for template in &other.templates {
+            if !merged.templates.iter().any(|t| t.target == template.target) {
+                merged.templates.push(template.clone());
+            }
+        }
+        for (key, value) in &other.template_vars {
+            merged
+                .template_vars
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+
+        merged.preferred |= other.preferred;
+        if merged.post_sync_command.is_none() {
+            merged.post_sync_command = other.post_sync_command.clone();
+        }
+        if merged.description.is_none() {
+            merged.description = other.description.clone();
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailing_slashes_name_the_same_directory() {
+        assert_eq!(normalize_remote_dir("proj"), "proj");
+        assert_eq!(normalize_remote_dir("proj/"), "proj");
+        assert_eq!(normalize_remote_dir("proj//"), "proj");
+        assert_eq!(normalize_remote_dir(" work//proj/ "), "work/proj");
+        assert_eq!(normalize_remote_dir("/srv/app/"), "/srv/app");
+    }
+
+    #[test]
+    fn home_relative_forms_are_relative() {
+        assert_eq!(normalize_remote_dir("./proj"), "proj");
+        assert_eq!(normalize_remote_dir("~/proj/"), "proj");
+        assert_eq!(normalize_remote_dir("~"), ".");
+        assert_eq!(normalize_remote_dir("./"), ".");
+        assert_eq!(normalize_remote_dir("/"), "/");
+    }
+
+    #[test]
+    fn full_dir_has_no_trailing_slash() {
+        assert_eq!(remote_full_dir("/home/me", "proj/"), "/home/me/proj");
+        assert_eq!(remote_full_dir("/home/me/", "~/a/b/"), "/home/me/a/b");
+        assert_eq!(remote_full_dir("/home/me", "/srv/app/"), "/srv/app");
+        assert_eq!(remote_full_dir("/home/me", "."), "/home/me");
+    }
+
+    fn entry_named(name: &str) -> RemoteEntry {
+        RemoteEntry {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn names_are_host_dir_slugs() {
+        assert_eq!(
+            generate_unique_name("alice@gpu-box", "project", &[]),
+            "gpu-box-project"
+        );
+        assert_eq!(
+            generate_unique_name("bob@[2001:db8::1]:2222", "~/work/My App/", &[]),
+            "2001-db8-1-my-app"
+        );
+        assert_eq!(
+            generate_unique_name("Host.example.com", "/srv/app", &[]),
+            "host.example.com-app"
+        );
+        assert_eq!(
+            generate_unique_name("container:devbox", "src", &[]),
+            "container-devbox-src"
+        );
+        assert_eq!(generate_unique_name("gpu-box", "~", &[]), "gpu-box");
+        assert_eq!(generate_unique_name("box", "..[a", &[]), "box-a");
+        assert_eq!(
+            generate_unique_name(&"h".repeat(100), "app", &[]),
+            "h".repeat(MAX_NAME_LEN)
+        );
+        assert_eq!(generate_unique_name("@@@", "", &[]), "remote");
+    }
+
+    #[test]
+    fn taken_names_get_the_lowest_free_suffix() {
+        let entries = [entry_named("box-app"), entry_named("box-app-3")];
+        assert_eq!(generate_unique_name("box", "app", &entries), "box-app-2");
+
+        // A removed remote's name is free again instead of counting upwards
+        let entries = [entry_named("box-app-2")];
+        assert_eq!(generate_unique_name("box", "app", &entries), "box-app");
+    }
+
+    #[test]
+    fn all_skips_disabled_remotes() {
+        let mut cache = crate::cache::RemoteMap::new();
+        cache.insert(
+            "/home/me/app".to_string(),
+            vec![entry_named("a"), entry_named("b"), entry_named("c")],
+        );
+        set_disabled(&mut cache, "/home/me/app", "b", true).unwrap();
+
+        let names = |cache: &crate::cache::RemoteMap| {
+            active_remotes(cache, "/home/me/app")
+                .iter()
+                .map(|entry| entry.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&cache), ["a", "c"]);
+        set_disabled(&mut cache, "/home/me/app", "b", false).unwrap();
+        assert_eq!(names(&cache), ["a", "b", "c"]);
+        assert!(active_remotes(&cache, "/elsewhere").is_empty());
+    }
+
+    // Xorshift generator for the property tests below, seeded per case so
+    // that a failure can be reproduced from the seed in its message
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn new(seed: u64) -> Self {
+            // Zero is a fixed point of xorshift
+            Self(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+        }
+
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+
+        // Random text from pieces that matter to host and directory parsing
+        fn text(&mut self, max_len: usize) -> String {
+            const PIECES: &[&str] = &[
+                "a", "Z", "7", "-", "_", ".", "/", "~", "@", ":", "[", "]", " ", "\t", "ü", "名",
+                "gpu", "box", "app", "..", "//", "22",
+            ];
+            (0..self.below(max_len + 1))
+                .map(|_| PIECES[self.below(PIECES.len())])
+                .collect()
+        }
+    }
+
+    const CASES: u64 = 500;
+
+    #[test]
+    fn generated_names_are_usable_and_unique() {
+        for seed in 0..CASES {
+            let mut rng = XorShift::new(seed);
+            let mut entries: Vec<RemoteEntry> = Vec::new();
+            for _ in 0..30 {
+                // Removals free names up again
+                if !entries.is_empty() && rng.below(4) == 0 {
+                    let index = rng.below(entries.len());
+                    entries.remove(index);
+                    continue;
+                }
+                // Now and then a host long enough to need cutting
+                let max_len = if rng.below(8) == 0 { 80 } else { 6 };
+                let host = rng.text(max_len);
+                let dir = rng.text(6);
+                let name = generate_unique_name(&host, &dir, &entries);
+                let case = format!("seed {}: {:?} {:?} -> {:?}", seed, host, dir, name);
+
+                assert_eq!(name_problem(&name), None, "{}", case);
+                assert_eq!(slugify(&name), name, "{}", case);
+                assert!(
+                    !entries.iter().any(|e| e.is_called(&name)),
+                    "{}: taken",
+                    case
+                );
+                // The same request gives the same name
+                assert_eq!(
+                    generate_unique_name(&host, &dir, &entries),
+                    name,
+                    "{}",
+                    case
+                );
+                // The order of the remotes doesn't matter
+                let reversed: Vec<RemoteEntry> = entries.iter().rev().cloned().collect();
+                assert_eq!(
+                    generate_unique_name(&host, &dir, &reversed),
+                    name,
+                    "{}",
+                    case
+                );
+                // Without other remotes the name has no suffix, and it only
+                // gets one when the plain name is taken
+                let plain = generate_unique_name(&host, &dir, &[]);
+                if name != plain {
+                    assert!(entries.iter().any(|e| e.is_called(&plain)), "{}", case);
+                }
+
+                let mut entry = entry_named(&name);
+                if rng.below(3) == 0 {
+                    entry
+                        .aliases
+                        .push(format!("{}-{}", plain, 2 + rng.below(3)));
+                }
+                entries.push(entry);
             }
         }
     }
 
-    // Return the base name with the next available index
-    format!("{}_{}", base_name, highest_index)
+    #[test]
+    fn suffixes_are_the_lowest_free_ones() {
+        for seed in 0..CASES {
+            let mut rng = XorShift::new(seed);
+            let host = rng.text(80);
+            let dir = rng.text(6);
+            let plain = generate_unique_name(&host, &dir, &[]);
+
+            // Take the plain name and a random set of suffixed ones
+            let mut entries = vec![entry_named(&plain)];
+            let mut names = vec![plain.clone()];
+            for _ in 0..rng.below(6) {
+                let name = generate_unique_name(&host, &dir, &entries);
+                entries.push(entry_named(&name));
+                names.push(name);
+            }
+            let freed = rng.below(names.len());
+            entries.remove(freed);
+
+            let name = generate_unique_name(&host, &dir, &entries);
+            assert_eq!(
+                name, names[freed],
+                "seed {}: {:?} {:?} after freeing {:?}",
+                seed, host, dir, names[freed]
+            );
+        }
+    }
+
+    #[test]
+    fn equivalent_spellings_get_the_same_name() {
+        for seed in 0..CASES {
+            let mut rng = XorShift::new(seed);
+            let dir = rng.text(6);
+            let host = format!("host{}", rng.below(100));
+            let name = generate_unique_name(&host, &dir, &[]);
+            let case = format!("seed {}: {:?}", seed, dir);
+
+            // Surrounding whitespace is trimmed, so the slashes go inside it
+            assert_eq!(
+                generate_unique_name(&host, &format!("{}//", dir.trim()), &[]),
+                name,
+                "{}",
+                case
+            );
+            assert_eq!(
+                generate_unique_name(&format!("user@{}:2222", host), &dir, &[]),
+                name,
+                "{}",
+                case
+            );
+            assert_eq!(
+                generate_unique_name(&host.to_uppercase(), &dir, &[]),
+                name,
+                "{}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn checked_names_are_usable_and_stable() {
+        let reserved = ["list".to_string(), "sync".to_string()];
+        for seed in 0..CASES {
+            let mut rng = XorShift::new(seed);
+            let max_len = if rng.below(8) == 0 { 100 } else { 8 };
+            let name = rng.text(max_len);
+            let case = format!("seed {}: {:?}", seed, name);
+
+            match checked_name(&name, &reserved) {
+                Ok(checked) => {
+                    assert_eq!(name_problem(&checked), None, "{}", case);
+                    assert!(!reserved.contains(&checked), "{}", case);
+                    // Usable names are kept, and a checked name checks as itself
+                    if name_problem(&name).is_none() {
+                        assert_eq!(checked, name, "{}", case);
+                    }
+                    assert_eq!(
+                        checked_name(&checked, &reserved).unwrap(),
+                        checked,
+                        "{}",
+                        case
+                    );
+                }
+                Err(_) => assert!(
+                    reserved.contains(&name) || slugify(&name).is_empty(),
+                    "{}",
+                    case
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn unusable_names_are_slugged() {
+        let reserved = ["list".to_string(), "remove".to_string()];
+        assert_eq!(checked_name("gpu-box", &reserved).unwrap(), "gpu-box");
+        assert_eq!(checked_name("GPU Box/2", &reserved).unwrap(), "gpu-box-2");
+        assert_eq!(checked_name("-x", &reserved).unwrap(), "x");
+        assert_eq!(checked_name(".\t-x", &reserved).unwrap(), "x");
+        assert_eq!(
+            checked_name(&"a".repeat(100), &reserved).unwrap().len(),
+            MAX_NAME_LEN
+        );
+        assert!(checked_name("list", &reserved).is_err());
+        assert!(checked_name("/ /", &reserved).is_err());
+    }
+
+    #[test]
+    fn patterns_pick_remotes_by_name() {
+        let entries = [
+            entry_named("gpu1"),
+            entry_named("web1"),
+            entry_named("gpu2"),
+        ];
+        let names = |patterns: &[&str]| {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            match_remotes(&entries, &patterns)
+        };
+        assert_eq!(names(&["gpu*"]).unwrap(), vec![0, 2]);
+        assert_eq!(names(&["web1", "gpu?", "gpu1"]).unwrap(), vec![0, 1, 2]);
+        assert!(names(&["gpu*", "db*"]).is_err());
+    }
+
+    #[test]
+    fn aliases_must_not_clash() {
+        let aliased = |name: &str, aliases: &[&str]| RemoteEntry {
+            aliases: aliases.iter().map(|a| a.to_string()).collect(),
+            ..entry_named(name)
+        };
+        let entries = [aliased("gpu-box", &["g"]), aliased("web", &["w"])];
+        let reserved = ["list".to_string()];
+        assert!(entries[0].is_called("g"));
+        assert!(check_aliases(&entries, &entries[0], &reserved).is_ok());
+        assert!(check_aliases(&entries, &aliased("web", &["w", "x"]), &reserved).is_ok());
+        assert!(check_aliases(&entries, &aliased("web", &["g"]), &reserved).is_err());
+        assert!(check_aliases(&entries, &aliased("web", &["gpu-box"]), &reserved).is_err());
+        assert!(check_aliases(&entries, &aliased("web", &["web"]), &reserved).is_err());
+        assert!(check_aliases(&entries, &aliased("web", &["list"]), &reserved).is_err());
+        assert!(check_aliases(&entries, &aliased("web", &["a b"]), &reserved).is_err());
+        assert_eq!(generate_unique_name("g", ".", &entries), "g-2");
+    }
+
+    #[test]
+    fn duplicates_ignore_trailing_slashes() {
+        let entry = |name: &str, dir: &str| RemoteEntry {
+            name: name.to_string(),
+            remote_host: "host".to_string(),
+            remote_dir: dir.to_string(),
+            ..Default::default()
+        };
+        let entries = [entry("a", "proj"), entry("b", "proj/"), entry("c", "other")];
+        assert_eq!(find_duplicates(&entries), vec![vec![0, 1]]);
+    }
 }
@@ -0,0 +1,288 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use crate::cache::{get_config_dir, key_path, path_key};
+use crate::remote::{shell_quote, RemoteTarget};
+use crate::sync::{
+    dir_contents, list_files, rsync_path, run_remote_script, sync_directory, RsyncOptions,
+};
+
+// Where blobs are kept on the remote, relative to its home directory. Shared
+// by every project synced to the same host.
+pub const STORE_DIR: &str = ".cache/sync-rs/blobs";
+
+// Hash of a local file, reused while its size and modification time are unchanged
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedHash {
+    size: u64,
+    mtime: u64,
+    hash: String,
+}
+
+// Send files of at least `min_size` bytes to the remote's content-addressed
+// store and hard link them into `remote_dir`, so identical files synced from
+// several projects only cross the network once. The regular sync has to skip
+// these files afterwards (`--max-size`), or it would replace the links.
+pub fn dedup_large_files(
+    local_dir: &Path,
+    target: &RemoteTarget,
+    remote_home: &str,
+    remote_dir: &str,
+    options: &RsyncOptions,
+    min_size: u64,
+) -> Result<()> {
+    let mut list_options = options.clone();
+    list_options
+        .extra_args
+        .push(format!("--min-size={}", min_size));
+    let local_dir = std::path::absolute(local_dir)?;
+    let files = list_files(&dir_contents(&local_dir.to_string_lossy()), &list_options)?;
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let hashes = hash_files(&local_dir, &files)?;
+    let store = format!("{}/{}", remote_home, STORE_DIR);
+
+    // Ask the remote which blobs it's missing
+    let missing: Vec<String> = run_remote_script(target, &missing_script(&store, &hashes))?
+        .lines()
+        .map(str::to_string)
+        .collect();
+
+    if !missing.is_empty() {
+        println!(
+            "Uploading {} large file(s) to the shared store",
+            missing.len()
+        );
+        upload_blobs(&local_dir, target, &store, &missing, &hashes, options)?;
+    }
+
+    run_remote_script(target, &link_script(&store, remote_dir, &hashes))?;
+
+    Ok(())
+}
+
+// Remote script printing the hashes `store` has no blob for
+fn missing_script(store: &str, hashes: &HashMap<String, String>) -> String {
+    let mut script = format!("s={}\nmkdir -p \"$s\" || exit 1\n", shell_quote(store));
+    let unique: BTreeSet<&String> = hashes.values().collect();
+    for hash in &unique {
+        script.push_str(&format!("[ -e \"$s/{0}\" ] || echo {0}\n", hash));
+    }
+    script
+}
+
+// Remote script linking every file into the project, leaving those already
+// linked alone, then dropping blobs no project refers to anymore
+fn link_script(store: &str, remote_dir: &str, hashes: &HashMap<String, String>) -> String {
+    let mut script = format!("s={}\nd={}\n", shell_quote(store), shell_quote(remote_dir));
+    for (path, hash) in hashes {
+        let file = shell_quote(path);
+        script.push_str(&format!(
+            "[ \"$d\"/{0} -ef \"$s/{1}\" ] || {{ mkdir -p \"$(dirname \"$d\"/{0})\" && \
+             {{ ln -f \"$s/{1}\" \"$d\"/{0} 2>/dev/null || cp -p \"$s/{1}\" \"$d\"/{0}; }}; }} || exit 1\n",
+            file, hash
+        ));
+    }
+    script.push_str("find \"$s\" -type f -links 1 -exec rm -f {} +\n");
+    script
+}
+
+// Copy the blobs for `missing` hashes into the store, staged locally as
+// symlinks named after their hash
+fn upload_blobs(
+    local_dir: &Path,
+    target: &RemoteTarget,
+    store: &str,
+    missing: &[String],
+    hashes: &HashMap<String, String>,
+    options: &RsyncOptions,
+) -> Result<()> {
+    let staging_dir = std::env::temp_dir().join(format!("sync-rs-blobs-{}", std::process::id()));
+    fs::create_dir_all(&staging_dir).context("Failed to create staging directory")?;
+
+    for hash in missing {
+        if let Some((path, _)) = hashes.iter().find(|(_, h)| *h == hash) {
+            symlink(local_dir.join(rsync_path(path)), staging_dir.join(hash))
+                .context("Failed to stage file for upload")?;
+        }
+    }
+
+    let upload_options = RsyncOptions {
+        filter: None,
+        delete: false,
+        extra_args: [options.extra_args.clone(), vec!["--copy-links".to_string()]].concat(),
+        ..options.clone()
+    };
+    let result = sync_directory(
+        &format!("{}/", staging_dir.display()),
+        &format!("{}/", target.rsync_location(store)),
+        &upload_options,
+    );
+
+    fs::remove_dir_all(&staging_dir).context("Failed to clean up staging directory")?;
+    result?;
+    Ok(())
+}
+
+// SHA-256 of each file, using the local hash cache where possible
+fn hash_files(local_dir: &Path, files: &[(String, u64)]) -> Result<HashMap<String, String>> {
+    let cache_path = get_config_dir()?.join("hashes.json");
+    let mut cache: HashMap<String, CachedHash> = fs::read(&cache_path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+
+    let mut hashes = HashMap::new();
+    let mut to_hash = Vec::new();
+
+    for (path, size) in files {
+        let full_path = local_dir.join(rsync_path(path));
+        let key = path_key(&full_path);
+        let mtime = modified_secs(&full_path)?;
+        match cache.get(&key) {
+            Some(cached) if cached.size == *size && cached.mtime == mtime => {
+                hashes.insert(path.clone(), cached.hash.clone());
+            }
+            _ => to_hash.push((path.clone(), full_path, *size, mtime)),
+        }
+    }
+
+    if !to_hash.is_empty() {
+        println!("Hashing {} large file(s)", to_hash.len());
+    }
+    for (path, full_path, size, mtime) in to_hash {
+        let hash = sha256(&full_path)?;
+        cache.insert(
+            path_key(&full_path),
+            CachedHash {
+                size,
+                mtime,
+                hash: hash.clone(),
+            },
+        );
+        hashes.insert(path, hash);
+    }
+
+    // Forget files that no longer exist
+    cache.retain(|path, _| key_path(path).exists());
+    let file = File::create(&cache_path).context("Failed to create hash cache")?;
+    serde_json::to_writer(file, &cache).context("Failed to write hash cache")?;
+
+    Ok(hashes)
+}
+
+fn modified_secs(path: &Path) -> Result<u64> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .context(format!("Failed to read metadata of {}", path.display()))?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default())
+}
+
+// Hash a file with `sha256sum`, or `shasum` where that's missing (macOS)
+pub fn sha256(path: &Path) -> Result<String> {
+    let output = Command::new("sha256sum")
+        .arg(path)
+        .output()
+        .or_else(|_| {
+            Command::new("shasum")
+                .args(["-a", "256"])
+                .arg(path)
+                .output()
+        })
+        .context("Failed to execute sha256sum or shasum")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to hash {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+        .context(format!("Failed to hash {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    // The scripts only need a POSIX shell, so they're run here on a local
+    // directory standing in for the remote
+    fn run(script: &str) -> String {
+        let output = Command::new("sh").arg("-c").arg(script).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).to_string()
+    }
+
+    #[test]
+    fn blobs_are_linked_into_projects_and_collected() {
+        let root = std::env::temp_dir().join(format!("sync-rs-dedup-{}", std::process::id()));
+        let store = root.join("store").to_string_lossy().to_string();
+        let project = root.join("my project").to_string_lossy().to_string();
+        let hashes: HashMap<String, String> = [
+            ("weights.bin".to_string(), "aaaa".to_string()),
+            ("data/it's.csv".to_string(), "bbbb".to_string()),
+            ("data/copy.csv".to_string(), "bbbb".to_string()),
+        ]
+        .into();
+
+        let mut missing: Vec<String> = run(&missing_script(&store, &hashes))
+            .lines()
+            .map(str::to_string)
+            .collect();
+        missing.sort();
+        assert_eq!(missing, ["aaaa", "bbbb"]);
+
+        fs::write(root.join("store/aaaa"), "weights").unwrap();
+        fs::write(root.join("store/bbbb"), "rows").unwrap();
+        fs::write(root.join("store/cccc"), "no longer used").unwrap();
+        assert_eq!(run(&missing_script(&store, &hashes)), "");
+
+        run(&link_script(&store, &project, &hashes));
+        // Twice, as every sync does, which leaves the links alone
+        run(&link_script(&store, &project, &hashes));
+        let linked = |path: &str| fs::metadata(root.join("my project").join(path)).unwrap();
+        let blob = fs::metadata(root.join("store/bbbb")).unwrap();
+        let results = (
+            fs::read_to_string(root.join("my project/weights.bin")).unwrap(),
+            linked("data/it's.csv").ino() == blob.ino(),
+            linked("data/copy.csv").ino() == blob.ino(),
+            root.join("store/cccc").exists(),
+        );
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(results, ("weights".to_string(), true, true, false));
+    }
+
+    #[test]
+    fn files_hash_to_their_sha256() {
+        let path = std::env::temp_dir().join(format!("sync-rs-sha-{}", std::process::id()));
+        fs::write(&path, "abc").unwrap();
+        let hash = sha256(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            hash.unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(sha256(Path::new("/nonexistent/sync-rs")).is_err());
+    }
+}
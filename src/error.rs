@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+// Failures that programs using sync-rs as a library may want to tell apart,
+// e.g. to retry after a dropped connection but ask for a key after a failed
+// login. Everything else is `Other`, with the message the CLI would print.
+#[derive(Debug)]
+pub enum SyncError {
+    // The remote refused our keys or password
+    SshAuthFailed { host: String },
+    // The remote couldn't be reached, or the connection dropped
+    SshConnectionFailed { host: String, message: String },
+    // rsync isn't installed here (`host` is None) or on the remote
+    RsyncMissing { host: Option<String> },
+    RemoteDirMissing { dir: String },
+    CacheCorrupt { path: PathBuf, message: String },
+    NoRemote { dir: PathBuf },
+    // Several remotes and none of them preferred, so one has to be named
+    AmbiguousRemote { dir: PathBuf },
+    CommandFailed { what: String, code: Option<i32> },
+    Cancelled { what: String },
+    Other(anyhow::Error),
+}
+
+pub type SyncResult<T> = Result<T, SyncError>;
+
+// rsync's exit codes for network trouble: socket I/O, a broken data stream,
+// timeouts, and the remote shell failing to connect
+const RSYNC_NETWORK_EXITS: &[i32] = &[10, 12, 30, 35, 255];
+
+impl SyncError {
+    // Failure of a command that ran, from its exit code
+    pub fn command_failed(what: &str, code: Option<i32>) -> Self {
+        Self::CommandFailed {
+            what: what.to_string(),
+            code,
+        }
+    }
+
+    // Whether the same sync may work if tried again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::SshConnectionFailed { .. } => true,
+            Self::CommandFailed { what, code } => {
+                what == "rsync" && code.is_some_and(|code| RSYNC_NETWORK_EXITS.contains(&code))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SshAuthFailed { host } => write!(f, "SSH login to {} was refused", host),
+            Self::SshConnectionFailed { host, message } => {
+                write!(f, "Failed to connect to {}: {}", host, message)
+            }
+            Self::RsyncMissing { host: None } => write!(f, "rsync is not installed"),
+            Self::RsyncMissing { host: Some(host) } => {
+                write!(f, "rsync is not installed on {}", host)
+            }
+            Self::RemoteDirMissing { dir } => write!(f, "Remote directory {} is missing", dir),
+            Self::CacheCorrupt { path, message } => {
+                write!(f, "Failed to parse {}: {}", path.display(), message)
+            }
+            Self::NoRemote { dir } => write!(f, "No remotes for {}", dir.display()),
+            Self::AmbiguousRemote { dir } => write!(
+                f,
+                "Several remotes for {}, name the one to sync to",
+                dir.display()
+            ),
+            Self::CommandFailed { what, code } => {
+                write!(f, "{} failed with exit code: {:?}", what, code)
+            }
+            Self::Cancelled { what } => write!(f, "{} was cancelled", what),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SyncError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+// Errors from the modules still using anyhow keep their kind when one of
+// the variants above is what failed, with any context added on the way
+// left out
+impl From<anyhow::Error> for SyncError {
+    fn from(e: anyhow::Error) -> Self {
+        // Runners report typed failures wrapped in an io::Error
+        let wrapped = e
+            .downcast_ref::<std::io::Error>()
+            .and_then(|io| io.get_ref())
+            .is_some_and(|inner| inner.is::<Self>());
+        if wrapped {
+            if let Ok(io) = e.downcast::<std::io::Error>() {
+                return io.into();
+            }
+            unreachable!("checked to hold an io::Error");
+        }
+        e.downcast().unwrap_or_else(Self::Other)
+    }
+}
+
+impl From<std::io::Error> for SyncError {
+    fn from(e: std::io::Error) -> Self {
+        if !e.get_ref().is_some_and(|inner| inner.is::<Self>()) {
+            return Self::Other(e.into());
+        }
+        match e.into_inner().map(|inner| inner.downcast::<Self>()) {
+            Some(Ok(inner)) => *inner,
+            _ => unreachable!("checked to hold a SyncError"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn kinds_survive_anyhow_and_context() {
+        let failed: anyhow::Result<()> = Err(SyncError::command_failed("rsync", Some(12)).into());
+        let error = SyncError::from(failed.context("Syncing to gpu-box").unwrap_err());
+        assert!(matches!(
+            error,
+            SyncError::CommandFailed { code: Some(12), .. }
+        ));
+        assert!(error.is_transient());
+        assert_eq!(error.to_string(), "rsync failed with exit code: Some(12)");
+
+        let error = SyncError::from(anyhow::anyhow!("Invalid remote name"));
+        assert!(matches!(error, SyncError::Other(_)));
+        assert!(!error.is_transient());
+        assert_eq!(error.to_string(), "Invalid remote name");
+    }
+
+    #[test]
+    fn kinds_survive_runner_errors() {
+        let refused = SyncError::SshAuthFailed {
+            host: "me@box".to_string(),
+        };
+        let failed: std::io::Result<()> = Err(std::io::Error::other(refused));
+        let error = SyncError::from(failed.context("Failed to get remote home").unwrap_err());
+        assert!(matches!(error, SyncError::SshAuthFailed { host } if host == "me@box"));
+
+        let error = SyncError::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(matches!(error, SyncError::Other(_)));
+    }
+}
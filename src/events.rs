@@ -0,0 +1,155 @@
+use serde_json::{json, Map, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::term::warn;
+
+// Append-only JSONL stream of what sync-rs is doing, one object per line with
+// at least `ts` (Unix time in milliseconds) and `event`, for statuslines and
+// other tools that shouldn't have to parse the human-readable output
+static EVENT_LOG: Mutex<Option<File>> = Mutex::new(None);
+
+// Start writing events to `path`, a regular file (appended to) or a FIFO.
+// Problems are reported but never stop a sync.
+pub fn init(path: &Path) {
+    let is_fifo = fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo());
+
+    let mut options = OpenOptions::new();
+    if is_fifo {
+        // Don't hang when nobody is reading the FIFO
+        options.write(true).custom_flags(libc::O_NONBLOCK);
+    } else {
+        options.create(true).append(true);
+    }
+
+    match options.open(path) {
+        Ok(file) => *EVENT_LOG.lock().unwrap() = Some(file),
+        Err(e) if is_fifo => {
+            // ENXIO: no reader on the other end, which is fine
+            if e.raw_os_error() != Some(libc::ENXIO) {
+                warn(format_args!(
+                    "Could not open event log {}: {}",
+                    path.display(),
+                    e
+                ));
+            }
+        }
+        Err(e) => warn(format_args!(
+            "Could not open event log {}: {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+pub fn enabled() -> bool {
+    EVENT_LOG.lock().unwrap().is_some()
+}
+
+// Record an event with the given fields (a JSON object)
+pub fn emit(event: &str, fields: Value) {
+    let mut log = EVENT_LOG.lock().unwrap();
+    let Some(file) = log.as_mut() else {
+        return;
+    };
+
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let mut record = Map::new();
+    record.insert("ts".to_string(), json!(ts));
+    record.insert("event".to_string(), json!(event));
+    if let Value::Object(fields) = fields {
+        record.extend(fields);
+    }
+
+    let mut line = Value::Object(record).to_string();
+    line.push('\n');
+    // A single write keeps lines from interleaving with other writers
+    if file.write_all(line.as_bytes()).is_err() {
+        // The reader went away; stop trying rather than failing every write
+        *log = None;
+    }
+}
+
+// Format for `--log-file-format` that `read_rsync_changes` understands
+pub const RSYNC_LOG_FORMAT: &str = "%i %b %n";
+
+// Transferred and deleted files in an rsync log written with
+// `RSYNC_LOG_FORMAT`, and the bytes that went over the wire
+pub fn read_rsync_changes(log_path: &Path) -> (Vec<String>, Vec<String>, u64) {
+    let Ok(contents) = fs::read_to_string(log_path) else {
+        return (Vec::new(), Vec::new(), 0);
+    };
+
+    let mut transferred = Vec::new();
+    let mut deleted = Vec::new();
+    let mut bytes = 0;
+    for line in contents.lines() {
+        // Lines look like `2024/01/01 12:00:00 [123] <f+++++++++ 1234 path`
+        let Some((_, item)) = line.split_once("] ") else {
+            continue;
+        };
+        let Some((flags, rest)) = item.split_once(' ') else {
+            continue;
+        };
+        // rsync logs other lines too, e.g. `sent 1234 bytes  received ...`
+        // at the end, which would count the bytes twice
+        let itemized = flags.len() == 11 && flags.starts_with(['<', '>', 'c', 'h', '.']);
+        if !itemized && flags != "*deleting" {
+            continue;
+        }
+        let Some((sent, path)) = rest.trim_start().split_once(' ') else {
+            continue;
+        };
+        bytes += sent.parse::<u64>().unwrap_or_default();
+        if flags == "*deleting" {
+            deleted.push(path.to_string());
+        } else if (flags.starts_with('<') || flags.starts_with('>')) && flags[1..].starts_with('f')
+        {
+            transferred.push(path.to_string());
+        }
+    }
+
+    (transferred, deleted, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rsync_logs_list_the_changed_files() {
+        let log = "\
+2024/05/01 12:00:00 [4242] building file list
+2024/05/01 12:00:00 [4242] cd+++++++++ 0 src/
+2024/05/01 12:00:00 [4242] <f+++++++++ 1200 src/main.rs
+2024/05/01 12:00:00 [4242] <f.st...... 34 notes with  spaces.txt
+2024/05/01 12:00:00 [4242] *deleting   0 old build/out.o
+2024/05/01 12:00:00 [4242] .f...p..... 0 run.sh
+2024/05/01 12:00:00 [4242] >f+++++++++ 66 pulled.csv
+2024/05/01 12:00:01 [4242] sent 1400 bytes  received 35 bytes  total size 5000
+no prefix <f+++++++++ 9 stray.txt
+";
+        let path = std::env::temp_dir().join(format!("sync-rs-rsync-log-{}", std::process::id()));
+        fs::write(&path, log).unwrap();
+        let (transferred, deleted, bytes) = read_rsync_changes(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            transferred,
+            ["src/main.rs", "notes with  spaces.txt", "pulled.csv"]
+        );
+        assert_eq!(deleted, ["old build/out.o"]);
+        // Only the files' own bytes count, not rsync's summary line
+        assert_eq!(bytes, 1300);
+
+        let missing = std::env::temp_dir().join("sync-rs-no-such-log");
+        assert_eq!(read_rsync_changes(&missing), (Vec::new(), Vec::new(), 0));
+    }
+}
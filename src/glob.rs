@@ -0,0 +1,132 @@
+// Minimal glob matching in the spirit of rsync's include/exclude patterns:
+// `*` matches within a path component, `**` matches across components and
+// `?` matches any single character other than `/`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            (0..=text.len()).any(|i| match_from(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if match_from(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => match text.first() {
+            Some(c) if *c != '/' => match_from(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(p) => text.first() == Some(p) && match_from(&pattern[1..], &text[1..]),
+    }
+}
+
+// Whether a relative path falls under an rsync-style pattern. Patterns starting
+// with `/` are anchored at the transfer root, others may match at any depth,
+// and a match on a directory covers everything beneath it.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let anchored = pattern.starts_with('/');
+    let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+    let components: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+
+    let starts = if anchored { 0..1 } else { 0..components.len() };
+    for start in starts {
+        for end in start + 1..=components.len() {
+            if glob_match(pattern, &components[start..end].join("/")) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_within_or_across_components() {
+        let cases = [
+            ("*.log", "app.log", true),
+            ("*.log", "logs/app.log", false),
+            ("*", "", true),
+            ("*", "a/b", false),
+            ("logs/*", "logs/app.log", true),
+            ("logs/*", "logs/2024/app.log", false),
+            ("**", "a/b/c", true),
+            ("**", "", true),
+            ("logs/**", "logs/2024/app.log", true),
+            ("logs/**", "logs/", true),
+            ("**/app.log", "a/b/app.log", true),
+            ("**/app.log", "app.log", false),
+            ("a/**/z", "a/z", false),
+            ("a/**/z", "a/b/c/z", true),
+            ("file?.txt", "file1.txt", true),
+            ("file?.txt", "file.txt", false),
+            ("file?.txt", "file12.txt", false),
+            ("a?b", "a/b", false),
+            ("checkpoints", "checkpoints", true),
+            ("checkpoints", "checkpoints2", false),
+            ("", "", true),
+            ("", "a", false),
+            ("*.tar.gz", "data.tar.gz", true),
+            ("*ç*", "façade", true),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob_match(pattern, text),
+                expected,
+                "{} vs {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn paths_match_anchored_or_at_any_depth() {
+        let cases = [
+            // Unanchored patterns match at any depth
+            ("*.log", "app.log", true),
+            ("*.log", "a/b/app.log", true),
+            ("node_modules", "web/node_modules", true),
+            ("node_modules", "web/node_modules/react/index.js", true),
+            ("data/raw", "project/data/raw/a.csv", true),
+            ("data/raw", "data/rawer", false),
+            // Anchored ones only at the root
+            ("/build", "build/out.o", true),
+            ("/build", "src/build/out.o", false),
+            ("/src/*.rs", "src/main.rs", true),
+            ("/src/*.rs", "src/bin/tool.rs", false),
+            // A trailing slash on either side is ignored
+            ("checkpoints/", "checkpoints", true),
+            ("checkpoints/", "run/checkpoints/", true),
+            ("logs/**", "logs/a/b.txt", true),
+            ("?", "a/b", true),
+            ("*.log", "a.logx", false),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(
+                path_matches(pattern, path),
+                expected,
+                "{} vs {}",
+                pattern,
+                path
+            );
+        }
+    }
+}
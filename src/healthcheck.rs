@@ -0,0 +1,94 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::process::Command;
+
+use crate::runner;
+use crate::term::warn;
+
+// Seconds to wait for the monitoring service before giving up on a ping
+const PING_TIMEOUT: &str = "10";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Ping {
+    Start,
+    Success,
+    Failure,
+}
+
+// Ping a healthchecks.io-style URL: `URL/start` when a sync starts, `URL`
+// when it succeeds and `URL/fail` when it fails, with `metadata` as the JSON
+// body for the service to show alongside. Monitoring is best effort, so
+// failures are only reported.
+pub fn ping(url: &str, kind: Ping, metadata: &Value) {
+    let base = url.trim_end_matches('/');
+    let url = match kind {
+        Ping::Start => format!("{}/start", base),
+        Ping::Success => base.to_string(),
+        Ping::Failure => format!("{}/fail", base),
+    };
+    if let Err(e) = post(&url, metadata) {
+        warn(format_args!("Healthcheck ping to {} failed: {:#}", url, e));
+    }
+}
+
+fn post(url: &str, body: &Value) -> Result<()> {
+    let mut cmd = Command::new("curl");
+    cmd.args([
+        "--silent",
+        "--show-error",
+        "--fail",
+        "--output",
+        "/dev/null",
+    ])
+    .args(["--max-time", PING_TIMEOUT, "--retry", "3"])
+    .args(["--header", "Content-Type: application/json"])
+    .args(["--data-binary", "@-", url]);
+    let output = runner::output_with_input(&mut cmd, body.to_string().as_bytes())
+        .context("Failed to run curl")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "curl exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{with_runner, MockRunner};
+    use serde_json::json;
+    use std::rc::Rc;
+
+    #[test]
+    fn pings_go_to_the_url_for_each_stage() {
+        let runner = Rc::new(MockRunner::default().reply(22, "", "HTTP 404"));
+        let metadata = json!({"remote": "gpu-box"});
+        with_runner(runner.clone(), || {
+            // A failed ping is only a warning, and the next one still goes out
+            ping("https://hc-ping.com/abc", Ping::Failure, &metadata);
+            ping("https://hc-ping.com/abc/", Ping::Start, &metadata);
+            ping("https://hc-ping.com/abc//", Ping::Success, &metadata);
+        });
+
+        let urls: Vec<String> = runner
+            .commands()
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://hc-ping.com/abc/fail",
+                "https://hc-ping.com/abc/start",
+                "https://hc-ping.com/abc"
+            ]
+        );
+        assert!(runner.commands()[0].starts_with("curl --silent --show-error --fail"));
+        assert!(runner.commands()[0].contains(" --data-binary @- "));
+        assert_eq!(runner.inputs(), [r#"{"remote":"gpu-box"}"#; 3]);
+    }
+}
@@ -0,0 +1,147 @@
+use clap::Command;
+
+// Annotated workflows shown by `--help` and `sync-rs help-examples`
+pub const EXAMPLES: &str = "\
+  # Add a remote for the current project and sync to it
+  sync-rs user@gpu-box projects/myapp
+
+  # Later syncs reuse the saved remote
+  sync-rs
+
+  # Add a second remote under a name and make it the default
+  sync-rs user@cluster scratch/myapp -n cluster -P
+
+  # Sync, run the tests there and open a shell in the project
+  sync-rs -p \"cargo test\" -s
+
+  # Keep checkpoints written on the remote from being deleted
+  sync-rs --protect checkpoints/
+
+  # Sync to a running container or Kubernetes pod
+  sync-rs docker:devbox /workspace
+  sync-rs k8s:ml/app=trainer /workspace
+
+  # Push a large dataset in resumable chunks
+  sync-rs --chunked --chunk-size 10G
+
+  # Show sync status in a tmux status bar
+  set -g status-right '#(cd #{pane_current_path} && sync-rs statusline)'
+";
+
+// Help text appended to `--help`
+pub fn examples_help() -> String {
+    format!("Examples:\n{}", EXAMPLES)
+}
+
+// Render a man page in roff for the given command
+pub fn render_man(cmd: &Command) -> String {
+    let name = cmd.get_name().to_string();
+    let version = cmd.get_version().unwrap_or_default();
+    let mut page = String::new();
+
+    page.push_str(&format!(
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"\n",
+        escape(&name.to_uppercase()),
+        escape(&name),
+        escape(version)
+    ));
+
+    page.push_str(".SH NAME\n");
+    let about = cmd.get_about().map(|about| about.to_string());
+    page.push_str(&format!(
+        "{} \\- {}\n",
+        escape(&name),
+        escape(about.as_deref().unwrap_or_default())
+    ));
+
+    page.push_str(".SH SYNOPSIS\n");
+    let mut synopsis = format!("\\fB{}\\fR [OPTIONS]", escape(&name));
+    for arg in cmd.get_positionals() {
+        synopsis.push_str(&format!(
+            " [{}]",
+            escape(&arg.get_id().to_string().to_uppercase())
+        ));
+    }
+    if cmd.get_subcommands().next().is_some() {
+        synopsis.push_str(" [COMMAND]");
+    }
+    page.push_str(&format!("{}\n", synopsis));
+
+    if let Some(description) = cmd.get_long_about().or(cmd.get_about()) {
+        page.push_str(".SH DESCRIPTION\n");
+        page.push_str(&format!("{}\n", escape(&description.to_string())));
+    }
+
+    page.push_str(".SH OPTIONS\n");
+    for arg in cmd.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        let mut flags = Vec::new();
+        if let Some(short) = arg.get_short() {
+            flags.push(format!("\\fB\\-{}\\fR", short));
+        }
+        if let Some(long) = arg.get_long() {
+            flags.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+        }
+        let mut item = if flags.is_empty() {
+            format!(
+                "\\fI{}\\fR",
+                escape(&arg.get_id().to_string().to_uppercase())
+            )
+        } else {
+            flags.join(", ")
+        };
+        if !flags.is_empty() && arg.get_action().takes_values() {
+            let value = arg
+                .get_value_names()
+                .and_then(|names| names.first())
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| arg.get_id().to_string().to_uppercase());
+            item.push_str(&format!(" \\fI{}\\fR", escape(&value)));
+        }
+
+        let help = arg
+            .get_long_help()
+            .or(arg.get_help())
+            .map(|help| help.to_string())
+            .unwrap_or_default();
+        page.push_str(&format!(".TP\n{}\n{}\n", item, escape(&help)));
+    }
+
+    let subcommands: Vec<&Command> = cmd
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .collect();
+    if !subcommands.is_empty() {
+        page.push_str(".SH COMMANDS\n");
+        for sub in subcommands {
+            let about = sub.get_about().map(|about| about.to_string());
+            page.push_str(&format!(
+                ".TP\n\\fB{}\\fR\n{}\n",
+                escape(sub.get_name()),
+                escape(about.as_deref().unwrap_or_default())
+            ));
+        }
+    }
+
+    page.push_str(".SH EXAMPLES\n.nf\n");
+    for line in EXAMPLES.lines() {
+        page.push_str(&format!("{}\n", escape(line)));
+    }
+    page.push_str(".fi\n");
+
+    page
+}
+
+// Escape text for roff, including lines that would be read as requests
+fn escape(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line.replace('\\', "\\e").replace('-', "\\-");
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,155 @@
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Component, Path};
+
+use crate::settings::settings;
+use crate::template::{strip_template_extension, TemplateSpec};
+
+// What home mode sends from the `[home]` list in the settings
+#[derive(Debug, Default, PartialEq)]
+pub struct HomePlan {
+    // Files and directories sent as they are
+    pub paths: Vec<String>,
+    // Listed files with a template extension, rendered for each remote
+    pub templates: Vec<TemplateSpec>,
+    // Listed but not there, left out with a warning
+    pub missing: Vec<String>,
+}
+
+// The `[home]` list, when a sync from `current_dir` is in home mode: the
+// list isn't empty and `current_dir` is the home directory
+pub fn home_files(current_dir: &Path) -> Option<&'static [String]> {
+    let files = &settings().home.files;
+    let home = dirs::home_dir()?;
+    let resolved = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    (!files.is_empty() && resolved(current_dir) == resolved(&home)).then_some(files.as_slice())
+}
+
+// Sort the listed paths, relative to `base`, into what's sent as it is and
+// what's rendered. Anything but a plain relative path is refused, so that
+// the list can never add up to the whole home directory.
+pub fn plan(base: &Path, files: &[String]) -> Result<HomePlan> {
+    let mut plan = HomePlan::default();
+    for file in files {
+        let file = file.trim_end_matches('/');
+        check_path(file)?;
+        let path = base.join(file);
+        if fs::symlink_metadata(&path).is_err() {
+            plan.missing.push(file.to_string());
+        } else if let Some(target) = strip_template_extension(file).filter(|_| path.is_file()) {
+            plan.templates.push(TemplateSpec {
+                source: file.to_string(),
+                target: target.to_string(),
+            });
+        } else {
+            plan.paths.push(file.to_string());
+        }
+    }
+    Ok(plan)
+}
+
+fn check_path(file: &str) -> Result<()> {
+    let plain = !file.is_empty()
+        && !file.starts_with('~')
+        && !file.contains(['*', '?', '[', '\n'])
+        && Path::new(file)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !plain {
+        anyhow::bail!(
+            "[home] files must name files or directories below the home directory, like .bashrc or .config/nvim, not '{}'",
+            file
+        );
+    }
+    Ok(())
+}
+
+// rsync arguments sending exactly the plan's paths, with everything under
+// listed directories but what matches `excludes`
+pub fn filter_args(plan: &HomePlan, excludes: &[String]) -> Vec<String> {
+    let mut rules: Vec<String> = excludes
+        .iter()
+        .map(|pattern| format!("- {}", pattern))
+        .collect();
+    // Template sources may be inside a listed directory
+    rules.extend(
+        plan.templates
+            .iter()
+            .map(|template| format!("- /{}", template.source)),
+    );
+
+    // rsync only looks inside directories that are included themselves
+    let parents: BTreeSet<&Path> = plan
+        .paths
+        .iter()
+        .flat_map(|path| Path::new(path).ancestors().skip(1))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+    rules.extend(
+        parents
+            .into_iter()
+            .map(|dir| format!("+ /{}/", dir.display())),
+    );
+    for path in &plan.paths {
+        rules.push(format!("+ /{}", path));
+        rules.push(format!("+ /{}/***", path));
+    }
+    rules.push("- *".to_string());
+
+    rules
+        .into_iter()
+        .map(|rule| format!("--filter={}", rule))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_listed_paths_are_sent() {
+        let base = std::env::temp_dir().join(format!("sync-rs-home-{}", std::process::id()));
+        fs::create_dir_all(base.join(".config/nvim")).unwrap();
+        fs::write(base.join(".bashrc"), "").unwrap();
+        fs::write(base.join(".gitconfig.tmpl"), "").unwrap();
+        let files = [".bashrc", ".config/nvim/", ".gitconfig.tmpl", ".vimrc"].map(String::from);
+        let plan = plan(&base, &files).unwrap();
+        fs::remove_dir_all(&base).unwrap();
+
+        assert_eq!(plan.paths, [".bashrc", ".config/nvim"]);
+        assert_eq!(
+            plan.templates,
+            [TemplateSpec {
+                source: ".gitconfig.tmpl".to_string(),
+                target: ".gitconfig".to_string(),
+            }]
+        );
+        assert_eq!(plan.missing, [".vimrc"]);
+        assert_eq!(
+            filter_args(&plan, &["*.log".to_string()]),
+            [
+                "--filter=- *.log",
+                "--filter=- /.gitconfig.tmpl",
+                "--filter=+ /.config/",
+                "--filter=+ /.bashrc",
+                "--filter=+ /.bashrc/***",
+                "--filter=+ /.config/nvim",
+                "--filter=+ /.config/nvim/***",
+                "--filter=- *",
+            ]
+        );
+
+        for file in [
+            "",
+            ".",
+            "..",
+            "/etc/hosts",
+            "~/.bashrc",
+            "a/../..",
+            ".config/*",
+        ] {
+            assert!(super::plan(&base, &[file.to_string()]).is_err(), "{}", file);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cache::get_config_dir;
+use crate::net::{connect_time, SshHost};
+use crate::remote::RemoteTarget;
+use crate::term::warn;
+
+// Link measurements are redone after this long
+const PROBE_MAX_AGE: u64 = 7 * 24 * 60 * 60;
+// Amount of incompressible data sent to estimate bandwidth
+const PROBE_BYTES: usize = 4 << 20;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What we know about a host, shared by every remote pointing to it
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HostInfo {
+    #[serde(default)]
+    pub link: Option<LinkProfile>,
+}
+
+// Measured connection characteristics
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkProfile {
+    // Round-trip time in milliseconds, from a TCP connect to the SSH port
+    pub rtt_ms: Option<f64>,
+    // Upload bandwidth in bytes per second
+    pub bandwidth: Option<u64>,
+    // Unix timestamp of the measurement
+    pub measured_at: u64,
+}
+
+// Transfer settings picked for a link
+#[derive(Debug, Clone, Default)]
+pub struct Strategy {
+    pub rsync_args: Vec<String>,
+    pub tar_stream: bool,
+}
+
+impl LinkProfile {
+    pub fn strategy(&self) -> Strategy {
+        let mut strategy = Strategy::default();
+        let fast = self.bandwidth.is_some_and(|b| b >= 50_000_000);
+        let slow = self.bandwidth.is_some_and(|b| b < 1_000_000);
+        let near = self.rtt_ms.is_some_and(|rtt| rtt < 5.0);
+        let far = self.rtt_ms.is_some_and(|rtt| rtt >= 50.0);
+
+        // On a fast local link, compressing and computing deltas costs more than it saves
+        if fast {
+            strategy.rsync_args.push("--no-compress".to_string());
+            if near {
+                strategy.rsync_args.push("--whole-file".to_string());
+            }
+        } else if slow {
+            strategy.rsync_args.push("--compress-level=9".to_string());
+        }
+
+        // rsync's per-file round trips add up on distant hosts
+        strategy.tar_stream = far;
+
+        strategy
+    }
+
+    pub fn describe(&self) -> String {
+        let rtt = self
+            .rtt_ms
+            .map_or("unknown RTT".to_string(), |rtt| format!("{:.1} ms", rtt));
+        let bandwidth = self.bandwidth.map_or("unknown bandwidth".to_string(), |b| {
+            format!("{:.1} MB/s", b as f64 / 1_000_000.0)
+        });
+        format!("{}, {}", rtt, bandwidth)
+    }
+
+    fn is_stale(&self) -> bool {
+        now().saturating_sub(self.measured_at) > PROBE_MAX_AGE
+    }
+}
+
+pub fn get_hosts_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("hosts.json"))
+}
+
+pub fn read_hosts(hosts_path: &Path) -> Result<HashMap<String, HostInfo>> {
+    if !hosts_path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let data = fs::read(hosts_path).context("Failed to read hosts file")?;
+
+    // Everything in here can be measured again, so a corrupt file isn't fatal
+    Ok(serde_json::from_slice(&data).unwrap_or_else(|_| {
+        warn(format_args!("Could not parse hosts file, starting fresh"));
+        HashMap::new()
+    }))
+}
+
+pub fn save_hosts(hosts_path: &Path, hosts: &HashMap<String, HostInfo>) -> Result<()> {
+    let file = File::create(hosts_path).context("Failed to create hosts file")?;
+    serde_json::to_writer_pretty(file, hosts).context("Failed to write hosts file")
+}
+
+// Link profile for an SSH host, measured on first use and when it gets old
+pub fn link_profile(target: &RemoteTarget, port: u16) -> Result<Option<LinkProfile>> {
+    let RemoteTarget::Ssh { host, .. } = target else {
+        return Ok(None);
+    };
+
+    let hosts_path = get_hosts_path()?;
+    let mut hosts = read_hosts(&hosts_path)?;
+    let info = hosts.entry(host.clone()).or_default();
+
+    if let Some(link) = info.link.as_ref().filter(|link| !link.is_stale()) {
+        return Ok(Some(link.clone()));
+    }
+
+    println!("Measuring connection to {}...", host);
+    let link = probe_link(target, host, port)?;
+    println!("Link to {}: {}", host, link.describe());
+    info.link = Some(link.clone());
+    save_hosts(&hosts_path, &hosts)?;
+
+    Ok(Some(link))
+}
+
+// Drop the link measurements of the host `target` connects to, so the next
+// sync measures again. Returns whether there were any.
+pub fn forget_link(target: &RemoteTarget) -> Result<bool> {
+    let RemoteTarget::Ssh { host, .. } = target else {
+        return Ok(false);
+    };
+
+    let hosts_path = get_hosts_path()?;
+    let mut hosts = read_hosts(&hosts_path)?;
+    if hosts
+        .get_mut(host)
+        .and_then(|info| info.link.take())
+        .is_none()
+    {
+        return Ok(false);
+    }
+    save_hosts(&hosts_path, &hosts)?;
+    Ok(true)
+}
+
+fn probe_link(target: &RemoteTarget, host: &str, port: u16) -> Result<LinkProfile> {
+    let destination = SshHost::parse(host);
+    let port = destination.port.unwrap_or(port);
+
+    // Best of three connects, as the first may include a DNS lookup
+    let rtt = (0..3)
+        .filter_map(|_| connect_time(&destination.host, port, PROBE_TIMEOUT))
+        .min()
+        .map(|rtt| rtt.as_secs_f64() * 1000.0);
+
+    // Time an upload against an empty one to leave out connection setup
+    let baseline = time_upload(target, &[])?;
+    let elapsed = time_upload(target, &probe_data())?;
+    let bandwidth = elapsed
+        .checked_sub(baseline)
+        .filter(|transfer| !transfer.is_zero())
+        .map(|transfer| (PROBE_BYTES as f64 / transfer.as_secs_f64()) as u64);
+
+    Ok(LinkProfile {
+        rtt_ms: rtt,
+        bandwidth,
+        measured_at: now(),
+    })
+}
+
+fn time_upload(target: &RemoteTarget, data: &[u8]) -> Result<Duration> {
+    let started = Instant::now();
+    let mut child = target
+        .command("cat > /dev/null")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to execute bandwidth probe")?;
+
+    child
+        .stdin
+        .take()
+        .context("Failed to open probe input")?
+        .write_all(data)
+        .context("Failed to send probe data")?;
+
+    let status = child.wait().context("Failed to wait for bandwidth probe")?;
+    if !status.success() {
+        anyhow::bail!("Bandwidth probe failed with exit code: {:?}", status.code());
+    }
+
+    Ok(started.elapsed())
+}
+
+// Pseudo-random bytes, so that SSH compression can't shrink them
+fn probe_data() -> Vec<u8> {
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    (0..PROBE_BYTES)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(rtt_ms: Option<f64>, bandwidth: Option<u64>) -> LinkProfile {
+        LinkProfile {
+            rtt_ms,
+            bandwidth,
+            measured_at: now(),
+        }
+    }
+
+    #[test]
+    fn strategies_follow_the_link() {
+        let cases = [
+            // Same rack: no compression, no deltas
+            (
+                Some(0.3),
+                Some(100_000_000),
+                vec!["--no-compress", "--whole-file"],
+                false,
+            ),
+            // Fast but across a WAN: deltas still pay off
+            (Some(20.0), Some(100_000_000), vec!["--no-compress"], false),
+            (Some(80.0), Some(100_000_000), vec!["--no-compress"], true),
+            // Slow links get the strongest compression
+            (Some(20.0), Some(500_000), vec!["--compress-level=9"], false),
+            (Some(120.0), Some(500_000), vec!["--compress-level=9"], true),
+            (Some(20.0), Some(10_000_000), vec![], false),
+            // Nothing measured, nothing changed
+            (None, None, vec![], false),
+        ];
+        for (rtt, bandwidth, args, tar_stream) in cases {
+            let strategy = link(rtt, bandwidth).strategy();
+            assert_eq!(strategy.rsync_args, args, "{:?} {:?}", rtt, bandwidth);
+            assert_eq!(strategy.tar_stream, tar_stream, "{:?} {:?}", rtt, bandwidth);
+        }
+    }
+
+    #[test]
+    fn measurements_describe_and_age() {
+        assert_eq!(
+            link(Some(12.345), Some(2_500_000)).describe(),
+            "12.3 ms, 2.5 MB/s"
+        );
+        assert_eq!(
+            link(None, None).describe(),
+            "unknown RTT, unknown bandwidth"
+        );
+
+        let mut profile = link(None, None);
+        assert!(!profile.is_stale());
+        profile.measured_at = now() - PROBE_MAX_AGE - 1;
+        assert!(profile.is_stale());
+    }
+
+    #[test]
+    fn hosts_files_round_trip() {
+        let path = std::env::temp_dir().join(format!("sync-rs-hosts-{}.json", std::process::id()));
+        assert!(read_hosts(&path).unwrap().is_empty());
+
+        let hosts: HashMap<String, HostInfo> = [(
+            "me@box".to_string(),
+            HostInfo {
+                link: Some(link(Some(1.5), Some(42))),
+            },
+        )]
+        .into();
+        save_hosts(&path, &hosts).unwrap();
+        let read = read_hosts(&path).unwrap();
+        let read_link = read["me@box"].link.clone().unwrap();
+        assert_eq!(
+            (read_link.rtt_ms, read_link.bandwidth),
+            (Some(1.5), Some(42))
+        );
+
+        // A corrupt file starts over instead of failing syncs
+        fs::write(&path, "{ not json").unwrap();
+        let corrupt = read_hosts(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(corrupt.is_empty());
+    }
+
+    #[test]
+    fn only_ssh_hosts_are_measured() {
+        let container = RemoteTarget::parse("docker:web");
+        assert!(link_profile(&container, 22).unwrap().is_none());
+        assert!(!forget_link(&container).unwrap());
+    }
+
+    #[test]
+    fn probe_data_does_not_compress() {
+        let data = probe_data();
+        assert_eq!(data.len(), PROBE_BYTES);
+        assert_eq!(data, probe_data());
+        let mut seen = [0usize; 256];
+        for byte in &data {
+            seen[*byte as usize] += 1;
+        }
+        // Every byte value shows up about equally often
+        let expected = PROBE_BYTES / 256;
+        assert!(seen
+            .iter()
+            .all(|&count| count > expected * 9 / 10 && count < expected * 11 / 10));
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::sync::OnceLock;
+
+use crate::cache::get_config_dir;
+
+// User-facing messages by key. English is the fallback for anything missing
+// from the selected language.
+const EN: &[(&str, &str)] = &[
+    (
+        "prompt.remote_host",
+        "Enter remote host (e.g., user@host): ",
+    ),
+    (
+        "prompt.remote_dir",
+        "Enter remote directory (relative to remote home): ",
+    ),
+    ("prompt.selection", "Enter selection (1-{}): "),
+    (
+        "prompt.select_remote",
+        "Multiple remote configurations found. Please select one:",
+    ),
+    (
+        "list.empty",
+        "No remote configurations found for this directory.",
+    ),
+    ("list.header", "Remote configurations for this directory:"),
+    ("list.preferred", " (preferred)"),
+    ("list.disabled", " (disabled)"),
+    ("list.last_sync", "synced {}"),
+    ("list.never_synced", "never synced"),
+    ("list.budget", "{} of {} transferred this month"),
+    ("remove.done", "Removed remote configuration '{}'"),
+    ("sync.start", "Syncing to {} ({})"),
+    ("pull.start", "Pulling from {} ({})"),
+    ("sync.post_command", "Executing post-sync command: {}"),
+    ("sync.shell", "Opening interactive shell in {}"),
+    ("status.just_now", "just now"),
+    ("status.minutes_ago", "{}m ago"),
+    ("status.hours_ago", "{}h ago"),
+    ("status.days_ago", "{}d ago"),
+];
+
+const ZH: &[(&str, &str)] = &[
+    ("prompt.remote_host", "请输入远程主机（例如 user@host）："),
+    ("prompt.remote_dir", "请输入远程目录（相对于远程主目录）："),
+    ("prompt.selection", "请选择 (1-{})："),
+    ("prompt.select_remote", "找到多个远程配置，请选择一个："),
+    ("list.empty", "当前目录没有远程配置。"),
+    ("list.header", "当前目录的远程配置："),
+    ("list.preferred", "（首选）"),
+    ("list.disabled", "（已停用）"),
+    ("list.last_sync", "{}同步"),
+    ("list.never_synced", "从未同步"),
+    ("list.budget", "本月已传输 {}，预算 {}"),
+    ("remove.done", "已删除远程配置 '{}'"),
+    ("sync.start", "正在同步到 {} ({})"),
+    ("pull.start", "正在从 {} 拉取 ({})"),
+    ("sync.post_command", "正在执行同步后命令：{}"),
+    ("sync.shell", "正在 {} 中打开交互式 shell"),
+    ("status.just_now", "刚刚"),
+    ("status.minutes_ago", "{} 分钟前"),
+    ("status.hours_ago", "{} 小时前"),
+    ("status.days_ago", "{} 天前"),
+];
+
+static LANG: OnceLock<String> = OnceLock::new();
+static CATALOG: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+// Select the message language: `lang` if given (from --lang), otherwise the
+// locale from LC_ALL, LC_MESSAGES or LANG. The catalog itself is only loaded
+// when the first message is looked up.
+pub fn init(lang: Option<&str>) {
+    let lang = lang
+        .map(str::to_string)
+        .or_else(locale_language)
+        .unwrap_or_else(|| "en".to_string());
+    let _ = LANG.set(lang);
+}
+
+// Messages in ~/.config/sync-rs/messages/<lang>.json override the built-in
+// ones, which is also how to add a language
+fn load_catalog() -> HashMap<String, String> {
+    let lang = LANG.get().map(String::as_str).unwrap_or("en");
+    let overrides = get_config_dir()
+        .ok()
+        .map(|dir| dir.join("messages").join(format!("{}.json", lang)))
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|data| serde_json::from_slice::<HashMap<String, String>>(&data).ok());
+    catalog(lang, overrides.unwrap_or_default())
+}
+
+// English, then the built-in messages for `lang`, then the user's overrides
+fn catalog(lang: &str, overrides: HashMap<String, String>) -> HashMap<String, String> {
+    let builtin = match lang {
+        "zh" => ZH,
+        _ => &[],
+    };
+
+    let mut catalog: HashMap<String, String> = EN
+        .iter()
+        .chain(builtin)
+        .map(|(key, message)| (key.to_string(), message.to_string()))
+        .collect();
+    catalog.extend(overrides);
+
+    catalog
+}
+
+// Language code of the current locale, e.g. `zh` for `zh_CN.UTF-8`
+fn locale_language() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .and_then(|value| language(&value))
+}
+
+fn language(locale: &str) -> Option<String> {
+    Some(
+        locale
+            .split(['_', '.', '@'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase(),
+    )
+    .filter(|lang| !lang.is_empty() && lang != "c" && lang != "posix")
+}
+
+// Look up a message and fill its `{}` placeholders with `args` in order.
+// Unknown keys come back as the key itself, so a gap is visible but harmless.
+pub fn tr(key: &str, args: &[&dyn Display]) -> String {
+    lookup(CATALOG.get_or_init(load_catalog), key, args)
+}
+
+fn lookup(catalog: &HashMap<String, String>, key: &str, args: &[&dyn Display]) -> String {
+    let template = catalog.get(key).map(String::as_str).unwrap_or(key);
+
+    let mut message = String::with_capacity(template.len());
+    let mut args = args.iter();
+    let mut rest = template;
+    while let Some(index) = rest.find("{}") {
+        message.push_str(&rest[..index]);
+        match args.next() {
+            Some(arg) => message.push_str(&arg.to_string()),
+            None => message.push_str("{}"),
+        }
+        rest = &rest[index + 2..];
+    }
+    message.push_str(rest);
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_translation_has_an_english_original() {
+        let english: HashMap<_, _> = EN.iter().copied().collect();
+        for (key, message) in ZH {
+            let original = english
+                .get(key)
+                .unwrap_or_else(|| panic!("{} isn't in EN", key));
+            assert_eq!(
+                message.matches("{}").count(),
+                original.matches("{}").count(),
+                "placeholders of {}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn messages_fall_back_to_english_then_the_key() {
+        let zh = catalog("zh", HashMap::new());
+        assert_eq!(lookup(&zh, "list.preferred", &[]), "（首选）");
+        assert_eq!(lookup(&zh, "no.such.key", &[&1]), "no.such.key");
+
+        // Languages without built-in messages get English
+        let fr = catalog("fr", HashMap::new());
+        assert_eq!(lookup(&fr, "list.preferred", &[]), " (preferred)");
+
+        // User overrides win, and anything they leave out falls back
+        let overrides = [("list.preferred".to_string(), " (préféré)".to_string())];
+        let fr = catalog("fr", overrides.into());
+        assert_eq!(lookup(&fr, "list.preferred", &[]), " (préféré)");
+        assert_eq!(lookup(&fr, "list.disabled", &[]), " (disabled)");
+    }
+
+    #[test]
+    fn placeholders_are_filled_in_order() {
+        let en = catalog("en", HashMap::new());
+        assert_eq!(
+            lookup(&en, "sync.start", &[&"gpu", &"box:~/app"]),
+            "Syncing to gpu (box:~/app)"
+        );
+        // Missing arguments leave the placeholder, extra ones are dropped
+        assert_eq!(lookup(&en, "sync.start", &[&"gpu"]), "Syncing to gpu ({})");
+        assert_eq!(lookup(&en, "status.hours_ago", &[&3, &4]), "3h ago");
+        // Arguments aren't formatted again
+        assert_eq!(
+            lookup(&en, "remove.done", &[&"{}"]),
+            "Removed remote configuration '{}'"
+        );
+    }
+
+    #[test]
+    fn languages_come_from_locales() {
+        assert_eq!(language("zh_CN.UTF-8").as_deref(), Some("zh"));
+        assert_eq!(language("de@euro").as_deref(), Some("de"));
+        assert_eq!(language("EN").as_deref(), Some("en"));
+        assert_eq!(language("C.UTF-8"), None);
+        assert_eq!(language("POSIX"), None);
+        assert_eq!(language(""), None);
+    }
+}
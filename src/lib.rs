@@ -1,7 +1,52 @@
 pub mod cache;
+pub mod chunked;
 pub mod config;
+pub mod dedup;
+pub mod error;
+pub mod events;
+pub mod glob;
+pub mod healthcheck;
+pub mod help;
+pub mod home;
+pub mod hosts;
+pub mod i18n;
+pub mod keys;
+#[cfg(feature = "native-ssh")]
+pub mod native_ssh;
+pub mod nested;
+pub mod net;
+pub mod notify;
+pub mod plugin;
+pub mod policy;
+pub mod remote;
+pub mod report;
+pub mod resolve;
+pub mod rpc;
+pub mod runner;
+pub mod schedule;
+pub mod scripts;
+pub mod selection;
+pub mod session;
+pub mod settings;
+pub mod sftp;
+pub mod snapshot;
+pub mod sshconfig;
+pub mod state;
+pub mod statusline;
 pub mod sync;
+pub mod task;
+pub mod template;
+pub mod term;
+pub mod timings;
+pub mod toml;
+pub mod transport;
+pub mod vm;
+pub mod walk;
+pub mod watch;
 
 // Re-export key types for easier external use
 pub use cache::{get_cache_path, MigrationManager, RemoteMap};
 pub use config::RemoteEntry;
+pub use error::{SyncError, SyncResult};
+pub use remote::RemoteTarget;
+pub use state::{RemoteState, StateMap};
@@ -1,25 +1,79 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 // Import from our crate modules
 use sync_rs::{
-    cache::{get_cache_path, MigrationManager, RemoteMap},
+    cache::{
+        describe_changes, get_cache_path, path_key, projects_dir, undo_last_change, validate_cache,
+        MigrationManager, RemoteMap,
+    },
+    chunked::{format_size, parse_size, DEFAULT_CHUNK_SIZE},
     config::{
-        generate_unique_name, list_remotes, prompt_remote_info, remove_remote, select_remote,
+        active_remotes, check_aliases, checked_name, confirm, find_duplicates,
+        generate_unique_name, list_remotes, merge_entries, normalize_remote_dir, prompt_remote_dir,
+        remote_full_dir, remove_remotes, rename_remote, select_from, set_disabled, tag_remotes,
         RemoteEntry,
     },
-    sync::{execute_ssh_command, get_remote_home, open_remote_shell, sync_directory},
+    error::SyncError,
+    events,
+    help::{examples_help, render_man, EXAMPLES},
+    home,
+    hosts::forget_link,
+    i18n::{self, tr},
+    nested::NestedRepos,
+    plugin::{find_plugin, list_plugins},
+    policy::load_policy,
+    remote::{shell_quote, RemoteTarget},
+    report::{send_report, MailReport},
+    rpc,
+    schedule::{current_bwlimit, BandwidthWindow},
+    scripts::{trust_hook_scripts, untrust_hook_scripts, HOOKS_DIR},
+    selection::RemoteRequest,
+    session::{
+        in_remote_dir, policy_for_target, preflight, pull_filter, record_transfer,
+        run_post_command, Syncer, MISSING_DIR_EXIT, TRASH_DIR,
+    },
+    settings::{get_settings_path, settings},
+    snapshot::{get_snapshots_path, read_snapshots, save_snapshots, Snapshot},
+    sshconfig::{host_block, ssh_config_hosts, SshConfigHost},
+    state::{
+        get_state_path, read_remote_state, read_state, save_remote_state, save_state, RemoteState,
+    },
+    statusline::{format_ago, statusline},
+    sync::{
+        check_free_space, dir_contents, get_remote_home, list_remote_files, open_remote_shell,
+        run_remote_script, sync_directory, RsyncOptions, LOCAL_SOURCE,
+    },
+    template::TemplateSpec,
+    term::{bold, dim, green, warn, yellow, Pager},
+    timings::{format_ms, TimedRun, Timings},
+    toml,
+    transport::TransportKind,
+    watch::Watcher,
 };
 
 // This application requires a Unix-like environment
 #[cfg(windows)]
 compile_error!("This application does not support Windows. Please use Linux or macOS.");
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None, after_long_help = examples_help())]
 struct Args {
-    /// Remote host (e.g., user@host)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Remote host (e.g., user@host), or on its own the name of a configured remote
     remote_host: Option<String>,
 
     /// Remote directory (relative to remote home)
@@ -45,52 +99,776 @@ struct Args {
     #[arg(short, long)]
     list: bool,
 
-    /// Remove a remote configuration by name
-    #[arg(short = 'r', long)]
-    remove: Option<String>,
+    /// Remove remote configurations by name or glob pattern, e.g. 'gpu*' (can specify multiple)
+    #[arg(short = 'r', long, value_name = "NAME", num_args = 1..)]
+    remove: Vec<String>,
+
+    /// Don't ask before changing several remotes at once
+    #[arg(short = 'y', long, global = true)]
+    yes: bool,
 
     /// Enable delete mode for override paths (default: disabled)
     #[arg(short = 'd', long)]
     delete_override: bool,
 
+    /// Move files deleted or overwritten on the remote into .sync-rs-trash instead of removing them
+    #[arg(long)]
+    trash: bool,
+
+    /// Turn --trash off again
+    #[arg(long, conflicts_with = "trash")]
+    no_trash: bool,
+
+    /// Give files of a local user another owner on the remote, e.g. me:www-data or '*:app' (can specify multiple; "" removes them)
+    #[arg(long, value_name = "FROM:TO", value_parser = parse_owner_mapping)]
+    usermap: Vec<String>,
+
+    /// Like --usermap, for groups
+    #[arg(long, value_name = "FROM:TO", value_parser = parse_owner_mapping)]
+    groupmap: Vec<String>,
+
+    /// Run rsync with sudo on the remote, e.g. so that --usermap works without logging in as root
+    #[arg(long)]
+    sudo_rsync: bool,
+
+    /// Turn --sudo-rsync off again
+    #[arg(long, conflicts_with = "sudo_rsync")]
+    no_sudo_rsync: bool,
+
+    /// What to do with git repositories inside the directory, like submodules: sync, exclude-git or skip
+    #[arg(long, value_name = "MODE", value_parser = NestedRepos::parse)]
+    nested_repos: Option<NestedRepos>,
+
+    /// Only sync git submodules that are initialized here, leaving the others alone on the remote
+    #[arg(long)]
+    initialized_submodules_only: bool,
+
+    /// Turn --initialized-submodules-only off again
+    #[arg(long, conflicts_with = "initialized_submodules_only")]
+    no_initialized_submodules_only: bool,
+
+    /// After syncing, warn about submodules checked out differently on the remote
+    #[arg(long)]
+    check_submodules: bool,
+
+    /// Turn --check-submodules off again
+    #[arg(long, conflicts_with = "check_submodules")]
+    no_check_submodules: bool,
+
+    /// How to send files: rsync, sftp (changed files, no deletions), tar (everything, no deletions) or auto
+    #[arg(long, value_name = "TRANSPORT", value_parser = TransportKind::parse)]
+    transport: Option<TransportKind>,
+
     /// Set this remote as the preferred one for this directory
     #[arg(short = 'P', long)]
     preferred: bool,
 
+    /// Allow replacing the remote named with --name when a host and directory are given
+    #[arg(long)]
+    update: bool,
+
+    /// Always pick the remote from a menu, even when there's a preferred or last-used one
+    #[arg(long, conflicts_with_all = ["name", "remote_host"])]
+    select: bool,
+
+    /// Sync to every remote of this directory that isn't disabled, one after another
+    #[arg(long, conflicts_with_all = ["name", "remote_host", "select", "pull", "watch", "no_cache"])]
+    all: bool,
+
+    /// With --all, sync to up to this many remotes at the same time
+    #[arg(long, value_name = "N", requires = "all")]
+    parallel: Option<usize>,
+
     /// Patterns to ignore (can specify multiple)
     #[arg(short = 'i', long = "ignore")]
     ignore_patterns: Vec<String>,
+
+    /// Remote-generated paths that are never deleted or overwritten (can specify multiple)
+    #[arg(long = "protect")]
+    protected_paths: Vec<String>,
+
+    /// Template to render and sync as SOURCE[:TARGET] (can specify multiple)
+    #[arg(long = "template", value_parser = TemplateSpec::parse)]
+    templates: Vec<TemplateSpec>,
+
+    /// Template variable as KEY=VALUE (can specify multiple)
+    #[arg(long = "var", value_parser = parse_key_value)]
+    template_vars: Vec<(String, String)>,
+
+    /// Extra ssh option as KEY=VALUE, e.g. Port=2222 (can specify multiple)
+    #[arg(long = "ssh-option")]
+    ssh_options: Vec<String>,
+
+    /// Command printing the host address at sync time (ec2:TAG=VALUE and gcp:LABEL=VALUE look up cloud instances)
+    #[arg(long)]
+    host_command: Option<String>,
+
+    /// Other address of the same machine, used when the host isn't reachable (can specify multiple)
+    #[arg(long = "alt-host")]
+    alternate_hosts: Vec<String>,
+
+    /// Look the host up on your tailnet and pick whichever address is reachable
+    #[arg(long)]
+    tailscale: bool,
+
+    /// Turn --tailscale off again
+    #[arg(long, conflicts_with = "tailscale")]
+    no_tailscale: bool,
+
+    /// MAC address to wake the machine with (wake-on-LAN) when it's not reachable
+    #[arg(long)]
+    wol_mac: Option<String>,
+
+    /// Seconds to wait for the machine to come up after waking it (default: 120)
+    #[arg(long)]
+    wol_timeout: Option<u64>,
+
+    /// Bandwidth limit as [DAYS ]HH:MM-HH:MM=LIMIT, e.g. "mon-fri 09:00-18:00=2m" (can specify multiple)
+    #[arg(long = "bandwidth-window", value_parser = validate_bandwidth_window)]
+    bandwidth_windows: Vec<String>,
+
+    /// Bandwidth limit for this run only, overriding any schedule (rsync --bwlimit)
+    #[arg(long)]
+    bwlimit: Option<String>,
+
+    /// Store files of at least SIZE (e.g. 100M) once per host and hard link them into the project
+    #[arg(long = "dedup", value_name = "SIZE", value_parser = parse_size)]
+    dedup_min_size: Option<u64>,
+
+    /// Send many changed files as a single tar stream, which is much faster than rsync for lots of small files on slow links
+    #[arg(long)]
+    tar_stream: bool,
+
+    /// Turn --tar-stream off again
+    #[arg(long, conflicts_with = "tar_stream")]
+    no_tar_stream: bool,
+
+    /// Keep rsync's defaults instead of adapting compression, delta transfer and tar streaming to the measured link
+    #[arg(long)]
+    fixed_strategy: bool,
+
+    /// Turn --fixed-strategy off again
+    #[arg(long, conflicts_with = "fixed_strategy")]
+    no_fixed_strategy: bool,
+
+    /// Create the remote from a running Vagrant machine or Multipass instance
+    #[arg(long)]
+    vm: bool,
+
+    /// Overwrite the remote even if it changed since the last sync, and allow
+    /// syncing from the home directory, `/` or a configured never-sync path
+    #[arg(long)]
+    force: bool,
+
+    /// Push in resumable chunks, picking up where an interrupted run left off
+    #[arg(long)]
+    chunked: bool,
+
+    /// Approximate size of each chunk with --chunked, e.g. 512M (default: 1G)
+    #[arg(long, value_parser = parse_size, requires = "chunked")]
+    chunk_size: Option<u64>,
+
+    /// Plugin to run as a pre- and post-sync hook (can specify multiple)
+    #[arg(long = "plugin")]
+    plugins: Vec<String>,
+
+    /// Command used instead of ssh to reach the host, e.g. "tsh ssh {host}" (the remote command is appended)
+    #[arg(long)]
+    connect_command: Option<String>,
+
+    /// Note shown next to the remote's name in lists, e.g. "A100 box, use for big runs"
+    #[arg(long)]
+    description: Option<String>,
+
+    /// Other name for this remote, usable wherever its name is, e.g. `g` (can specify multiple; "" removes them)
+    #[arg(long = "alias", value_name = "ALIAS")]
+    aliases: Vec<String>,
+
+    /// URL pinged on sync start, success (URL) and failure (URL/fail), healthchecks.io style
+    #[arg(long)]
+    healthcheck_url: Option<String>,
+
+    /// Only run the post-sync command when files matching this pattern changed (can specify multiple)
+    #[arg(long = "trigger", value_name = "PATTERN")]
+    post_command_triggers: Vec<String>,
+
+    /// Run the post-sync command even if no (relevant) files changed
+    #[arg(long)]
+    always_run: bool,
+
+    /// Run the post-sync command at most once per this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    post_command_cooldown: Option<u64>,
+
+    /// Run a post-sync command held back by the cooldown once syncs have been quiet for a full cooldown
+    #[arg(long)]
+    post_command_coalesce: bool,
+
+    /// Turn --post-command-coalesce off again
+    #[arg(long, conflicts_with = "post_command_coalesce")]
+    no_post_command_coalesce: bool,
+
+    /// Create the remote directory and its parents when they're missing instead of failing
+    #[arg(long)]
+    auto_create: bool,
+
+    /// Turn --auto-create off again
+    #[arg(long, conflicts_with = "auto_create")]
+    no_auto_create: bool,
+
+    /// Group to give synced files on the remote, made group-writable with setgid directories
+    #[arg(long, value_name = "GROUP")]
+    shared_group: Option<String>,
+
+    /// Also set default ACLs for the shared group on remote directories
+    #[arg(long)]
+    shared_acl: bool,
+
+    /// Turn --shared-acl off again
+    #[arg(long, conflicts_with = "shared_acl")]
+    no_shared_acl: bool,
+
+    /// Shell to run the post-sync command with on the remote, e.g. "bash" or "zsh -l"
+    #[arg(long, value_name = "SHELL")]
+    remote_shell: Option<String>,
+
+    /// SSH host to reach the remote through when it can't be reached directly; files are staged there ("" removes it)
+    #[arg(long, value_name = "HOST")]
+    relay: Option<String>,
+
+    /// Remove the files staged on the relay once they've been forwarded
+    #[arg(long)]
+    relay_cleanup: bool,
+
+    /// Turn --relay-cleanup off again
+    #[arg(long, conflicts_with = "relay_cleanup")]
+    no_relay_cleanup: bool,
+
+    /// Refuse to sync when it would delete more than this many files on the remote
+    #[arg(long)]
+    max_deletes: Option<usize>,
+
+    /// Warn when syncs and pulls move more than this a month, e.g. 50G for a metered host
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    monthly_budget: Option<u64>,
+
+    /// Check the remote again instead of reusing results from the last minute
+    #[arg(long)]
+    fresh: bool,
+
+    /// Take the remote from the command line alone, without reading or writing the cache or state
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Language for messages, e.g. en or zh (default: from the locale)
+    #[arg(long, global = true)]
+    lang: Option<String>,
+
+    /// Print listings, timings and dry runs straight to the terminal instead of through $PAGER
+    #[arg(long, global = true)]
+    no_pager: bool,
+
+    /// Append JSONL events (sync started/finished, file counts, hook results) to a file or FIFO [env: SYNC_RS_EVENT_LOG]
+    #[arg(long)]
+    event_log: Option<PathBuf>,
+
+    /// Print how long each phase of the sync took
+    #[arg(long)]
+    timings: bool,
+
+    /// End with the sync's counts (files, bytes, deletions, per path) as a JSON line instead of a summary
+    #[arg(long)]
+    summary_json: bool,
+
+    /// Email a summary of the sync to the [report] recipients in the settings, on failure or always
+    #[arg(long, value_name = "WHEN")]
+    report: Option<ReportWhen>,
+
+    /// Copy the remote directory back here instead of syncing to it, without deleting anything locally
+    #[arg(long)]
+    pull: bool,
+
+    /// Keep running and sync again whenever local files change
+    #[arg(long, conflicts_with_all = ["pull", "no_cache"])]
+    watch: bool,
+
+    /// Show what a sync would transfer and delete, without changing anything on the remote
+    #[arg(long, conflicts_with_all = ["pull", "watch"])]
+    dry_run: bool,
+
+    /// Also bring back files created or changed on the remote since the last sync; newer copies win on both sides
+    #[arg(long, conflicts_with_all = ["pull", "dry_run"])]
+    bidirectional: bool,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Sync to a remote; the same as leaving out the subcommand
+    #[command(disable_help_flag = true)]
+    Sync {
+        /// [REMOTE_HOST REMOTE_DIR] [OPTIONS], see `sync-rs --help`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
+    /// Copy a remote's directory back here; the same as `--pull`
+    #[command(disable_help_flag = true)]
+    Pull {
+        /// [REMOTE_HOST REMOTE_DIR] [OPTIONS], see `sync-rs --help`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
+    /// Manage this directory's remote configurations without syncing
+    Remote {
+        #[command(subcommand)]
+        action: RemoteCommand,
+    },
+
+    /// Open an interactive shell in a remote's directory without syncing
+    Shell {
+        /// Remote to use, picked as for syncing if left out
+        name: Option<String>,
+    },
+
+    /// Check a remote again (home directory, rsync, reachability) and forget its link measurements, e.g. after a server upgrade
+    Refresh {
+        /// Remote to check, picked as for syncing if left out
+        name: Option<String>,
+        /// Check every remote of this directory that isn't disabled
+        #[arg(long, conflicts_with = "name")]
+        all: bool,
+    },
+
+    /// Run a command in the directory of every remote of this directory, e.g. to clear caches or check versions
+    ExecAll {
+        /// Run on up to this many remotes at the same time
+        #[arg(long, value_name = "N")]
+        parallel: Option<usize>,
+        /// Command to run, after --
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Show what syncing from the home directory sends, from the [home] list in the settings
+    Home,
+
+    /// Print a compact status segment for the current directory, for tmux or shell prompts
+    Statusline,
+
+    /// Speak JSON-RPC over stdin/stdout for editor plugins
+    Rpc,
+
+    /// Show annotated examples of common workflows
+    HelpExamples,
+
+    /// Print the man page (roff) to stdout
+    Man,
+
+    /// List plugins (sync-rs-<name> executables) found on PATH
+    Plugins,
+
+    /// Revert the most recent change to the remote configurations
+    Undo,
+
+    /// Find remotes of this directory that point at the same place and merge them
+    Duplicates,
+
+    /// Compare the files on two remotes of this directory, e.g. staging and prod before a release
+    Compare { first: String, second: String },
+
+    /// Add or remove tags on remotes, picked by name or glob pattern
+    Tag {
+        #[command(subcommand)]
+        action: TagCommand,
+    },
+
+    /// Keep a remote but stop offering or picking it, e.g. while the server is down
+    Disable { name: String },
+
+    /// Make a disabled remote available again
+    Enable { name: String },
+
+    /// Save or restore named snapshots of this directory's remote configurations
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+
+    /// Inspect the cache of remote configurations
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+
+    /// List, restore or empty the files a remote's syncs moved into its trash (see --trash)
+    Trash {
+        /// Remote to use, picked as for syncing if left out
+        #[arg(short = 'n', long = "name", global = true)]
+        remote: Option<String>,
+        #[command(subcommand)]
+        action: TrashCommand,
+    },
+
+    /// Let syncs run this project's scripts in .sync-rs/hooks, or stop them
+    Hooks {
+        #[command(subcommand)]
+        action: HooksCommand,
+    },
+
+    /// Show phase timings of recent syncs from this directory
+    Timings {
+        /// Print the recorded runs as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Run a post-sync command held back by its cooldown (started by sync-rs itself)
+    #[command(hide = true)]
+    RunDeferred { remote: String, token: u64 },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum RemoteCommand {
+    /// Add a remote, or update the one for the same place
+    #[command(disable_help_flag = true)]
+    Add {
+        /// REMOTE_HOST REMOTE_DIR [OPTIONS], taking the same options as syncing
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
+    /// List the remotes of this directory
+    List,
+
+    /// Remove remotes by name or glob pattern
+    Remove {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+
+    /// Give a remote a new name
+    Rename { old: String, new: String },
+
+    /// Add remotes for hosts of ~/.ssh/config, picked from a list if none are given
+    ImportSsh {
+        /// Host entries to import
+        hosts: Vec<String>,
+        /// Remote directory for the imported remotes (asked for if left out)
+        #[arg(long)]
+        dir: Option<String>,
+    },
+
+    /// Write the remotes of this directory as JSON, e.g. to share with teammates
+    Export {
+        /// File to write to instead of stdout
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Write ssh_config Host blocks instead, so `ssh NAME` reaches the same hosts
+        #[arg(long)]
+        ssh_config: bool,
+    },
+
+    /// Add remotes written by `remote export` to this directory
+    Import {
+        /// File to read from instead of stdin
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum TagCommand {
+    /// Tag remotes, e.g. `tag add staging web1 web2`
+    Add {
+        tag: String,
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+
+    /// Take a tag off remotes
+    Remove {
+        tag: String,
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ConfigCommand {
+    /// Save this directory's remote configurations under a name
+    Snapshot { name: String },
+
+    /// Replace this directory's remote configurations with a snapshot
+    Restore { name: String },
+
+    /// List this directory's snapshots
+    Snapshots,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum TrashCommand {
+    /// List the trashed files, by the sync that trashed them
+    List,
+
+    /// Copy trashed files back into this directory, leaving existing files alone
+    Restore {
+        /// Batch to restore from, as shown by `trash list`
+        batch: String,
+        /// Files or directories in the batch (default: all of it)
+        paths: Vec<String>,
+    },
+
+    /// Delete trashed files for good
+    Empty {
+        /// Batches to delete (default: the whole trash)
+        batches: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum HooksCommand {
+    /// Run the hook scripts as they are now; changing them needs trusting them again
+    Trust,
+
+    /// Stop running the hook scripts
+    Untrust,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum CacheCommand {
+    /// Print the location of the directory holding each project's remotes
+    Path,
+
+    /// Print the remote configurations of all directories
+    Dump {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DumpFormat::Text)]
+        format: DumpFormat,
+    },
+
+    /// Check the cache for schema errors and paths that no longer exist
+    Validate,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ReportWhen {
+    Failure,
+    Always,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DumpFormat {
+    Text,
+    Json,
+    Toml,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let is_builtin = |name: &str| {
+        Args::command()
+            .get_subcommands()
+            .any(|sub| sub.get_name() == name)
+    };
+
+    // Aliases from the settings come first, so that they can stand for
+    // plugins too
+    let raw_args: Vec<String> =
+        settings().expand_alias(env::args().skip(1).collect(), is_builtin)?;
+
+    // `sync-rs NAME ARGS...` runs the `sync-rs-NAME` plugin if there is one.
+    // Built-in subcommands win, but building the command to check is only
+    // worth it once a plugin was found.
+    if let Some(name) = raw_args.first().filter(|name| !name.starts_with('-')) {
+        if let Some(plugin) = find_plugin(name) {
+            if !is_builtin(name) {
+                let status = process::Command::new(plugin)
+                    .args(&raw_args[1..])
+                    .status()?;
+                process::exit(status.code().unwrap_or(1));
+            }
+        }
+    }
+
+    let mut args = Args::parse_from(env::args().take(1).chain(raw_args.clone()));
+    // `sync-rs sync ARGS...` spells out the default command
+    match &args.command {
+        Some(Command::Sync { args: rest }) => {
+            args = Args::parse_from(env::args().take(1).chain(rest.clone()));
+        }
+        Some(Command::Pull { args: rest }) => {
+            args = Args::parse_from(env::args().take(1).chain(rest.clone()));
+            args.pull = true;
+        }
+        _ => {}
+    }
+    i18n::init(args.lang.as_deref());
+
+    if let Some(path) = args
+        .event_log
+        .clone()
+        .or_else(|| env::var_os("SYNC_RS_EVENT_LOG").map(PathBuf::from))
+    {
+        events::init(&path);
+    }
 
     // Get current directory and cache path
     let current_dir = env::current_dir()?;
-    let current_dir_str = current_dir.to_str().unwrap_or_default().to_string();
+    let current_dir_str = path_key(&current_dir);
+
+    // Prompts run this all the time, so it only reads the state file
+    if let Some(Command::Statusline) = args.command {
+        let state = read_state(&get_state_path()?)?;
+        if let Some(line) = statusline(&state, &current_dir_str) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    // Output that can run past a screen goes through a pager
+    let _pager = match &args.command {
+        Some(
+            Command::HelpExamples
+            | Command::Timings { .. }
+            | Command::Cache {
+                action: CacheCommand::Dump { .. },
+            }
+            | Command::Remote {
+                action: RemoteCommand::List,
+            },
+        ) => Pager::start(args.no_pager),
+        None if args.list => Pager::start(args.no_pager),
+        _ => None,
+    };
+
+    match &args.command {
+        Some(Command::Rpc) => return rpc::serve(),
+        Some(Command::HelpExamples) => {
+            print!("{}", EXAMPLES);
+            return Ok(());
+        }
+        Some(Command::Man) => {
+            print!("{}", render_man(&Args::command()));
+            return Ok(());
+        }
+        Some(Command::Plugins) => {
+            for name in list_plugins() {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
+        Some(Command::Cache { action }) => return cache_command(action),
+        Some(Command::Undo) => return undo(),
+        Some(Command::Duplicates) => return merge_duplicates(&current_dir_str),
+        Some(Command::Compare { first, second }) => {
+            return compare_remotes(&current_dir_str, first, second)
+        }
+        Some(Command::Remote { action }) => {
+            return remote_command(action, &current_dir_str, args.yes)
+        }
+        Some(Command::Shell { name }) => return shell_command(name.clone()),
+        Some(Command::Refresh { name, all }) => {
+            return refresh_command(&current_dir_str, name.clone(), *all)
+        }
+        Some(Command::Home) => return home_command(),
+        Some(Command::ExecAll { parallel, command }) => {
+            return exec_all_command(&current_dir_str, &command.join(" "), *parallel)
+        }
+        Some(Command::Tag { action }) => return tag_command(action, &current_dir_str, args.yes),
+        Some(Command::Disable { name }) => {
+            return set_remote_disabled(&current_dir_str, name, true)
+        }
+        Some(Command::Enable { name }) => {
+            return set_remote_disabled(&current_dir_str, name, false)
+        }
+        Some(Command::Config { action }) => return config_command(action, &current_dir_str),
+        Some(Command::Trash { remote, action }) => {
+            return trash_command(action, remote.clone(), args.yes, &args)
+        }
+        Some(Command::Timings { json }) => return show_timings(&current_dir_str, *json),
+        Some(Command::Hooks { action }) => return hooks_command(action),
+        Some(Command::RunDeferred { remote, token }) => {
+            return deferred_post_command(&current_dir_str, remote, *token)
+        }
+        _ => {}
+    }
+
+    // Pure CLI mode for scripts and debugging: the remote comes from the
+    // arguments alone and no cache or state file is read or written
+    if args.no_cache {
+        let (Some(host), Some(dir)) = (args.remote_host.clone(), args.remote_dir.clone()) else {
+            anyhow::bail!("--no-cache requires remote_host and remote_dir");
+        };
+        let mut entry = RemoteEntry {
+            name: match &args.name {
+                Some(name) => new_remote_name(name)?,
+                None => generate_unique_name(&host, &dir, &[]),
+            },
+            remote_host: host,
+            remote_dir: normalize_remote_dir(&dir),
+            ..Default::default()
+        };
+        apply_entry_args(&mut entry, &args);
+        settings().defaults.apply(&mut entry);
+        if args.pull {
+            return perform_pull(&entry, &args, &mut RemoteState::default());
+        }
+        let syncer = cli_syncer(&args).remote(entry.clone());
+        if args.dry_run {
+            return dry_run(&syncer, &entry, &args, &mut RemoteState::default());
+        }
+        return run_sync(
+            &syncer,
+            &entry,
+            &args,
+            &current_dir_str,
+            &mut RemoteState::default(),
+        );
+    }
+
     let cache_path = get_cache_path()?;
 
     // Initialize migration manager with current program version
     let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
 
+    // Listing only needs this directory's remotes
+    if args.list {
+        let cache = migration_manager.read_dir_cache(&cache_path, &current_dir_str)?;
+        let state = read_state(&get_state_path()?)?;
+        list_remotes(&cache, state.get(&current_dir_str), &current_dir_str)?;
+        return Ok(());
+    }
+
     // Read or initialize cache with migration support
     let mut cache: RemoteMap = migration_manager.read_cache(&cache_path)?;
 
-    // Ensure the current directory exists in the cache
-    if !cache.contains_key(&current_dir_str) {
-        cache.insert(current_dir_str.clone(), Vec::new());
+    if !args.remove.is_empty() {
+        if remove_remotes(&mut cache, &current_dir_str, &args.remove, args.yes)? {
+            migration_manager.save_cache(&cache_path, &cache)?;
+        }
+        return Ok(());
     }
 
-    // Handle command-line options
-    if args.list {
-        list_remotes(&cache, &current_dir_str)?;
-        return Ok(());
+    if args.all {
+        if has_entry_args(&args) || args.preferred {
+            anyhow::bail!("Remote settings can't be changed for all remotes at once");
+        }
+        return sync_all(&args, &raw_args, &cache, &current_dir_str);
     }
 
-    if let Some(name) = args.remove.clone() {
-        remove_remote(&mut cache, &current_dir_str, &name)?;
-        migration_manager.save_cache(&cache_path, &cache)?;
-        return Ok(());
+    // `sync-rs NAME` is short for `sync-rs -n NAME`
+    if let (Some(host), None, None) = (&args.remote_host, &args.remote_dir, &args.name) {
+        let known = cache
+            .get(&current_dir_str)
+            .is_some_and(|entries| entries.iter().any(|e| e.is_called(host)));
+        if known {
+            args.name = args.remote_host.take();
+        } else if !host.contains('@') {
+            anyhow::bail!(
+                "No remote named '{}' for this directory; give both remote_host and remote_dir to add one",
+                host
+            );
+        }
     }
 
     // Validate host/dir pairing if provided
@@ -101,249 +879,1777 @@ fn main() -> Result<()> {
     }
 
     // Determine which remote to use or add new one
-    let remote_entry = determine_remote_config(
-        &args,
-        &mut cache,
-        &current_dir_str,
-        &migration_manager,
-        &cache_path,
-    )?;
+    let syncer = cli_syncer(&args);
+    let remote_entry = syncer.resolve_remote()?;
+
+    // Start watching before the first sync, so changes made during it aren't missed
+    let mut watcher = args.watch.then(|| {
+        Watcher::new(
+            &current_dir,
+            &remote_entry.ignore_patterns,
+            &remote_entry.override_paths,
+        )
+    });
 
-    // Perform the sync operation
-    perform_sync(&remote_entry, args.shell, args.delete_override)?;
+    let state_path = get_state_path()?;
+    loop {
+        // Load runtime state for the chosen remote, afresh each time as other
+        // runs may have synced in the meantime
+        let mut remote_state =
+            read_remote_state(&state_path, &current_dir_str, &remote_entry.name)?;
 
+        // Persist state even if a later step of the sync fails
+        let result = if args.pull {
+            perform_pull(&remote_entry, &args, &mut remote_state)
+        } else if args.dry_run {
+            dry_run(&syncer, &remote_entry, &args, &mut remote_state)
+        } else {
+            run_sync(
+                &syncer,
+                &remote_entry,
+                &args,
+                &current_dir_str,
+                &mut remote_state,
+            )
+        };
+        save_remote_state(
+            &state_path,
+            &current_dir_str,
+            &remote_entry.name,
+            &remote_state,
+        )?;
+
+        let Some(watcher) = &mut watcher else {
+            return result;
+        };
+        // A failed sync shouldn't end the loop, the next change may well fix it
+        if let Err(e) = result {
+            warn(format_args!("Sync failed: {:#}", e));
+        }
+        println!("{}", dim("Watching for changes (Ctrl-C to stop)"));
+        let changed = watcher.wait();
+        match &changed[..] {
+            [path] => println!("{} changed", path),
+            _ => println!("{} files changed", changed.len()),
+        }
+    }
+}
+
+// Sync to each active remote of the directory in turn (or some at a time with
+// --parallel), going on after failures, and sum up how each went
+fn sync_all(args: &Args, raw_args: &[String], cache: &RemoteMap, current_dir: &str) -> Result<()> {
+    let entries = active_remotes(cache, current_dir);
+    if entries.is_empty() {
+        anyhow::bail!("No remotes to sync to for this directory");
+    }
+
+    let results = match args.parallel {
+        Some(jobs) if jobs > 1 => sync_parallel(&entries, jobs, raw_args),
+        _ => sync_each(&entries, args, current_dir)?,
+    };
+
+    println!();
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    for (name, result) in &results {
+        match result {
+            Ok(()) => println!("{} {}", green("✓"), name),
+            Err(e) => println!("{} {} {}", yellow("✗"), name, dim(&format!("- {:#}", e))),
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} syncs failed", failed, results.len());
+    }
     Ok(())
 }
 
-// Determine which remote configuration to use based on args and cache
-fn determine_remote_config(
+// Sync to `entries` one after another in this process
+fn sync_each<'a>(
+    entries: &[&'a RemoteEntry],
     args: &Args,
-    cache: &mut RemoteMap,
     current_dir: &str,
-    migration_manager: &MigrationManager,
-    cache_path: &std::path::Path,
-) -> Result<RemoteEntry> {
-    let remote_entry = if let (Some(h), Some(d)) =
-        (args.remote_host.clone(), args.remote_dir.clone())
-    {
-        // Create new remote entry with name based on just the host
-        let name = if let Some(name) = args.name.as_ref() {
-            name.clone()
-        } else if let Some(entry) = cache.get(current_dir).and_then(|entries| {
-            entries
-                .iter()
-                .find(|e| e.remote_host == h && e.remote_dir == d)
-        }) {
-            entry.name.clone()
+) -> Result<Vec<(&'a str, Result<()>)>> {
+    let state_path = get_state_path()?;
+    let syncer = cli_syncer(args);
+    let mut results = Vec::new();
+    for &saved in entries {
+        let mut entry = saved.clone();
+        settings().defaults.apply(&mut entry);
+        let mut remote_state = read_remote_state(&state_path, current_dir, &entry.name)?;
+        let result = if args.dry_run {
+            dry_run(&syncer, &entry, args, &mut remote_state)
         } else {
-            generate_unique_name(&h, cache, current_dir)
-        };
-
-        let entry = RemoteEntry {
-            name: name.clone(),
-            remote_host: h,
-            remote_dir: d,
-            override_paths: args.override_path.clone(),
-            post_sync_command: args.post_command.clone(),
-            preferred: args.preferred,
-            ignore_patterns: args.ignore_patterns.clone(),
+            run_sync(&syncer, &entry, args, current_dir, &mut remote_state)
         };
+        save_remote_state(&state_path, current_dir, &entry.name, &remote_state)?;
+        if let Err(e) = &result {
+            warn(format_args!("{}: {:#}", entry.name, e));
+        }
+        results.push((saved.name.as_str(), result));
+    }
+    Ok(results)
+}
 
-        // If this is being set as preferred, unset preferred status for all other entries
-        if args.preferred {
-            if let Some(entries) = cache.get_mut(current_dir) {
-                for e in entries.iter_mut() {
-                    e.preferred = false;
-                }
+// Sync to `entries` in child processes, up to `jobs` at a time, passing on
+// the arguments of this run. Each line they print is prefixed with the
+// remote's name. Prompts can't be answered, so they fail instead.
+fn sync_parallel<'a>(
+    entries: &[&'a RemoteEntry],
+    jobs: usize,
+    raw_args: &[String],
+) -> Vec<(&'a str, Result<()>)> {
+    let mut child_args = Vec::new();
+    let mut rest = raw_args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--all" => {}
+            "--parallel" => {
+                rest.next();
             }
+            _ if arg.starts_with("--parallel=") => {}
+            _ => child_args.push(arg.clone()),
         }
+    }
 
-        // Check if name already exists and update or add
-        let entries = cache.get_mut(current_dir).unwrap();
-        if let Some(index) = entries.iter().position(|e| e.name == name) {
-            entries[index] = entry.clone();
-        } else {
-            entries.push(entry.clone());
+    let width = entries
+        .iter()
+        .map(|e| e.name.len())
+        .max()
+        .unwrap_or_default();
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(entries.len()) {
+            scope.spawn(|| {
+                while let Some(entry) = entries.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let prefix = dim(&format!("{:width$} |", entry.name));
+                    let result = run_prefixed(&child_args, &entry.name, &prefix);
+                    results.lock().unwrap().push((entry.name.as_str(), result));
+                }
+            });
         }
+    });
 
-        migration_manager.save_cache(cache_path, cache)?;
-        entry
-    } else {
-        // Use existing entry
-        let entries = cache.get(current_dir).unwrap();
-
-        if entries.is_empty() {
-            // Prompt for new remote info
-            let (h, d) = prompt_remote_info()?;
-            let default_name = generate_unique_name(&h, cache, current_dir);
-            let name = args.name.clone().unwrap_or(default_name);
-
-            let entry = RemoteEntry {
-                name,
-                remote_host: h,
-                remote_dir: d,
-                override_paths: args.override_path.clone(),
-                post_sync_command: args.post_command.clone(),
-                preferred: args.preferred,
-                ignore_patterns: args.ignore_patterns.clone(),
-            };
+    // Report in the order of the remotes, not of finishing
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(name, _)| entries.iter().position(|e| e.name == *name));
+    results
+}
 
-            cache.get_mut(current_dir).unwrap().push(entry.clone());
-            migration_manager.save_cache(cache_path, cache)?;
-            entry
-        } else if entries.len() == 1 {
-            // Use the only entry
-            let mut entry = entries[0].clone();
-
-            // Update with new parameters if provided
-            if !args.override_path.is_empty() {
-                entry.override_paths = args.override_path.clone();
-                cache.get_mut(current_dir).unwrap()[0].override_paths = args.override_path.clone();
-            }
+// Run sync-rs for the remote `name`, printing its output line by line after `prefix`
+fn run_prefixed(args: &[String], name: &str, prefix: &str) -> Result<()> {
+    let mut command = process::Command::new(env::current_exe()?);
+    command.args(args).args(["-n", name]);
+    // The error of a failed run is its last line
+    let mut last_error = None;
+    let status = print_prefixed(&mut command, prefix, |line| {
+        if let Some(error) = line.strip_prefix("Error: ") {
+            last_error = Some(error.to_string());
+        }
+    })
+    .context("Failed to run sync-rs")?;
+    if !status.success() {
+        return Err(match last_error {
+            Some(error) => anyhow::anyhow!(error),
+            None => anyhow::anyhow!("sync-rs exited with code: {:?}", status.code()),
+        });
+    }
+    Ok(())
+}
 
-            if args.post_command.is_some() {
-                entry.post_sync_command = args.post_command.clone();
-                cache.get_mut(current_dir).unwrap()[0].post_sync_command =
-                    args.post_command.clone();
-            }
+// Run `command` without input, printing its output line by line after
+// `prefix` and passing each line of its stderr to `inspect` as well
+fn print_prefixed(
+    command: &mut process::Command,
+    prefix: &str,
+    mut inspect: impl FnMut(&str),
+) -> io::Result<process::ExitStatus> {
+    let mut child = command
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+    let stderr = child.stderr.take().ok_or(io::ErrorKind::BrokenPipe)?;
 
-            if args.preferred {
-                entry.preferred = true;
-                cache.get_mut(current_dir).unwrap()[0].preferred = true;
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            for line in io::BufReader::new(stdout).lines().map_while(Result::ok) {
+                println!("{} {}", prefix, line);
             }
+        });
+        for line in io::BufReader::new(stderr).lines().map_while(Result::ok) {
+            eprintln!("{} {}", prefix, line);
+            inspect(&line);
+        }
+    });
+    child.wait()
+}
 
-            if !args.ignore_patterns.is_empty() {
-                entry.ignore_patterns = args.ignore_patterns.clone();
-                cache.get_mut(current_dir).unwrap()[0].ignore_patterns =
-                    args.ignore_patterns.clone();
-            }
+// Sync to the remote, then report how it went as the command line asks
+fn run_sync(
+    syncer: &Syncer,
+    remote_entry: &RemoteEntry,
+    args: &Args,
+    current_dir: &str,
+    state: &mut RemoteState,
+) -> Result<()> {
+    let started = Instant::now();
+    let mut timings = Timings::default();
+    let result = syncer.sync_to(remote_entry, state, &mut timings);
+    let elapsed = started.elapsed();
+    let report = result.as_ref().ok().cloned().flatten();
 
-            migration_manager.save_cache(cache_path, cache)?;
-            entry
-        } else {
-            // Multiple entries, check for preferred or prompt for selection
-            let name = if args.preferred {
-                // If setting preferred, use the name from args
-                args.name
-                    .clone()
-                    .ok_or_else(|| anyhow::anyhow!("Name required when setting preferred remote"))?
-            } else {
-                if let Some(name) = args.name.clone() {
-                    name
-                } else if let Some(preferred) = entries.iter().find(|e| e.preferred) {
-                    preferred.name.clone()
-                } else {
-                    select_remote(entries)?
-                }
-            };
+    if args.report == Some(ReportWhen::Always)
+        || (args.report == Some(ReportWhen::Failure) && result.is_err())
+    {
+        let report = MailReport {
+            dir: current_dir,
+            remote: remote_entry,
+            duration: elapsed,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            transferred: state.last_transferred,
+            deleted: state.last_deleted,
+        };
+        if let Err(e) = send_report(&settings().report, &report) {
+            warn(format_args!("Could not send the sync report: {:#}", e));
+        }
+    }
+
+    if args.summary_json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else if let Some(report) = &report {
+        println!(
+            "Sent {} file(s) ({}), deleted {}, in {:.1}s",
+            report.files_transferred,
+            format_size(report.bytes_sent),
+            report.deleted,
+            elapsed.as_secs_f64()
+        );
+    }
+
+    if args.timings {
+        timings.print(elapsed);
+    }
+
+    result.map(drop)
+}
 
-            let entry = entries
+fn hooks_command(action: &HooksCommand) -> Result<()> {
+    match action {
+        HooksCommand::Trust => {
+            let scripts = trust_hook_scripts(Path::new("."))?;
+            if scripts.is_empty() {
+                println!("No hook scripts in {}", HOOKS_DIR);
+            }
+            for script in scripts {
+                println!("Trusted {}", script.display());
+            }
+        }
+        HooksCommand::Untrust => {
+            if untrust_hook_scripts(Path::new("."))? {
+                println!("Hook scripts are no longer run");
+            } else {
+                println!("Hook scripts weren't trusted");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn show_timings(current_dir: &str, as_json: bool) -> Result<()> {
+    let state = read_state(&get_state_path()?)?;
+    let remotes: BTreeMap<&String, &Vec<TimedRun>> = state
+        .get(current_dir)
+        .map(|remotes| {
+            remotes
                 .iter()
-                .find(|e| e.name == name)
-                .ok_or_else(|| anyhow::anyhow!("Remote with name '{}' not found", name))?
-                .clone();
-
-            // Update with new parameters if provided
-            if !args.override_path.is_empty()
-                || args.post_command.is_some()
-                || args.preferred
-                || !args.ignore_patterns.is_empty()
-            {
-                let mut updated_entry = entry.clone();
-
-                if !args.override_path.is_empty() {
-                    updated_entry.override_paths = args.override_path.clone();
-                }
+                .filter(|(_, remote_state)| !remote_state.timings.is_empty())
+                .map(|(name, remote_state)| (name, &remote_state.timings))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&remotes)?);
+        return Ok(());
+    }
+    if remotes.is_empty() {
+        println!("No recorded syncs for this directory");
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    for (name, runs) in remotes {
+        println!("{}", bold(name));
+        for run in runs.iter().rev() {
+            let phases: Vec<String> = run
+                .phases
+                .iter()
+                .map(|phase| format!("{} {}", phase.name, format_ms(phase.ms)))
+                .collect();
+            println!(
+                "  {:>10}  {}  {:>7}  {}",
+                format_ago(now.saturating_sub(run.at)),
+                if run.ok { "✓" } else { "✗" },
+                format_ms(run.total_ms),
+                dim(&phases.join(", "))
+            );
+        }
+    }
+    Ok(())
+}
+
+fn undo() -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let current = migration_manager.read_cache(&cache_path)?;
+
+    if !undo_last_change(&cache_path)? {
+        println!("Nothing to undo");
+        return Ok(());
+    }
+
+    let restored = migration_manager.read_cache(&cache_path)?;
+    println!("Restored the remote configurations as they were before the last change:");
+    for change in describe_changes(&current, &restored) {
+        println!("  {}", change);
+    }
+
+    Ok(())
+}
+
+// Compare the file trees of two remotes by size and modification time, failing
+// if they differ so that scripts can check for it
+fn compare_remotes(current_dir: &str, first: &str, second: &str) -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let cache = migration_manager.read_cache(&cache_path)?;
+    let entries = cache
+        .get(current_dir)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    let mut listings = Vec::new();
+    for name in [first, second] {
+        let entry = entries
+            .iter()
+            .find(|e| e.is_called(name))
+            .ok_or_else(|| anyhow::anyhow!("Remote with name '{}' not found", name))?;
+        let target = RemoteTarget::from_entry(entry)?;
+        if matches!(target, RemoteTarget::Relay { .. }) {
+            anyhow::bail!("Remotes behind a relay can't be compared");
+        }
+        let remote_dir = remote_full_dir(&get_remote_home(&target)?, &entry.remote_dir);
+        let location = target.rsync_location(&remote_dir);
+        println!("Listing {} ({})", bold(name), location);
+        let files = list_remote_files(&location, &target.rsync_options())?
+            .with_context(|| format!("{} doesn't exist on {}", remote_dir, name))?;
+        listings.push(files);
+    }
+    let (a, b) = (&listings[0], &listings[1]);
 
-                if args.post_command.is_some() {
-                    updated_entry.post_sync_command = args.post_command.clone();
+    let only_first: Vec<String> = a
+        .keys()
+        .filter(|path| !b.contains_key(*path))
+        .cloned()
+        .collect();
+    let only_second: Vec<String> = b
+        .keys()
+        .filter(|path| !a.contains_key(*path))
+        .cloned()
+        .collect();
+    let mut differing = Vec::new();
+    for (path, file) in a {
+        let Some(other) = b.get(path) else {
+            continue;
+        };
+        if file.size != other.size {
+            differing.push(format!("{} (size {} vs {})", path, file.size, other.size));
+        } else if file.mtime != other.mtime {
+            differing.push(format!(
+                "{} (modified {} vs {})",
+                path, file.mtime, other.mtime
+            ));
+        }
+    }
+
+    let print_paths = |heading: &str, paths: &[String]| {
+        if !paths.is_empty() {
+            println!("{}", bold(heading));
+            for path in paths {
+                println!("  {}", path);
+            }
+        }
+    };
+    print_paths(&format!("Only on {}:", first), &only_first);
+    print_paths(&format!("Only on {}:", second), &only_second);
+    print_paths("Different:", &differing);
+
+    let same = a.len() - only_first.len() - differing.len();
+    if only_first.is_empty() && only_second.is_empty() && differing.is_empty() {
+        println!("{} and {} are identical ({} files)", first, second, same);
+        return Ok(());
+    }
+    anyhow::bail!(
+        "{} and {} differ: {} only on {}, {} only on {}, {} different, {} the same",
+        first,
+        second,
+        only_first.len(),
+        first,
+        only_second.len(),
+        second,
+        differing.len(),
+        same
+    )
+}
+
+fn remote_command(action: &RemoteCommand, current_dir: &str, yes: bool) -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let mut cache = migration_manager.read_cache(&cache_path)?;
+
+    match action {
+        RemoteCommand::Add { args } => {
+            let args = Args::parse_from(
+                ["sync-rs remote add".to_string()]
+                    .into_iter()
+                    .chain(args.clone()),
+            );
+            if args.remote_host.is_none() || args.remote_dir.is_none() {
+                anyhow::bail!("Both remote_host and remote_dir must be provided");
+            }
+            let entry = cli_syncer(&args).resolve_remote()?;
+            println!(
+                "Added {} {}",
+                bold(&entry.name),
+                dim(&format!("({}:{})", entry.remote_host, entry.remote_dir))
+            );
+        }
+        RemoteCommand::List => {
+            let state = read_state(&get_state_path()?)?;
+            list_remotes(&cache, state.get(current_dir), current_dir)?;
+        }
+        RemoteCommand::Remove { names } => {
+            if remove_remotes(&mut cache, current_dir, names, yes)? {
+                migration_manager.save_cache(&cache_path, &cache)?;
+            }
+        }
+        RemoteCommand::Rename { old, new } => {
+            let new = new_remote_name(new)?;
+            let old = migration_manager.update_cache(&cache_path, &mut cache, |cache| {
+                rename_remote(cache, current_dir, old, &new)
+            })?;
+
+            // Runtime state follows the remote to its new name
+            let state_path = get_state_path()?;
+            let mut state = read_state(&state_path)?;
+            if let Some(states) = state.get_mut(current_dir) {
+                if let Some(remote_state) = states.remove(&old) {
+                    states.insert(new.clone(), remote_state);
+                    save_state(&state_path, &state)?;
+                }
+            }
+            println!("Renamed '{}' to '{}'", old, new);
+        }
+        RemoteCommand::ImportSsh { hosts, dir } => {
+            let candidates = ssh_config_hosts()?;
+            let picked: Vec<&SshConfigHost> = if hosts.is_empty() {
+                if candidates.is_empty() {
+                    anyhow::bail!("No hosts to import in ~/.ssh/config");
                 }
+                let options: Vec<String> = candidates
+                    .iter()
+                    .map(|host| format!("{} ({})", host.alias, host.remote_host))
+                    .collect();
+                vec![&candidates[select_from("Select a host:", &options)?]]
+            } else {
+                hosts
+                    .iter()
+                    .map(|alias| {
+                        candidates
+                            .iter()
+                            .find(|host| host.alias == *alias)
+                            .ok_or_else(|| anyhow::anyhow!("No host '{}' in ~/.ssh/config", alias))
+                    })
+                    .collect::<Result<_>>()?
+            };
 
-                if args.preferred {
-                    // Unset preferred status for all other entries
-                    for e in cache.get_mut(current_dir).unwrap().iter_mut() {
-                        e.preferred = false;
+            for host in picked {
+                let remote_dir = match dir {
+                    Some(dir) => normalize_remote_dir(dir),
+                    None => {
+                        println!("Directory on {}", bold(&host.alias));
+                        prompt_remote_dir()?
                     }
-                    updated_entry.preferred = true;
+                };
+                // Named after the host, unless that name is taken
+                let entry = RemoteEntry {
+                    name: host.alias.clone(),
+                    remote_host: host.remote_host.clone(),
+                    remote_dir,
+                    ssh_options: host.ssh_options.clone(),
+                    ..Default::default()
+                };
+                import_entry(
+                    &mut cache,
+                    current_dir,
+                    &migration_manager,
+                    &cache_path,
+                    entry,
+                )?;
+            }
+        }
+        RemoteCommand::Export {
+            file,
+            ssh_config: true,
+        } => {
+            let remotes = cache.get(current_dir).cloned().unwrap_or_default();
+            let mut blocks = Vec::new();
+            for entry in &remotes {
+                match host_block(entry) {
+                    Some(block) => blocks.push(block),
+                    None => warn(format_args!(
+                        "Leaving out '{}': plain ssh can't reach {}",
+                        entry.name, entry.remote_host
+                    )),
                 }
-
-                if !args.ignore_patterns.is_empty() {
-                    updated_entry.ignore_patterns = args.ignore_patterns.clone();
+            }
+            if blocks.is_empty() {
+                anyhow::bail!("No remotes to export for this directory");
+            }
+            let config = blocks.join("\n\n");
+            match file {
+                Some(file) => {
+                    fs::write(file, config + "\n")
+                        .context(format!("Failed to write {}", file.display()))?;
+                    println!("Exported {} host(s) to {}", blocks.len(), file.display());
                 }
+                None => println!("{}", config),
+            }
+        }
+        RemoteCommand::Export { file, .. } => {
+            // Without the directory they belong to, which is only meaningful
+            // on this machine; `import` adds them to whichever directory it runs in
+            let export = RemotesExport {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                remotes: cache.get(current_dir).cloned().unwrap_or_default(),
+            };
+            if export.remotes.is_empty() {
+                anyhow::bail!("No remotes to export for this directory");
+            }
+            let json = serde_json::to_string_pretty(&export)?;
+            match file {
+                Some(file) => {
+                    fs::write(file, json + "\n")
+                        .context(format!("Failed to write {}", file.display()))?;
+                    println!(
+                        "Exported {} remote(s) to {}",
+                        export.remotes.len(),
+                        file.display()
+                    );
+                }
+                None => println!("{}", json),
+            }
+        }
+        RemoteCommand::Import { file } => {
+            let json = match file {
+                Some(file) => fs::read_to_string(file)
+                    .context(format!("Failed to read {}", file.display()))?,
+                None => io::read_to_string(io::stdin()).context("Failed to read stdin")?,
+            };
+            let export: RemotesExport =
+                serde_json::from_str(&json).context("Not a `sync-rs remote export` file")?;
+            for entry in export.remotes {
+                import_entry(
+                    &mut cache,
+                    current_dir,
+                    &migration_manager,
+                    &cache_path,
+                    entry,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
 
-                // Update in cache
-                if let Some(index) = cache
-                    .get_mut(current_dir)
-                    .unwrap()
-                    .iter()
-                    .position(|e| e.name == name)
+// What `remote export` writes and `remote import` reads
+#[derive(Serialize, Deserialize)]
+struct RemotesExport {
+    // sync-rs version that exported the remotes
+    version: String,
+    remotes: Vec<RemoteEntry>,
+}
+
+// Add a remote from elsewhere to the directory, unless one already syncs to
+// the same place. A name or alias that's taken is replaced or dropped, and a
+// directory keeps its preferred remote.
+fn import_entry(
+    cache: &mut RemoteMap,
+    current_dir: &str,
+    migration_manager: &MigrationManager,
+    cache_path: &Path,
+    mut entry: RemoteEntry,
+) -> Result<()> {
+    let added = migration_manager.update_cache(cache_path, cache, |cache| {
+        let entries = cache.entry(current_dir.to_string()).or_default();
+        let remote_dir = normalize_remote_dir(&entry.remote_dir);
+        if let Some(existing) = entries.iter().find(|e| {
+            e.remote_host == entry.remote_host && normalize_remote_dir(&e.remote_dir) == remote_dir
+        }) {
+            println!(
+                "{} already syncs to {}:{}",
+                bold(&existing.name),
+                entry.remote_host,
+                remote_dir
+            );
+            return Ok(None);
+        }
+
+        entry.name = match new_remote_name(&entry.name) {
+            Ok(name) if !entries.iter().any(|e| e.is_called(&name)) => name,
+            _ => generate_unique_name(&entry.remote_host, &remote_dir, entries),
+        };
+        let reserved = reserved_names();
+        let mut aliases = std::mem::take(&mut entry.aliases);
+        aliases.retain(|alias| {
+            let mut alone = entry.clone();
+            alone.aliases = vec![alias.clone()];
+            check_aliases(entries, &alone, &reserved).is_ok()
+        });
+        entry.aliases = aliases;
+        if entries.iter().any(|e| e.preferred) {
+            entry.preferred = false;
+        }
+        entries.push(entry.clone());
+        Ok(Some(entry))
+    })?;
+
+    if let Some(entry) = added {
+        println!(
+            "Added {} {}",
+            bold(&entry.name),
+            dim(&format!("({}:{})", entry.remote_host, entry.remote_dir))
+        );
+    }
+    Ok(())
+}
+
+// Open a shell in the remote directory, picking the remote as a sync would
+fn shell_command(name: Option<String>) -> Result<()> {
+    let mut args = Args::parse_from(["sync-rs"]);
+    args.name = name;
+    let entry = cli_syncer(&args).resolve_remote()?;
+
+    let target = RemoteTarget::from_entry(&entry)?;
+    let remote_dir = remote_full_dir(&get_remote_home(&target)?, &entry.remote_dir);
+    println!(
+        "{}",
+        tr("sync.shell", &[&target.rsync_location(&remote_dir)])
+    );
+    open_remote_shell(&target, &remote_dir)
+}
+
+// List the files home mode sends, and what the templates among them become
+fn home_command() -> Result<()> {
+    let files = &settings().home.files;
+    if files.is_empty() {
+        println!(
+            "Home mode is off: list files under [home] in {} to turn it on",
+            get_settings_path()?.display()
+        );
+        return Ok(());
+    }
+    let home = dirs::home_dir().context("Could not find the home directory")?;
+    let plan = home::plan(&home, files)?;
+    for path in &plan.paths {
+        println!("{}", path);
+    }
+    for template in &plan.templates {
+        println!("{} -> {} (template)", template.source, template.target);
+    }
+    for path in &plan.missing {
+        println!("{} {}", path, yellow("(missing)"));
+    }
+    Ok(())
+}
+
+// Run `command` in the directory of each active remote, some at a time with
+// `parallel`, with each line of output prefixed with the remote's name, and
+// sum up how it went on each
+fn exec_all_command(current_dir: &str, command: &str, parallel: Option<usize>) -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let cache = migration_manager.read_cache(&cache_path)?;
+    let entries = active_remotes(&cache, current_dir);
+    if entries.is_empty() {
+        anyhow::bail!("No remotes to run the command on for this directory");
+    }
+
+    // Finding each remote's directory may ask for a password, so it happens
+    // one remote at a time before anything runs
+    let policy = load_policy()?;
+    let state_path = get_state_path()?;
+    let mut jobs = Vec::new();
+    let mut results = Vec::new();
+    for &saved in &entries {
+        let mut entry = match &policy {
+            Some(policy) => policy.apply(saved)?,
+            None => saved.clone(),
+        };
+        settings().defaults.apply(&mut entry);
+        let mut state = read_remote_state(&state_path, current_dir, &entry.name)?;
+        let checked = preflight(&entry, false, &mut state).and_then(|checked| {
+            let entry = policy_for_target(&entry, &checked.target)?;
+            Ok((checked, entry))
+        });
+        match checked {
+            Ok((checked, entry)) => {
+                let remote_dir = remote_full_dir(&checked.remote_home, &entry.remote_dir);
+                let full_command = in_remote_dir(&entry, &remote_dir, command, false);
+                jobs.push((
+                    saved.name.as_str(),
+                    checked.target,
+                    full_command,
+                    remote_dir,
+                ));
+            }
+            Err(e) => results.push((saved.name.as_str(), Err(e))),
+        }
+        save_remote_state(&state_path, current_dir, &entry.name, &state)?;
+    }
+
+    let width = entries
+        .iter()
+        .map(|e| e.name.len())
+        .max()
+        .unwrap_or_default();
+    let next = AtomicUsize::new(0);
+    let finished = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..parallel.unwrap_or(1).clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| {
+                while let Some((name, target, full_command, remote_dir)) =
+                    jobs.get(next.fetch_add(1, Ordering::SeqCst))
                 {
-                    cache.get_mut(current_dir).unwrap()[index] = updated_entry.clone();
-                    migration_manager.save_cache(cache_path, cache)?;
-                    updated_entry
-                } else {
-                    entry
+                    let prefix = dim(&format!("{:width$} |", name));
+                    let result = print_prefixed(&mut target.command(full_command), &prefix, |_| {})
+                        .context("Failed to execute SSH command")
+                        .and_then(|status| match status.code() {
+                            _ if status.success() => Ok(()),
+                            Some(MISSING_DIR_EXIT) => Err(SyncError::RemoteDirMissing {
+                                dir: remote_dir.clone(),
+                            }
+                            .into()),
+                            code => Err(anyhow::anyhow!("exited with code: {:?}", code)),
+                        });
+                    finished.lock().unwrap().push((*name, result));
                 }
-            } else {
-                entry
-            }
+            });
         }
-    };
+    });
+    results.extend(finished.into_inner().unwrap());
 
-    Ok(remote_entry)
+    // Report in the order of the remotes, not of finishing
+    results.sort_by_key(|(name, _)| entries.iter().position(|e| e.name == *name));
+    println!();
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    for (name, result) in &results {
+        match result {
+            Ok(()) => println!("{} {}", green("✓"), name),
+            Err(e) => println!("{} {} {}", yellow("✗"), name, dim(&format!("- {:#}", e))),
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!(
+            "The command failed on {} of {} remotes",
+            failed,
+            results.len()
+        );
+    }
+    Ok(())
 }
 
-// Perform the actual sync operation
-fn perform_sync(remote_entry: &RemoteEntry, open_shell: bool, delete_override: bool) -> Result<()> {
-    // Get remote home directory
-    let remote_home = get_remote_home(&remote_entry.remote_host)?;
-    let remote_full_dir = if remote_entry.remote_dir.starts_with('/') {
-        remote_entry.remote_dir.clone()
+// Redo the checks made before syncing, which are otherwise reused for
+// PREFLIGHT_TTL seconds, and drop the link measurements, which are otherwise
+// kept for a week
+fn refresh_command(current_dir: &str, name: Option<String>, all: bool) -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let cache = migration_manager.read_cache(&cache_path)?;
+
+    let entries = if all {
+        active_remotes(&cache, current_dir)
+            .into_iter()
+            .cloned()
+            .collect()
     } else {
-        format!("{}/{}", remote_home, remote_entry.remote_dir)
+        let mut args = Args::parse_from(["sync-rs"]);
+        args.name = name;
+        vec![cli_syncer(&args).resolve_remote()?]
     };
-    println!(
-        "Syncing to {} ({}:{})",
-        remote_entry.name, remote_entry.remote_host, remote_full_dir
-    );
+    if entries.is_empty() {
+        anyhow::bail!("No remotes for this directory");
+    }
 
-    // Sync main directory with .gitignore filtering and any additional ignore patterns
-    let destination = format!("{}:{}", remote_entry.remote_host, remote_full_dir);
+    let policy = load_policy()?;
+    let state_path = get_state_path()?;
+    let mut failed = 0;
+    for entry in &entries {
+        let entry = match &policy {
+            Some(policy) => policy.apply(entry)?,
+            None => entry.clone(),
+        };
+        let mut state = read_remote_state(&state_path, current_dir, &entry.name)?;
+        match preflight(&entry, true, &mut state) {
+            Ok(checked) => {
+                let rsync = match (checked.rsync_version, checked.has_rsync) {
+                    (Some((major, minor, patch)), _) => {
+                        format!("rsync {}.{}.{}", major, minor, patch)
+                    }
+                    (None, true) => "rsync".to_string(),
+                    (None, false) => "no rsync".to_string(),
+                };
+                println!(
+                    "{}: home {}, {}",
+                    bold(&entry.name),
+                    checked.remote_home,
+                    rsync
+                );
+                if forget_link(&checked.target)? {
+                    println!("  link measurements dropped, the next sync measures again");
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                state.preflight = None;
+                warn(format_args!("{}: {:#}", entry.name, e));
+            }
+        }
+        save_remote_state(&state_path, current_dir, &entry.name, &state)?;
+    }
 
-    // Start with .gitignore filter
-    let mut filter_strings = vec![String::from(":- .gitignore")];
+    if failed > 0 {
+        anyhow::bail!(
+            "{} of {} remote(s) couldn't be checked",
+            failed,
+            entries.len()
+        );
+    }
+    Ok(())
+}
+
+fn trash_command(
+    action: &TrashCommand,
+    name: Option<String>,
+    yes: bool,
+    args: &Args,
+) -> Result<()> {
+    let mut remote_args = Args::parse_from(["sync-rs"]);
+    remote_args.name = name;
+    let entry = cli_syncer(&remote_args).resolve_remote()?;
+    let target = RemoteTarget::from_entry(&entry)?;
+    let trash = format!(
+        "{}/{}",
+        remote_full_dir(&get_remote_home(&target)?, &entry.remote_dir),
+        TRASH_DIR
+    );
 
-    // Add additional ignore patterns
-    for pattern in &remote_entry.ignore_patterns {
-        // Format as rsync exclude pattern
-        filter_strings.push(format!("- {}", pattern));
+    match action {
+        TrashCommand::List => {
+            let listing = run_remote_script(
+                &target,
+                &format!(
+                    "cd {} 2>/dev/null || exit 0; find . -type f | sort",
+                    shell_quote(&trash)
+                ),
+            )?;
+            let mut batches: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+            for path in listing.lines().filter_map(|line| line.strip_prefix("./")) {
+                if let Some((batch, file)) = path.split_once('/') {
+                    batches.entry(batch).or_default().push(file);
+                }
+            }
+            if batches.is_empty() {
+                println!("The trash of {} is empty", bold(&entry.name));
+                return Ok(());
+            }
+            let _pager = Pager::start(args.no_pager);
+            for (batch, files) in &batches {
+                println!(
+                    "{} {}",
+                    bold(batch),
+                    dim(&format!("({} file(s))", files.len()))
+                );
+                for file in files {
+                    println!("  {}", file);
+                }
+            }
+        }
+        TrashCommand::Restore { batch, paths } => {
+            check_batch_name(batch)?;
+            let source = target.rsync_location(&format!("{}/{}", trash, batch));
+            let mut options = target.rsync_options();
+            options.extra_args.push("--ignore-existing".to_string());
+            if paths.is_empty() {
+                sync_directory(&dir_contents(&source), LOCAL_SOURCE, &options)?;
+            } else {
+                // `/./` marks where the part of the path to recreate here starts
+                options.extra_args.push("--relative".to_string());
+                for path in paths {
+                    let path = path.trim_start_matches("./").trim_end_matches('/');
+                    sync_directory(&format!("{}/./{}", source, path), LOCAL_SOURCE, &options)?;
+                }
+            }
+            println!(
+                "Restored from {}; sync to put the files back on {}",
+                batch,
+                bold(&entry.name)
+            );
+        }
+        TrashCommand::Empty { batches } => {
+            let targets = if batches.is_empty() {
+                if !yes && !confirm(&format!("Empty the trash of {}?", entry.name))? {
+                    return Ok(());
+                }
+                vec![shell_quote(&trash)]
+            } else {
+                batches
+                    .iter()
+                    .map(|batch| {
+                        check_batch_name(batch)?;
+                        Ok(shell_quote(&format!("{}/{}", trash, batch)))
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            };
+            run_remote_script(&target, &format!("rm -rf {}", targets.join(" ")))?;
+            println!("Emptied the trash of {}", bold(&entry.name));
+        }
     }
+    Ok(())
+}
 
-    // Join filters with commas for rsync
-    let filter_string = filter_strings.join(",");
+// Batches are single directories in the trash; anything else could reach outside it
+fn check_batch_name(batch: &str) -> Result<()> {
+    if batch.is_empty() || batch.contains('/') || batch == "." || batch == ".." {
+        anyhow::bail!("Invalid trash batch '{}'", batch);
+    }
+    Ok(())
+}
 
-    sync_directory(".", &destination, Some(&filter_string), true)?;
+fn tag_command(action: &TagCommand, current_dir: &str, yes: bool) -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let mut cache = migration_manager.read_cache(&cache_path)?;
 
-    // Sync additional paths
-    for path in &remote_entry.override_paths {
-        sync_directory(path, &destination, None, delete_override)?;
+    let changed = match action {
+        TagCommand::Add { tag, names } => {
+            tag_remotes(&mut cache, current_dir, tag, names, true, yes)?
+        }
+        TagCommand::Remove { tag, names } => {
+            tag_remotes(&mut cache, current_dir, tag, names, false, yes)?
+        }
+    };
+    if changed {
+        migration_manager.save_cache(&cache_path, &cache)?;
     }
+    Ok(())
+}
+
+fn set_remote_disabled(current_dir: &str, name: &str, disabled: bool) -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let mut cache = RemoteMap::new();
+    migration_manager.update_cache(&cache_path, &mut cache, |cache| {
+        set_disabled(cache, current_dir, name, disabled)
+    })
+}
+
+fn merge_duplicates(current_dir: &str) -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let mut cache = migration_manager.read_cache(&cache_path)?;
+    let entries = cache.get(current_dir).cloned().unwrap_or_default();
 
-    // Execute post-sync command if specified
-    if let Some(cmd) = &remote_entry.post_sync_command {
-        println!("Executing post-sync command: {}", cmd);
-        let full_command = format!("cd {} && {}", remote_full_dir, cmd);
-        execute_ssh_command(&remote_entry.remote_host, &full_command)?;
+    let groups = find_duplicates(&entries);
+    if groups.is_empty() {
+        println!("No duplicate remotes found for this directory.");
+        return Ok(());
     }
 
-    // Open interactive shell if requested
-    if open_shell {
+    let mut merged_entries = entries.clone();
+    let mut removed = Vec::new();
+    for group in groups {
+        let first = &entries[group[0]];
         println!(
-            "Opening interactive shell in {}:{}",
-            remote_entry.remote_host, remote_full_dir
+            "{} {}:",
+            bold("Same host and directory"),
+            dim(&format!("({}:{})", first.remote_host, first.remote_dir))
         );
-        open_remote_shell(&remote_entry.remote_host, &remote_full_dir)?;
+        for &i in &group {
+            println!("  {}", entries[i].name);
+        }
+        if !confirm("Merge them into one remote?")? {
+            continue;
+        }
+
+        let names: Vec<String> = group.iter().map(|&i| entries[i].name.clone()).collect();
+        let keep = group[select_from("Keep which name?", &names)?];
+        let others: Vec<&RemoteEntry> = group
+            .iter()
+            .filter(|&&i| i != keep)
+            .map(|&i| &entries[i])
+            .collect();
+        merged_entries[keep] = merge_entries(&entries[keep], &others);
+        removed.extend(group.iter().filter(|&&i| i != keep));
+        println!("Merged into '{}'", entries[keep].name);
+    }
+
+    if !removed.is_empty() {
+        let merged_entries = merged_entries
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !removed.contains(i))
+            .map(|(_, e)| e)
+            .collect();
+        cache.insert(current_dir.to_string(), merged_entries);
+        migration_manager.save_cache(&cache_path, &cache)?;
+    }
+
+    Ok(())
+}
+
+fn config_command(action: &ConfigCommand, current_dir: &str) -> Result<()> {
+    let cache_path = get_cache_path()?;
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let snapshots_path = get_snapshots_path()?;
+    let mut snapshots = read_snapshots(&snapshots_path)?;
+
+    match action {
+        ConfigCommand::Snapshot { name } => {
+            let entries = migration_manager
+                .read_cache(&cache_path)?
+                .remove(current_dir)
+                .unwrap_or_default();
+            let snapshot = Snapshot {
+                created_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                entries,
+            };
+            let count = snapshot.entries.len();
+            snapshots
+                .entry(current_dir.to_string())
+                .or_default()
+                .insert(name.clone(), snapshot);
+            save_snapshots(&snapshots_path, &snapshots)?;
+            println!(
+                "Saved {} remote configuration(s) as snapshot '{}'",
+                count, name
+            );
+        }
+        ConfigCommand::Restore { name } => {
+            let snapshot = snapshots
+                .get(current_dir)
+                .and_then(|dir_snapshots| dir_snapshots.get(name))
+                .ok_or_else(|| anyhow::anyhow!("Snapshot '{}' not found", name))?;
+
+            // Saving keeps the replaced configuration for `sync-rs undo`
+            let mut cache = migration_manager.read_cache(&cache_path)?;
+            cache.insert(current_dir.to_string(), snapshot.entries.clone());
+            migration_manager.save_cache(&cache_path, &cache)?;
+            println!(
+                "Restored {} remote configuration(s) from snapshot '{}'",
+                snapshot.entries.len(),
+                name
+            );
+        }
+        ConfigCommand::Snapshots => {
+            let dir_snapshots = snapshots.get(current_dir).cloned().unwrap_or_default();
+            if dir_snapshots.is_empty() {
+                println!("No snapshots for this directory.");
+            }
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            for (name, snapshot) in dir_snapshots {
+                println!(
+                    "{} {}",
+                    bold(&name),
+                    dim(&format!(
+                        "({} remote(s), {})",
+                        snapshot.entries.len(),
+                        format_ago(now.saturating_sub(snapshot.created_at))
+                    ))
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn cache_command(action: &CacheCommand) -> Result<()> {
+    let cache_path = get_cache_path()?;
+
+    match action {
+        CacheCommand::Path => println!("{}", projects_dir(&cache_path).display()),
+        CacheCommand::Dump { format } => {
+            let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+            let cache: BTreeMap<String, Vec<RemoteEntry>> = migration_manager
+                .read_cache(&cache_path)?
+                .into_iter()
+                .collect();
+
+            match format {
+                DumpFormat::Json => println!("{}", serde_json::to_string_pretty(&cache)?),
+                DumpFormat::Toml => print!("{}", toml::to_string(&serde_json::to_value(&cache)?)?),
+                DumpFormat::Text => {
+                    for (dir, entries) in &cache {
+                        println!("{}", bold(dir));
+                        for entry in entries {
+                            let preferred = if entry.preferred {
+                                green(&tr("list.preferred", &[]))
+                            } else {
+                                String::new()
+                            };
+                            println!(
+                                "  {}{} {}",
+                                bold(&entry.name),
+                                preferred,
+                                dim(&format!("({}:{})", entry.remote_host, entry.remote_dir))
+                            );
+
+                            // Every setting that isn't at its default
+                            let serde_json::Value::Object(fields) = serde_json::to_value(entry)?
+                            else {
+                                continue;
+                            };
+                            for (key, value) in fields {
+                                let value = match value {
+                                    serde_json::Value::String(s) if !s.is_empty() => s,
+                                    serde_json::Value::Null | serde_json::Value::Bool(false) => {
+                                        continue
+                                    }
+                                    serde_json::Value::String(_) => continue,
+                                    serde_json::Value::Array(a) if a.is_empty() => continue,
+                                    serde_json::Value::Object(o) if o.is_empty() => continue,
+                                    value => value.to_string(),
+                                };
+                                if !["name", "preferred", "remote_host", "remote_dir"]
+                                    .contains(&key.as_str())
+                                {
+                                    println!("    {} {}", dim(&format!("{}:", key)), value);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        CacheCommand::Validate => {
+            let problems = validate_cache(&cache_path)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                anyhow::bail!(
+                    "Found {} problem(s) in {}",
+                    problems.len(),
+                    projects_dir(&cache_path).display()
+                );
+            }
+            println!("{} is valid", projects_dir(&cache_path).display());
+        }
+    }
+
+    Ok(())
+}
+
+// A sync from the current directory as the command line asks for it. The
+// per-remote settings it gives are saved to the remote picked.
+fn cli_syncer(args: &Args) -> Syncer {
+    let syncer = Syncer::new(".")
+        .request(RemoteRequest {
+            name: args.name.clone(),
+            host_dir: args.remote_host.clone().zip(args.remote_dir.clone()),
+            set_preferred: args.preferred,
+            update: args.update,
+            select: args.select,
+        })
+        .vm(args.vm)
+        .reserved_names(reserved_names())
+        .interactive(true)
+        .delete_override(args.delete_override)
+        .always_run_post_command(args.always_run)
+        .force(args.force)
+        .fresh(args.fresh)
+        .both_ways(args.bidirectional)
+        // Pure CLI mode doesn't touch the saved measurements in hosts.json
+        .measure_link(!args.no_cache)
+        .chunked(
+            args.chunked
+                .then(|| args.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE)),
+        )
+        .bwlimit(args.bwlimit.clone())
+        .open_shell(args.shell)
+        .on_deferred_post_command(schedule_post_command);
+    if !has_entry_args(args) {
+        return syncer;
+    }
+    let args = args.clone();
+    syncer.configure(move |entry| apply_entry_args(entry, &args))
+}
+
+// Whether any per-remote settings were given on the command line
+fn has_entry_args(args: &Args) -> bool {
+    !args.override_path.is_empty()
+        || args.post_command.is_some()
+        || !args.ignore_patterns.is_empty()
+        || !args.protected_paths.is_empty()
+        || !args.templates.is_empty()
+        || !args.template_vars.is_empty()
+        || !args.ssh_options.is_empty()
+        || args.host_command.is_some()
+        || !args.alternate_hosts.is_empty()
+        || args.tailscale
+        || args.no_tailscale
+        || args.wol_mac.is_some()
+        || args.wol_timeout.is_some()
+        || !args.bandwidth_windows.is_empty()
+        || args.dedup_min_size.is_some()
+        || args.tar_stream
+        || args.no_tar_stream
+        || args.fixed_strategy
+        || args.no_fixed_strategy
+        || !args.plugins.is_empty()
+        || args.max_deletes.is_some()
+        || args.monthly_budget.is_some()
+        || args.trash
+        || args.no_trash
+        || !args.usermap.is_empty()
+        || !args.groupmap.is_empty()
+        || args.sudo_rsync
+        || args.no_sudo_rsync
+        || args.nested_repos.is_some()
+        || args.initialized_submodules_only
+        || args.no_initialized_submodules_only
+        || args.check_submodules
+        || args.no_check_submodules
+        || args.transport.is_some()
+        || args.connect_command.is_some()
+        || args.healthcheck_url.is_some()
+        || args.description.is_some()
+        || !args.aliases.is_empty()
+        || !args.post_command_triggers.is_empty()
+        || args.post_command_cooldown.is_some()
+        || args.post_command_coalesce
+        || args.no_post_command_coalesce
+        || args.remote_shell.is_some()
+        || args.auto_create
+        || args.no_auto_create
+        || args.shared_group.is_some()
+        || args.shared_acl
+        || args.no_shared_acl
+        || args.relay.is_some()
+        || args.relay_cleanup
+        || args.no_relay_cleanup
+}
+
+// A usable name for a new remote from the one asked for. Subcommand and
+// option names (`list`, `remove`, ...) are reserved, so that commands taking
+// a remote name can't be confused with them.
+fn new_remote_name(name: &str) -> Result<String> {
+    checked_name(name, &reserved_names())
+}
+
+// Words that can't name a remote, as they'd be taken for a subcommand or option
+fn reserved_names() -> Vec<String> {
+    let command = Args::command();
+    command
+        .get_subcommands()
+        .flat_map(|cmd| std::iter::once(cmd.get_name()).chain(cmd.get_all_aliases()))
+        .chain(command.get_arguments().filter_map(|arg| arg.get_long()))
+        .chain(["help", "version"])
+        .map(str::to_string)
+        .collect()
+}
+
+// Overwrite the settings of an entry with those given on the command line
+fn apply_entry_args(entry: &mut RemoteEntry, args: &Args) {
+    if !args.override_path.is_empty() {
+        entry.override_paths = args.override_path.clone();
+    }
+
+    if args.post_command.is_some() {
+        entry.post_sync_command = args.post_command.clone();
+    }
+
+    if !args.ignore_patterns.is_empty() {
+        entry.ignore_patterns = args.ignore_patterns.clone();
+    }
+
+    if !args.protected_paths.is_empty() {
+        entry.protected_paths = args.protected_paths.clone();
+    }
+
+    if !args.templates.is_empty() {
+        entry.templates = args.templates.clone();
+    }
+
+    if !args.ssh_options.is_empty() {
+        entry.ssh_options = args.ssh_options.clone();
+    }
+
+    if args.host_command.is_some() {
+        entry.host_command = args.host_command.clone();
+    }
+
+    if !args.alternate_hosts.is_empty() {
+        entry.alternate_hosts = args.alternate_hosts.clone();
+    }
+
+    if args.tailscale {
+        entry.tailscale = true;
+    }
+    if args.no_tailscale {
+        entry.tailscale = false;
+    }
+
+    if args.wol_mac.is_some() {
+        entry.wol_mac = args.wol_mac.clone();
+    }
+
+    if args.wol_timeout.is_some() {
+        entry.wol_timeout = args.wol_timeout;
+    }
+
+    if !args.bandwidth_windows.is_empty() {
+        entry.bandwidth_windows = args.bandwidth_windows.clone();
+    }
+
+    if args.dedup_min_size.is_some() {
+        entry.dedup_min_size = args.dedup_min_size;
+    }
+
+    if args.tar_stream {
+        entry.tar_stream = true;
+    }
+    if args.no_tar_stream {
+        entry.tar_stream = false;
+    }
+
+    if args.fixed_strategy {
+        entry.fixed_strategy = true;
+    }
+    if args.no_fixed_strategy {
+        entry.fixed_strategy = false;
+    }
+
+    if !args.plugins.is_empty() {
+        entry.plugins = args.plugins.clone();
+    }
+
+    if args.max_deletes.is_some() {
+        entry.max_deletes = args.max_deletes;
+    }
+
+    if args.monthly_budget.is_some() {
+        entry.monthly_budget = args.monthly_budget;
+    }
+
+    if args.trash {
+        entry.trash = true;
+    }
+    if args.no_trash {
+        entry.trash = false;
+    }
+
+    if !args.usermap.is_empty() {
+        entry.usermap = args
+            .usermap
+            .iter()
+            .filter(|rule| !rule.is_empty())
+            .cloned()
+            .collect();
+    }
+
+    if !args.groupmap.is_empty() {
+        entry.groupmap = args
+            .groupmap
+            .iter()
+            .filter(|rule| !rule.is_empty())
+            .cloned()
+            .collect();
+    }
+
+    if args.sudo_rsync {
+        entry.sudo_rsync = true;
+    }
+    if args.no_sudo_rsync {
+        entry.sudo_rsync = false;
+    }
+
+    if let Some(nested_repos) = args.nested_repos {
+        entry.nested_repos = nested_repos;
+    }
+
+    if args.initialized_submodules_only {
+        entry.initialized_submodules_only = true;
+    }
+    if args.no_initialized_submodules_only {
+        entry.initialized_submodules_only = false;
+    }
+
+    if args.check_submodules {
+        entry.check_submodules = true;
+    }
+    if args.no_check_submodules {
+        entry.check_submodules = false;
+    }
+
+    if let Some(transport) = args.transport {
+        entry.transport = transport;
+    }
+
+    if args.connect_command.is_some() {
+        entry.connect_command = args.connect_command.clone();
+    }
+
+    if args.healthcheck_url.is_some() {
+        entry.healthcheck_url = args.healthcheck_url.clone();
+    }
+
+    // An empty description clears it
+    if let Some(description) = &args.description {
+        entry.description = Some(description.clone()).filter(|d| !d.is_empty());
+    }
+
+    if !args.aliases.is_empty() {
+        entry.aliases = args
+            .aliases
+            .iter()
+            .filter(|alias| !alias.is_empty())
+            .cloned()
+            .collect();
+    }
+
+    if !args.post_command_triggers.is_empty() {
+        entry.post_command_triggers = args.post_command_triggers.clone();
+    }
+
+    if args.post_command_cooldown.is_some() {
+        entry.post_command_cooldown = args.post_command_cooldown;
+    }
+
+    if args.post_command_coalesce {
+        entry.post_command_coalesce = true;
+    }
+    if args.no_post_command_coalesce {
+        entry.post_command_coalesce = false;
+    }
+
+    if args.auto_create {
+        entry.auto_create = true;
+    }
+    if args.no_auto_create {
+        entry.auto_create = false;
+    }
+
+    if let Some(group) = &args.shared_group {
+        entry.shared_group = Some(group.clone()).filter(|g| !g.is_empty());
+    }
+
+    if args.shared_acl {
+        entry.shared_acl = true;
+    }
+    if args.no_shared_acl {
+        entry.shared_acl = false;
+    }
+
+    // An empty shell goes back to the login shell
+    if let Some(shell) = &args.remote_shell {
+        entry.remote_shell = Some(shell.trim().to_string()).filter(|s| !s.is_empty());
+    }
+
+    if let Some(relay) = &args.relay {
+        entry.relay = Some(relay.clone()).filter(|r| !r.is_empty());
+    }
+    if args.relay_cleanup {
+        entry.relay_cleanup = true;
+    }
+    if args.no_relay_cleanup {
+        entry.relay_cleanup = false;
+    }
+
+    // Variables are merged so they can be set one at a time
+    entry
+        .template_vars
+        .extend(args.template_vars.iter().cloned());
+}
+
+fn validate_bandwidth_window(s: &str) -> Result<String> {
+    BandwidthWindow::parse(s)?;
+    Ok(s.to_string())
+}
+
+// A `FROM:TO` rule for rsync's --usermap and --groupmap, or "" to remove them
+fn parse_owner_mapping(s: &str) -> Result<String> {
+    if !s.is_empty() {
+        match s.split_once(':') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() && !s.contains(',') => {}
+            _ => anyhow::bail!("Expected FROM:TO, got '{}'", s),
+        }
+    }
+    Ok(s.to_string())
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected KEY=VALUE, got '{}'", s))?;
+    Ok((key.trim().to_string(), value.to_string()))
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Start a background sync-rs that runs the held-back post-sync command after
+// a quiet period. Each sync replaces the token, so earlier waiters give up.
+fn schedule_post_command(remote_entry: &RemoteEntry, token: u64) -> Result<()> {
+    let mut cmd =
+        process::Command::new(env::current_exe().context("Failed to find the sync-rs executable")?);
+    cmd.args(["run-deferred", &remote_entry.name, &token.to_string()])
+        .stdin(process::Stdio::null());
+    // Output goes to the terminal, but a pipe must not be held open by the
+    // waiter, or whoever reads it would wait for the quiet period too
+    if !io::stdout().is_terminal() {
+        cmd.stdout(process::Stdio::null())
+            .stderr(process::Stdio::null());
+    }
+    cmd.spawn()
+        .context("Failed to schedule the post-sync command")?;
+    println!("It will run once syncs have been quiet for the cooldown");
+    Ok(())
+}
+
+fn deferred_post_command(current_dir: &str, name: &str, token: u64) -> Result<()> {
+    let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+    let cache = migration_manager.read_cache(&get_cache_path()?)?;
+    let entry = cache
+        .get(current_dir)
+        .and_then(|entries| entries.iter().find(|e| e.name == name))
+        .ok_or_else(|| anyhow::anyhow!("Remote with name '{}' not found", name))?;
+    let Some(cmd) = &entry.post_sync_command else {
+        return Ok(());
+    };
+
+    std::thread::sleep(std::time::Duration::from_secs(
+        entry.post_command_cooldown.unwrap_or_default(),
+    ));
+
+    // Give up if a newer sync scheduled its own run or ran the command itself
+    let state_path = get_state_path()?;
+    let mut state = read_state(&state_path)?;
+    let Some(remote_state) = state
+        .get_mut(current_dir)
+        .and_then(|remotes| remotes.get_mut(name))
+        .filter(|remote_state| remote_state.pending_post_command == Some(token))
+    else {
+        return Ok(());
+    };
+    remote_state.pending_post_command = None;
+    remote_state.last_post_command_at = Some(unix_millis() / 1000);
+    save_state(&state_path, &state)?;
+
+    let target = RemoteTarget::from_entry(entry)?;
+    let remote_full_dir = remote_full_dir(&get_remote_home(&target)?, &entry.remote_dir);
+    run_post_command(entry, cmd, &target, &remote_full_dir)
+}
+
+// Copy the remote directory back into the current one, e.g. to fetch results
+// and logs. The same ignore patterns apply, but protected paths are pulled:
+// they're usually exactly the outputs wanted back. Nothing local is deleted.
+fn perform_pull(remote_entry: &RemoteEntry, args: &Args, state: &mut RemoteState) -> Result<()> {
+    let policy_entry = match load_policy()? {
+        Some(policy) => policy.apply(remote_entry)?,
+        None => remote_entry.clone(),
+    };
+    let remote_entry = &policy_entry;
+
+    let preflight = preflight(remote_entry, args.fresh, state)?;
+    // Checked again for the machine the host resolved to
+    let policy_entry = policy_for_target(remote_entry, &preflight.target)?;
+    let remote_entry = &policy_entry;
+    if !preflight.has_rsync {
+        anyhow::bail!("Pulling needs rsync on the remote");
+    }
+    if matches!(preflight.target, RemoteTarget::Relay { .. }) {
+        anyhow::bail!("Pulling doesn't work through a relay");
+    }
+    let target = &preflight.target;
+    let remote_full_dir = remote_full_dir(&preflight.remote_home, &remote_entry.remote_dir);
+    let source = target.rsync_location(&remote_full_dir);
+    println!(
+        "{}",
+        tr("pull.start", &[&bold(&remote_entry.name), &source])
+    );
+
+    let mut options = target.rsync_options();
+    options
+        .extra_args
+        .extend(settings().defaults.rsync_args.iter().cloned());
+    options.remote_version = preflight.rsync_version;
+    let windows = remote_entry
+        .bandwidth_windows
+        .iter()
+        .map(|window| BandwidthWindow::parse(window))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(limit) = args.bwlimit.clone().or_else(|| current_bwlimit(&windows)) {
+        println!("Limiting bandwidth to {}", limit);
+        options.extra_args.push(format!("--bwlimit={}", limit));
+    }
+    // Pulls count towards the monthly budget too
+    let rsync_log = env::temp_dir().join(format!("sync-rs-rsync-{}.log", process::id()));
+    if remote_entry.monthly_budget.is_some() {
+        options.extra_args.extend([
+            format!("--log-file={}", rsync_log.display()),
+            format!("--log-file-format={}", events::RSYNC_LOG_FORMAT),
+        ]);
+    }
+    let override_options = options.clone();
+    options.filter = Some(pull_filter(remote_entry, Path::new(LOCAL_SOURCE)));
+
+    check_free_space(&dir_contents(&source), LOCAL_SOURCE, &options)?;
+    let result = pull_paths(remote_entry, &source, &options, &override_options);
+    if remote_entry.monthly_budget.is_some() {
+        let (_, _, bytes) = events::read_rsync_changes(&rsync_log);
+        let _ = fs::remove_file(&rsync_log);
+        record_transfer(remote_entry, state, bytes);
+    }
+    result
+}
+
+// The main directory, then the additional paths, from the remote `source`
+fn pull_paths(
+    remote_entry: &RemoteEntry,
+    source: &str,
+    options: &RsyncOptions,
+    override_options: &RsyncOptions,
+) -> Result<()> {
+    sync_directory(&dir_contents(source), LOCAL_SOURCE, options)?;
+
+    // `dir` was synced to `REMOTE/dir`'s last component, so it comes back from
+    // there. `dir/` went into the remote directory itself, which the pull above
+    // already brought back.
+    for path in &remote_entry.override_paths {
+        if path.ends_with('/') {
+            continue;
+        }
+        let local = Path::new(path);
+        let Some(name) = local.file_name() else {
+            continue;
+        };
+        let parent = match local.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().to_string(),
+            _ => LOCAL_SOURCE.to_string(),
+        };
+        let remote_path = format!("{}/{}", source, name.to_string_lossy());
+        sync_directory(&remote_path, &parent, override_options)?;
     }
 
     Ok(())
 }
+
+// Print what a sync would change, through a pager. With --all, one pager
+// per remote would be more in the way than no pager.
+fn dry_run(
+    syncer: &Syncer,
+    remote_entry: &RemoteEntry,
+    args: &Args,
+    state: &mut RemoteState,
+) -> Result<()> {
+    let _pager = Pager::start(args.no_pager || args.all);
+    syncer.show_changes(remote_entry, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Switch = (&'static str, fn(&RemoteEntry) -> bool);
+
+    // Each stored on/off setting, with its flag (`--no-` turns it off) and
+    // where it's kept
+    const SWITCHES: &[Switch] = &[
+        ("tailscale", |entry| entry.tailscale),
+        ("tar-stream", |entry| entry.tar_stream),
+        ("fixed-strategy", |entry| entry.fixed_strategy),
+        ("trash", |entry| entry.trash),
+        ("sudo-rsync", |entry| entry.sudo_rsync),
+        ("initialized-submodules-only", |entry| {
+            entry.initialized_submodules_only
+        }),
+        ("check-submodules", |entry| entry.check_submodules),
+        ("post-command-coalesce", |entry| entry.post_command_coalesce),
+        ("auto-create", |entry| entry.auto_create),
+        ("shared-acl", |entry| entry.shared_acl),
+        ("relay-cleanup", |entry| entry.relay_cleanup),
+    ];
+
+    fn applied(entry: &RemoteEntry, flags: &[&str]) -> RemoteEntry {
+        let args =
+            Args::try_parse_from(std::iter::once("sync-rs").chain(flags.iter().copied())).unwrap();
+        let mut entry = entry.clone();
+        apply_entry_args(&mut entry, &args);
+        entry
+    }
+
+    #[test]
+    fn switches_turn_stored_settings_on_and_off() {
+        for &(name, stored) in SWITCHES {
+            let on = format!("--{}", name);
+            let off = format!("--no-{}", name);
+
+            let entry = applied(&RemoteEntry::default(), &[&on]);
+            assert!(stored(&entry), "{} sets it", on);
+            let entry = applied(&entry, &[&off]);
+            assert!(!stored(&entry), "{} clears it", off);
+            assert!(
+                Args::try_parse_from(["sync-rs", on.as_str(), off.as_str()]).is_err(),
+                "{} and {} together",
+                on,
+                off
+            );
+
+            for flags in [[on.as_str()], [off.as_str()]] {
+                let args = Args::try_parse_from(["sync-rs", flags[0]]).unwrap();
+                assert!(has_entry_args(&args), "{} updates the entry", flags[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn settings_without_flags_are_kept() {
+        let mut entry = RemoteEntry::default();
+        for &(name, _) in SWITCHES {
+            entry = applied(&entry, &[&format!("--{}", name)]);
+        }
+        let before = serde_json::to_value(&entry).unwrap();
+
+        let args = Args::try_parse_from(["sync-rs"]).unwrap();
+        assert!(!has_entry_args(&args));
+        let after = applied(&entry, &[]);
+        assert_eq!(serde_json::to_value(&after).unwrap(), before);
+        assert!(SWITCHES.iter().all(|(_, stored)| stored(&after)));
+    }
+}
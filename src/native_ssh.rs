@@ -0,0 +1,1187 @@
+use anyhow::anyhow;
+use std::ffi::{c_char, c_int, c_long, c_uint, c_void, CStr, CString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Output};
+use std::ptr::{null, null_mut};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::error::SyncError;
+use crate::net::SshHost;
+use crate::runner::{CommandRunner, SystemRunner};
+use crate::settings::settings;
+use crate::sshconfig::{ssh_config_hosts, SshConfigHost};
+
+// Built-in SSH client (the `native-ssh` feature), for machines without the
+// ssh binary. libssh2 is loaded when it's first needed, so building needs no
+// SSH library, and only machines that turn the client on need libssh2.
+
+type Ptr = *mut c_void;
+
+// Declares the libssh2 functions used here, found by name when the library
+// is loaded
+macro_rules! functions {
+    ($($name:ident($($arg:ty),*) $(-> $ret:ty)?;)*) => {
+        struct Lib {
+            $($name: unsafe extern "C" fn($($arg),*) $(-> $ret)?,)*
+        }
+
+        impl Lib {
+            // SAFETY: `handle` is from dlopen, and each function is declared
+            // as in libssh2.h
+            unsafe fn bind(handle: Ptr) -> Result<Self, String> {
+                Ok(Self {
+                    $($name: {
+                        let name = concat!("libssh2_", stringify!($name), "\0");
+                        let symbol = libc::dlsym(handle, name.as_ptr().cast());
+                        if symbol.is_null() {
+                            return Err(format!("{} is missing", name.trim_end_matches('\0')));
+                        }
+                        std::mem::transmute::<Ptr, unsafe extern "C" fn($($arg),*) $(-> $ret)?>(
+                            symbol,
+                        )
+                    },)*
+                })
+            }
+        }
+    };
+}
+
+functions! {
+    init(c_int) -> c_int;
+    session_init_ex(Ptr, Ptr, Ptr, Ptr) -> Ptr;
+    session_set_blocking(Ptr, c_int);
+    session_set_timeout(Ptr, c_long);
+    session_handshake(Ptr, c_int) -> c_int;
+    session_hostkey(Ptr, *mut libc::size_t, *mut c_int) -> *const c_char;
+    session_last_error(Ptr, *mut *mut c_char, *mut c_int, c_int) -> c_int;
+    session_block_directions(Ptr) -> c_int;
+    session_disconnect_ex(Ptr, c_int, *const c_char, *const c_char) -> c_int;
+    session_free(Ptr) -> c_int;
+    knownhost_init(Ptr) -> Ptr;
+    knownhost_readfile(Ptr, *const c_char, c_int) -> c_int;
+    knownhost_checkp(Ptr, *const c_char, c_int, *const c_char, libc::size_t, c_int, *mut Ptr) -> c_int;
+    knownhost_addc(
+        Ptr,
+        *const c_char,
+        *const c_char,
+        *const c_char,
+        libc::size_t,
+        *const c_char,
+        libc::size_t,
+        c_int,
+        *mut Ptr
+    ) -> c_int;
+    knownhost_writeline(Ptr, Ptr, *mut c_char, libc::size_t, *mut libc::size_t, c_int) -> c_int;
+    knownhost_free(Ptr);
+    userauth_list(Ptr, *const c_char, c_uint) -> *mut c_char;
+    userauth_authenticated(Ptr) -> c_int;
+    userauth_publickey_fromfile_ex(
+        Ptr,
+        *const c_char,
+        c_uint,
+        *const c_char,
+        *const c_char,
+        *const c_char
+    ) -> c_int;
+    agent_init(Ptr) -> Ptr;
+    agent_connect(Ptr) -> c_int;
+    agent_list_identities(Ptr) -> c_int;
+    agent_get_identity(Ptr, *mut Ptr, Ptr) -> c_int;
+    agent_userauth(Ptr, *const c_char, Ptr) -> c_int;
+    agent_disconnect(Ptr) -> c_int;
+    agent_free(Ptr);
+    channel_open_ex(Ptr, *const c_char, c_uint, c_uint, c_uint, *const c_char, c_uint) -> Ptr;
+    channel_request_pty_ex(
+        Ptr,
+        *const c_char,
+        c_uint,
+        *const c_char,
+        c_uint,
+        c_int,
+        c_int,
+        c_int,
+        c_int
+    ) -> c_int;
+    channel_request_pty_size_ex(Ptr, c_int, c_int, c_int, c_int) -> c_int;
+    channel_process_startup(Ptr, *const c_char, c_uint, *const c_char, c_uint) -> c_int;
+    channel_read_ex(Ptr, c_int, *mut c_char, libc::size_t) -> libc::ssize_t;
+    channel_write_ex(Ptr, c_int, *const c_char, libc::size_t) -> libc::ssize_t;
+    channel_send_eof(Ptr) -> c_int;
+    channel_eof(Ptr) -> c_int;
+    channel_close(Ptr) -> c_int;
+    channel_wait_closed(Ptr) -> c_int;
+    channel_get_exit_status(Ptr) -> c_int;
+    channel_free(Ptr) -> c_int;
+}
+
+// Constants from libssh2.h
+const ERROR_EAGAIN: c_int = -37;
+const SESSION_BLOCK_INBOUND: c_int = 1;
+const SESSION_BLOCK_OUTBOUND: c_int = 2;
+const KNOWNHOST_TYPE_PLAIN: c_int = 1;
+const KNOWNHOST_KEYENC_RAW: c_int = 1 << 16;
+const KNOWNHOST_KEY_SHIFT: c_int = 18;
+const KNOWNHOST_FILE_OPENSSH: c_int = 1;
+const KNOWNHOST_CHECK_MATCH: c_int = 0;
+const KNOWNHOST_CHECK_MISMATCH: c_int = 1;
+const KNOWNHOST_CHECK_NOTFOUND: c_int = 2;
+const CHANNEL_WINDOW_DEFAULT: c_uint = 2 * 1024 * 1024;
+const CHANNEL_PACKET_DEFAULT: c_uint = 32768;
+const EXTENDED_DATA_STDERR: c_int = 1;
+const DISCONNECT_BY_APPLICATION: c_int = 11;
+
+const LIBRARY_NAMES: &[&str] = &[
+    "libssh2.so.1\0",
+    "libssh2.so\0",
+    "libssh2.1.dylib\0",
+    "libssh2.dylib\0",
+];
+
+static LIB: OnceLock<Result<Lib, String>> = OnceLock::new();
+
+fn lib() -> Result<&'static Lib, SyncError> {
+    LIB.get_or_init(|| {
+        let handle = LIBRARY_NAMES
+            .iter()
+            .find_map(|name| {
+                // SAFETY: name is NUL-terminated
+                let handle = unsafe {
+                    libc::dlopen(name.as_ptr().cast(), libc::RTLD_NOW | libc::RTLD_LOCAL)
+                };
+                (!handle.is_null()).then_some(handle)
+            })
+            .ok_or("libssh2 isn't installed")?;
+        // SAFETY: handle is from dlopen and is never closed
+        let lib = unsafe { Lib::bind(handle)? };
+        // SAFETY: called once, before any other libssh2 function
+        if unsafe { (lib.init)(0) } != 0 {
+            return Err("libssh2 failed to start".to_string());
+        }
+        Ok(lib)
+    })
+    .as_ref()
+    .map_err(|e| SyncError::Other(anyhow!("The built-in SSH client needs libssh2: {}", e)))
+}
+
+// ssh_config options the built-in client follows
+const SUPPORTED_OPTIONS: &[&str] = &[
+    "HostName",
+    "User",
+    "Port",
+    "IdentityFile",
+    "IdentitiesOnly",
+    "UserKnownHostsFile",
+    "StrictHostKeyChecking",
+    "ConnectTimeout",
+];
+
+// Options that only tune how the ssh binary behaves, which it can do without
+const IGNORED_OPTIONS: &[&str] = &[
+    "BatchMode",
+    "CheckHostIP",
+    "Compression",
+    "ControlMaster",
+    "ControlPath",
+    "ControlPersist",
+    "HashKnownHosts",
+    "KbdInteractiveAuthentication",
+    "LogLevel",
+    "PasswordAuthentication",
+    "ServerAliveCountMax",
+    "ServerAliveInterval",
+    "TCPKeepAlive",
+    "UpdateHostKeys",
+    "VisualHostKey",
+];
+
+// Key files ssh tries when none is configured, in its order
+const DEFAULT_IDENTITIES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+// An ssh command line as `RemoteTarget` builds it, to run with the built-in
+// client instead
+#[derive(Debug, PartialEq)]
+pub struct SshCommand {
+    pub destination: SshHost,
+    // `-o` options and then those of ~/.ssh/config, as (keyword, value). The
+    // first value for a keyword is the one used, as with ssh.
+    pub options: Vec<(String, String)>,
+    pub command: String,
+    // Whether a terminal was asked for with `-t`
+    pub tty: bool,
+}
+
+impl SshCommand {
+    // The ssh invocation `cmd` makes, or None for other commands and ssh
+    // arguments `RemoteTarget` doesn't use
+    pub fn parse(cmd: &Command) -> Option<Self> {
+        if cmd.get_program() != settings().ssh.binary() {
+            return None;
+        }
+        let mut args = cmd.get_args().map(|arg| arg.to_str());
+        let mut tty = false;
+        let mut port = None;
+        let mut options = Vec::new();
+        loop {
+            match args.next()?? {
+                "-t" => tty = true,
+                "-p" => port = Some(args.next()??.parse().ok()?),
+                "-o" => options.push(split_option(args.next()??)?),
+                destination if !destination.starts_with('-') => {
+                    let command = args.next()??.to_string();
+                    if args.next().is_some() {
+                        return None;
+                    }
+                    let mut destination = SshHost::parse(destination);
+                    destination.port = port;
+                    return Some(Self {
+                        destination,
+                        options,
+                        command,
+                        tty,
+                    });
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    // Fill in what ~/.ssh/config has for the host, as ssh would, and refuse
+    // options the built-in client can't follow
+    pub fn settle(mut self, config: &[SshConfigHost]) -> Result<Self, SyncError> {
+        if let Some(host) = config
+            .iter()
+            .find(|host| host.alias == self.destination.host)
+        {
+            let configured = SshHost::parse(&host.remote_host);
+            self.options
+                .push(("HostName".to_string(), configured.host.clone()));
+            if let Some(user) = configured.user {
+                self.options.push(("User".to_string(), user));
+            }
+            self.options.extend(
+                host.ssh_options
+                    .iter()
+                    .filter_map(|option| split_option(option)),
+            );
+        }
+
+        if let Some((keyword, _)) = self.options.iter().find(|(keyword, _)| {
+            !SUPPORTED_OPTIONS.contains(&keyword.as_str())
+                && !IGNORED_OPTIONS.contains(&keyword.as_str())
+        }) {
+            return Err(SyncError::Other(anyhow!(
+                "The built-in SSH client doesn't support the ssh option {}",
+                keyword
+            )));
+        }
+
+        // The destination's own user and `-p` win over options
+        if let Some(host) = self.option("HostName") {
+            self.destination.host = host.to_string();
+        }
+        if self.destination.user.is_none() {
+            self.destination.user = self.option("User").map(str::to_string);
+        }
+        if self.destination.port.is_none() {
+            self.destination.port = self.option("Port").and_then(|port| port.parse().ok());
+        }
+        Ok(self)
+    }
+
+    fn option(&self, keyword: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(key, _)| key == keyword)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn enabled(&self, keyword: &str) -> bool {
+        self.option(keyword)
+            .is_some_and(|value| value.eq_ignore_ascii_case("yes"))
+    }
+
+    // Run the command with its output passed through, and with this terminal
+    // as its input when it asked for one
+    pub fn status(&self) -> Result<ExitStatus, SyncError> {
+        let session = self.connect()?;
+        let channel = self.start(&session)?;
+        let input = if self.tty {
+            Input::Terminal
+        } else {
+            Input::Bytes(&[])
+        };
+        let _raw = self.tty.then(RawTerminal::enable).flatten();
+        let code = channel.pump(&self.destination, input, &mut |stream, data| {
+            let _ = if stream == EXTENDED_DATA_STDERR {
+                io::stderr().write_all(data)
+            } else {
+                let mut stdout = io::stdout();
+                stdout.write_all(data).and_then(|_| stdout.flush())
+            };
+        })?;
+        Ok(ExitStatus::from_raw(code << 8))
+    }
+
+    // Run the command with `input` on its stdin, collecting its output
+    pub fn output(&self, input: &[u8]) -> Result<Output, SyncError> {
+        let session = self.connect()?;
+        let channel = self.start(&session)?;
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let code = channel.pump(
+            &self.destination,
+            Input::Bytes(input),
+            &mut |stream, data| {
+                if stream == EXTENDED_DATA_STDERR {
+                    stderr.extend_from_slice(data);
+                } else {
+                    stdout.extend_from_slice(data);
+                }
+            },
+        )?;
+        Ok(Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout,
+            stderr,
+        })
+    }
+
+    fn connection_failed(&self, message: String) -> SyncError {
+        SyncError::SshConnectionFailed {
+            host: self.destination.to_string(),
+            message,
+        }
+    }
+
+    fn connect(&self) -> Result<Session, SyncError> {
+        let lib = lib()?;
+        let port = self.destination.port.unwrap_or(22);
+        let timeout = self
+            .option("ConnectTimeout")
+            .and_then(|secs| secs.parse().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs);
+
+        let addresses = (self.destination.host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|e| self.connection_failed(e.to_string()))?;
+        let mut error = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        let mut socket = None;
+        for address in addresses {
+            let connected = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+                None => TcpStream::connect(address),
+            };
+            match connected {
+                Ok(stream) => {
+                    socket = Some(stream);
+                    break;
+                }
+                Err(e) => error = e,
+            }
+        }
+        let socket = socket.ok_or_else(|| self.connection_failed(error.to_string()))?;
+
+        // SAFETY: null allocators select libssh2's own
+        let raw = unsafe { (lib.session_init_ex)(null_mut(), null_mut(), null_mut(), null_mut()) };
+        if raw.is_null() {
+            return Err(self.connection_failed("libssh2 couldn't start a session".to_string()));
+        }
+        let session = Session { lib, raw, socket };
+        // SAFETY: raw is a live session; the socket stays open as long as it
+        unsafe {
+            (lib.session_set_blocking)(raw, 1);
+            if let Some(timeout) = timeout {
+                (lib.session_set_timeout)(raw, timeout.as_millis() as c_long);
+            }
+            if (lib.session_handshake)(raw, session.socket.as_raw_fd()) != 0 {
+                return Err(self.connection_failed(session.error()));
+            }
+        }
+        self.check_host_key(&session)?;
+        self.authenticate(&session)?;
+        // SAFETY: raw is a live session
+        unsafe { (lib.session_set_timeout)(raw, 0) };
+        Ok(session)
+    }
+
+    // known_hosts files to check the host key against
+    fn known_hosts(&self) -> Vec<PathBuf> {
+        match self.option("UserKnownHostsFile") {
+            Some(files) => files.split_whitespace().map(expand_home).collect(),
+            None => dirs::home_dir()
+                .map(|home| vec![home.join(".ssh").join("known_hosts")])
+                .unwrap_or_default(),
+        }
+    }
+
+    // Make sure the server's host key is the one in known_hosts. Unknown
+    // hosts are refused, unless StrictHostKeyChecking says to accept them.
+    fn check_host_key(&self, session: &Session) -> Result<(), SyncError> {
+        let lib = session.lib;
+        let mut len = 0;
+        let mut kind = 0;
+        // SAFETY: the key points into the session, which outlives its use
+        let key = unsafe { (lib.session_hostkey)(session.raw, &mut len, &mut kind) };
+        if key.is_null() {
+            return Err(self.connection_failed(session.error()));
+        }
+        // libssh2's host key types (RSA, DSS, ECDSA 256/384/521, Ed25519)
+        // are one below its known_hosts key types
+        if !(1..=6).contains(&kind) {
+            return Err(self.connection_failed("unknown host key type".to_string()));
+        }
+        let typemask =
+            KNOWNHOST_TYPE_PLAIN | KNOWNHOST_KEYENC_RAW | ((kind + 1) << KNOWNHOST_KEY_SHIFT);
+
+        let hosts = KnownHosts::new(session)?;
+        for file in self.known_hosts() {
+            let Ok(path) = CString::new(file.as_os_str().as_bytes()) else {
+                continue;
+            };
+            // Missing or unreadable files count as empty, as with ssh
+            // SAFETY: hosts is live and path a valid C string
+            unsafe { (lib.knownhost_readfile)(hosts.raw, path.as_ptr(), KNOWNHOST_FILE_OPENSSH) };
+        }
+        let host = &self.destination.host;
+        let port = self.destination.port.unwrap_or(22);
+        let name = CString::new(host.as_str()).map_err(|e| SyncError::Other(e.into()))?;
+        // SAFETY: key is len bytes long and name a valid C string
+        let check = unsafe {
+            (lib.knownhost_checkp)(
+                hosts.raw,
+                name.as_ptr(),
+                c_int::from(port),
+                key,
+                len,
+                typemask,
+                null_mut(),
+            )
+        };
+
+        let strict = self
+            .option("StrictHostKeyChecking")
+            .unwrap_or("ask")
+            .to_ascii_lowercase();
+        match check {
+            KNOWNHOST_CHECK_MATCH => Ok(()),
+            KNOWNHOST_CHECK_MISMATCH => Err(SyncError::Other(anyhow!(
+                "The host key of {} doesn't match the one in known_hosts. If it changed on \
+                 purpose, remove the old one with `ssh-keygen -R '{}'`",
+                host,
+                known_hosts_name(host, port)
+            ))),
+            KNOWNHOST_CHECK_NOTFOUND if strict == "no" || strict == "off" => Ok(()),
+            KNOWNHOST_CHECK_NOTFOUND if strict == "accept-new" => {
+                // SAFETY: as for the check
+                let line = unsafe { hosts.line(host, port, key, len, typemask) }?;
+                self.remember_host(&line)
+            }
+            KNOWNHOST_CHECK_NOTFOUND => Err(SyncError::Other(anyhow!(
+                "The host key of {} isn't known yet. Connect once with ssh to check and save \
+                 it, or set StrictHostKeyChecking=accept-new",
+                host
+            ))),
+            _ => Err(self.connection_failed(session.error())),
+        }
+    }
+
+    // Add a host key line to the first known_hosts file
+    fn remember_host(&self, line: &str) -> Result<(), SyncError> {
+        let Some(file) = self.known_hosts().into_iter().next() else {
+            return Ok(());
+        };
+        if file.as_os_str() == "/dev/null" {
+            return Ok(());
+        }
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file)?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    // Log in with the agent's keys, then with the key files, as ssh does
+    fn authenticate(&self, session: &Session) -> Result<(), SyncError> {
+        let lib = session.lib;
+        let user = match &self.destination.user {
+            Some(user) => user.clone(),
+            None => local_user().ok_or_else(|| {
+                SyncError::Other(anyhow!("Failed to find the user name to log in with"))
+            })?,
+        };
+        let c_user = CString::new(user.as_str()).map_err(|e| SyncError::Other(e.into()))?;
+        let refused = || SyncError::SshAuthFailed {
+            host: self.destination.to_string(),
+        };
+
+        // Asking for the login methods logs in right away if none is needed
+        // SAFETY: c_user is user.len() bytes plus a NUL
+        let methods =
+            unsafe { (lib.userauth_list)(session.raw, c_user.as_ptr(), user.len() as c_uint) };
+        if methods.is_null() {
+            // SAFETY: raw is a live session
+            return match unsafe { (lib.userauth_authenticated)(session.raw) } {
+                1 => Ok(()),
+                _ => Err(self.connection_failed(session.error())),
+            };
+        }
+        // SAFETY: libssh2 returns a NUL-terminated list it owns
+        let methods = unsafe { CStr::from_ptr(methods) }.to_string_lossy();
+        if !methods.split(',').any(|method| method == "publickey") {
+            return Err(refused());
+        }
+
+        if !self.enabled("IdentitiesOnly") && session.agent_login(&c_user) {
+            return Ok(());
+        }
+        for file in self.identity_files() {
+            let Ok(path) = CString::new(file.as_os_str().as_bytes()) else {
+                continue;
+            };
+            // Without a public key file libssh2 derives the key from the
+            // private one. Keys with a passphrase need the agent.
+            // SAFETY: all strings are valid C strings
+            let status = unsafe {
+                (lib.userauth_publickey_fromfile_ex)(
+                    session.raw,
+                    c_user.as_ptr(),
+                    user.len() as c_uint,
+                    null(),
+                    path.as_ptr(),
+                    c"".as_ptr(),
+                )
+            };
+            if status == 0 {
+                return Ok(());
+            }
+        }
+        Err(refused())
+    }
+
+    // IdentityFile keys, or ssh's default ones that exist
+    fn identity_files(&self) -> Vec<PathBuf> {
+        let configured: Vec<PathBuf> = self
+            .options
+            .iter()
+            .filter(|(keyword, _)| keyword == "IdentityFile")
+            .map(|(_, file)| expand_home(file))
+            .collect();
+        if !configured.is_empty() {
+            return configured;
+        }
+        let Some(ssh_dir) = dirs::home_dir().map(|home| home.join(".ssh")) else {
+            return Vec::new();
+        };
+        DEFAULT_IDENTITIES
+            .iter()
+            .map(|name| ssh_dir.join(name))
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    // Open a channel running the command, with a terminal if one was asked for
+    fn start<'a>(&self, session: &'a Session) -> Result<Channel<'a>, SyncError> {
+        let lib = session.lib;
+        // SAFETY: the channel type is 7 bytes long
+        let raw = unsafe {
+            (lib.channel_open_ex)(
+                session.raw,
+                c"session".as_ptr(),
+                7,
+                CHANNEL_WINDOW_DEFAULT,
+                CHANNEL_PACKET_DEFAULT,
+                null(),
+                0,
+            )
+        };
+        if raw.is_null() {
+            return Err(self.connection_failed(session.error()));
+        }
+        let channel = Channel { session, raw };
+
+        if self.tty {
+            let term = std::env::var("TERM").unwrap_or_else(|_| "xterm".to_string());
+            let (width, height) = terminal_size();
+            // SAFETY: term is passed with its length; no terminal modes
+            let status = unsafe {
+                (lib.channel_request_pty_ex)(
+                    raw,
+                    term.as_ptr().cast(),
+                    term.len() as c_uint,
+                    null(),
+                    0,
+                    width,
+                    height,
+                    0,
+                    0,
+                )
+            };
+            if status != 0 {
+                return Err(self.connection_failed(session.error()));
+            }
+        }
+
+        // SAFETY: the request and command are passed with their lengths
+        let status = unsafe {
+            (lib.channel_process_startup)(
+                raw,
+                c"exec".as_ptr(),
+                4,
+                self.command.as_ptr().cast(),
+                self.command.len() as c_uint,
+            )
+        };
+        if status != 0 {
+            return Err(self.connection_failed(session.error()));
+        }
+        Ok(channel)
+    }
+}
+
+// `KEY=VALUE` or `KEY VALUE` as a (keyword, value) pair, with the keyword
+// spelled as in SUPPORTED_OPTIONS and IGNORED_OPTIONS
+fn split_option(option: &str) -> Option<(String, String)> {
+    let (keyword, value) = option.split_once(|c: char| c == '=' || c.is_whitespace())?;
+    let keyword = SUPPORTED_OPTIONS
+        .iter()
+        .chain(IGNORED_OPTIONS)
+        .find(|known| known.eq_ignore_ascii_case(keyword))
+        .map_or_else(|| keyword.to_string(), |known| known.to_string());
+    let value = value
+        .trim_start_matches(|c: char| c == '=' || c.is_whitespace())
+        .trim()
+        .trim_matches('"');
+    Some((keyword, value.to_string()))
+}
+
+// How known_hosts names a host: `[host]:port` for ports other than 22
+fn known_hosts_name(host: &str, port: u16) -> String {
+    match port {
+        22 => host.to_string(),
+        port => format!("[{}]:{}", host, port),
+    }
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+// Name of the user running sync-rs, which ssh logs in as by default
+fn local_user() -> Option<String> {
+    if let Ok(user) = std::env::var("USER").or_else(|_| std::env::var("LOGNAME")) {
+        return Some(user);
+    }
+    // SAFETY: getpwuid's result is read before any other call could reuse it
+    unsafe {
+        let entry = libc::getpwuid(libc::getuid());
+        (!entry.is_null()).then(|| {
+            CStr::from_ptr((*entry).pw_name)
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+}
+
+// Columns and rows of this terminal, or 80x24
+fn terminal_size() -> (c_int, c_int) {
+    // SAFETY: winsize is plain data, filled in by the ioctl
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0
+        || size.ws_col == 0
+    {
+        return (80, 24);
+    }
+    (c_int::from(size.ws_col), c_int::from(size.ws_row))
+}
+
+struct Session {
+    lib: &'static Lib,
+    raw: Ptr,
+    // Kept open for as long as the session uses it
+    socket: TcpStream,
+}
+
+impl Session {
+    // libssh2's description of the last failure
+    fn error(&self) -> String {
+        let mut message: *mut c_char = null_mut();
+        // SAFETY: without a buffer asked for, message points into the session
+        unsafe { (self.lib.session_last_error)(self.raw, &mut message, null_mut(), 0) };
+        if message.is_null() {
+            return "the connection failed".to_string();
+        }
+        // SAFETY: message is NUL-terminated and read before the next call
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Log in with one of ssh-agent's keys, if an agent is running and the
+    // server takes one
+    fn agent_login(&self, user: &CStr) -> bool {
+        let lib = self.lib;
+        // SAFETY: identities belong to the agent and are only used while it's
+        // connected
+        unsafe {
+            let agent = (lib.agent_init)(self.raw);
+            if agent.is_null() {
+                return false;
+            }
+            let mut logged_in = false;
+            if (lib.agent_connect)(agent) == 0 {
+                if (lib.agent_list_identities)(agent) == 0 {
+                    let mut previous = null_mut();
+                    loop {
+                        let mut identity = null_mut();
+                        if (lib.agent_get_identity)(agent, &mut identity, previous) != 0 {
+                            break;
+                        }
+                        if (lib.agent_userauth)(agent, user.as_ptr(), identity) == 0 {
+                            logged_in = true;
+                            break;
+                        }
+                        previous = identity;
+                    }
+                }
+                (lib.agent_disconnect)(agent);
+            }
+            (lib.agent_free)(agent);
+            logged_in
+        }
+    }
+
+    // Wait until the socket is ready for what libssh2 is waiting on, or
+    // `stdin` has input. Returns whether stdin does.
+    fn wait(&self, stdin: bool) -> bool {
+        // SAFETY: raw is a live session
+        let directions = unsafe { (self.lib.session_block_directions)(self.raw) };
+        let mut events = 0;
+        if directions & SESSION_BLOCK_INBOUND != 0 {
+            events |= libc::POLLIN;
+        }
+        if directions & SESSION_BLOCK_OUTBOUND != 0 {
+            events |= libc::POLLOUT;
+        }
+        let mut fds = [
+            libc::pollfd {
+                fd: self.socket.as_raw_fd(),
+                events,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: if stdin { libc::STDIN_FILENO } else { -1 },
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // Short waits when libssh2 isn't blocked on the socket, and to notice
+        // the terminal being resized
+        let timeout = if events == 0 || stdin { 100 } else { 1000 };
+        // SAFETY: fds is a valid array of two pollfds
+        unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout) };
+        fds[1].revents & (libc::POLLIN | libc::POLLHUP) != 0
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // SAFETY: raw is a live session, freed only here
+        unsafe {
+            (self.lib.session_set_blocking)(self.raw, 1);
+            (self.lib.session_disconnect_ex)(
+                self.raw,
+                DISCONNECT_BY_APPLICATION,
+                c"Done".as_ptr(),
+                c"".as_ptr(),
+            );
+            (self.lib.session_free)(self.raw);
+        }
+    }
+}
+
+struct KnownHosts<'a> {
+    session: &'a Session,
+    raw: Ptr,
+}
+
+impl<'a> KnownHosts<'a> {
+    fn new(session: &'a Session) -> Result<Self, SyncError> {
+        // SAFETY: session.raw is a live session
+        let raw = unsafe { (session.lib.knownhost_init)(session.raw) };
+        if raw.is_null() {
+            return Err(SyncError::Other(anyhow!("{}", session.error())));
+        }
+        Ok(Self { session, raw })
+    }
+
+    // The known_hosts line for a host key, added to this collection
+    //
+    // SAFETY: key must point to len bytes
+    unsafe fn line(
+        &self,
+        host: &str,
+        port: u16,
+        key: *const c_char,
+        len: libc::size_t,
+        typemask: c_int,
+    ) -> Result<String, SyncError> {
+        let lib = self.session.lib;
+        let name =
+            CString::new(known_hosts_name(host, port)).map_err(|e| SyncError::Other(e.into()))?;
+        let mut entry = null_mut();
+        if (lib.knownhost_addc)(
+            self.raw,
+            name.as_ptr(),
+            null(),
+            key,
+            len,
+            null(),
+            0,
+            typemask,
+            &mut entry,
+        ) != 0
+        {
+            return Err(SyncError::Other(anyhow!("{}", self.session.error())));
+        }
+        let mut buffer = vec![0u8; 8192];
+        let mut written = 0;
+        if (lib.knownhost_writeline)(
+            self.raw,
+            entry,
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+            &mut written,
+            KNOWNHOST_FILE_OPENSSH,
+        ) != 0
+        {
+            return Err(SyncError::Other(anyhow!("{}", self.session.error())));
+        }
+        buffer.truncate(written);
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+impl Drop for KnownHosts<'_> {
+    fn drop(&mut self) {
+        // SAFETY: raw is live, freed only here
+        unsafe { (self.session.lib.knownhost_free)(self.raw) };
+    }
+}
+
+// Where a command's input comes from
+enum Input<'a> {
+    Bytes(&'a [u8]),
+    // This terminal's, forwarded as it's typed
+    Terminal,
+}
+
+struct Channel<'a> {
+    session: &'a Session,
+    raw: Ptr,
+}
+
+impl Channel<'_> {
+    // Feed the command its input and hand its output (stream 0 for stdout,
+    // EXTENDED_DATA_STDERR for stderr) to `sink` until it's done, and return
+    // its exit code
+    fn pump(
+        &self,
+        host: &SshHost,
+        input: Input,
+        sink: &mut dyn FnMut(c_int, &[u8]),
+    ) -> Result<i32, SyncError> {
+        let lib = self.session.lib;
+        let dropped = || SyncError::SshConnectionFailed {
+            host: host.to_string(),
+            message: self.session.error(),
+        };
+        let (mut pending, mut stdin_open) = match input {
+            Input::Bytes(bytes) => (bytes.to_vec(), false),
+            Input::Terminal => (Vec::new(), true),
+        };
+        let mut eof_sent = false;
+        let mut size = terminal_size();
+        let mut buffer = vec![0u8; CHANNEL_PACKET_DEFAULT as usize];
+
+        // SAFETY: raw is a live channel of a live session, and every buffer
+        // is passed with its length
+        unsafe {
+            (lib.session_set_blocking)(self.session.raw, 0);
+            loop {
+                let mut busy = false;
+
+                if !pending.is_empty() {
+                    let written =
+                        (lib.channel_write_ex)(self.raw, 0, pending.as_ptr().cast(), pending.len());
+                    if written > 0 {
+                        pending.drain(..written as usize);
+                        busy = true;
+                    } else if written != ERROR_EAGAIN as libc::ssize_t {
+                        return Err(dropped());
+                    }
+                } else if !stdin_open && !eof_sent {
+                    match (lib.channel_send_eof)(self.raw) {
+                        0 => eof_sent = true,
+                        ERROR_EAGAIN => {}
+                        _ => return Err(dropped()),
+                    }
+                }
+
+                for stream in [0, EXTENDED_DATA_STDERR] {
+                    loop {
+                        let read = (lib.channel_read_ex)(
+                            self.raw,
+                            stream,
+                            buffer.as_mut_ptr().cast(),
+                            buffer.len(),
+                        );
+                        if read > 0 {
+                            sink(stream, &buffer[..read as usize]);
+                            busy = true;
+                        } else if read == 0 || read == ERROR_EAGAIN as libc::ssize_t {
+                            break;
+                        } else {
+                            return Err(dropped());
+                        }
+                    }
+                }
+
+                // Only reported once all output has been read
+                if (lib.channel_eof)(self.raw) == 1 {
+                    break;
+                }
+
+                if let Input::Terminal = input {
+                    let now = terminal_size();
+                    if now != size
+                        && (lib.channel_request_pty_size_ex)(self.raw, now.0, now.1, 0, 0)
+                            != ERROR_EAGAIN
+                    {
+                        size = now;
+                    }
+                }
+
+                if !busy && self.session.wait(stdin_open && pending.is_empty()) {
+                    let read =
+                        libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr().cast(), buffer.len());
+                    match read {
+                        0 => stdin_open = false,
+                        read if read > 0 => pending.extend_from_slice(&buffer[..read as usize]),
+                        _ => {}
+                    }
+                }
+            }
+
+            // The exit status comes with the channel closing
+            (lib.session_set_blocking)(self.session.raw, 1);
+            (lib.channel_close)(self.raw);
+            (lib.channel_wait_closed)(self.raw);
+            Ok((lib.channel_get_exit_status)(self.raw))
+        }
+    }
+}
+
+impl Drop for Channel<'_> {
+    fn drop(&mut self) {
+        // SAFETY: raw is a live channel, freed only here
+        unsafe { (self.session.lib.channel_free)(self.raw) };
+    }
+}
+
+// Puts this terminal into raw mode while a remote shell uses it, so that
+// keys like Ctrl-C reach the remote instead of stopping sync-rs
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    fn enable() -> Option<Self> {
+        // SAFETY: termios is plain data, filled in by tcgetattr before use
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return None;
+            }
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return None;
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+            Some(Self { original })
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in `enable`
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+// Runs commands like `SystemRunner`, except that ssh command lines for SSH
+// remotes go through the built-in client. Used when `ssh.native` is set.
+pub struct NativeSshRunner;
+
+impl NativeSshRunner {
+    fn ssh_command(cmd: &Command) -> io::Result<Option<SshCommand>> {
+        let Some(ssh) = SshCommand::parse(cmd) else {
+            return Ok(None);
+        };
+        let config = ssh_config_hosts().map_err(io::Error::other)?;
+        ssh.settle(&config).map(Some).map_err(io::Error::other)
+    }
+}
+
+impl CommandRunner for NativeSshRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        match Self::ssh_command(cmd)? {
+            Some(ssh) => ssh.status().map_err(io::Error::other),
+            None => SystemRunner.status(cmd),
+        }
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        self.output_with_input(cmd, &[])
+    }
+
+    fn output_shown(
+        &self,
+        cmd: &mut Command,
+        show: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> io::Result<Output> {
+        let Some(ssh) = Self::ssh_command(cmd)? else {
+            return SystemRunner.output_shown(cmd, show);
+        };
+        let output = ssh.output(&[]).map_err(io::Error::other)?;
+        let mut stdout = io::stdout();
+        for line in output
+            .stdout
+            .split_inclusive(|&byte| byte == b'\r' || byte == b'\n')
+        {
+            if show(line) {
+                let _ = stdout.write_all(line);
+            }
+        }
+        let _ = stdout.flush();
+        Ok(output)
+    }
+
+    fn output_with_input(&self, cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+        match Self::ssh_command(cmd)? {
+            Some(ssh) => ssh.output(input).map_err(io::Error::other),
+            None => SystemRunner.output_with_input(cmd, input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::RemoteTarget;
+
+    fn parsed(target: &str, options: &[&str]) -> SshCommand {
+        let target = RemoteTarget::Ssh {
+            host: target.to_string(),
+            options: options.iter().map(|option| option.to_string()).collect(),
+        };
+        SshCommand::parse(&target.command("echo $HOME")).unwrap()
+    }
+
+    #[test]
+    fn ssh_command_lines_are_read_back() {
+        let ssh = parsed(
+            "me@box:2222",
+            &["IdentityFile=~/.ssh/work", "ConnectTimeout 5"],
+        );
+        assert_eq!(ssh.destination.to_string(), "me@box:2222");
+        assert_eq!(ssh.command, "echo $HOME");
+        assert!(!ssh.tty);
+        assert_eq!(
+            ssh.options,
+            [
+                ("IdentityFile".to_string(), "~/.ssh/work".to_string()),
+                ("ConnectTimeout".to_string(), "5".to_string()),
+            ]
+        );
+
+        let target = RemoteTarget::parse("me@[2001:db8::1]:22");
+        let ssh = SshCommand::parse(&target.interactive_command("exec $SHELL -l")).unwrap();
+        assert!(ssh.tty);
+        assert_eq!(ssh.destination.host, "2001:db8::1");
+        assert_eq!(ssh.destination.port, Some(22));
+
+        // Other programs, and ssh arguments sync-rs doesn't pass, stay with
+        // the system runner
+        assert_eq!(SshCommand::parse(&Command::new("rsync")), None);
+        let mut forwarding = Command::new("ssh");
+        forwarding.args(["-L", "8080:localhost:80", "box", "true"]);
+        assert_eq!(SshCommand::parse(&forwarding), None);
+    }
+
+    #[test]
+    fn config_and_options_settle_like_ssh() {
+        let config = [SshConfigHost {
+            alias: "gpu".to_string(),
+            remote_host: "alice@10.0.0.7".to_string(),
+            ssh_options: vec![
+                "Port=2200".to_string(),
+                "IdentityFile=~/.ssh/gpu".to_string(),
+            ],
+        }];
+
+        let ssh = parsed("gpu", &[]).settle(&config).unwrap();
+        assert_eq!(ssh.destination.to_string(), "alice@10.0.0.7:2200");
+
+        // The command line wins over options, and options over the config
+        let ssh = parsed("bob@gpu", &["Port=2022", "User=carol"])
+            .settle(&config)
+            .unwrap();
+        assert_eq!(ssh.destination.to_string(), "bob@10.0.0.7:2022");
+        let ssh = parsed("gpu:22", &[]).settle(&config).unwrap();
+        assert_eq!(ssh.destination.to_string(), "alice@10.0.0.7:22");
+
+        // Hosts without a config block are used as they are
+        let ssh = parsed("box", &["ServerAliveInterval=30"])
+            .settle(&config)
+            .unwrap();
+        assert_eq!(ssh.destination.to_string(), "box");
+
+        let unsupported = parsed("box", &["ProxyJump=bastion"]).settle(&config);
+        assert!(
+            matches!(unsupported, Err(SyncError::Other(e)) if e.to_string().contains("ProxyJump"))
+        );
+    }
+
+    #[test]
+    fn connection_failures_have_their_own_kind() {
+        // Nothing listens on port 1 here
+        let ssh = parsed("127.0.0.1:1", &["ConnectTimeout=5"])
+            .settle(&[])
+            .unwrap();
+        match ssh.output(b"") {
+            Err(SyncError::SshConnectionFailed { host, .. }) => assert_eq!(host, "127.0.0.1:1"),
+            // Machines without libssh2 can't get as far as connecting
+            Err(SyncError::Other(e)) => assert!(e.to_string().contains("libssh2")),
+            other => panic!("unexpected result: {:?}", other.map(|output| output.status)),
+        }
+    }
+}
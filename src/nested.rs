@@ -0,0 +1,211 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::walk::walk_tree;
+
+// What to do with git repositories inside the synced directory, like
+// submodules or vendored checkouts
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NestedRepos {
+    // Sync them like any other directory
+    #[default]
+    Sync,
+    // Sync their working trees but not their `.git`
+    ExcludeGit,
+    // Leave them out altogether
+    Skip,
+}
+
+impl NestedRepos {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "sync" => Ok(Self::Sync),
+            "exclude-git" => Ok(Self::ExcludeGit),
+            "skip" => Ok(Self::Skip),
+            _ => anyhow::bail!("Expected sync, exclude-git or skip, got '{}'", s),
+        }
+    }
+
+    // Paths below `root` to leave out, relative to it
+    pub fn excluded_paths(self, root: &Path) -> Vec<String> {
+        if self == Self::Sync {
+            return Vec::new();
+        }
+        find_nested_repos(root)
+            .iter()
+            .map(|repo| match self {
+                Self::ExcludeGit => format!("{}/.git", repo.display()),
+                _ => repo.display().to_string(),
+            })
+            .collect()
+    }
+
+    // rsync filter rules for the nested repositories below `root`
+    pub fn filter_rules(self, root: &Path) -> Vec<String> {
+        self.excluded_paths(root)
+            .iter()
+            .map(|path| format!("- /{}", path))
+            .collect()
+    }
+}
+
+// A line of `git submodule status`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmoduleStatus {
+    pub path: String,
+    pub commit: String,
+    // ' ' checked out at the recorded commit, '+' at another one, '-' not
+    // initialized, 'U' with merge conflicts
+    pub state: char,
+}
+
+pub fn parse_submodule_status(output: &str) -> Vec<SubmoduleStatus> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let state = line.chars().next()?;
+            let mut fields = line[state.len_utf8()..].split_whitespace();
+            Some(SubmoduleStatus {
+                commit: fields.next()?.to_string(),
+                path: fields.next()?.to_string(),
+                state,
+            })
+        })
+        .collect()
+}
+
+// Submodules of the git repository at `root`, if it is one
+pub fn submodule_status(root: &Path) -> Vec<SubmoduleStatus> {
+    let Ok(output) = Command::new("git")
+        .args(["submodule", "status"])
+        .current_dir(root)
+        .output()
+    else {
+        return Vec::new();
+    };
+    if !output.status.success() {
+        return Vec::new();
+    }
+    parse_submodule_status(&String::from_utf8_lossy(&output.stdout))
+}
+
+// rsync filter rules leaving out the submodules that aren't initialized
+// here, whose directories are empty, so that the remote's copies stay as they are
+pub fn uninitialized_submodule_rules(root: &Path) -> Vec<String> {
+    submodule_status(root)
+        .iter()
+        .filter(|module| module.state == '-')
+        .map(|module| format!("- /{}/", module.path))
+        .collect()
+}
+
+// Submodules checked out differently on the remote than here, described
+pub fn submodule_mismatches(local: &[SubmoduleStatus], remote: &[SubmoduleStatus]) -> Vec<String> {
+    local
+        .iter()
+        .filter(|module| module.state != '-')
+        .filter_map(|module| {
+            let short = |commit: &str| commit.chars().take(8).collect::<String>();
+            match remote.iter().find(|other| other.path == module.path) {
+                None => Some(format!("{}: missing on the remote", module.path)),
+                Some(other) if other.state == '-' => {
+                    Some(format!("{}: not initialized on the remote", module.path))
+                }
+                Some(other) if other.commit != module.commit => Some(format!(
+                    "{}: at {} on the remote, {} here",
+                    module.path,
+                    short(&other.commit),
+                    short(&module.commit)
+                )),
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+// Directories below `root` with a `.git` of their own, relative to it.
+// Submodules have a `.git` file pointing into the parent's `.git`, other
+// checkouts a `.git` directory. Repositories inside nested ones are found
+// too, so that their `.git` is left out as well.
+fn find_nested_repos(root: &Path) -> Vec<PathBuf> {
+    walk_tree(root, |path| path.file_name() != Some(OsStr::new(".git")))
+        .into_iter()
+        .filter(|entry| entry.path.file_name() == Some(OsStr::new(".git")))
+        .filter_map(|entry| entry.path.parent().map(Path::to_path_buf))
+        .filter(|repo| !repo.as_os_str().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn submodules_and_checkouts_are_found() {
+        let root = std::env::temp_dir().join(format!("sync-rs-nested-test-{}", std::process::id()));
+        fs::create_dir_all(root.join(".git/modules/lib")).unwrap();
+        fs::create_dir_all(root.join("vendor/lib")).unwrap();
+        fs::write(
+            root.join("vendor/lib/.git"),
+            "gitdir: ../../.git/modules/lib",
+        )
+        .unwrap();
+        fs::create_dir_all(root.join("third_party/tool/.git")).unwrap();
+        fs::create_dir_all(root.join("third_party/tool/deps/inner/.git")).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+
+        let exclude_git = NestedRepos::ExcludeGit.filter_rules(&root);
+        let skip = NestedRepos::Skip.filter_rules(&root);
+        let sync = NestedRepos::Sync.filter_rules(&root);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            exclude_git,
+            [
+                "- /third_party/tool/.git",
+                "- /third_party/tool/deps/inner/.git",
+                "- /vendor/lib/.git",
+            ]
+        );
+        assert_eq!(
+            skip,
+            [
+                "- /third_party/tool",
+                "- /third_party/tool/deps/inner",
+                "- /vendor/lib",
+            ]
+        );
+        assert!(sync.is_empty());
+    }
+
+    #[test]
+    fn submodules_checked_out_differently_are_mismatches() {
+        let local = parse_submodule_status(
+            " 1111111111111111111111111111111111111111 libs/a (v1.0)\n\
+             +2222222222222222222222222222222222222222 libs/b (heads/main)\n\
+             -3333333333333333333333333333333333333333 libs/optional\n\
+             \u{20}4444444444444444444444444444444444444444 libs/c (v2)\n",
+        );
+        assert_eq!(local.len(), 4);
+        assert_eq!(local[1].state, '+');
+        assert_eq!(local[2].path, "libs/optional");
+
+        let remote = parse_submodule_status(
+            " 1111111111111111111111111111111111111111 libs/a (v1.0)\n\
+             \u{20}9999999999999999999999999999999999999999 libs/b (heads/main)\n\
+             -4444444444444444444444444444444444444444 libs/c\n",
+        );
+        assert_eq!(
+            submodule_mismatches(&local, &remote),
+            [
+                "libs/b: at 99999999 on the remote, 22222222 here",
+                "libs/c: not initialized on the remote",
+            ]
+        );
+    }
+}
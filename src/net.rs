@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+// Whether a TCP connection to host:port can be established within the timeout
+pub fn is_reachable(host: &str, port: u16, timeout: Duration) -> bool {
+    let Ok(addrs) = (host, port).to_socket_addrs() else {
+        return false;
+    };
+
+    addrs
+        .into_iter()
+        .any(|addr| TcpStream::connect_timeout(&addr, timeout).is_ok())
+}
+
+// Time taken to establish a TCP connection to host:port, if it succeeds
+pub fn connect_time(host: &str, port: u16, timeout: Duration) -> Option<Duration> {
+    let addr = (host, port).to_socket_addrs().ok()?.next()?;
+    let started = Instant::now();
+    TcpStream::connect_timeout(&addr, timeout).ok()?;
+    Some(started.elapsed())
+}
+
+// An SSH destination as written in `remote_host`: `[user@]host[:port]`.
+// IPv6 literals may be bare (`::1`) or bracketed (`[::1]`), and need the
+// brackets when followed by a port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshHost {
+    pub user: Option<String>,
+    // Host name or address, without brackets
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl SshHost {
+    pub fn parse(remote_host: &str) -> Self {
+        let (user, rest) = match remote_host.rsplit_once('@') {
+            Some((user, rest)) => (Some(user.to_string()), rest),
+            None => (None, remote_host),
+        };
+
+        let (host, port) = if let Some((host, after)) = rest
+            .strip_prefix('[')
+            .and_then(|inner| inner.split_once(']'))
+        {
+            (host, after.strip_prefix(':').and_then(|p| p.parse().ok()))
+        } else {
+            // A single colon separates a port; more make an IPv6 literal
+            match rest.split_once(':') {
+                Some((host, port)) if !port.contains(':') => match port.parse() {
+                    Ok(port) => (host, Some(port)),
+                    Err(_) => (rest, None),
+                },
+                _ => (rest, None),
+            }
+        };
+
+        Self {
+            user,
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.host.contains(':')
+    }
+
+    // Destination argument for ssh, which takes IPv6 literals without brackets
+    pub fn ssh_destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    // `[user@]host:path` for rsync, bracketing IPv6 literals so the path
+    // separator can be told apart
+    pub fn rsync_location(&self, path: &str) -> String {
+        let host = if self.is_ipv6() {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match &self.user {
+            Some(user) => format!("{}@{}:{}", user, host, path),
+            None => format!("{}:{}", host, path),
+        }
+    }
+
+    // The same destination with another host, keeping the user and port
+    pub fn with_host(&self, host: &str) -> Self {
+        Self {
+            host: SshHost::parse(host).host,
+            ..self.clone()
+        }
+    }
+}
+
+impl std::fmt::Display for SshHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        match self.port {
+            Some(port) if self.is_ipv6() => write!(f, "[{}]:{}", self.host, port),
+            Some(port) => write!(f, "{}:{}", self.host, port),
+            None => write!(f, "{}", self.host),
+        }
+    }
+}
+
+// SSH port configured through `Port=N` in the ssh options, or the default
+pub fn ssh_port(ssh_options: &[String]) -> u16 {
+    ssh_options
+        .iter()
+        .find_map(|option| {
+            let (key, value) = option.split_once('=')?;
+            key.trim()
+                .eq_ignore_ascii_case("port")
+                .then(|| value.trim().parse().ok())?
+        })
+        .unwrap_or(22)
+}
+
+// Broadcast a wake-on-LAN magic packet for the given MAC address
+pub fn send_magic_packet(mac: &str) -> Result<()> {
+    let bytes: Vec<u8> = mac
+        .split([':', '-'])
+        .map(|part| u8::from_str_radix(part, 16))
+        .collect::<std::result::Result<_, _>>()
+        .ok()
+        .filter(|bytes: &Vec<u8>| bytes.len() == 6)
+        .context(format!("Invalid MAC address '{}'", mac))?;
+
+    // Six 0xFF bytes followed by the MAC address repeated 16 times
+    let mut packet = vec![0xFF; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&bytes);
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0").context("Failed to open UDP socket")?;
+    socket
+        .set_broadcast(true)
+        .context("Failed to enable broadcast")?;
+    socket
+        .send_to(&packet, "255.255.255.255:9")
+        .context("Failed to send wake-on-LAN packet")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_user_and_host() {
+        let host = SshHost::parse("alice@gpu-box");
+        assert_eq!(host.user.as_deref(), Some("alice"));
+        assert_eq!(host.host, "gpu-box");
+        assert_eq!(host.port, None);
+        assert_eq!(host.ssh_destination(), "alice@gpu-box");
+        assert_eq!(host.rsync_location("/srv/app"), "alice@gpu-box:/srv/app");
+    }
+
+    #[test]
+    fn parses_host_with_port() {
+        let host = SshHost::parse("alice@gpu-box:2222");
+        assert_eq!(host.host, "gpu-box");
+        assert_eq!(host.port, Some(2222));
+        assert_eq!(host.ssh_destination(), "alice@gpu-box");
+        assert_eq!(host.rsync_location("app"), "alice@gpu-box:app");
+        assert_eq!(host.to_string(), "alice@gpu-box:2222");
+    }
+
+    #[test]
+    fn parses_bare_ipv6() {
+        let host = SshHost::parse("bob@2001:db8::1");
+        assert_eq!(host.user.as_deref(), Some("bob"));
+        assert_eq!(host.host, "2001:db8::1");
+        assert_eq!(host.port, None);
+        assert_eq!(host.ssh_destination(), "bob@2001:db8::1");
+        assert_eq!(host.rsync_location("/data"), "bob@[2001:db8::1]:/data");
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6_with_port() {
+        let host = SshHost::parse("[fe80::1]:2222");
+        assert_eq!(host.user, None);
+        assert_eq!(host.host, "fe80::1");
+        assert_eq!(host.port, Some(2222));
+        assert_eq!(host.ssh_destination(), "fe80::1");
+        assert_eq!(host.rsync_location("app"), "[fe80::1]:app");
+        assert_eq!(host.to_string(), "[fe80::1]:2222");
+    }
+
+    #[test]
+    fn replaces_host_keeping_user_and_port() {
+        let host = SshHost::parse("carol@desktop:2200").with_host("[::1]");
+        assert_eq!(host.to_string(), "carol@[::1]:2200");
+        assert_eq!(host.rsync_location("x"), "carol@[::1]:x");
+    }
+
+    #[test]
+    fn reads_port_from_ssh_options() {
+        assert_eq!(ssh_port(&["port = 2222".to_string()]), 2222);
+        assert_eq!(ssh_port(&["User=me".to_string()]), 22);
+    }
+}
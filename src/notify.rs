@@ -0,0 +1,198 @@
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use crate::walk::walk_tree;
+
+// Callback telling which directories, relative to the watched root, to watch
+pub type Descend = Box<dyn Fn(&Path) -> bool + Sync>;
+
+// Change notifications for directories below a root, from inotify on Linux.
+// Elsewhere `new` returns None and callers go back to polling.
+pub struct Notifier {
+    #[cfg(target_os = "linux")]
+    fd: std::os::fd::OwnedFd,
+    #[cfg(target_os = "linux")]
+    root: PathBuf,
+    #[cfg(target_os = "linux")]
+    descend: Descend,
+    // Watched directories by watch descriptor, relative to the root
+    #[cfg(target_os = "linux")]
+    dirs: HashMap<i32, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Notifier {
+    const EVENTS: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_ATTRIB
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF;
+
+    pub fn new(root: &Path, descend: Descend) -> Option<Self> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: no pointers are passed; the result is checked before use
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return None;
+        }
+        Some(Self {
+            // SAFETY: fd was just opened and nothing else owns it
+            fd: unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) },
+            root: root.to_path_buf(),
+            descend,
+            dirs: HashMap::new(),
+        })
+    }
+
+    // Watch `dir` and the directories below it that `descend` allows. Fails
+    // once the system's limit on watches is reached.
+    pub fn watch_tree(&mut self, dir: &Path) -> bool {
+        if !self.watch(dir) {
+            return false;
+        }
+        let descend = &self.descend;
+        let below: Vec<PathBuf> = walk_tree(&self.root.join(dir), |path| descend(&dir.join(path)))
+            .into_iter()
+            .filter(|entry| entry.is_dir)
+            .map(|entry| dir.join(entry.path))
+            .filter(|path| descend(path))
+            .collect();
+        below.iter().all(|path| self.watch(path))
+    }
+
+    fn watch(&mut self, dir: &Path) -> bool {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        let Ok(path) = std::ffi::CString::new(self.root.join(dir).as_os_str().as_bytes()) else {
+            return true;
+        };
+        // SAFETY: path is a valid C string for the duration of the call
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                self.fd.as_raw_fd(),
+                path.as_ptr(),
+                Self::EVENTS | libc::IN_ONLYDIR,
+            )
+        };
+        if wd < 0 {
+            // Gone or unreadable directories just aren't watched
+            return std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOSPC);
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        true
+    }
+
+    // Block until something changes in a watched directory or `timeout`
+    // passes, and tell which. Directories created in the meantime are
+    // watched from then on.
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        use std::os::fd::AsRawFd;
+
+        let mut fd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: fd is a single valid pollfd
+        if unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) } <= 0 {
+            return false;
+        }
+
+        let mut new_dirs = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            // SAFETY: reads at most `buf.len()` bytes into `buf`
+            let read =
+                unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if read <= 0 {
+                break;
+            }
+            new_dirs.extend(self.created_dirs(&buf[..read as usize]));
+        }
+        for dir in new_dirs {
+            if (self.descend)(&dir) {
+                self.watch_tree(&dir);
+            }
+        }
+        true
+    }
+
+    // Directories created or moved into watched ones, from a buffer of
+    // `inotify_event`s
+    fn created_dirs(&self, mut events: &[u8]) -> Vec<PathBuf> {
+        const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+        let field = |bytes: &[u8], at: usize| {
+            u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap_or_default())
+        };
+
+        let mut dirs = Vec::new();
+        while events.len() >= HEADER {
+            let wd = field(events, 0) as i32;
+            let mask = field(events, 4);
+            let len = field(events, 12) as usize;
+            let name = &events[HEADER..(HEADER + len).min(events.len())];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                if let Some(parent) = self.dirs.get(&wd) {
+                    use std::os::unix::ffi::OsStrExt;
+                    dirs.push(parent.join(std::ffi::OsStr::from_bytes(name)));
+                }
+            }
+            events = &events[(HEADER + len).min(events.len())..];
+        }
+        dirs
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Notifier {
+    pub fn new(_root: &Path, _descend: Descend) -> Option<Self> {
+        None
+    }
+
+    pub fn watch_tree(&mut self, _dir: &Path) -> bool {
+        false
+    }
+
+    pub fn wait(&mut self, _timeout: Duration) -> bool {
+        false
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn changes_in_new_directories_are_seen() {
+        let root = std::env::temp_dir().join(format!("sync-rs-notify-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        let descend = |path: &Path| path != Path::new("target");
+        let mut notifier = Notifier::new(&root, Box::new(descend)).unwrap();
+        assert!(notifier.watch_tree(Path::new("")));
+        let quiet = Duration::from_millis(50);
+        assert!(!notifier.wait(quiet));
+
+        fs::create_dir(root.join("src/new")).unwrap();
+        assert!(notifier.wait(quiet));
+        fs::write(root.join("src/new/lib.rs"), "").unwrap();
+        let seen_new = notifier.wait(quiet);
+        fs::write(root.join("target/out"), "").unwrap();
+        let seen_skipped = notifier.wait(quiet);
+        fs::remove_dir_all(&root).unwrap();
+        assert!(seen_new);
+        assert!(!seen_skipped);
+    }
+}
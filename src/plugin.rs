@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+
+// Executables named `sync-rs-<name>` on PATH are plugins. They can be used
+// as extra subcommands (`sync-rs <name> ARGS...`), as hooks listed on a
+// remote (`<plugin> hook pre-sync|post-sync`, with the sync context in
+// SYNC_RS_* environment variables) and as remote types
+// (`plugin:<name>:TARGET`, reached through `<plugin> rsh TARGET COMMAND...`).
+pub const PLUGIN_PREFIX: &str = "sync-rs-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    PreSync,
+    PostSync,
+}
+
+impl HookStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PreSync => "pre-sync",
+            Self::PostSync => "post-sync",
+        }
+    }
+}
+
+// What a hook gets to know about the sync
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    pub local_dir: String,
+    pub remote_name: String,
+    pub remote_host: String,
+    pub remote_dir: String,
+    // Files (with sizes) the sync is about to send, when they were looked up
+    pub changed_files: Option<Vec<(String, u64)>>,
+}
+
+// A hook step registered from Rust code using the library. A failing
+// pre-sync hook stops the sync.
+pub trait Hook: Send + Sync {
+    fn name(&self) -> &str;
+    fn run(&self, stage: HookStage, context: &HookContext) -> Result<()>;
+}
+
+static HOOKS: Mutex<Vec<Box<dyn Hook>>> = Mutex::new(Vec::new());
+
+pub fn register_hook(hook: Box<dyn Hook>) {
+    HOOKS.lock().unwrap().push(hook);
+}
+
+// Run registered hooks, then the executable plugins named in `plugins`
+pub fn run_hooks(stage: HookStage, context: &HookContext, plugins: &[String]) -> Result<()> {
+    for hook in HOOKS.lock().unwrap().iter() {
+        hook.run(stage, context).context(format!(
+            "Hook '{}' failed ({})",
+            hook.name(),
+            stage.as_str()
+        ))?;
+    }
+
+    for name in plugins {
+        let path = find_plugin(name).context(format!(
+            "Plugin '{}' not found (no {}{} on PATH)",
+            name, PLUGIN_PREFIX, name
+        ))?;
+        let status = Command::new(path)
+            .args(["hook", stage.as_str()])
+            .env("SYNC_RS_STAGE", stage.as_str())
+            .env("SYNC_RS_LOCAL_DIR", &context.local_dir)
+            .env("SYNC_RS_REMOTE", &context.remote_name)
+            .env("SYNC_RS_REMOTE_HOST", &context.remote_host)
+            .env("SYNC_RS_REMOTE_DIR", &context.remote_dir)
+            .status()
+            .context(format!("Failed to execute plugin '{}'", name))?;
+
+        if !status.success() {
+            anyhow::bail!(
+                "Plugin '{}' failed ({}) with exit code: {:?}",
+                name,
+                stage.as_str(),
+                status.code()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Path of the `sync-rs-<name>` executable on PATH
+pub fn find_plugin(name: &str) -> Option<PathBuf> {
+    let file_name = format!("{}{}", PLUGIN_PREFIX, name);
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(&file_name))
+        .find(|path| is_executable(path))
+}
+
+// Names of all plugins on PATH
+pub fn list_plugins() -> Vec<String> {
+    let Some(path) = env::var_os("PATH") else {
+        return Vec::new();
+    };
+
+    let mut names = BTreeSet::new();
+    for dir in env::split_paths(&path) {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if let Some(name) = file_name.strip_prefix(PLUGIN_PREFIX) {
+                if !name.is_empty() && is_executable(&entry.path()) {
+                    names.insert(name.to_string());
+                }
+            }
+        }
+    }
+
+    names.into_iter().collect()
+}
+
+fn is_executable(path: &std::path::Path) -> bool {
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::config::RemoteEntry;
+use crate::glob::glob_match;
+use crate::net::SshHost;
+use crate::remote::RemoteTarget;
+use crate::toml;
+
+// Org-wide guardrails set by an administrator. They apply to every sync on
+// the machine and can't be turned off per remote.
+pub const POLICY_PATH: &str = "/etc/sync-rs/policy.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    // Host patterns (`*.prod.example.com`) or `HOST:DIR` patterns that must
+    // never be synced to
+    pub forbidden_destinations: Vec<String>,
+    // Patterns excluded from every sync, e.g. `.env`
+    pub required_ignores: Vec<String>,
+    // Most files a single sync may delete on the remote
+    pub max_deletes: Option<usize>,
+    // Hosts that may only be synced to with protected paths
+    pub protected: Vec<ProtectedRule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtectedRule {
+    pub hosts: Vec<String>,
+    // Paths always protected on these hosts. Without any, the remote must
+    // have protected paths of its own.
+    pub paths: Vec<String>,
+}
+
+// The machine's policy, if there is one
+pub fn load_policy() -> Result<Option<Policy>> {
+    let path = Path::new(POLICY_PATH);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let data = fs::read_to_string(path).context(format!("Failed to read {}", POLICY_PATH))?;
+    let value = toml::parse(&data).context(format!("Failed to parse {}", POLICY_PATH))?;
+    let policy =
+        serde_json::from_value(value).context(format!("Invalid policy in {}", POLICY_PATH))?;
+    Ok(Some(policy))
+}
+
+impl Policy {
+    // The entry as the policy allows it to be synced: required ignores and
+    // protected paths added and the delete cap lowered. Fails if the
+    // destination is forbidden or needs protected paths it doesn't have.
+    pub fn apply(&self, entry: &RemoteEntry) -> Result<RemoteEntry> {
+        self.apply_to_hosts(entry, &[])
+    }
+
+    // The same for a sync to `target`, which the entry's host resolved to.
+    // Host commands, Tailscale lookups and relays pick the machine that's
+    // actually reached, so the rules hold for it and for any relay too.
+    pub fn apply_to_target(
+        &self,
+        entry: &RemoteEntry,
+        target: &RemoteTarget,
+    ) -> Result<RemoteEntry> {
+        let mut resolved = vec![target.host()];
+        if let RemoteTarget::Relay { relay, .. } = target {
+            resolved.push(relay);
+        }
+        self.apply_to_hosts(entry, &resolved)
+    }
+
+    fn apply_to_hosts(&self, entry: &RemoteEntry, resolved: &[&str]) -> Result<RemoteEntry> {
+        let hosts: Vec<&str> = std::iter::once(entry.remote_host.as_str())
+            .chain(entry.alternate_hosts.iter().map(String::as_str))
+            .chain(resolved.iter().copied())
+            .collect();
+        let any_host_matches = |pattern: &str| hosts.iter().any(|host| host_matches(pattern, host));
+
+        for pattern in &self.forbidden_destinations {
+            let forbidden = hosts.iter().find(|host| {
+                host_matches(pattern, host)
+                    || glob_match(pattern, &format!("{}:{}", host, entry.remote_dir))
+            });
+            if let Some(host) = forbidden {
+                anyhow::bail!(
+                    "Syncing to {}:{} is forbidden by {} (matches '{}')",
+                    host,
+                    entry.remote_dir,
+                    POLICY_PATH,
+                    pattern
+                );
+            }
+        }
+
+        let mut entry = entry.clone();
+
+        for pattern in &self.required_ignores {
+            if !entry.ignore_patterns.contains(pattern) {
+                entry.ignore_patterns.push(pattern.clone());
+            }
+        }
+
+        for rule in &self.protected {
+            if !rule.hosts.iter().any(|pattern| any_host_matches(pattern)) {
+                continue;
+            }
+            if rule.paths.is_empty() && entry.protected_paths.is_empty() {
+                anyhow::bail!(
+                    "{} requires protected paths for {} (set them with --protect)",
+                    POLICY_PATH,
+                    entry.remote_host
+                );
+            }
+            for path in &rule.paths {
+                if !entry.protected_paths.contains(path) {
+                    entry.protected_paths.push(path.clone());
+                }
+            }
+        }
+
+        if let Some(max) = self.max_deletes {
+            entry.max_deletes = Some(entry.max_deletes.map_or(max, |own| own.min(max)));
+        }
+
+        Ok(entry)
+    }
+}
+
+// Host patterns match the host with or without its `user@` and `:port` parts
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let bare = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    glob_match(pattern, host)
+        || glob_match(pattern, bare)
+        || glob_match(pattern, &SshHost::parse(host).host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(host: &str) -> RemoteEntry {
+        RemoteEntry {
+            name: "box".to_string(),
+            remote_host: host.to_string(),
+            remote_dir: "app".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn policy(toml: &str) -> Policy {
+        serde_json::from_value(toml::parse(toml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn hosts_match_with_or_without_user_and_port() {
+        assert!(host_matches("*.prod.example.com", "db.prod.example.com"));
+        assert!(host_matches("*.prod.example.com", "me@db.prod.example.com"));
+        assert!(host_matches(
+            "*.prod.example.com",
+            "me@db.prod.example.com:2222"
+        ));
+        assert!(host_matches("me@db*", "me@db1"));
+        assert!(!host_matches(
+            "*.prod.example.com",
+            "db.staging.example.com"
+        ));
+        assert!(!host_matches("db", "db2"));
+    }
+
+    #[test]
+    fn forbidden_destinations_are_refused() {
+        let policy = policy(
+            r#"
+forbidden_destinations = ["*.prod.example.com", "build-*:/srv/*"]
+"#,
+        );
+        assert!(policy.apply(&entry("me@dev.example.com")).is_ok());
+        assert!(policy.apply(&entry("me@db.prod.example.com")).is_err());
+
+        let mut alternate = entry("me@10.0.0.3");
+        alternate.alternate_hosts = vec!["db.prod.example.com".to_string()];
+        assert!(policy.apply(&alternate).is_err());
+
+        let mut srv = entry("build-1");
+        assert!(policy.apply(&srv).is_ok());
+        srv.remote_dir = "/srv/app".to_string();
+        let err = policy.apply(&srv).unwrap_err().to_string();
+        assert!(err.contains("build-1:/srv/app"), "{}", err);
+    }
+
+    #[test]
+    fn entries_get_required_ignores_protection_and_delete_caps() {
+        let policy = policy(
+            r#"
+required_ignores = [".env"]
+max_deletes = 100
+
+[[protected]]
+hosts = ["gpu-*"]
+paths = ["checkpoints/"]
+
+[[protected]]
+hosts = ["*.shared.example.com"]
+"#,
+        );
+
+        let mut own = entry("gpu-1");
+        own.ignore_patterns = vec![".env".to_string()];
+        own.max_deletes = Some(500);
+        let applied = policy.apply(&own).unwrap();
+        assert_eq!(applied.ignore_patterns, [".env"]);
+        assert_eq!(applied.protected_paths, ["checkpoints/"]);
+        assert_eq!(applied.max_deletes, Some(100));
+
+        let applied = policy.apply(&entry("laptop")).unwrap();
+        assert!(applied.protected_paths.is_empty());
+        assert_eq!(applied.max_deletes, Some(100));
+
+        let mut lower = entry("laptop");
+        lower.max_deletes = Some(10);
+        assert_eq!(policy.apply(&lower).unwrap().max_deletes, Some(10));
+
+        // Hosts covered by a rule without paths need protected paths of their own
+        let mut shared = entry("ci.shared.example.com");
+        assert!(policy.apply(&shared).is_err());
+        shared.protected_paths = vec!["results/".to_string()];
+        assert!(policy.apply(&shared).is_ok());
+    }
+
+    #[test]
+    fn rules_hold_for_the_resolved_target() {
+        let policy = policy(
+            r#"
+forbidden_destinations = ["*.prod.example.com"]
+
+[[protected]]
+hosts = ["gpu-*"]
+paths = ["checkpoints/"]
+"#,
+        );
+        // A host command or Tailscale lookup resolving to another machine
+        let entry = entry("devbox");
+        let resolved = RemoteTarget::parse("me@db.prod.example.com");
+        assert!(policy.apply(&entry).is_ok());
+        assert!(policy.apply_to_target(&entry, &resolved).is_err());
+
+        let gpu = RemoteTarget::parse("gpu-7:2222");
+        let applied = policy.apply_to_target(&entry, &gpu).unwrap();
+        assert_eq!(applied.protected_paths, ["checkpoints/"]);
+
+        // The relay hop counts as well
+        let relayed = RemoteTarget::Relay {
+            relay: "jump.prod.example.com".to_string(),
+            host: "inner".to_string(),
+            options: Vec::new(),
+        };
+        assert!(policy.apply_to_target(&entry, &relayed).is_err());
+    }
+
+    #[test]
+    fn unknown_policy_keys_are_refused() {
+        let value = toml::parse("max_delete = 5").unwrap();
+        assert!(serde_json::from_value::<Policy>(value).is_err());
+    }
+}
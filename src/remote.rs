@@ -0,0 +1,625 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+use crate::config::RemoteEntry;
+use crate::net::SshHost;
+use crate::plugin::PLUGIN_PREFIX;
+use crate::resolve::resolve_remote_host;
+use crate::settings::settings;
+use crate::sync::RsyncOptions;
+
+// Where a remote entry points to, parsed from its `remote_host`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RemoteTarget {
+    // Plain SSH destination (`user@host` or an ssh config alias), with extra
+    // `-o KEY=VALUE` options such as Port or IdentityFile
+    Ssh {
+        host: String,
+        options: Vec<String>,
+    },
+    // Running container, reached through the container engine's exec.
+    // `container:NAME`, `docker:NAME` or `podman:NAME`, optionally `@HOST`
+    // for an engine on another machine reached over SSH.
+    Container {
+        engine: String,
+        container: String,
+        engine_host: Option<String>,
+    },
+    // Pod reached through `kubectl exec`:
+    // `k8s:[[CONTEXT/]NAMESPACE/]POD`, where POD may be a label selector
+    // such as `app=trainer` that is resolved to a running pod
+    Kubernetes {
+        context: Option<String>,
+        namespace: Option<String>,
+        pod: String,
+    },
+    // Remote type provided by a plugin: `plugin:NAME:TARGET`, reached through
+    // `sync-rs-NAME rsh [-t] TARGET COMMAND...`, which works like ssh
+    Plugin {
+        plugin: String,
+        target: String,
+    },
+    // Host reached through a connection command template such as
+    // `tsh ssh {host}`, which works like ssh: the remote command is appended.
+    // Without `{host}`, the host is passed as the first argument.
+    Command {
+        template: String,
+        host: String,
+    },
+    // SSH host only reachable from another machine, the relay, which commands
+    // go through. Files can't be sent straight to it, so they're staged on the
+    // relay and forwarded from there.
+    Relay {
+        relay: String,
+        host: String,
+        options: Vec<String>,
+    },
+}
+
+impl RemoteTarget {
+    pub fn parse(remote_host: &str) -> Self {
+        for (prefix, engine) in [
+            ("container:", "docker"),
+            ("docker:", "docker"),
+            ("podman:", "podman"),
+        ] {
+            if let Some(rest) = remote_host.strip_prefix(prefix) {
+                let (container, engine_host) = match rest.split_once('@') {
+                    Some((container, host)) => (container, Some(host.to_string())),
+                    None => (rest, None),
+                };
+                return Self::Container {
+                    engine: engine.to_string(),
+                    container: container.to_string(),
+                    engine_host,
+                };
+            }
+        }
+
+        if let Some((plugin, target)) = remote_host
+            .strip_prefix("plugin:")
+            .and_then(|rest| rest.split_once(':'))
+        {
+            return Self::Plugin {
+                plugin: plugin.to_string(),
+                target: target.to_string(),
+            };
+        }
+
+        if let Some(rest) = remote_host.strip_prefix("k8s:") {
+            let mut parts: Vec<&str> = rest.splitn(3, '/').collect();
+            let pod = parts.pop().unwrap_or_default().to_string();
+            let namespace = parts.pop().map(str::to_string);
+            let context = parts.pop().map(str::to_string);
+            return Self::Kubernetes {
+                context,
+                namespace,
+                pod,
+            };
+        }
+
+        Self::Ssh {
+            host: remote_host.to_string(),
+            options: Vec::new(),
+        }
+    }
+
+    // Target for an entry, including its connection settings and anything
+    // that has to be looked up before connecting
+    pub fn from_entry(entry: &RemoteEntry) -> Result<Self> {
+        let target = match Self::parse(&resolve_remote_host(entry)?) {
+            Self::Ssh { host, .. } if entry.relay.is_some() => Self::Relay {
+                relay: entry.relay.clone().unwrap_or_default(),
+                host,
+                options: entry.ssh_options.clone(),
+            },
+            _ if entry.relay.is_some() => {
+                anyhow::bail!("Only SSH hosts can be reached through a relay")
+            }
+            Self::Ssh { host, .. } => match &entry.connect_command {
+                Some(template) => Self::Command {
+                    template: template.clone(),
+                    host,
+                },
+                None => Self::Ssh {
+                    host,
+                    options: entry.ssh_options.clone(),
+                },
+            },
+            target => target,
+        };
+        target.resolve()
+    }
+
+    // Resolve a pod label selector to a running pod
+    fn resolve(self) -> Result<Self> {
+        match self {
+            Self::Kubernetes {
+                context,
+                namespace,
+                pod,
+            } if pod.contains('=') => {
+                let output = kubectl_command(&context, &namespace)
+                    .args(["get", "pods", "-l", &pod])
+                    .args(["--field-selector=status.phase=Running"])
+                    .args(["-o", "jsonpath={.items[0].metadata.name}"])
+                    .output()
+                    .context("Failed to execute kubectl")?;
+
+                if !output.status.success() {
+                    anyhow::bail!(
+                        "Failed to look up pods for '{}': {}",
+                        pod,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+
+                let name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                if name.is_empty() {
+                    anyhow::bail!("No running pod matches selector '{}'", pod);
+                }
+
+                Ok(Self::Kubernetes {
+                    context,
+                    namespace,
+                    pod: name,
+                })
+            }
+            target => Ok(target),
+        }
+    }
+
+    // Command running `remote_command` through a shell on the remote
+    pub fn command(&self, remote_command: &str) -> Command {
+        self.build_command(remote_command, false)
+    }
+
+    // `sftp` reading batch commands from stdin, for SSH remotes
+    pub fn sftp_command(&self) -> Option<Command> {
+        let Self::Ssh { host, options } = self else {
+            return None;
+        };
+        let ssh = &settings().ssh;
+        let mut cmd = Command::new("sftp");
+        if let Some(binary) = &ssh.binary {
+            cmd.args(["-S", binary]);
+        }
+        let destination = SshHost::parse(host);
+        if let Some(port) = destination.port {
+            cmd.args(["-P", &port.to_string()]);
+        }
+        for option in options.iter().chain(&ssh.options) {
+            cmd.args(["-o", option]);
+        }
+        // Brackets keep an IPv6 address from being read as `host:path`
+        let host = if destination.is_ipv6() {
+            format!("[{}]", destination.host)
+        } else {
+            destination.host.clone()
+        };
+        cmd.args(["-b", "-"]);
+        cmd.arg(match &destination.user {
+            Some(user) => format!("{}@{}", user, host),
+            None => host,
+        });
+        Some(cmd)
+    }
+
+    // Like `command`, but with a terminal attached for interactive use
+    pub fn interactive_command(&self, remote_command: &str) -> Command {
+        self.build_command(remote_command, true)
+    }
+
+    fn build_command(&self, remote_command: &str, interactive: bool) -> Command {
+        match self {
+            Self::Ssh { host, options } => {
+                let ssh = &settings().ssh;
+                let mut cmd = Command::new(ssh.binary());
+                if interactive {
+                    cmd.arg("-t"); // Force pseudo-terminal allocation for interactive shell
+                }
+                let destination = SshHost::parse(host);
+                if let Some(port) = destination.port {
+                    cmd.args(["-p", &port.to_string()]);
+                }
+                for option in options.iter().chain(&ssh.options) {
+                    cmd.args(["-o", option]);
+                }
+                cmd.arg(destination.ssh_destination()).arg(remote_command);
+                cmd
+            }
+            Self::Container {
+                engine,
+                container,
+                engine_host,
+            } => {
+                let mut cmd = Command::new(engine);
+                if let Some(host) = engine_host {
+                    cmd.args(["-H", &format!("ssh://{}", host)]);
+                }
+                cmd.arg("exec");
+                cmd.arg(if interactive { "-it" } else { "-i" });
+                cmd.args([container, "sh", "-c", remote_command]);
+                cmd
+            }
+            Self::Kubernetes {
+                context,
+                namespace,
+                pod,
+            } => {
+                let mut cmd = kubectl_command(context, namespace);
+                cmd.arg("exec");
+                cmd.arg(if interactive { "-it" } else { "-i" });
+                cmd.args([pod, "--", "sh", "-c", remote_command]);
+                cmd
+            }
+            Self::Plugin { plugin, target } => {
+                let mut cmd = Command::new(format!("{}{}", PLUGIN_PREFIX, plugin));
+                cmd.arg("rsh");
+                if interactive {
+                    cmd.arg("-t");
+                }
+                cmd.args([target, remote_command]);
+                cmd
+            }
+            Self::Command { template, host } => {
+                let mut cmd = Command::new("sh");
+                cmd.args(["-c", &connect_script(template), host, remote_command]);
+                cmd
+            }
+            Self::Relay {
+                relay,
+                host,
+                options,
+            } => {
+                // ssh on the relay, as a command line for the relay's shell
+                let mut ssh = String::from("ssh");
+                if interactive {
+                    ssh.push_str(" -t");
+                }
+                let destination = SshHost::parse(host);
+                if let Some(port) = destination.port {
+                    ssh.push_str(&format!(" -p {}", port));
+                }
+                for option in options {
+                    ssh.push_str(&format!(" -o {}", shell_quote(option)));
+                }
+                ssh.push_str(&format!(
+                    " {} {}",
+                    shell_quote(&destination.ssh_destination()),
+                    shell_quote(remote_command)
+                ));
+                Self::parse(relay).build_command(&ssh, interactive)
+            }
+        }
+    }
+
+    // The host, container, pod or plugin target, for messages
+    pub fn host(&self) -> &str {
+        match self {
+            Self::Ssh { host, .. } | Self::Command { host, .. } | Self::Relay { host, .. } => host,
+            Self::Container { container, .. } => container,
+            Self::Kubernetes { pod, .. } => pod,
+            Self::Plugin { target, .. } => target,
+        }
+    }
+
+    // Location of `path` on this remote in rsync's `host:path` syntax
+    pub fn rsync_location(&self, path: &str) -> String {
+        match self {
+            Self::Ssh { host, .. } => SshHost::parse(host).rsync_location(path),
+            Self::Container { container, .. } => format!("{}:{}", container, path),
+            Self::Kubernetes { pod, .. } => format!("{}:{}", pod, path),
+            Self::Plugin { target, .. } => format!("{}:{}", target, path),
+            Self::Command { host, .. } => SshHost::parse(host).rsync_location(path),
+            // As seen from the relay
+            Self::Relay { host, .. } => SshHost::parse(host).rsync_location(path),
+        }
+    }
+
+    // Base rsync options needed to reach this remote
+    pub fn rsync_options(&self) -> RsyncOptions {
+        match self {
+            Self::Ssh { host, options } => {
+                let ssh = &settings().ssh;
+                let port = SshHost::parse(host).port;
+                if options.is_empty()
+                    && port.is_none()
+                    && ssh.binary.is_none()
+                    && ssh.options.is_empty()
+                {
+                    return RsyncOptions::default();
+                }
+
+                let mut rsh = rsh_quote(ssh.binary());
+                if let Some(port) = port {
+                    rsh.push_str(&format!(" -p {}", port));
+                }
+                for option in options.iter().chain(&ssh.options) {
+                    rsh.push_str(&format!(" -o {}", rsh_quote(option)));
+                }
+                RsyncOptions {
+                    rsh: Some(rsh),
+                    ..Default::default()
+                }
+            }
+            Self::Container {
+                engine,
+                engine_host,
+                ..
+            } => {
+                let mut rsh = engine.clone();
+                if let Some(host) = engine_host {
+                    rsh.push_str(&format!(" -H ssh://{}", host));
+                }
+                rsh.push_str(" exec -i");
+
+                RsyncOptions {
+                    rsh: Some(rsh),
+                    // The exec stream doesn't support rsync's non-blocking I/O
+                    extra_args: vec!["--blocking-io".to_string()],
+                    ..Default::default()
+                }
+            }
+            Self::Kubernetes {
+                context, namespace, ..
+            } => {
+                // kubectl needs `--` between the pod and the command, which rsync
+                // can't produce itself, so wrap it in a shell taking the pod as $0
+                let mut kubectl = String::from("kubectl");
+                if let Some(context) = context {
+                    kubectl.push_str(&format!(" --context {}", shell_quote(context)));
+                }
+                if let Some(namespace) = namespace {
+                    kubectl.push_str(&format!(" -n {}", shell_quote(namespace)));
+                }
+                let script = format!("exec {} exec -i \"$0\" -- \"$@\"", kubectl);
+
+                RsyncOptions {
+                    rsh: Some(format!("sh -c {}", rsh_quote(&script))),
+                    extra_args: vec!["--blocking-io".to_string()],
+                    ..Default::default()
+                }
+            }
+            // rsync calls the shell with the host and its command, like ssh
+            Self::Command { template, .. } => RsyncOptions {
+                rsh: Some(format!("sh -c {}", rsh_quote(&connect_script(template)))),
+                ..Default::default()
+            },
+            Self::Plugin { plugin, .. } => RsyncOptions {
+                rsh: Some(format!("{}{} rsh", PLUGIN_PREFIX, plugin)),
+                ..Default::default()
+            },
+            // For rsync running on the relay, with the relay's own ssh
+            Self::Relay { host, options, .. } => {
+                let mut rsh = String::from("ssh");
+                if let Some(port) = SshHost::parse(host).port {
+                    rsh.push_str(&format!(" -p {}", port));
+                }
+                for option in options {
+                    rsh.push_str(&format!(" -o {}", rsh_quote(option)));
+                }
+                RsyncOptions {
+                    rsh: (rsh != "ssh").then_some(rsh),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+}
+
+fn kubectl_command(context: &Option<String>, namespace: &Option<String>) -> Command {
+    let mut cmd = Command::new("kubectl");
+    if let Some(context) = context {
+        cmd.args(["--context", context]);
+    }
+    if let Some(namespace) = namespace {
+        cmd.args(["-n", namespace]);
+    }
+    cmd
+}
+
+// Shell script running a connection command template, taking the host as $0
+// and the remote command as the remaining arguments
+fn connect_script(template: &str) -> String {
+    if template.contains("{host}") {
+        format!("exec {} \"$@\"", template.replace("{host}", "\"$0\""))
+    } else {
+        format!("exec {} \"$0\" \"$@\"", template)
+    }
+}
+
+// Whether `s` is a single word for a shell or rsync's `-e` as it is
+fn plain_word(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-+=:@,%".contains(c))
+}
+
+// Quote a string for safe use as a single word in a POSIX shell command
+pub fn shell_quote(s: &str) -> String {
+    if plain_word(s) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Quote a string as a single word of rsync's `-e` command. rsync splits it
+// into words itself: quotes group, and a doubled quote inside them stands
+// for the quote, so `shell_quote`'s `'\''` would come apart there.
+pub fn rsh_quote(s: &str) -> String {
+    if plain_word(s) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rsh(target: &RemoteTarget) -> Option<String> {
+        target.rsync_options().rsh
+    }
+
+    #[test]
+    fn parses_every_kind_of_target() {
+        assert_eq!(
+            RemoteTarget::parse("me@box:2222"),
+            RemoteTarget::Ssh {
+                host: "me@box:2222".to_string(),
+                options: Vec::new(),
+            }
+        );
+        assert_eq!(
+            RemoteTarget::parse("podman:web@builder"),
+            RemoteTarget::Container {
+                engine: "podman".to_string(),
+                container: "web".to_string(),
+                engine_host: Some("builder".to_string()),
+            }
+        );
+        assert_eq!(
+            RemoteTarget::parse("container:web"),
+            RemoteTarget::Container {
+                engine: "docker".to_string(),
+                container: "web".to_string(),
+                engine_host: None,
+            }
+        );
+        assert_eq!(
+            RemoteTarget::parse("k8s:prod/ml/app=trainer"),
+            RemoteTarget::Kubernetes {
+                context: Some("prod".to_string()),
+                namespace: Some("ml".to_string()),
+                pod: "app=trainer".to_string(),
+            }
+        );
+        assert_eq!(
+            RemoteTarget::parse("k8s:ml/trainer-0"),
+            RemoteTarget::Kubernetes {
+                context: None,
+                namespace: Some("ml".to_string()),
+                pod: "trainer-0".to_string(),
+            }
+        );
+        assert_eq!(
+            RemoteTarget::parse("plugin:vast:1234"),
+            RemoteTarget::Plugin {
+                plugin: "vast".to_string(),
+                target: "1234".to_string(),
+            }
+        );
+        // A plugin needs a target after its name
+        assert!(matches!(
+            RemoteTarget::parse("plugin:vast"),
+            RemoteTarget::Ssh { .. }
+        ));
+    }
+
+    #[test]
+    fn locations_use_rsync_syntax() {
+        let cases = [
+            (RemoteTarget::parse("me@box:2222"), "me@box:/srv/app"),
+            (
+                RemoteTarget::parse("me@2001:db8::1"),
+                "me@[2001:db8::1]:/srv/app",
+            ),
+            (RemoteTarget::parse("docker:web@builder"), "web:/srv/app"),
+            (
+                RemoteTarget::parse("k8s:ml/trainer-0"),
+                "trainer-0:/srv/app",
+            ),
+            (RemoteTarget::parse("plugin:vast:1234"), "1234:/srv/app"),
+            (
+                RemoteTarget::Command {
+                    template: "tsh ssh".to_string(),
+                    host: "me@node".to_string(),
+                },
+                "me@node:/srv/app",
+            ),
+            (
+                RemoteTarget::Relay {
+                    relay: "bastion".to_string(),
+                    host: "me@inner:2200".to_string(),
+                    options: Vec::new(),
+                },
+                "me@inner:/srv/app",
+            ),
+        ];
+        for (target, location) in cases {
+            assert_eq!(target.rsync_location("/srv/app"), location);
+        }
+    }
+
+    #[test]
+    fn ssh_options_are_quoted_for_rsync() {
+        let target = RemoteTarget::Ssh {
+            host: "me@box:2222".to_string(),
+            options: vec![
+                "IdentityFile=/keys/it's mine".to_string(),
+                "User=me".to_string(),
+            ],
+        };
+        let ssh = rsh(&target).unwrap();
+        assert!(
+            ssh.contains(" -p 2222 -o 'IdentityFile=/keys/it''s mine' -o User=me"),
+            "{}",
+            ssh
+        );
+
+        let relay = RemoteTarget::Relay {
+            relay: "bastion".to_string(),
+            host: "me@inner:2200".to_string(),
+            options: vec!["ProxyCommand=nc %h %p".to_string()],
+        };
+        assert_eq!(
+            rsh(&relay).unwrap(),
+            "ssh -p 2200 -o 'ProxyCommand=nc %h %p'"
+        );
+        let bare_relay = RemoteTarget::Relay {
+            relay: "bastion".to_string(),
+            host: "inner".to_string(),
+            options: Vec::new(),
+        };
+        assert_eq!(rsh(&bare_relay), None);
+    }
+
+    #[test]
+    fn other_targets_get_their_own_shell() {
+        let container = RemoteTarget::parse("docker:web@builder").rsync_options();
+        assert_eq!(
+            container.rsh.as_deref(),
+            Some("docker -H ssh://builder exec -i")
+        );
+        assert_eq!(container.extra_args, ["--blocking-io"]);
+
+        assert_eq!(
+            rsh(&RemoteTarget::parse("k8s:my ctx/ml/trainer-0")).unwrap(),
+            r#"sh -c 'exec kubectl --context ''my ctx'' -n ml exec -i "$0" -- "$@"'"#
+        );
+        assert_eq!(
+            rsh(&RemoteTarget::Command {
+                template: "tsh ssh {host}".to_string(),
+                host: "node".to_string(),
+            })
+            .unwrap(),
+            r#"sh -c 'exec tsh ssh "$0" "$@"'"#
+        );
+        assert_eq!(
+            rsh(&RemoteTarget::parse("plugin:vast:1234")).unwrap(),
+            "sync-rs-vast rsh"
+        );
+    }
+
+    #[test]
+    fn quoting_follows_each_parser() {
+        assert_eq!(shell_quote("plain/path-1.0"), "plain/path-1.0");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(rsh_quote("plain/path-1.0"), "plain/path-1.0");
+        assert_eq!(rsh_quote("a b"), "'a b'");
+        assert_eq!(rsh_quote("it's"), "'it''s'");
+    }
+}
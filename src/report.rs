@@ -0,0 +1,146 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::ffi::CStr;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::config::RemoteEntry;
+
+// Where sync reports are mailed, from the `[report]` section of the settings
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportSettings {
+    pub to: Vec<String>,
+    // Defaults to sync-rs@HOSTNAME
+    pub from: Option<String>,
+    // sendmail-compatible program, used unless an SMTP server is set
+    pub sendmail: Option<String>,
+    // `smtp://` or `smtps://` URL of a server to send through with curl
+    pub smtp_url: Option<String>,
+    // Login for the SMTP server. The password comes from SYNC_RS_SMTP_PASSWORD
+    // so that it stays out of the settings file.
+    pub smtp_user: Option<String>,
+}
+
+// The outcome of one sync, as mailed
+pub struct MailReport<'a> {
+    pub dir: &'a str,
+    pub remote: &'a RemoteEntry,
+    pub duration: Duration,
+    pub error: Option<String>,
+    // Files sent and deleted, when rsync logged them
+    pub transferred: Option<usize>,
+    pub deleted: Option<usize>,
+}
+
+impl MailReport<'_> {
+    fn subject(&self) -> String {
+        format!(
+            "[sync-rs] {} {} -> {}",
+            if self.error.is_some() { "FAILED" } else { "OK" },
+            self.dir,
+            self.remote.name
+        )
+    }
+
+    fn body(&self) -> String {
+        let mut body = format!(
+            "Directory: {}\nRemote: {} ({}:{})\nResult: {}\nDuration: {:.1}s\n",
+            self.dir,
+            self.remote.name,
+            self.remote.remote_host,
+            self.remote.remote_dir,
+            if self.error.is_some() {
+                "failed"
+            } else {
+                "succeeded"
+            },
+            self.duration.as_secs_f64()
+        );
+        if let Some(transferred) = self.transferred {
+            body.push_str(&format!("Files transferred: {}\n", transferred));
+        }
+        if let Some(deleted) = self.deleted {
+            body.push_str(&format!("Files deleted: {}\n", deleted));
+        }
+        if let Some(error) = &self.error {
+            body.push_str(&format!("\nError:\n{}\n", error));
+        }
+        body
+    }
+}
+
+pub fn send_report(settings: &ReportSettings, report: &MailReport) -> Result<()> {
+    if settings.to.is_empty() {
+        anyhow::bail!("No report recipients; set `to` in the [report] settings");
+    }
+
+    let from = settings
+        .from
+        .clone()
+        .unwrap_or_else(|| format!("sync-rs@{}", hostname()));
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+        from,
+        settings.to.join(", "),
+        report.subject(),
+        report.body().replace('\n', "\r\n")
+    );
+
+    let mut cmd = match &settings.smtp_url {
+        Some(url) => {
+            let mut cmd = Command::new("curl");
+            cmd.args(["--silent", "--show-error", "--url", url]);
+            // Upgrade plain connections with STARTTLS where the server offers it
+            if url.starts_with("smtp://") {
+                cmd.arg("--ssl");
+            }
+            cmd.args(["--mail-from", &from]);
+            for to in &settings.to {
+                cmd.args(["--mail-rcpt", to]);
+            }
+            if let Some(user) = &settings.smtp_user {
+                let password = std::env::var("SYNC_RS_SMTP_PASSWORD").unwrap_or_default();
+                cmd.args(["--user", &format!("{}:{}", user, password)]);
+            }
+            cmd.args(["--upload-file", "-"]);
+            cmd
+        }
+        None => {
+            let mut cmd = Command::new(settings.sendmail.as_deref().unwrap_or("sendmail"));
+            // Take the recipients from the headers
+            cmd.arg("-t");
+            cmd
+        }
+    };
+
+    let program = cmd.get_program().to_string_lossy().to_string();
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run {} to send the report", program))?;
+    child
+        .stdin
+        .take()
+        .context("Failed to open mailer stdin")?
+        .write_all(message.as_bytes())
+        .context("Failed to pass the report to the mailer")?;
+
+    let status = child.wait().context("Failed to wait for the mailer")?;
+    if !status.success() {
+        anyhow::bail!("{} failed to send the report: {}", program, status);
+    }
+    Ok(())
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: buf is writable for its full length, which is what we pass
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    CStr::from_bytes_until_nul(&buf)
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "localhost".to_string())
+}
@@ -0,0 +1,233 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::RemoteEntry;
+use crate::net::{is_reachable, send_magic_packet, ssh_port, SshHost};
+use crate::remote::RemoteTarget;
+use crate::term::warn;
+
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_WOL_TIMEOUT: u64 = 120;
+
+// Work out the host to connect to for an entry. With a host command, its output
+// replaces `{}` in `remote_host`, or the host part after any `user@` otherwise.
+// With alternate hosts or Tailscale enabled, the first reachable address wins,
+// and with a wake-on-LAN address a sleeping machine is woken up first.
+pub fn resolve_remote_host(entry: &RemoteEntry) -> Result<String> {
+    let remote_host = match &entry.host_command {
+        Some(host_command) => {
+            let address = run_host_command(host_command)?;
+            println!("Resolved {} to {}", entry.name, address);
+
+            if entry.remote_host.contains("{}") {
+                entry.remote_host.replace("{}", &address)
+            } else {
+                SshHost::parse(&entry.remote_host)
+                    .with_host(&address)
+                    .to_string()
+            }
+        }
+        None => entry.remote_host.clone(),
+    };
+
+    if !matches!(RemoteTarget::parse(&remote_host), RemoteTarget::Ssh { .. })
+        || (entry.alternate_hosts.is_empty() && !entry.tailscale && entry.wol_mac.is_none())
+    {
+        return Ok(remote_host);
+    }
+
+    let destination = SshHost::parse(&remote_host);
+    let host = destination.host.as_str();
+    let mut candidates = vec![host.to_string()];
+    candidates.extend(
+        entry
+            .alternate_hosts
+            .iter()
+            .map(|alternate| SshHost::parse(alternate).host),
+    );
+    if entry.tailscale {
+        candidates.extend(tailscale_addresses(host)?);
+    }
+
+    let port = destination
+        .port
+        .unwrap_or_else(|| ssh_port(&entry.ssh_options));
+    if let Some(candidate) = first_reachable(&candidates, port) {
+        if candidate != host {
+            println!("Using {} for {} (reachable)", candidate, entry.name);
+        }
+        return Ok(destination.with_host(candidate).to_string());
+    }
+
+    if let Some(mac) = &entry.wol_mac {
+        println!(
+            "{} is not reachable, sending wake-on-LAN packet",
+            entry.name
+        );
+        send_magic_packet(mac)?;
+
+        let timeout = Duration::from_secs(entry.wol_timeout.unwrap_or(DEFAULT_WOL_TIMEOUT));
+        let started = Instant::now();
+        while started.elapsed() < timeout {
+            thread::sleep(REACHABILITY_TIMEOUT);
+            if let Some(candidate) = first_reachable(&candidates, port) {
+                println!(
+                    "{} is up after {}s",
+                    entry.name,
+                    started.elapsed().as_secs()
+                );
+                return Ok(destination.with_host(candidate).to_string());
+            }
+        }
+
+        anyhow::bail!(
+            "{} did not come up within {}s after wake-on-LAN",
+            entry.name,
+            timeout.as_secs()
+        );
+    }
+
+    // Could be an ssh config alias we can't resolve ourselves, so let ssh try
+    warn(format_args!(
+        "none of {} is reachable, trying {} anyway",
+        candidates.join(", "),
+        host
+    ));
+    Ok(remote_host)
+}
+
+fn first_reachable(candidates: &[String], port: u16) -> Option<&String> {
+    candidates
+        .iter()
+        .find(|candidate| is_reachable(candidate, port, REACHABILITY_TIMEOUT))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TailscaleStatus {
+    #[serde(default)]
+    peer: HashMap<String, TailscalePeer>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TailscalePeer {
+    host_name: String,
+    #[serde(rename = "DNSName")]
+    dns_name: String,
+    #[serde(default, rename = "TailscaleIPs")]
+    tailscale_ips: Vec<String>,
+    #[serde(default)]
+    online: bool,
+}
+
+// Tailnet addresses (MagicDNS name, then IPs) of the peer matching `host`
+fn tailscale_addresses(host: &str) -> Result<Vec<String>> {
+    let output = Command::new("tailscale")
+        .args(["status", "--json"])
+        .output()
+        .context("Failed to execute tailscale status")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "tailscale status failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let status: TailscaleStatus =
+        serde_json::from_slice(&output.stdout).context("Failed to parse tailscale status")?;
+
+    let short_name = host.split('.').next().unwrap_or(host);
+    let Some(peer) = status.peer.into_values().find(|peer| {
+        peer.host_name.eq_ignore_ascii_case(short_name)
+            || peer
+                .dns_name
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(host)
+            || peer
+                .dns_name
+                .split('.')
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case(short_name))
+    }) else {
+        warn(format_args!("{} is not a peer on your tailnet", host));
+        return Ok(Vec::new());
+    };
+
+    if !peer.online {
+        warn(format_args!(
+            "{} is offline on your tailnet",
+            peer.host_name
+        ));
+    }
+
+    let mut addresses = vec![peer.dns_name.trim_end_matches('.').to_string()];
+    addresses.extend(peer.tailscale_ips);
+    Ok(addresses)
+}
+
+// Run a host lookup: `ec2:TAG=VALUE` and `gcp:LABEL=VALUE` use the cloud CLIs
+// to find a running instance, anything else is run as a local shell command
+fn run_host_command(host_command: &str) -> Result<String> {
+    let mut cmd = if let Some(tag) = host_command.strip_prefix("ec2:") {
+        let (key, value) = tag
+            .split_once('=')
+            .context("EC2 lookup must look like ec2:TAG=VALUE")?;
+        let mut cmd = Command::new("aws");
+        cmd.args(["ec2", "describe-instances", "--filters"])
+            .arg(format!("Name=tag:{},Values={}", key, value))
+            .arg("Name=instance-state-name,Values=running")
+            .args([
+                "--query",
+                "Reservations[0].Instances[0].PublicIpAddress",
+                "--output",
+                "text",
+            ]);
+        cmd
+    } else if let Some(label) = host_command.strip_prefix("gcp:") {
+        let (key, value) = label
+            .split_once('=')
+            .context("GCP lookup must look like gcp:LABEL=VALUE")?;
+        let mut cmd = Command::new("gcloud");
+        cmd.args(["compute", "instances", "list"])
+            .arg(format!(
+                "--filter=labels.{}={} AND status=RUNNING",
+                key, value
+            ))
+            .arg("--format=value(networkInterfaces[0].accessConfigs[0].natIP)")
+            .arg("--limit=1");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", host_command]);
+        cmd
+    };
+
+    let output = cmd.output().context("Failed to execute host command")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Host command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let address = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+
+    // The AWS CLI prints "None" when nothing matched
+    if address.is_empty() || address == "None" {
+        anyhow::bail!("Host command '{}' returned no address", host_command);
+    }
+
+    Ok(address)
+}
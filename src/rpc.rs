@@ -0,0 +1,409 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::cache::{get_cache_path, path_key, MigrationManager};
+use crate::state::{get_state_path, read_state};
+use crate::statusline::statusline;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct DirParams {
+    dir: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SyncParams {
+    dir: Option<String>,
+    remote: Option<String>,
+    // Extra command line arguments for the sync
+    args: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct WatchParams {
+    enabled: bool,
+}
+
+impl Default for WatchParams {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+// Shared between the request loop, sync threads and the event forwarder
+struct Server {
+    output: Mutex<Box<dyn Write + Send>>,
+    watching: AtomicBool,
+    events_path: PathBuf,
+}
+
+impl Server {
+    fn send(&self, message: Value) {
+        let mut output = self.output.lock().unwrap();
+        // A single line per message, as the protocol is newline-delimited
+        let _ = writeln!(output, "{}", message);
+        let _ = output.flush();
+    }
+
+    fn respond(&self, id: Option<Value>, result: Result<Value, (i64, String)>) {
+        // Notifications (requests without an id) get no response
+        let Some(id) = id else {
+            return;
+        };
+        let message = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        };
+        self.send(message);
+    }
+}
+
+// Serve newline-delimited JSON-RPC 2.0 on stdin/stdout for editor plugins.
+// Methods: `listRemotes`, `status`, `sync`, `watchEvents` and `shutdown`.
+// Syncs run in the background; while watching, their events from the event
+// log are forwarded as `event` notifications.
+pub fn serve() -> Result<()> {
+    let events_path = env::temp_dir().join(format!("sync-rs-rpc-{}.fifo", std::process::id()));
+    make_fifo(&events_path)?;
+
+    let server = Arc::new(Server {
+        output: Mutex::new(Box::new(io::stdout())),
+        watching: AtomicBool::new(false),
+        events_path: events_path.clone(),
+    });
+    forward_events(&server)?;
+
+    let result = handle_requests(&server, io::stdin().lock());
+    let _ = fs::remove_file(&events_path);
+    result
+}
+
+fn handle_requests(server: &Arc<Server>, input: impl BufRead) -> Result<()> {
+    for line in input.lines() {
+        let line = line.context("Failed to read request")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request: Request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                server.respond(Some(Value::Null), Err((PARSE_ERROR, e.to_string())));
+                continue;
+            }
+        };
+
+        match request.method.as_str() {
+            "listRemotes" => {
+                let result = params(request.params).and_then(|p: DirParams| list_remotes(p.dir));
+                server.respond(request.id, result);
+            }
+            "status" => {
+                let result = params(request.params).and_then(|p: DirParams| status(p.dir));
+                server.respond(request.id, result);
+            }
+            "sync" => match params::<SyncParams>(request.params) {
+                Ok(p) => {
+                    let server = Arc::clone(server);
+                    thread::spawn(move || {
+                        let result = run_sync(&server, p);
+                        server.respond(request.id, result);
+                    });
+                }
+                Err(e) => server.respond(request.id, Err(e)),
+            },
+            "watchEvents" => {
+                let result = params(request.params).map(|p: WatchParams| {
+                    server.watching.store(p.enabled, Ordering::SeqCst);
+                    json!({"watching": p.enabled})
+                });
+                server.respond(request.id, result);
+            }
+            "shutdown" => {
+                server.respond(request.id, Ok(Value::Null));
+                break;
+            }
+            method => server.respond(
+                request.id,
+                Err((METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+fn params<T: for<'de> Deserialize<'de> + Default>(params: Value) -> Result<T, (i64, String)> {
+    if params.is_null() {
+        return Ok(T::default());
+    }
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn server_error(e: anyhow::Error) -> (i64, String) {
+    (SERVER_ERROR, format!("{:#}", e))
+}
+
+fn resolve_dir(dir: Option<String>) -> Result<String, (i64, String)> {
+    match dir {
+        Some(dir) => Ok(dir),
+        None => env::current_dir()
+            .map(|dir| path_key(&dir))
+            .map_err(|e| server_error(e.into())),
+    }
+}
+
+// Remotes configured for a directory, with their last sync
+fn list_remotes(dir: Option<String>) -> Result<Value, (i64, String)> {
+    let dir = resolve_dir(dir)?;
+    let read = || -> Result<Value> {
+        let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+        let cache = migration_manager.read_dir_cache(&get_cache_path()?, &dir)?;
+        let state = read_state(&get_state_path()?)?;
+        let remote_states = state.get(&dir);
+
+        let remotes: Vec<Value> = cache
+            .get(&dir)
+            .into_iter()
+            .flatten()
+            .map(|entry| {
+                let mut value = json!(entry);
+                if let Some(remote_state) = remote_states.and_then(|s| s.get(&entry.name)) {
+                    value["last_sync_at"] = json!(remote_state.last_sync_at);
+                    value["last_sync_ok"] = json!(remote_state.last_sync_ok);
+                }
+                value
+            })
+            .collect();
+        Ok(json!(remotes))
+    };
+    read().map_err(server_error)
+}
+
+fn status(dir: Option<String>) -> Result<Value, (i64, String)> {
+    let dir = resolve_dir(dir)?;
+    let state = read_state(&get_state_path().map_err(server_error)?).map_err(server_error)?;
+    Ok(json!({
+        "statusline": statusline(&state, &dir),
+        "remotes": state.get(&dir),
+    }))
+}
+
+// Run a sync as a child process, so its human-readable output can't end up
+// in the protocol stream, and have it report events to our FIFO
+fn run_sync(server: &Server, p: SyncParams) -> Result<Value, (i64, String)> {
+    let dir = resolve_dir(p.dir)?;
+    let exe = env::current_exe().map_err(|e| server_error(e.into()))?;
+
+    let mut cmd = Command::new(exe);
+    if let Some(remote) = &p.remote {
+        cmd.args(["--name", remote]);
+    }
+    let output = cmd
+        .args(&p.args)
+        .current_dir(&dir)
+        .env("SYNC_RS_EVENT_LOG", &server.events_path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| server_error(e.into()))?;
+
+    let mut log = String::from_utf8_lossy(&output.stdout).to_string();
+    log.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    Ok(json!({
+        "success": output.status.success(),
+        "exit_code": output.status.code(),
+        "output": log,
+    }))
+}
+
+// Forward every line written to the event FIFO as a notification while watching
+fn forward_events(server: &Arc<Server>) -> Result<()> {
+    // Opened for reading and writing, so it never sees end-of-file when a
+    // sync finishes and the open doesn't wait for a writer
+    let fifo = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&server.events_path)
+        .context("Failed to open event FIFO")?;
+
+    let server = Arc::clone(server);
+    thread::spawn(move || {
+        for line in BufReader::new(fifo).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if !server.watching.load(Ordering::SeqCst) {
+                continue;
+            }
+            if let Ok(event) = serde_json::from_str::<Value>(&line) {
+                server.send(json!({"jsonrpc": "2.0", "method": "event", "params": event}));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn make_fifo(path: &Path) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("Invalid FIFO path")?;
+    // SAFETY: c_path is a valid NUL-terminated string
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to create event FIFO");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Collects what the server writes
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // The responses to a session of newline-delimited requests
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let output = Output::default();
+        let server = Arc::new(Server {
+            output: Mutex::new(Box::new(output.clone())),
+            watching: AtomicBool::new(false),
+            events_path: PathBuf::from("/nonexistent"),
+        });
+        let input: String = requests
+            .iter()
+            .map(|request| format!("{}\n", request))
+            .collect();
+        handle_requests(&server, input.as_bytes()).unwrap();
+
+        let written = output.0.lock().unwrap().clone();
+        String::from_utf8(written)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn requests_get_responses_with_their_id() {
+        let responses = session(&[
+            json!({"jsonrpc": "2.0", "id": 1, "method": "watchEvents"}),
+            json!({"jsonrpc": "2.0", "id": "two", "method": "watchEvents", "params": {"enabled": false}}),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "status", "params": {"dir": "/nonexistent/project"}}),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "shutdown"}),
+            // Never read, the server has stopped
+            json!({"jsonrpc": "2.0", "id": 5, "method": "status"}),
+        ]);
+        assert_eq!(responses.len(), 4);
+        assert_eq!(
+            responses[0],
+            json!({"jsonrpc": "2.0", "id": 1, "result": {"watching": true}})
+        );
+        assert_eq!(
+            responses[1],
+            json!({"jsonrpc": "2.0", "id": "two", "result": {"watching": false}})
+        );
+        assert_eq!(responses[2]["id"], 3);
+        assert_eq!(responses[2]["result"]["statusline"], Value::Null);
+        assert_eq!(
+            responses[3],
+            json!({"jsonrpc": "2.0", "id": 4, "result": null})
+        );
+    }
+
+    #[test]
+    fn bad_requests_get_errors() {
+        let output = Output::default();
+        let server = Arc::new(Server {
+            output: Mutex::new(Box::new(output.clone())),
+            watching: AtomicBool::new(false),
+            events_path: PathBuf::from("/nonexistent"),
+        });
+        let input = "not json\n\n{\"id\": 1, \"method\": \"rename\"}\n\
+            {\"id\": 2, \"method\": \"watchEvents\", \"params\": {\"enabled\": \"yes\"}}\n\
+            {\"id\": 3, \"method\": \"sync\", \"params\": [1]}\n\
+            {\"method\": \"watchEvents\"}\n";
+        handle_requests(&server, input.as_bytes()).unwrap();
+        // The notification without an id still took effect, without a response
+        assert!(server.watching.load(Ordering::SeqCst));
+
+        let written = output.0.lock().unwrap().clone();
+        let responses: Vec<Value> = String::from_utf8(written)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let errors: Vec<(Value, i64)> = responses
+            .iter()
+            .map(|response| {
+                (
+                    response["id"].clone(),
+                    response["error"]["code"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (Value::Null, PARSE_ERROR),
+                (json!(1), METHOD_NOT_FOUND),
+                (json!(2), INVALID_PARAMS),
+                (json!(3), INVALID_PARAMS),
+            ]
+        );
+        assert_eq!(responses[1]["error"]["message"], "Unknown method 'rename'");
+    }
+
+    #[test]
+    fn params_default_when_left_out() {
+        let sync: SyncParams = params(Value::Null).unwrap();
+        assert!(sync.dir.is_none() && sync.remote.is_none() && sync.args.is_empty());
+        let sync: SyncParams = params(json!({"remote": "gpu", "args": ["--dry-run"]})).unwrap();
+        assert_eq!(sync.remote.as_deref(), Some("gpu"));
+        assert_eq!(sync.args, ["--dry-run"]);
+        assert!(params::<WatchParams>(json!({})).unwrap().enabled);
+        assert!(params::<DirParams>(json!({"dir": 1})).is_err());
+    }
+}
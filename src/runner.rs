@@ -0,0 +1,235 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::rc::Rc;
+
+// Runs the commands `sync` builds, like rsync or ssh. Tests put a
+// `MockRunner` in place with `with_runner`, so that what would be run and
+// how failures are handled can be checked without rsync or an SSH host.
+// Commands whose output is read while they run (progress, tar streams) are
+// always started for real.
+pub trait CommandRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus>;
+    fn output(&self, cmd: &mut Command) -> io::Result<Output>;
+    // Like `output`, but stdout is also passed through as it comes, a line
+    // (ending with `\r` or `\n`) at a time, for the lines `show` accepts
+    fn output_shown(
+        &self,
+        cmd: &mut Command,
+        show: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> io::Result<Output>;
+    // Like `output`, with `input` written to stdin
+    fn output_with_input(&self, cmd: &mut Command, input: &[u8]) -> io::Result<Output>;
+}
+
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        cmd.status()
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        cmd.output()
+    }
+
+    fn output_shown(
+        &self,
+        cmd: &mut Command,
+        show: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> io::Result<Output> {
+        let mut child = cmd.stdout(Stdio::piped()).spawn()?;
+        let mut stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        let mut captured = Vec::new();
+        let mut line_start = 0;
+        let mut pass = |line: &[u8]| {
+            if show(line) {
+                let mut out = io::stdout();
+                let _ = out.write_all(line);
+                let _ = out.flush();
+            }
+        };
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = stdout.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            for &byte in &buf[..n] {
+                captured.push(byte);
+                if byte == b'\r' || byte == b'\n' {
+                    pass(&captured[line_start..]);
+                    line_start = captured.len();
+                }
+            }
+        }
+        if line_start < captured.len() {
+            pass(&captured[line_start..]);
+        }
+        Ok(Output {
+            status: child.wait()?,
+            stdout: captured,
+            stderr: Vec::new(),
+        })
+    }
+
+    fn output_with_input(&self, cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .ok_or(io::ErrorKind::BrokenPipe)?
+            .write_all(input)?;
+        child.wait_with_output()
+    }
+}
+
+thread_local! {
+    static RUNNER: RefCell<Option<Rc<dyn CommandRunner>>> = const { RefCell::new(None) };
+}
+
+// Puts the previous runner back, even if `with_runner`'s closure panics
+struct Restore(Option<Rc<dyn CommandRunner>>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        RUNNER.set(self.0.take());
+    }
+}
+
+// Call `f` with this thread's commands going to `runner`
+pub fn with_runner<T>(runner: Rc<dyn CommandRunner>, f: impl FnOnce() -> T) -> T {
+    let _restore = Restore(RUNNER.replace(Some(runner)));
+    f()
+}
+
+fn current() -> Rc<dyn CommandRunner> {
+    RUNNER
+        .with_borrow(Clone::clone)
+        .unwrap_or_else(default_runner)
+}
+
+// `SystemRunner`, or the built-in SSH client's runner when it's turned on
+#[cfg(feature = "native-ssh")]
+fn default_runner() -> Rc<dyn CommandRunner> {
+    if crate::settings::settings().ssh.native {
+        Rc::new(crate::native_ssh::NativeSshRunner)
+    } else {
+        Rc::new(SystemRunner)
+    }
+}
+
+#[cfg(not(feature = "native-ssh"))]
+fn default_runner() -> Rc<dyn CommandRunner> {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    if crate::settings::settings().ssh.native {
+        WARNED.call_once(|| {
+            crate::term::warn(format_args!(
+                "ssh.native is set, but this build has no built-in SSH client; using ssh"
+            ))
+        });
+    }
+    Rc::new(SystemRunner)
+}
+
+pub fn status(cmd: &mut Command) -> io::Result<ExitStatus> {
+    current().status(cmd)
+}
+
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    current().output(cmd)
+}
+
+pub fn output_shown(cmd: &mut Command, show: &mut dyn FnMut(&[u8]) -> bool) -> io::Result<Output> {
+    current().output_shown(cmd, show)
+}
+
+pub fn output_with_input(cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+    current().output_with_input(cmd, input)
+}
+
+// A scripted result for `MockRunner`
+#[derive(Debug, Clone, Default)]
+pub struct Reply {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+// Answers commands with the replies it was given, in order, and then with
+// success and no output. Every command line is recorded.
+#[derive(Default)]
+pub struct MockRunner {
+    replies: RefCell<VecDeque<Reply>>,
+    commands: RefCell<Vec<String>>,
+    inputs: RefCell<Vec<String>>,
+}
+
+impl MockRunner {
+    pub fn reply(self, code: i32, stdout: &str, stderr: &str) -> Self {
+        self.replies.borrow_mut().push_back(Reply {
+            code,
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+        });
+        self
+    }
+
+    // Command lines run so far, as the program and arguments separated by
+    // spaces
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.borrow().clone()
+    }
+
+    // What was written to the stdin of commands run with
+    // `output_with_input`, in order
+    pub fn inputs(&self) -> Vec<String> {
+        self.inputs.borrow().clone()
+    }
+
+    fn next(&self, cmd: &Command) -> Reply {
+        let line = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.commands.borrow_mut().push(line);
+        self.replies.borrow_mut().pop_front().unwrap_or_default()
+    }
+}
+
+impl CommandRunner for MockRunner {
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        Ok(ExitStatus::from_raw(self.next(cmd).code << 8))
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        let reply = self.next(cmd);
+        Ok(Output {
+            status: ExitStatus::from_raw(reply.code << 8),
+            stdout: reply.stdout.into_bytes(),
+            stderr: reply.stderr.into_bytes(),
+        })
+    }
+
+    fn output_shown(
+        &self,
+        cmd: &mut Command,
+        _show: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> io::Result<Output> {
+        self.output(cmd)
+    }
+
+    fn output_with_input(&self, cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+        self.inputs
+            .borrow_mut()
+            .push(String::from_utf8_lossy(input).to_string());
+        self.output(cmd)
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::{Context, Result};
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// Local weekday (0 = Sunday) and minutes since midnight
+pub fn local_time() -> (u32, u32) {
+    // SAFETY: localtime_r only writes to the tm struct we pass in
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_wday as u32, (tm.tm_hour * 60 + tm.tm_min) as u32)
+    }
+}
+
+// Bandwidth limit for a time window, written as
+// `[DAY[-DAY] ]HH:MM-HH:MM=LIMIT`, e.g. `mon-fri 09:00-18:00=2m`.
+// LIMIT is passed to rsync's --bwlimit, so `0` means unlimited.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandwidthWindow {
+    days: Option<(u32, u32)>,
+    start: u32,
+    end: u32,
+    limit: String,
+}
+
+impl BandwidthWindow {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || format!("Invalid bandwidth window '{}'", spec);
+
+        let (window, limit) = spec.rsplit_once('=').with_context(invalid)?;
+        let (days, range) = match window.trim().split_once(' ') {
+            Some((days, range)) => (Some(parse_days(days).with_context(invalid)?), range),
+            None => (None, window.trim()),
+        };
+        let (start, end) = range.split_once('-').with_context(invalid)?;
+        if limit.trim().is_empty() {
+            anyhow::bail!("{}: the limit is missing", invalid());
+        }
+
+        Ok(Self {
+            days,
+            start: parse_time(start).with_context(invalid)?,
+            end: parse_time(end).with_context(invalid)?,
+            limit: limit.trim().to_string(),
+        })
+    }
+
+    // Whether the window covers the given weekday and minute of the day.
+    // Windows ending before they start wrap past midnight and belong to the
+    // day they start on.
+    pub fn contains(&self, weekday: u32, minute: u32) -> bool {
+        let (in_range, day) = if self.start <= self.end {
+            (minute >= self.start && minute < self.end, weekday)
+        } else if minute >= self.start {
+            (true, weekday)
+        } else {
+            (minute < self.end, (weekday + 6) % 7)
+        };
+
+        in_range
+            && self.days.is_none_or(|(first, last)| {
+                if first <= last {
+                    (first..=last).contains(&day)
+                } else {
+                    day >= first || day <= last
+                }
+            })
+    }
+}
+
+fn parse_days(days: &str) -> Option<(u32, u32)> {
+    let day = |name: &str| {
+        let name = name.trim().to_lowercase();
+        DAY_NAMES
+            .iter()
+            .position(|day| name.starts_with(day))
+            .map(|i| i as u32)
+    };
+
+    match days.split_once('-') {
+        Some((first, last)) => Some((day(first)?, day(last)?)),
+        None => day(days).map(|d| (d, d)),
+    }
+}
+
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    let minute = hours * 60 + minutes;
+    (minutes < 60 && minute <= 24 * 60).then_some(minute)
+}
+
+// The bandwidth limit in effect now, from the first matching window
+pub fn current_bwlimit(windows: &[BandwidthWindow]) -> Option<String> {
+    let (weekday, minute) = local_time();
+    bwlimit_at(windows, weekday, minute)
+}
+
+fn bwlimit_at(windows: &[BandwidthWindow], weekday: u32, minute: u32) -> Option<String> {
+    windows
+        .iter()
+        .find(|window| window.contains(weekday, minute))
+        .map(|window| window.limit.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MON: u32 = 1;
+    const FRI: u32 = 5;
+    const SAT: u32 = 6;
+    const SUN: u32 = 0;
+
+    fn at(time: &str) -> u32 {
+        parse_time(time).unwrap()
+    }
+
+    #[test]
+    fn windows_parse() {
+        assert_eq!(
+            BandwidthWindow::parse("mon-fri 09:00-18:00=2m").unwrap(),
+            BandwidthWindow {
+                days: Some((MON, FRI)),
+                start: 9 * 60,
+                end: 18 * 60,
+                limit: "2m".to_string(),
+            }
+        );
+        assert_eq!(
+            BandwidthWindow::parse(" Saturday 22:30-06:00 = 0 ").unwrap(),
+            BandwidthWindow {
+                days: Some((SAT, SAT)),
+                start: 22 * 60 + 30,
+                end: 6 * 60,
+                limit: "0".to_string(),
+            }
+        );
+        let every_day = BandwidthWindow::parse("00:00-24:00=500").unwrap();
+        assert_eq!((every_day.days, every_day.end), (None, 24 * 60));
+
+        for spec in [
+            "",
+            "09:00-18:00",
+            "09:00-18:00=",
+            "09:00=1m",
+            "9-18=1m",
+            "09:60-18:00=1m",
+            "24:01-18:00=1m",
+            "25:00-26:00=1m",
+            "xx 09:00-18:00=1m",
+            "mon-xx 09:00-18:00=1m",
+            "mon-fri 09:00=1m",
+        ] {
+            assert!(BandwidthWindow::parse(spec).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn windows_cover_their_days_and_hours() {
+        let office = BandwidthWindow::parse("mon-fri 09:00-18:00=2m").unwrap();
+        assert!(office.contains(MON, at("09:00")));
+        assert!(office.contains(FRI, at("17:59")));
+        assert!(!office.contains(FRI, at("18:00")));
+        assert!(!office.contains(MON, at("08:59")));
+        assert!(!office.contains(SAT, at("12:00")));
+
+        // Past midnight, the window still belongs to the day it started on
+        let night = BandwidthWindow::parse("fri 22:00-06:00=0").unwrap();
+        assert!(night.contains(FRI, at("23:00")));
+        assert!(night.contains(SAT, at("05:59")));
+        assert!(!night.contains(SAT, at("06:00")));
+        assert!(!night.contains(SAT, at("23:00")));
+        assert!(!night.contains(FRI, at("05:00")));
+
+        // Day ranges can wrap around the week too
+        let weekend = BandwidthWindow::parse("sat-sun 00:00-24:00=0").unwrap();
+        assert!(weekend.contains(SAT, 0));
+        assert!(weekend.contains(SUN, at("23:59")));
+        assert!(!weekend.contains(MON, 0));
+    }
+
+    #[test]
+    fn the_first_matching_window_sets_the_limit() {
+        let windows: Vec<BandwidthWindow> = [
+            "mon-fri 12:00-13:00=10m",
+            "mon-fri 09:00-18:00=2m",
+            "22:00-06:00=0",
+        ]
+        .iter()
+        .map(|spec| BandwidthWindow::parse(spec).unwrap())
+        .collect();
+        let limit = |day, time| bwlimit_at(&windows, day, at(time));
+
+        assert_eq!(limit(MON, "12:30").as_deref(), Some("10m"));
+        assert_eq!(limit(MON, "10:00").as_deref(), Some("2m"));
+        assert_eq!(limit(SUN, "03:00").as_deref(), Some("0"));
+        assert_eq!(limit(SAT, "10:00"), None);
+        assert_eq!(bwlimit_at(&[], MON, 0), None);
+    }
+}
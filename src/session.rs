@@ -0,0 +1,1635 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cache::{get_cache_path, path_key, set_preferred, MigrationManager, RemoteMap};
+use crate::chunked::format_size;
+use crate::config::{
+    check_aliases, checked_name, confirm, generate_unique_name, prompt_remote_dir,
+    prompt_remote_info, remote_full_dir, select_from, select_remote, RemoteEntry,
+};
+use crate::error::{SyncError, SyncResult};
+use crate::events;
+use crate::glob::path_matches;
+use crate::healthcheck::{self, Ping};
+use crate::home::{self, home_files, HomePlan};
+use crate::hosts::{link_profile, Strategy};
+use crate::i18n::tr;
+use crate::nested::{
+    parse_submodule_status, submodule_mismatches, submodule_status, uninitialized_submodule_rules,
+};
+use crate::net::ssh_port;
+use crate::plugin::{run_hooks, Hook, HookContext, HookStage};
+use crate::policy::load_policy;
+use crate::remote::{shell_quote, RemoteTarget};
+use crate::schedule::{current_bwlimit, BandwidthWindow};
+use crate::scripts::{has_hook_scripts, run_hook_script};
+use crate::selection::{NewName, RemoteRequest, RemoteResolver, Resolution};
+use crate::settings::{get_settings_path, settings};
+use crate::state::{
+    get_state_path, read_remote_state, read_state, save_remote_state, Preflight, RemoteState,
+};
+use crate::sync::{
+    get_remote_home, itemized_path, listing_time, mkpath_supported, open_remote_shell,
+    remote_has_rsync, remote_rsync_version, run_remote_script, RsyncOptions, RsyncVersion,
+    SyncEvent, SyncReport, LOCAL_SOURCE, TAR_STREAM_MIN_FILES,
+};
+use crate::template::render_templates;
+use crate::term::{bold, warn};
+use crate::timings::{TimedRun, Timings, TIMING_HISTORY};
+use crate::transport::{Pull, TransferOptions, Transport, TransportKind, Upload};
+use crate::vm::{detect_vms, VmCandidate};
+
+// Directory in the remote directory that `--trash` moves deleted files into
+pub const TRASH_DIR: &str = ".sync-rs-trash";
+
+// Exit status of a command run by `in_remote_dir` when it can't enter the
+// remote directory, to tell that apart from the command itself failing
+pub const MISSING_DIR_EXIT: i32 = 97;
+
+// Changes the settings of the remote a `Syncer` picked
+type Configure = Box<dyn Fn(&mut RemoteEntry)>;
+
+// Arranges for a held-back post-sync command to run later
+type DeferPostCommand = Box<dyn Fn(&RemoteEntry, u64) -> Result<()>>;
+
+// Syncs a directory to one of its remotes. The binary syncs through this
+// too, so tools embedding sync-rs get the same sync:
+//
+//     Syncer::new("/home/me/project")
+//         .remote_name("gpu-box")
+//         .ignore("*.ckpt")
+//         .hook(Box::new(Notify))
+//         .run()?;
+//
+// The remote's saved settings apply, with the global defaults and the
+// machine's policy on top. Around the transfer, whichever transport it
+// takes, go the checks (remote changes, protected paths, the delete limit),
+// hooks and hook scripts, templates and the post-sync command, and how it
+// went is recorded in the sync state. Unless `interactive`, whatever would
+// need asking is an error instead.
+pub struct Syncer {
+    local_dir: PathBuf,
+    remote: Option<RemoteEntry>,
+    request: RemoteRequest,
+    vm: bool,
+    configure: Option<Configure>,
+    reserved_names: Vec<String>,
+    interactive: bool,
+    ignore_patterns: Vec<String>,
+    protected_paths: Vec<String>,
+    delete: bool,
+    delete_override: bool,
+    post_command: bool,
+    always_run_post_command: bool,
+    dry_run: bool,
+    force: bool,
+    fresh: bool,
+    both_ways: bool,
+    measure_link: bool,
+    chunk_size: Option<u64>,
+    bwlimit: Option<String>,
+    open_shell: bool,
+    hooks: Vec<Box<dyn Hook>>,
+    progress: Option<Rc<dyn Fn(SyncEvent)>>,
+    defer_post_command: Option<DeferPostCommand>,
+}
+
+impl Syncer {
+    pub fn new(local_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            local_dir: local_dir.into(),
+            remote: None,
+            request: RemoteRequest::default(),
+            vm: false,
+            configure: None,
+            reserved_names: Vec::new(),
+            interactive: false,
+            ignore_patterns: Vec::new(),
+            protected_paths: Vec::new(),
+            delete: true,
+            delete_override: false,
+            post_command: true,
+            always_run_post_command: false,
+            dry_run: false,
+            force: false,
+            fresh: false,
+            both_ways: false,
+            measure_link: true,
+            chunk_size: None,
+            bwlimit: None,
+            open_shell: false,
+            hooks: Vec::new(),
+            progress: None,
+            defer_post_command: None,
+        }
+    }
+
+    // Sync to this remote instead of one saved for the directory
+    pub fn remote(mut self, entry: RemoteEntry) -> Self {
+        self.remote = Some(entry);
+        self
+    }
+
+    // Sync to the saved remote of this name or alias. Without one, the
+    // remote is picked as the binary would.
+    pub fn remote_name(mut self, name: impl Into<String>) -> Self {
+        self.request.name = Some(name.into());
+        self
+    }
+
+    // Pick the saved remote, or add one, as `request` asks
+    pub fn request(mut self, request: RemoteRequest) -> Self {
+        self.request = request;
+        self
+    }
+
+    // Add a remote for a running Vagrant machine or Multipass instance
+    pub fn vm(mut self, vm: bool) -> Self {
+        self.vm = vm;
+        self
+    }
+
+    // Change the remote's settings with `configure` and save them, e.g. to
+    // what the command line gives
+    pub fn configure(mut self, configure: impl Fn(&mut RemoteEntry) + 'static) -> Self {
+        self.configure = Some(Box::new(configure));
+        self
+    }
+
+    // Words a remote added or renamed here can't be called, nor have as an alias
+    pub fn reserved_names(mut self, names: Vec<String>) -> Self {
+        self.reserved_names = names;
+        self
+    }
+
+    // Ask on the terminal instead of failing: which remote, whether to add
+    // one, what to do about changes on the remote
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
+    // Leave out paths matching `pattern`, on top of the remote's own patterns
+    pub fn ignore(mut self, pattern: impl Into<String>) -> Self {
+        self.ignore_patterns.push(pattern.into());
+        self
+    }
+
+    // Never delete or overwrite paths matching `pattern` on the remote
+    pub fn protect(mut self, pattern: impl Into<String>) -> Self {
+        self.protected_paths.push(pattern.into());
+        self
+    }
+
+    // Delete remote files that are gone here (on by default)
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    // Also delete within override paths (off by default, like `-d`)
+    pub fn delete_override(mut self, delete: bool) -> Self {
+        self.delete_override = delete;
+        self
+    }
+
+    // Run the remote's post-sync command afterwards (on by default)
+    pub fn post_command(mut self, run: bool) -> Self {
+        self.post_command = run;
+        self
+    }
+
+    // Run the post-sync command even when none of its triggers changed
+    pub fn always_run_post_command(mut self, always: bool) -> Self {
+        self.always_run_post_command = always;
+        self
+    }
+
+    // Show what would be sent without changing the remote
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    // Sync from the home directory or `/` too, and over changes made on the
+    // remote since the last sync
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    // Check the remote again instead of reusing a recent check
+    pub fn fresh(mut self, fresh: bool) -> Self {
+        self.fresh = fresh;
+        self
+    }
+
+    // Bring changes made on the remote back first, the newer copy of a file
+    // winning either way
+    pub fn both_ways(mut self, both_ways: bool) -> Self {
+        self.both_ways = both_ways;
+        self
+    }
+
+    // Pick transfer settings by measuring the link, remembered for the host
+    // (on by default)
+    pub fn measure_link(mut self, measure: bool) -> Self {
+        self.measure_link = measure;
+        self
+    }
+
+    // Push the project in checkpointed chunks of about this many bytes
+    // first, so an interrupted sync resumes where it stopped
+    pub fn chunked(mut self, chunk_size: Option<u64>) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    // Limit the bandwidth (rsync's --bwlimit) instead of following the
+    // remote's schedule
+    pub fn bwlimit(mut self, limit: Option<String>) -> Self {
+        self.bwlimit = limit;
+        self
+    }
+
+    // Open a shell in the remote directory once synced
+    pub fn open_shell(mut self, open: bool) -> Self {
+        self.open_shell = open;
+        self
+    }
+
+    // Run `hook` before and after this sync, after the globally registered ones
+    pub fn hook(mut self, hook: Box<dyn Hook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    // Report what the transfers are doing to `on_event`, instead of
+    // showing rsync's output. Override paths are separate transfers, each
+    // going through the phases again.
+    pub fn on_progress(mut self, on_event: impl Fn(SyncEvent) + 'static) -> Self {
+        self.progress = Some(Rc::new(on_event));
+        self
+    }
+
+    // When a coalescing post-sync command is held back by its cooldown,
+    // `schedule` arranges for it to run later, given the remote and the
+    // token now pending in its state. Without it, the command is skipped.
+    pub fn on_deferred_post_command(
+        mut self,
+        schedule: impl Fn(&RemoteEntry, u64) -> Result<()> + 'static,
+    ) -> Self {
+        self.defer_post_command = Some(Box::new(schedule));
+        self
+    }
+
+    // The remote that `run` syncs to, before the machine's policy applies.
+    // One added or changed on the way is saved.
+    pub fn resolve_remote(&self) -> SyncResult<RemoteEntry> {
+        let mut entry = match &self.remote {
+            Some(entry) => entry.clone(),
+            None => self.saved_remote()?,
+        };
+        entry
+            .ignore_patterns
+            .extend(self.ignore_patterns.iter().cloned());
+        entry
+            .protected_paths
+            .extend(self.protected_paths.iter().cloned());
+        settings().defaults.apply(&mut entry);
+        Ok(entry)
+    }
+
+    // Failures a caller may want to handle, like the remote being
+    // unreachable or without rsync, come back as their own `SyncError`.
+    // What was sent comes back as a `SyncReport`, empty for a dry run or a
+    // sync the pre-sync hook script skipped.
+    pub fn run(&self) -> SyncResult<SyncReport> {
+        let entry = self.resolve_remote()?;
+        let dir = dir_key(&self.local_dir);
+        let state_path = get_state_path()?;
+        let mut state = read_remote_state(&state_path, &dir, &entry.name)?;
+        // Persist state even if a later step of the sync fails
+        let result = if self.dry_run {
+            self.show_changes(&entry, &mut state).map(|()| None)
+        } else {
+            self.sync_to(&entry, &mut state, &mut Timings::default())
+        };
+        save_remote_state(&state_path, &dir, &entry.name, &state)?;
+        Ok(result?.unwrap_or_default())
+    }
+
+    // Sync to `entry`, recording in `state` how it went and in `timings`
+    // how long each phase took. None if the pre-sync hook script skipped it.
+    pub fn sync_to(
+        &self,
+        entry: &RemoteEntry,
+        state: &mut RemoteState,
+        timings: &mut Timings,
+    ) -> Result<Option<SyncReport>> {
+        let dir = dir_key(&self.local_dir);
+        let run = json!({
+            "dir": dir,
+            "remote": entry.name,
+            "host": entry.remote_host,
+        });
+        events::emit("sync_started", run.clone());
+        if let Some(url) = &entry.healthcheck_url {
+            healthcheck::ping(url, Ping::Start, &run);
+        }
+        let started = Instant::now();
+        state.last_transferred = None;
+        state.last_deleted = None;
+
+        let result = self.perform(entry, state, timings);
+        timings.finish();
+        let elapsed = started.elapsed();
+        let result = result.map(|report| {
+            report.map(|report| SyncReport {
+                duration_ms: elapsed.as_millis() as u64,
+                ..report
+            })
+        });
+        let finished_at = unix_millis() / 1000;
+        state.last_sync_at = Some(finished_at);
+        state.last_sync_ok = Some(result.is_ok());
+        state.timings.push(TimedRun {
+            at: finished_at,
+            ok: result.is_ok(),
+            total_ms: elapsed.as_millis() as u64,
+            phases: timings.phases.clone(),
+        });
+        let excess = state.timings.len().saturating_sub(TIMING_HISTORY);
+        state.timings.drain(..excess);
+        // A failure may come from something the checks would catch now
+        if result.is_err() {
+            state.preflight = None;
+        }
+
+        events::emit(
+            "sync_finished",
+            json!({
+                "dir": dir,
+                "remote": entry.name,
+                "success": result.is_ok(),
+                "duration_ms": elapsed.as_millis() as u64,
+                "phases": timings.phases,
+                "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+                "report": result.as_ref().ok().cloned().flatten(),
+            }),
+        );
+
+        if let Some(url) = &entry.healthcheck_url {
+            let kind = if result.is_ok() {
+                Ping::Success
+            } else {
+                Ping::Failure
+            };
+            let mut metadata = run;
+            metadata["duration_ms"] = json!(elapsed.as_millis() as u64);
+            metadata["phases"] = json!(timings.phases);
+            metadata["transferred"] = json!(state.last_transferred);
+            metadata["deleted"] = json!(state.last_deleted);
+            metadata["error"] = json!(result.as_ref().err().map(|e| format!("{:#}", e)));
+            healthcheck::ping(url, kind, &metadata);
+        }
+
+        result
+    }
+
+    // Print what a sync to `entry` would transfer and delete, for the
+    // project and each override path, using the transport's dry run. Only
+    // reads the remote.
+    pub fn show_changes(&self, entry: &RemoteEntry, state: &mut RemoteState) -> Result<()> {
+        let policy_entry = match load_policy()? {
+            Some(policy) => policy.apply(entry)?,
+            None => entry.clone(),
+        };
+        let entry = &policy_entry;
+
+        let preflight = preflight(entry, self.fresh, state)?;
+        // Checked again for the machine the host resolved to
+        let policy_entry = policy_for_target(entry, &preflight.target)?;
+        let entry = &policy_entry;
+        let remote_full_dir = remote_full_dir(&preflight.remote_home, &entry.remote_dir);
+        let options =
+            self.transfer_options(entry, &preflight, &Strategy::default(), state, false)?;
+        let transport = entry
+            .transport
+            .resolve(&preflight.target, preflight.has_rsync)?
+            .backend(&preflight.target, options);
+
+        println!(
+            "Dry run, nothing is changed on {} ({})",
+            bold(&entry.name),
+            preflight.target.rsync_location(&remote_full_dir)
+        );
+        let mut changes = 0;
+        let uploads = std::iter::once((LOCAL_SOURCE, Upload::Project)).chain(
+            entry
+                .override_paths
+                .iter()
+                .map(|path| (path.as_str(), Upload::Override(path))),
+        );
+        for (source, upload) in uploads {
+            let Some(steps) = transport.preview(upload, &remote_full_dir)? else {
+                anyhow::bail!("A dry run needs rsync on the remote");
+            };
+            for (step, lines) in steps {
+                let heading = match step.as_str() {
+                    "" => source.to_string(),
+                    step => format!("{} ({})", source, step),
+                };
+                changes += print_dry_run(&heading, lines);
+            }
+        }
+        if changes == 0 {
+            println!("Nothing to transfer or delete");
+        }
+        Ok(())
+    }
+
+    // The saved remote to use, adding or updating it as asked
+    fn saved_remote(&self) -> Result<RemoteEntry> {
+        let dir = dir_key(&self.local_dir);
+        let cache_path = get_cache_path()?;
+        let migration_manager = MigrationManager::new(env!("CARGO_PKG_VERSION").to_string());
+        let mut cache = migration_manager.read_cache(&cache_path)?;
+        if self.vm {
+            let entry = self.add_vm(&mut cache, &dir, &detect_vms(&self.local_dir)?)?;
+            migration_manager.save_cache(&cache_path, &cache)?;
+            return Ok(entry);
+        }
+
+        let entries = cache.get(&dir).map(Vec::as_slice).unwrap_or_default();
+        let state = read_state(&get_state_path()?)?;
+        let states = state.get(&dir);
+        let last_used = states.and_then(|states| {
+            states
+                .iter()
+                .filter(|(_, state)| state.last_sync_at.is_some())
+                .max_by_key(|(_, state)| state.last_sync_at)
+                .map(|(name, _)| name.as_str())
+        });
+        let preferred = self.request.set_preferred;
+
+        let index = match (RemoteResolver { entries, last_used }).resolve(&self.request)? {
+            Resolution::Add {
+                name,
+                host,
+                dir: remote_dir,
+            } => {
+                return migration_manager.update_cache(&cache_path, &mut cache, |cache| {
+                    let entries = cache.entry(dir.clone()).or_default();
+                    let name = match name {
+                        NewName::Reuse(name) => name,
+                        NewName::Requested(name) => checked_name(&name, &self.reserved_names)?,
+                        NewName::Generate => generate_unique_name(&host, &remote_dir, entries),
+                    };
+
+                    let mut entry = RemoteEntry {
+                        name: name.clone(),
+                        remote_host: host,
+                        remote_dir,
+                        preferred,
+                        ..Default::default()
+                    };
+                    self.apply_settings(&mut entry);
+                    check_aliases(entries, &entry, &self.reserved_names)?;
+
+                    // Check if name already exists and update or add
+                    if let Some(index) = entries.iter().position(|e| e.name == name) {
+                        entries[index] = entry.clone();
+                    } else {
+                        entries.push(entry.clone());
+                    }
+                    if preferred {
+                        set_preferred(entries, &name);
+                    }
+                    Ok(entry)
+                });
+            }
+            Resolution::Ask if !self.interactive => {
+                return Err(SyncError::NoRemote {
+                    dir: self.local_dir.clone(),
+                }
+                .into())
+            }
+            Resolution::Ask => {
+                // Offer local VMs before asking for the host by hand
+                let vms = if io::stdin().is_terminal() {
+                    detect_vms(&self.local_dir).unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+
+                if !vms.is_empty()
+                    && confirm(&format!(
+                        "Found {} running local VM(s). Create the remote from one?",
+                        vms.len()
+                    ))?
+                {
+                    let entry = self.add_vm(&mut cache, &dir, &vms)?;
+                    migration_manager.save_cache(&cache_path, &cache)?;
+                    return Ok(entry);
+                }
+
+                let (h, d) = prompt_remote_info()?;
+                return migration_manager.update_cache(&cache_path, &mut cache, |cache| {
+                    let entries = cache.entry(dir.clone()).or_default();
+                    let mut entry = RemoteEntry {
+                        name: match &self.request.name {
+                            Some(name) => checked_name(name, &self.reserved_names)?,
+                            None => generate_unique_name(&h, &d, entries),
+                        },
+                        remote_host: h,
+                        remote_dir: d,
+                        preferred,
+                        ..Default::default()
+                    };
+                    self.apply_settings(&mut entry);
+                    check_aliases(entries, &entry, &self.reserved_names)?;
+
+                    entries.push(entry.clone());
+                    Ok(entry)
+                });
+            }
+            Resolution::Use(index) => index,
+            Resolution::LastUsed(index) => {
+                if self.interactive {
+                    println!(
+                        "Using {}, the remote synced to last (pick another with -n NAME)",
+                        bold(&entries[index].name)
+                    );
+                }
+                index
+            }
+            Resolution::Select(_) if !self.interactive => {
+                return Err(SyncError::AmbiguousRemote {
+                    dir: self.local_dir.clone(),
+                }
+                .into())
+            }
+            Resolution::Select(indices) => {
+                let active: Vec<RemoteEntry> =
+                    indices.iter().map(|&i| entries[i].clone()).collect();
+                let name = select_remote(&active, states)?;
+                indices[active
+                    .iter()
+                    .position(|e| e.name == name)
+                    .unwrap_or_default()]
+            }
+        };
+
+        // Update with new settings if there are any
+        if self.configure.is_none() && !preferred {
+            return Ok(entries[index].clone());
+        }
+        let entries = cache.get_mut(&dir).unwrap();
+        if preferred {
+            let name = entries[index].name.clone();
+            set_preferred(entries, &name);
+        }
+        self.apply_settings(&mut entries[index]);
+        check_aliases(entries, &entries[index], &self.reserved_names)?;
+
+        let entry = entries[index].clone();
+        migration_manager.save_cache(&cache_path, &cache)?;
+        Ok(entry)
+    }
+
+    // Create a remote entry for one of the detected VMs and add it to the cache
+    fn add_vm(
+        &self,
+        cache: &mut RemoteMap,
+        dir: &str,
+        candidates: &[VmCandidate],
+    ) -> Result<RemoteEntry> {
+        let vm = match candidates {
+            [] => anyhow::bail!("No running Vagrant machine or Multipass instance found"),
+            [vm] => vm,
+            _ => {
+                let options: Vec<String> = candidates
+                    .iter()
+                    .map(|vm| format!("{} ({})", vm.name, vm.remote_host))
+                    .collect();
+                &candidates[select_from("Select a VM:", &options)?]
+            }
+        };
+        println!("Using {} ({})", vm.name, vm.remote_host);
+
+        let preferred = self.request.set_preferred;
+        let mut entry = RemoteEntry {
+            name: checked_name(
+                self.request.name.as_ref().unwrap_or(&vm.name),
+                &self.reserved_names,
+            )?,
+            remote_host: vm.remote_host.clone(),
+            remote_dir: prompt_remote_dir()?,
+            preferred,
+            ssh_options: vm.ssh_options.clone(),
+            ..Default::default()
+        };
+        self.apply_settings(&mut entry);
+
+        let entries = cache.entry(dir.to_string()).or_default();
+        match entries.iter().position(|e| e.name == entry.name) {
+            Some(index) => entries[index] = entry.clone(),
+            None => entries.push(entry.clone()),
+        }
+        if preferred {
+            set_preferred(entries, &entry.name);
+        }
+
+        Ok(entry)
+    }
+
+    fn apply_settings(&self, entry: &mut RemoteEntry) {
+        if let Some(configure) = &self.configure {
+            configure(entry);
+        }
+    }
+
+    // The sync itself. The counts come back unless the pre-sync hook script
+    // skipped it.
+    fn perform(
+        &self,
+        entry: &RemoteEntry,
+        state: &mut RemoteState,
+        timings: &mut Timings,
+    ) -> Result<Option<SyncReport>> {
+        timings.phase("preflight");
+
+        // The machine's policy applies on top of the remote's own settings
+        let policy_entry = match load_policy()? {
+            Some(policy) => policy.apply(entry)?,
+            None => entry.clone(),
+        };
+        let entry = &policy_entry;
+
+        // From the home directory, only what the settings list is sent
+        let home_plan = home_files(&self.local_dir)
+            .map(|files| home::plan(&self.local_dir, files))
+            .transpose()?;
+        if home_plan.is_none() && !self.force {
+            check_sync_source(&self.local_dir)?;
+        }
+
+        let preflight = preflight(entry, self.fresh, state)?;
+        // Checked again for the machine the host resolved to
+        let policy_entry = policy_for_target(entry, &preflight.target)?;
+        let entry = &policy_entry;
+        let target = preflight.target.clone();
+        let remote_full_dir = remote_full_dir(&preflight.remote_home, &entry.remote_dir);
+        println!(
+            "{}",
+            tr(
+                "sync.start",
+                &[&bold(&entry.name), &target.rsync_location(&remote_full_dir)]
+            )
+        );
+
+        // Pick transfer settings suited to the link, measured on first use
+        let strategy = if entry.fixed_strategy || !self.measure_link {
+            Strategy::default()
+        } else {
+            match link_profile(&target, ssh_port(&entry.ssh_options)) {
+                Ok(link) => link.map(|link| link.strategy()).unwrap_or_default(),
+                Err(e) => {
+                    warn(format_args!("Could not measure connection: {:#}", e));
+                    Strategy::default()
+                }
+            }
+        };
+
+        // rsync only creates the last directory of the destination, so a missing
+        // parent fails the first sync to a fresh host. Without --mkpath on both
+        // ends, create the whole path first.
+        let relayed = matches!(target, RemoteTarget::Relay { .. });
+        if entry.auto_create
+            && (relayed || !(preflight.has_rsync && mkpath_supported(preflight.rsync_version)))
+        {
+            let status = target
+                .command(&format!("mkdir -p {}", shell_quote(&remote_full_dir)))
+                .status()
+                .context("Failed to create the remote directory")?;
+            if !status.success() {
+                anyhow::bail!("Failed to create the remote directory {}", remote_full_dir);
+            }
+        }
+
+        let mut hook_context = HookContext {
+            local_dir: std::path::absolute(&self.local_dir)?
+                .to_string_lossy()
+                .to_string(),
+            remote_name: entry.name.clone(),
+            remote_host: entry.remote_host.clone(),
+            remote_dir: remote_full_dir.clone(),
+            changed_files: None,
+        };
+        timings.phase("pre-sync hooks");
+        run_hooks(HookStage::PreSync, &hook_context, &entry.plugins)?;
+        self.run_own_hooks(HookStage::PreSync, &hook_context)?;
+
+        if home_plan.is_some() && self.both_ways {
+            anyhow::bail!("Home mode only sends files");
+        }
+        let kind = entry.transport.resolve(&target, preflight.has_rsync)?;
+        let options =
+            self.transfer_options(entry, &preflight, &strategy, state, home_plan.is_some())?;
+        let transport = kind.backend(&target, options);
+        transport.check()?;
+        if !preflight.has_rsync {
+            warn(format_args!(
+                "rsync is not available on {}. {}",
+                entry.name,
+                transport.describe()
+            ));
+        }
+
+        let Some(report) = self.transfer(
+            entry,
+            transport.as_ref(),
+            home_plan.as_ref(),
+            state,
+            &mut hook_context,
+            timings,
+        )?
+        else {
+            println!("Sync skipped by the pre-sync hook script");
+            return Ok(None);
+        };
+        let changes = report.changes();
+
+        if let Some(group) = &entry.shared_group {
+            timings.phase("shared group");
+            share_with_group(
+                &target,
+                &remote_full_dir,
+                group,
+                entry.shared_acl,
+                !preflight.has_rsync,
+            )?;
+        }
+
+        if entry.check_submodules {
+            timings.phase("checks");
+            check_submodules(&target, &remote_full_dir, &self.local_dir)?;
+        }
+
+        // Execute post-sync command if specified, unless nothing it cares about
+        // changed or it ran too recently
+        let relevant = self.always_run_post_command
+            || changes.as_ref().is_none_or(|changes| {
+                changes.iter().any(|path| {
+                    entry.post_command_triggers.is_empty()
+                        || entry
+                            .post_command_triggers
+                            .iter()
+                            .any(|pattern| path_matches(pattern, path))
+                })
+            });
+        match &entry.post_sync_command {
+            Some(_) if !self.post_command => {}
+            Some(_) if !relevant => {
+                println!("Skipping the post-sync command: no relevant files changed");
+            }
+            Some(cmd) => {
+                timings.phase("post-sync command");
+                let now = unix_millis();
+                let since_last = state
+                    .last_post_command_at
+                    .map(|at| (now / 1000).saturating_sub(at));
+                match (entry.post_command_cooldown, since_last) {
+                    (Some(cooldown), Some(since_last)) if since_last < cooldown => {
+                        println!(
+                            "Skipping the post-sync command: it ran {}s ago (cooldown {}s)",
+                            since_last, cooldown
+                        );
+                        if let Some(schedule) = self
+                            .defer_post_command
+                            .as_ref()
+                            .filter(|_| entry.post_command_coalesce)
+                        {
+                            // Each sync replaces the token, so earlier waiters give up
+                            state.pending_post_command = Some(now);
+                            schedule(entry, now)?;
+                        }
+                    }
+                    _ => {
+                        state.last_post_command_at = Some(now / 1000);
+                        state.pending_post_command = None;
+                        run_post_command(entry, cmd, &target, &remote_full_dir)?;
+                    }
+                }
+            }
+            None => {}
+        }
+
+        timings.phase("post-sync hooks");
+        run_hooks(HookStage::PostSync, &hook_context, &entry.plugins)?;
+        self.run_own_hooks(HookStage::PostSync, &hook_context)?;
+        run_hook_script(HookStage::PostSync, &hook_context)?;
+
+        // Open interactive shell if requested
+        if self.open_shell {
+            timings.phase("shell");
+            println!(
+                "{}",
+                tr("sync.shell", &[&target.rsync_location(&remote_full_dir)])
+            );
+            open_remote_shell(&target, &remote_full_dir)?;
+        }
+
+        Ok(Some(report))
+    }
+
+    // How a sync to the remote goes, for whichever transport it uses. Home mode
+    // leaves ignored and protected paths out of what it lists.
+    fn transfer_options(
+        &self,
+        entry: &RemoteEntry,
+        preflight: &Preflight,
+        strategy: &Strategy,
+        state: &RemoteState,
+        home: bool,
+    ) -> Result<TransferOptions> {
+        let mut base_options = preflight.target.rsync_options();
+        base_options
+            .extra_args
+            .extend(strategy.rsync_args.iter().cloned());
+        base_options
+            .extra_args
+            .extend(settings().defaults.rsync_args.iter().cloned());
+        base_options.remote_version = preflight.rsync_version;
+        base_options.extra_args.extend(entry_rsync_args(
+            entry,
+            preflight.rsync_version,
+            unix_millis() / 1000,
+        ));
+
+        // An explicit limit wins over the remote's bandwidth schedule
+        let windows = entry
+            .bandwidth_windows
+            .iter()
+            .map(|window| BandwidthWindow::parse(window))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(limit) = self.bwlimit.clone().or_else(|| current_bwlimit(&windows)) {
+            println!("Limiting bandwidth to {}", limit);
+            base_options.extra_args.push(format!("--bwlimit={}", limit));
+        }
+
+        let filters = SyncFilters::new(entry, &self.local_dir);
+
+        // Join filters with commas for rsync
+        let mut main = RsyncOptions {
+            filter: Some(filters.main.join(",")),
+            delete: self.delete,
+            ..base_options.clone()
+        };
+        // Both sides may have changed files, and the newer copy wins
+        if self.both_ways {
+            main.extra_args.push("--update".to_string());
+        }
+        let overrides = RsyncOptions {
+            filter: Some(filters.protect.join(",")),
+            delete: self.delete_override,
+            ..base_options.clone()
+        };
+        let pull = RsyncOptions {
+            filter: Some(pull_filter(entry, &self.local_dir)),
+            delete: false,
+            ..base_options.clone()
+        };
+
+        let excludes = if home {
+            let mut excludes = entry.ignore_patterns.clone();
+            excludes.extend(entry.protected_paths.iter().cloned());
+            excludes
+        } else {
+            fallback_excludes(entry, &self.local_dir)
+        };
+
+        // `--transport tar` streams the first upload with tar, however small,
+        // and leaves later ones to rsync
+        let first_upload = entry.transport == TransportKind::Tar && state.last_push_at.is_none();
+        let tar_stream_min = if first_upload {
+            Some(1)
+        } else {
+            (entry.tar_stream || strategy.tar_stream).then_some(TAR_STREAM_MIN_FILES)
+        };
+
+        Ok(TransferOptions {
+            local_dir: self.local_dir.clone(),
+            base: base_options,
+            main,
+            overrides,
+            pull,
+            excludes,
+            protected: entry.protected_paths.clone(),
+            dedup: entry
+                .dedup_min_size
+                .map(|min_size| (preflight.remote_home.clone(), min_size)),
+            chunk_size: self.chunk_size,
+            tar_stream_min,
+            relay_cleanup: entry.relay_cleanup,
+            progress: self.progress.clone(),
+        })
+    }
+
+    // Send the project and override paths, or home mode's paths, with
+    // `transport`. The checks run the same whichever transport it is. None if
+    // the pre-sync hook script skipped the sync.
+    fn transfer(
+        &self,
+        entry: &RemoteEntry,
+        transport: &dyn Transport,
+        home_plan: Option<&HomePlan>,
+        state: &mut RemoteState,
+        hook_context: &mut HookContext,
+        timings: &mut Timings,
+    ) -> Result<Option<SyncReport>> {
+        let remote_full_dir = hook_context.remote_dir.clone();
+        if let Some(plan) = home_plan {
+            if !run_hook_script(HookStage::PreSync, hook_context)? {
+                return Ok(None);
+            }
+            timings.phase("main sync");
+            return transfer_home(entry, transport, plan, &remote_full_dir, &self.local_dir)
+                .map(Some);
+        }
+
+        // Project hook scripts get to see what's about to change, and may skip the sync
+        if has_hook_scripts(&self.local_dir) {
+            hook_context.changed_files = transport.pending(Upload::Project, &remote_full_dir)?;
+        }
+        if !run_hook_script(HookStage::PreSync, hook_context)? {
+            return Ok(None);
+        }
+
+        timings.phase("checks");
+
+        // Make sure we're not about to overwrite changes made by someone else.
+        // Syncing both ways brings those changes here instead.
+        if !self.force && !self.both_ways {
+            check_remote_staleness(entry, transport, &remote_full_dir, state, self.interactive)?;
+        }
+
+        // Verify the filters actually keep the transfers away from protected paths
+        if !entry.protected_paths.is_empty() {
+            verify_protected_paths(entry, transport, Upload::Project, &remote_full_dir)?;
+            for path in &entry.override_paths {
+                verify_protected_paths(entry, transport, Upload::Override(path), &remote_full_dir)?;
+            }
+        }
+
+        if let Some(max) = entry.max_deletes {
+            check_delete_count(transport, &remote_full_dir, max)?;
+        }
+
+        if self.both_ways {
+            timings.phase("pull");
+            transport.pull(&remote_full_dir, Pull::ChangedSince(state.last_push_at))?;
+        }
+
+        timings.phase("main sync");
+        let push_started = unix_millis() / 1000;
+        let mut report = transport.sync(Upload::Project, &remote_full_dir)?;
+        if !report.stopped() && !entry.override_paths.is_empty() {
+            timings.phase("override paths");
+            for path in &entry.override_paths {
+                report.append(transport.sync(Upload::Override(path), &remote_full_dir)?);
+                if report.stopped() {
+                    break;
+                }
+            }
+        }
+
+        record_transfer(entry, state, report.bytes_sent);
+        state.last_transferred = Some(report.files_transferred as usize);
+        state.last_deleted = Some(report.deleted as usize);
+        events::emit(
+            "transfer_finished",
+            json!({
+                "remote": entry.name,
+                "transferred": report.files_transferred,
+                "deleted": report.deleted,
+            }),
+        );
+
+        if report.stopped() {
+            // Part of the changes are on the remote now, which isn't someone
+            // else's doing, so don't warn about it next time
+            state.remote_fingerprint = transport.fingerprint(&remote_full_dir)?;
+            anyhow::bail!("Sync stopped at your request");
+        }
+        state.last_push_at = Some(push_started);
+
+        // Render templates with this remote's variables and sync them to their targets
+        if !entry.templates.is_empty() {
+            timings.phase("templates");
+            sync_templates(entry, transport, &remote_full_dir, &self.local_dir)?;
+        }
+
+        // Remember what the remote looks like now so foreign changes can be detected next time
+        timings.phase("checks");
+        state.remote_fingerprint = transport.fingerprint(&remote_full_dir)?;
+        transport.finish(&remote_full_dir)?;
+
+        Ok(Some(report))
+    }
+
+    fn run_own_hooks(&self, stage: HookStage, context: &HookContext) -> Result<()> {
+        for hook in &self.hooks {
+            hook.run(stage, context).context(format!(
+                "Hook '{}' failed ({})",
+                hook.name(),
+                stage.as_str()
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+// Key of `local_dir` in the cache and state files
+fn dir_key(local_dir: &Path) -> String {
+    path_key(
+        &local_dir
+            .canonicalize()
+            .unwrap_or_else(|_| local_dir.to_path_buf()),
+    )
+}
+
+// The entry as the machine's policy allows it for `target`, the machine its
+// host resolved to
+pub fn policy_for_target(remote_entry: &RemoteEntry, target: &RemoteTarget) -> Result<RemoteEntry> {
+    match load_policy()? {
+        Some(policy) => policy.apply_to_target(remote_entry, target),
+        None => Ok(remote_entry.clone()),
+    }
+}
+
+// Resolve the target and check the remote before syncing. Results are reused
+// for PREFLIGHT_TTL seconds (unless `fresh`), so quick successive runs skip
+// the SSH round trips.
+pub fn preflight(
+    remote_entry: &RemoteEntry,
+    fresh: bool,
+    state: &mut RemoteState,
+) -> Result<Preflight> {
+    let now = unix_millis() / 1000;
+    if let Some(preflight) = state
+        .preflight
+        .as_ref()
+        .filter(|preflight| !fresh && preflight.is_fresh(remote_entry, now))
+    {
+        return Ok(preflight.clone());
+    }
+
+    let target = RemoteTarget::from_entry(remote_entry)?;
+    let remote_home = get_remote_home(&target)?;
+    // Minimal containers and appliances often come without rsync
+    let rsync_version = remote_rsync_version(&target)?;
+    let has_rsync = rsync_version.is_some() || remote_has_rsync(&target)?;
+    let preflight = Preflight {
+        remote_host: remote_entry.remote_host.clone(),
+        ssh_options: remote_entry.ssh_options.clone(),
+        target,
+        remote_home,
+        has_rsync,
+        rsync_version,
+        checked_at: now,
+    };
+    state.preflight = Some(preflight.clone());
+
+    Ok(preflight)
+}
+
+// Add a transfer to the remote's history and warn when it takes the remote
+// past 80% of its monthly budget, or when the budget is already used up
+pub fn record_transfer(remote_entry: &RemoteEntry, state: &mut RemoteState, bytes: u64) {
+    let now = unix_millis() / 1000;
+    let before = state.transferred_in_month(now);
+    state.record_transfer(now, bytes);
+    let Some(budget) = remote_entry.monthly_budget else {
+        return;
+    };
+    let used = before + bytes;
+    if used >= budget {
+        warn(format_args!(
+            "{} has moved {} this month, over its budget of {}",
+            remote_entry.name,
+            format_size(used),
+            format_size(budget)
+        ));
+    } else if used * 5 >= budget * 4 && before * 5 < budget * 4 {
+        warn(format_args!(
+            "{} has moved {} of its {} monthly budget",
+            remote_entry.name,
+            format_size(used),
+            format_size(budget)
+        ));
+    }
+}
+
+// Refuse to push from the home directory, `/` or a never-sync path, where a
+// mistyped command would mirror (and --delete) far more than a project
+fn check_sync_source(local_dir: &Path) -> Result<()> {
+    let local_dir = fs::canonicalize(local_dir).unwrap_or_else(|_| local_dir.to_path_buf());
+    if settings().guarded_dirs().contains(&local_dir) {
+        anyhow::bail!(
+            "Refusing to sync from {}: it is your home directory, / or a never_sync path in {}. Use --force if you really mean it",
+            local_dir.display(),
+            get_settings_path()?.display()
+        );
+    }
+    Ok(())
+}
+
+// Send the paths home mode lists and render the templates among them for
+// this remote. Nothing is deleted.
+fn transfer_home(
+    remote_entry: &RemoteEntry,
+    transport: &dyn Transport,
+    plan: &HomePlan,
+    remote_full_dir: &str,
+    local_dir: &Path,
+) -> Result<SyncReport> {
+    for path in &plan.missing {
+        warn(format_args!(
+            "{} is listed under [home] in {}, but doesn't exist here",
+            path,
+            get_settings_path()?.display()
+        ));
+    }
+    let mut templated = remote_entry.clone();
+    templated.templates.extend(plan.templates.iter().cloned());
+    println!(
+        "Home mode: sending {} listed path(s) and {} template(s), nothing is deleted",
+        plan.paths.len(),
+        templated.templates.len()
+    );
+
+    let report = transport.sync(Upload::Home(plan), remote_full_dir)?;
+    if !templated.templates.is_empty() {
+        sync_templates(&templated, transport, remote_full_dir, local_dir)?;
+    }
+    transport.finish(remote_full_dir)?;
+    Ok(report)
+}
+
+// Warn about submodules the remote has checked out at other commits than
+// here, or not at all, e.g. when their `.git` isn't synced
+fn check_submodules(target: &RemoteTarget, remote_full_dir: &str, local_dir: &Path) -> Result<()> {
+    let local = submodule_status(local_dir);
+    if local.is_empty() {
+        return Ok(());
+    }
+    let remote = run_remote_script(
+        target,
+        &format!(
+            "cd {} && git submodule status 2>/dev/null || true",
+            shell_quote(remote_full_dir)
+        ),
+    )?;
+    let remote = parse_submodule_status(&remote);
+    if remote.is_empty() {
+        warn(format_args!(
+            "Can't check submodules on the remote: it has no git, or the directory isn't a git repository there"
+        ));
+        return Ok(());
+    }
+    let mismatches = submodule_mismatches(&local, &remote);
+    if !mismatches.is_empty() {
+        warn(format_args!(
+            "Submodules differ on the remote:\n  {}",
+            mismatches.join("\n  ")
+        ));
+    }
+    Ok(())
+}
+
+// Open the synced files up to `group`. rsync already sets the group and mode
+// of what it sends, so only a tar copy needs that done afterwards; ACLs are
+// always set here.
+fn share_with_group(
+    target: &RemoteTarget,
+    remote_full_dir: &str,
+    group: &str,
+    acl: bool,
+    tar_copy: bool,
+) -> Result<()> {
+    let dir = shell_quote(remote_full_dir);
+    let group = shell_quote(group);
+    let mut script = String::new();
+    if tar_copy {
+        script.push_str(&format!(
+            "chgrp -R {group} {dir} && chmod -R g+rwX {dir} && find {dir} -type d -exec chmod g+s {{}} + || exit 1\n"
+        ));
+    }
+    if acl {
+        script.push_str(&format!(
+            "find {dir} -type d -exec setfacl -m g:{group}:rwx,d:g:{group}:rwx {{}} + || exit 1\n"
+        ));
+    }
+    if script.is_empty() {
+        return Ok(());
+    }
+
+    run_remote_script(target, &script)
+        .map(|_| ())
+        .context(format!(
+            "Failed to share {} with group {}",
+            remote_full_dir, group
+        ))
+}
+
+// What uploads without rsync leave out of the project: ignored and
+// protected paths, and nested repositories as configured
+fn fallback_excludes(remote_entry: &RemoteEntry, local_dir: &Path) -> Vec<String> {
+    let mut excludes = remote_entry.ignore_patterns.clone();
+    excludes.extend(remote_entry.protected_paths.iter().cloned());
+    excludes.extend(remote_entry.nested_repos.excluded_paths(local_dir));
+    excludes
+}
+
+// Filter for copying from the remote into `local_dir`: ignore patterns
+// apply, protected paths don't, and rendered templates stay on the remote
+pub fn pull_filter(remote_entry: &RemoteEntry, local_dir: &Path) -> String {
+    let mut filter_strings = vec![String::from(":- .gitignore")];
+    for pattern in &remote_entry.ignore_patterns {
+        filter_strings.push(format!("- {}", pattern));
+    }
+    filter_strings.push(format!("- /{}/", TRASH_DIR));
+    filter_strings.extend(remote_entry.nested_repos.filter_rules(local_dir));
+    if remote_entry.initialized_submodules_only {
+        filter_strings.extend(uninitialized_submodule_rules(local_dir));
+    }
+    // Rendered templates are the remote's own, the local ones stay as they are
+    for template in &remote_entry.templates {
+        filter_strings.push(format!("- /{}", template.target.trim_start_matches('/')));
+    }
+    filter_strings.join(",")
+}
+
+// Warn if the remote changed since our last sync and, when interactive, let
+// the user decide how to proceed
+fn check_remote_staleness(
+    remote_entry: &RemoteEntry,
+    transport: &dyn Transport,
+    remote_full_dir: &str,
+    state: &RemoteState,
+    interactive: bool,
+) -> Result<()> {
+    let Some(last_fingerprint) = state.remote_fingerprint.as_deref() else {
+        return Ok(());
+    };
+
+    let current_fingerprint = transport.fingerprint(remote_full_dir)?;
+    if current_fingerprint.as_deref() == Some(last_fingerprint) {
+        return Ok(());
+    }
+
+    warn(format_args!(
+        "{} has changed since your last sync (someone else may have pushed or edited files in place)",
+        remote_entry.name
+    ));
+
+    if !interactive || !io::stdin().is_terminal() {
+        anyhow::bail!(
+            "Refusing to overwrite a modified remote non-interactively; use --force to sync anyway"
+        );
+    }
+
+    loop {
+        let mut choice = String::new();
+        print!("[c]ontinue, [p]ull remote changes first, show [d]iff, or [a]bort? ");
+        io::stdout().flush()?;
+        io::stdin().read_line(&mut choice)?;
+
+        match choice.trim() {
+            "c" => return Ok(()),
+            "p" => {
+                println!("Pulling remote changes from {}", remote_full_dir);
+                transport.pull(remote_full_dir, Pull::All)?;
+                return Ok(());
+            }
+            "d" => {
+                println!("Changes a sync would make to {}:", remote_full_dir);
+                for line in planned_changes(transport, Upload::Project, remote_full_dir)? {
+                    println!("  {}", line);
+                }
+            }
+            "a" | "" => anyhow::bail!("Sync aborted"),
+            _ => println!("Invalid choice"),
+        }
+    }
+}
+
+// What sending `upload` would change, for the checks that have to see it
+// first. A transport that can't tell may only go ahead if it deletes
+// nothing, as it leaves ignored and protected paths out on its own.
+fn planned_changes(
+    transport: &dyn Transport,
+    upload: Upload,
+    remote_full_dir: &str,
+) -> Result<Vec<String>> {
+    match transport.changes(upload, remote_full_dir)? {
+        Some(changes) => Ok(changes),
+        None if !transport.deletes() => Ok(Vec::new()),
+        None => anyhow::bail!(
+            "Can't tell beforehand what this sync would change ({}), so it can't be checked",
+            transport.describe()
+        ),
+    }
+}
+
+// Dry-run the sync and refuse to continue if it would delete more than `max` files
+fn check_delete_count(transport: &dyn Transport, remote_full_dir: &str, max: usize) -> Result<()> {
+    let deletions = planned_changes(transport, Upload::Project, remote_full_dir)?
+        .iter()
+        .filter(|line| line.starts_with("*deleting"))
+        .count();
+
+    if deletions > max {
+        anyhow::bail!(
+            "Sync would delete {} files on the remote, more than the limit of {}",
+            deletions,
+            max
+        );
+    }
+
+    Ok(())
+}
+
+// Dry-run a transfer and refuse to continue if it would touch a protected path
+fn verify_protected_paths(
+    remote_entry: &RemoteEntry,
+    transport: &dyn Transport,
+    upload: Upload,
+    remote_full_dir: &str,
+) -> Result<()> {
+    let violations: Vec<String> = planned_changes(transport, upload, remote_full_dir)?
+        .into_iter()
+        .filter(|line| {
+            itemized_path(line).is_some_and(|path| {
+                remote_entry
+                    .protected_paths
+                    .iter()
+                    .any(|pattern| path_matches(pattern, path))
+            })
+        })
+        .collect();
+
+    if !violations.is_empty() {
+        anyhow::bail!(
+            "Sync would modify protected paths, aborting:\n  {}",
+            violations.join("\n  ")
+        );
+    }
+
+    Ok(())
+}
+
+// Render the entry's templates from `local_dir` into a staging directory
+// and send its contents over
+fn sync_templates(
+    remote_entry: &RemoteEntry,
+    transport: &dyn Transport,
+    remote_full_dir: &str,
+    local_dir: &Path,
+) -> Result<()> {
+    let mut vars = BTreeMap::from([
+        ("name".to_string(), remote_entry.name.clone()),
+        ("remote_host".to_string(), remote_entry.remote_host.clone()),
+        ("remote_dir".to_string(), remote_full_dir.to_string()),
+    ]);
+    vars.extend(remote_entry.template_vars.clone());
+
+    let staging_dir = env::temp_dir().join(format!("sync-rs-templates-{}", std::process::id()));
+    let result = render_templates(local_dir, &remote_entry.templates, &vars, &staging_dir)
+        .and_then(|_| {
+            println!(
+                "Syncing {} rendered template(s)",
+                remote_entry.templates.len()
+            );
+            let staging = staging_dir.to_string_lossy();
+            transport.sync(Upload::Contents(&staging), remote_full_dir)?;
+            Ok(())
+        });
+    let _ = fs::remove_dir_all(&staging_dir);
+
+    result
+}
+
+// Print the files a transfer would send or delete, leaving out changes to
+// attributes only, and return how many there are
+fn print_dry_run(source: &str, changes: Vec<String>) -> usize {
+    let changes: Vec<String> = changes
+        .into_iter()
+        .filter(|line| !line.starts_with('.'))
+        .collect();
+    if changes.is_empty() {
+        return 0;
+    }
+
+    let deletions = changes
+        .iter()
+        .filter(|line| line.starts_with("*deleting"))
+        .count();
+    println!(
+        "{}: {} to transfer, {} to delete",
+        bold(source),
+        changes.len() - deletions,
+        deletions
+    );
+    for line in &changes {
+        println!("  {}", line);
+    }
+    changes.len()
+}
+
+// Filter rules for a sync: the ones for the project itself and the ones for
+// override paths, which only keep rsync away from what the remote owns
+pub struct SyncFilters {
+    pub main: Vec<String>,
+    pub protect: Vec<String>,
+}
+
+impl SyncFilters {
+    pub fn new(entry: &RemoteEntry, local_dir: &Path) -> Self {
+        // Start with .gitignore filter
+        let mut main = vec![String::from(":- .gitignore")];
+
+        // Add additional ignore patterns
+        for pattern in &entry.ignore_patterns {
+            // Format as rsync exclude pattern
+            main.push(format!("- {}", pattern));
+        }
+        main.extend(entry.nested_repos.filter_rules(local_dir));
+        if entry.initialized_submodules_only {
+            main.extend(uninitialized_submodule_rules(local_dir));
+        }
+
+        // Protected paths are neither sent (so never overwritten) nor deleted
+        let mut protect = Vec::new();
+        for pattern in &entry.protected_paths {
+            protect.push(format!("P {}", pattern));
+            protect.push(format!("- {}", pattern));
+        }
+        // The trash is the remote's own, even after --trash is turned off
+        protect.push(format!("- /{}/", TRASH_DIR));
+        main.extend(protect.iter().cloned());
+
+        // Rendered templates own their targets, so keep local copies from being synced over them
+        for template in &entry.templates {
+            main.push(format!("P /{}", template.target.trim_start_matches('/')));
+            main.push(format!("- /{}", template.target.trim_start_matches('/')));
+        }
+
+        Self { main, protect }
+    }
+}
+
+// rsync arguments following from the remote's own settings, for a sync
+// starting at `unix`
+pub fn entry_rsync_args(
+    entry: &RemoteEntry,
+    remote_version: Option<RsyncVersion>,
+    unix: u64,
+) -> Vec<String> {
+    let mut args = Vec::new();
+    if entry.auto_create && mkpath_supported(remote_version) {
+        args.push("--mkpath".to_string());
+    }
+    // Mapped owners only take when the receiving rsync runs as root. `-n`
+    // makes sudo fail instead of waiting for a password nobody can type.
+    if entry.sudo_rsync {
+        args.push("--rsync-path=sudo -n rsync".to_string());
+    }
+    if !entry.usermap.is_empty() {
+        args.push(format!("--usermap={}", entry.usermap.join(",")));
+    }
+    if !entry.groupmap.is_empty() {
+        args.push(format!("--groupmap={}", entry.groupmap.join(",")));
+    }
+    if let Some(group) = &entry.shared_group {
+        args.push(format!("--chown=:{}", group));
+        args.push("--chmod=Dg+rwxs,Fg+rw".to_string());
+    }
+    if entry.trash {
+        args.push("--backup".to_string());
+        args.push(format!("--backup-dir={}/{}", TRASH_DIR, trash_batch(unix)));
+    }
+    args
+}
+
+// Name of the trash directory for files replaced by a sync starting at
+// `unix`, in local time so that it sorts and reads well
+pub fn trash_batch(unix: u64) -> String {
+    listing_time(unix)
+        .replace('/', "-")
+        .replace(' ', "_")
+        .replace(':', "-")
+}
+
+// `cmd` run in `remote_full_dir` (created first if `create`) with the
+// remote's shell. A missing directory exits with `MISSING_DIR_EXIT`.
+pub fn in_remote_dir(
+    remote_entry: &RemoteEntry,
+    remote_full_dir: &str,
+    cmd: &str,
+    create: bool,
+) -> String {
+    let dir = shell_quote(remote_full_dir);
+    let enter_dir = if create {
+        format!("mkdir -p {} && cd {}", dir, dir)
+    } else {
+        format!("cd {}", dir)
+    };
+    let mut full_command = format!("{} || exit {}\n{}", enter_dir, MISSING_DIR_EXIT, cmd);
+    // The login shell then only has a single-quoted string to pass on, which
+    // sh, bash, zsh and fish all read the same way
+    if let Some(shell) = &remote_entry.remote_shell {
+        full_command = format!("{} -c {}", shell, shell_quote(&full_command));
+    }
+    full_command
+}
+
+pub fn run_post_command(
+    remote_entry: &RemoteEntry,
+    cmd: &str,
+    target: &RemoteTarget,
+    remote_full_dir: &str,
+) -> Result<()> {
+    println!("{}", tr("sync.post_command", &[&cmd]));
+    let full_command = in_remote_dir(remote_entry, remote_full_dir, cmd, remote_entry.auto_create);
+    let result = target
+        .command(&full_command)
+        .status()
+        .context("Failed to execute SSH command")
+        .and_then(|status| match status.code() {
+            _ if status.success() => Ok(()),
+            Some(MISSING_DIR_EXIT) => Err(anyhow::Error::new(SyncError::RemoteDirMissing {
+                dir: remote_full_dir.to_string(),
+            })
+            .context("The post-sync command didn't run (pass --auto-create to create the remote directory)")),
+            code => Err(anyhow::anyhow!(
+                "Post-sync command failed with exit code: {:?}",
+                code
+            )),
+        });
+    events::emit(
+        "hook_finished",
+        json!({
+            "remote": remote_entry.name,
+            "command": cmd,
+            "success": result.is_ok(),
+            "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+        }),
+    );
+    result
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_keep_override_paths_off_protected_paths() {
+        let entry = RemoteEntry {
+            ignore_patterns: vec!["*.log".to_string()],
+            protected_paths: vec!["/outputs".to_string()],
+            ..Default::default()
+        };
+        let filters = SyncFilters::new(&entry, Path::new("/nonexistent"));
+        assert_eq!(
+            filters.main,
+            [
+                ":- .gitignore",
+                "- *.log",
+                "P /outputs",
+                "- /outputs",
+                "- /.sync-rs-trash/"
+            ]
+        );
+        assert_eq!(
+            filters.protect,
+            ["P /outputs", "- /outputs", "- /.sync-rs-trash/"]
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::cache::get_config_dir;
+use crate::config::RemoteEntry;
+use crate::report::ReportSettings;
+use crate::term::warn;
+use crate::toml;
+
+// User-wide settings from ~/.config/sync-rs/config.toml, applying to every
+// remote (unlike the per-directory remote configurations in the cache)
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub ssh: SshSettings,
+    pub defaults: DefaultSettings,
+    pub report: ReportSettings,
+    // Local directories never to sync from (besides home and `/`), with `~`
+    // for the home directory
+    pub never_sync: Vec<String>,
+    // Shorthands for argument lists, e.g. `alias.deploy = "-n prod --yes"`,
+    // used like git aliases: `sync-rs deploy -s`
+    pub alias: BTreeMap<String, String>,
+    pub home: HomeSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshSettings {
+    // ssh executable to use instead of `ssh` from PATH, e.g. a wrapper
+    pub binary: Option<String>,
+    // `-o KEY=VALUE` options for every connection. A remote's own options
+    // come first, so they win over these.
+    pub options: Vec<String>,
+    // Run ssh commands with the built-in client instead of the ssh binary,
+    // in builds with the `native-ssh` feature
+    pub native: bool,
+}
+
+// Home mode: syncing from the home directory sends just these files and
+// directories, relative to it, instead of being refused
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomeSettings {
+    pub files: Vec<String>,
+}
+
+// Settings every remote starts from, under what it sets itself
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultSettings {
+    // Ignored for every remote, in addition to the remote's own patterns
+    pub ignore: Vec<String>,
+    // Extra rsync arguments for every transfer, e.g. `--compress-level=9`
+    pub rsync_args: Vec<String>,
+    // Post-sync behaviour for remotes that don't set their own
+    pub post_command_cooldown: Option<u64>,
+    pub post_command_coalesce: bool,
+    pub remote_shell: Option<String>,
+    pub auto_create: bool,
+}
+
+impl DefaultSettings {
+    // Fill in what `entry` leaves unset. The result isn't meant to be saved,
+    // so that editing the defaults changes every remote.
+    pub fn apply(&self, entry: &mut RemoteEntry) {
+        let mut ignore_patterns: Vec<String> = self
+            .ignore
+            .iter()
+            .filter(|pattern| !entry.ignore_patterns.contains(pattern))
+            .cloned()
+            .collect();
+        ignore_patterns.append(&mut entry.ignore_patterns);
+        entry.ignore_patterns = ignore_patterns;
+        entry.post_command_cooldown = entry.post_command_cooldown.or(self.post_command_cooldown);
+        entry.post_command_coalesce |= self.post_command_coalesce;
+        if entry.remote_shell.is_none() {
+            entry.remote_shell = self.remote_shell.clone();
+        }
+        entry.auto_create |= self.auto_create;
+    }
+}
+
+impl SshSettings {
+    pub fn binary(&self) -> &str {
+        self.binary.as_deref().unwrap_or("ssh")
+    }
+}
+
+impl Settings {
+    // Directories a sync must not start from without --force: pushing
+    // everything under them with --delete is almost never intended
+    pub fn guarded_dirs(&self) -> Vec<PathBuf> {
+        let home = dirs::home_dir();
+        let mut dirs = vec![PathBuf::from("/")];
+        dirs.extend(home.clone());
+        dirs.extend(
+            self.never_sync
+                .iter()
+                .map(|path| match (path.strip_prefix('~'), &home) {
+                    (Some(rest), Some(home)) => home.join(rest.trim_start_matches('/')),
+                    _ => PathBuf::from(path),
+                }),
+        );
+        // Resolved paths, so that symlinks can't get around the guard
+        dirs.into_iter()
+            .map(|dir| fs::canonicalize(&dir).unwrap_or(dir))
+            .collect()
+    }
+
+    // `args` with a leading alias replaced by what it stands for. Aliases can
+    // refer to other aliases, but not to themselves, and can't shadow the
+    // built-in commands in `builtins`.
+    pub fn expand_alias(
+        &self,
+        mut args: Vec<String>,
+        builtins: impl Fn(&str) -> bool,
+    ) -> Result<Vec<String>> {
+        let mut seen = Vec::new();
+        while let Some(expansion) = args.first().and_then(|name| self.alias.get(name)) {
+            let name = args[0].clone();
+            if builtins(&name) {
+                warn(format_args!(
+                    "Alias '{}' has the name of a built-in command and is ignored",
+                    name
+                ));
+                break;
+            }
+            if seen.contains(&name) {
+                anyhow::bail!("Alias '{}' refers to itself", name);
+            }
+
+            let words = split_words(expansion).context(format!("Invalid alias '{}'", name))?;
+            args.splice(..1, words);
+            seen.push(name);
+        }
+        Ok(args)
+    }
+}
+
+// Split a command line into words as a POSIX shell would, honoring single
+// and double quotes and backslash escapes (but no expansions)
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\\' => {
+                let escaped = chars.next().context("Trailing backslash")?;
+                word.get_or_insert_default().push(escaped);
+            }
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next().context("Unterminated single quote")? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next().context("Unterminated double quote")? {
+                        '"' => break,
+                        '\\' => {
+                            let escaped = chars.next().context("Unterminated double quote")?;
+                            if !matches!(escaped, '"' | '\\' | '$' | '`') {
+                                word.push('\\');
+                            }
+                            word.push(escaped);
+                        }
+                        c => word.push(c),
+                    }
+                }
+            }
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+pub fn get_settings_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("config.toml"))
+}
+
+pub fn read_settings() -> Result<Settings> {
+    let path = get_settings_path()?;
+    if !path.exists() {
+        return Ok(Settings::default());
+    }
+
+    let data = fs::read_to_string(&path).context("Failed to read settings file")?;
+    let value = toml::parse(&data).context(format!("Failed to parse {}", path.display()))?;
+    serde_json::from_value(value).context(format!("Invalid settings in {}", path.display()))
+}
+
+// The settings, loaded on first use. A broken file is reported and ignored
+// so that it can't lock you out of commands that would help fix it.
+pub fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(|| {
+        read_settings().unwrap_or_else(|e| {
+            warn(format_args!("{:#}", e));
+            Settings::default()
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_split_like_a_shell() {
+        assert_eq!(
+            split_words(r#"-n prod  -p "make test" --var 'A=b c' x\ y "" "q\"t""#).unwrap(),
+            [
+                "-n",
+                "prod",
+                "-p",
+                "make test",
+                "--var",
+                "A=b c",
+                "x y",
+                "",
+                "q\"t"
+            ]
+        );
+        assert!(split_words("'open").is_err());
+    }
+
+    #[test]
+    fn defaults_go_under_the_remote_settings() {
+        let defaults = DefaultSettings {
+            ignore: vec!["target".to_string(), ".venv".to_string()],
+            post_command_cooldown: Some(30),
+            remote_shell: Some("bash".to_string()),
+            ..Default::default()
+        };
+        let mut entry = RemoteEntry {
+            ignore_patterns: vec![".venv".to_string(), "data".to_string()],
+            post_command_cooldown: Some(5),
+            ..Default::default()
+        };
+        defaults.apply(&mut entry);
+        assert_eq!(entry.ignore_patterns, ["target", ".venv", "data"]);
+        assert_eq!(entry.post_command_cooldown, Some(5));
+        assert_eq!(entry.remote_shell.as_deref(), Some("bash"));
+        assert!(!entry.auto_create);
+    }
+
+    #[test]
+    fn aliases_expand_in_front() {
+        let settings = Settings {
+            alias: BTreeMap::from([
+                ("deploy".to_string(), "-n prod --yes".to_string()),
+                ("ship".to_string(), "deploy -s".to_string()),
+                ("loop".to_string(), "loop".to_string()),
+                ("list".to_string(), "-l".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let builtin = |name: &str| name == "list";
+
+        assert_eq!(
+            settings
+                .expand_alias(args(&["ship", "-P"]), builtin)
+                .unwrap(),
+            args(&["-n", "prod", "--yes", "-s", "-P"])
+        );
+        assert_eq!(
+            settings.expand_alias(args(&["list"]), builtin).unwrap(),
+            args(&["list"])
+        );
+        assert!(settings.expand_alias(args(&["loop"]), builtin).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use crate::glob::path_matches;
+use crate::remote::{shell_quote, RemoteTarget};
+use crate::runner;
+use crate::sync::git_listed_files;
+use crate::term::warn;
+use crate::walk::walk_tree;
+
+// A file to send, relative to the directory uploads start from
+#[derive(Debug, Clone, PartialEq)]
+struct LocalFile {
+    path: String,
+    size: u64,
+    mtime: i64,
+}
+
+// Whether the `sftp` client is on PATH
+pub fn sftp_available() -> bool {
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join("sftp").is_file()))
+}
+
+// Copy `source` into `remote_dir` through the remote's SFTP server, for
+// hosts without rsync. Like `tar_upload`, a source of `.` or ending in `/`
+// copies its contents, otherwise the file or directory itself. Only files
+// whose size or modification time differ from the remote copy are sent,
+// with their times kept so the next upload can tell, and nothing is
+// deleted on the remote. Returns the number of files and bytes sent.
+pub fn sftp_upload(
+    target: &RemoteTarget,
+    source: &str,
+    remote_dir: &str,
+    respect_gitignore: bool,
+    excludes: &[String],
+) -> Result<(usize, u64)> {
+    let Some(mut sftp) = target.sftp_command() else {
+        anyhow::bail!("SFTP only works with SSH remotes");
+    };
+    let (base, files, unnamed) = local_files(source, respect_gitignore, excludes)?;
+    if unnamed > 0 {
+        warn(format_args!(
+            "Leaving out {} file(s) whose names aren't valid UTF-8, which SFTP can't send",
+            unnamed
+        ));
+    }
+    let remote = remote_files(target, remote_dir);
+    let changed: Vec<&LocalFile> = files
+        .iter()
+        .filter(|file| remote.get(&file.path) != Some(&(file.size, file.mtime)))
+        .collect();
+    if changed.is_empty() {
+        return Ok((0, 0));
+    }
+
+    println!("Sending {} changed file(s) over SFTP", changed.len());
+    let mut child = sftp
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Failed to execute sftp")?;
+    child
+        .stdin
+        .take()
+        .context("Failed to open sftp input")?
+        .write_all(batch_script(&base, remote_dir, &changed).as_bytes())
+        .context("Failed to send commands to sftp")?;
+    let status = child.wait().context("Failed to wait for sftp")?;
+    if !status.success() {
+        anyhow::bail!("sftp failed with exit code: {:?}", status.code());
+    }
+    Ok((changed.len(), changed.iter().map(|file| file.size).sum()))
+}
+
+// Each file a copy of `source` takes along, picked like `sftp_upload` picks
+// them, with its size. For counting what a full copy with tar sends.
+pub fn local_file_sizes(
+    source: &str,
+    respect_gitignore: bool,
+    excludes: &[String],
+) -> Result<Vec<(String, u64)>> {
+    let (_, files, _) = local_files(source, respect_gitignore, excludes)?;
+    Ok(files
+        .into_iter()
+        .map(|file| (file.path, file.size))
+        .collect())
+}
+
+// The directory paths are relative to, the files below it to consider, and
+// how many were left out for names that aren't valid UTF-8
+fn local_files(
+    source: &str,
+    respect_gitignore: bool,
+    excludes: &[String],
+) -> Result<(PathBuf, Vec<LocalFile>, usize)> {
+    let path = Path::new(source);
+    let (base, paths) = if source == "." || source.ends_with('/') {
+        // Let git decide what's ignored when possible, so .gitignore is honoured
+        let listed = respect_gitignore
+            .then(|| git_listed_files(path))
+            .flatten()
+            .map(|list| {
+                list.split(|&byte| byte == 0)
+                    .filter(|name| !name.is_empty())
+                    .map(|name| PathBuf::from(String::from_utf8_lossy(name).into_owned()))
+                    .collect()
+            });
+        (
+            path.to_path_buf(),
+            listed.unwrap_or_else(|| walked_files(path, Path::new(""))),
+        )
+    } else {
+        let name = path
+            .file_name()
+            .context(format!("Invalid source path '{}'", source))?;
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+        let paths = if path.is_dir() {
+            walked_files(path, Path::new(name))
+        } else {
+            vec![PathBuf::from(name)]
+        };
+        (parent.unwrap_or(Path::new(".")).to_path_buf(), paths)
+    };
+
+    let mut files = Vec::new();
+    let mut unnamed = 0;
+    for path in paths {
+        let Some(name) = path.to_str() else {
+            unnamed += 1;
+            continue;
+        };
+        if excludes.iter().any(|pattern| path_matches(pattern, name)) {
+            continue;
+        }
+        // Submodules and broken links
+        let Ok(metadata) = fs::metadata(base.join(&path)) else {
+            continue;
+        };
+        if metadata.is_file() {
+            files.push(LocalFile {
+                path: name.to_string(),
+                size: metadata.len(),
+                mtime: metadata.mtime(),
+            });
+        }
+    }
+    Ok((base, files, unnamed))
+}
+
+// Files below `root`, as paths starting with `prefix`
+fn walked_files(root: &Path, prefix: &Path) -> Vec<PathBuf> {
+    walk_tree(root, |path| path != Path::new(".git"))
+        .into_iter()
+        .filter(|entry| !entry.is_dir)
+        .map(|entry| prefix.join(entry.path))
+        .collect()
+}
+
+// Size and modification time of each file below `remote_dir`. Empty if the
+// directory doesn't exist yet, or the remote has no shell or GNU-style
+// `stat`, in which case every file is sent.
+fn remote_files(target: &RemoteTarget, remote_dir: &str) -> HashMap<String, (u64, i64)> {
+    let listing = runner::output(
+        target
+            .command(&format!(
+                "cd {} && find . -type f -exec stat -c '%s %Y %n' {{}} +",
+                shell_quote(remote_dir)
+            ))
+            .stderr(Stdio::null()),
+    );
+    let Some(output) = listing.ok().filter(|output| output.status.success()) else {
+        return HashMap::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let size = fields.next()?.parse().ok()?;
+            let mtime = fields.next()?.parse().ok()?;
+            let path = fields.next()?.strip_prefix("./")?;
+            Some((path.to_string(), (size, mtime)))
+        })
+        .collect()
+}
+
+// Commands for `sftp -b` creating the directories needed and sending the
+// files. A leading `-` lets a command fail, e.g. `mkdir` of a directory
+// that exists.
+fn batch_script(base: &Path, remote_dir: &str, files: &[&LocalFile]) -> String {
+    let remote_dir = remote_dir.trim_end_matches('/');
+    // sftp has no `mkdir -p`, so every level is made
+    let mut dirs = BTreeSet::new();
+    let remote_files = files
+        .iter()
+        .map(|file| format!("{}/{}", remote_dir, file.path))
+        .collect::<Vec<_>>();
+    for path in &remote_files {
+        for dir in Path::new(path).ancestors().skip(1) {
+            if dir.parent().is_some() && !dir.as_os_str().is_empty() {
+                dirs.insert(dir.display().to_string());
+            }
+        }
+    }
+
+    // Sorted, so each directory comes after its parent
+    let mut script = String::new();
+    for dir in dirs {
+        script.push_str(&format!("-mkdir {}\n", quote(&dir)));
+    }
+    for (file, remote_file) in files.iter().zip(&remote_files) {
+        script.push_str(&format!(
+            "put -p {} {}\n",
+            quote(&base.join(&file.path).to_string_lossy()),
+            quote(remote_file)
+        ));
+    }
+    script
+}
+
+// An argument of an sftp command, which keeps spaces and glob characters
+// inside double quotes
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_files_become_a_batch() {
+        let file = |path: &str| LocalFile {
+            path: path.to_string(),
+            size: 1,
+            mtime: 0,
+        };
+        let files = [file("src/app/main.py"), file("my \"notes\".txt")];
+        let files: Vec<&LocalFile> = files.iter().collect();
+        assert_eq!(
+            batch_script(Path::new("."), "/home/me/app/", &files),
+            "-mkdir \"/home\"
+-mkdir \"/home/me\"
+-mkdir \"/home/me/app\"
+-mkdir \"/home/me/app/src\"
+-mkdir \"/home/me/app/src/app\"
+put -p \"./src/app/main.py\" \"/home/me/app/src/app/main.py\"
+put -p \"./my \\\"notes\\\".txt\" \"/home/me/app/my \\\"notes\\\".txt\"
+"
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::cache::get_config_dir;
+use crate::config::RemoteEntry;
+
+// A named, point-in-time copy of one directory's remote configurations
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    // Unix time the snapshot was taken
+    pub created_at: u64,
+    pub entries: Vec<RemoteEntry>,
+}
+
+// Directory -> snapshot name -> snapshot
+pub type SnapshotMap = HashMap<String, BTreeMap<String, Snapshot>>;
+
+pub fn get_snapshots_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("snapshots.json"))
+}
+
+pub fn read_snapshots(path: &Path) -> Result<SnapshotMap> {
+    if !path.exists() {
+        return Ok(SnapshotMap::new());
+    }
+
+    let data = fs::read(path).context("Failed to read snapshots file")?;
+    serde_json::from_slice(&data).context("Failed to parse snapshots file")
+}
+
+pub fn save_snapshots(path: &Path, snapshots: &SnapshotMap) -> Result<()> {
+    let file = File::create(path).context("Failed to create snapshots file")?;
+    serde_json::to_writer_pretty(file, snapshots).context("Failed to write snapshots file")
+}
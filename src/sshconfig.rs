@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use std::fs;
+
+use crate::config::RemoteEntry;
+use crate::net::SshHost;
+use crate::remote::RemoteTarget;
+
+// A `Host` of ~/.ssh/config that can be turned into a remote entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshConfigHost {
+    pub alias: String,
+    // `user@host` from User and HostName, falling back to the alias
+    pub remote_host: String,
+    pub ssh_options: Vec<String>,
+}
+
+// ssh_config keywords worth keeping on the entry, so that it works the same
+// without the config file, e.g. on a teammate's machine
+const KEPT_SSH_OPTIONS: &[&str] = &[
+    "Port",
+    "IdentityFile",
+    "IdentitiesOnly",
+    "CertificateFile",
+    "ProxyJump",
+    "ProxyCommand",
+    "UserKnownHostsFile",
+    "StrictHostKeyChecking",
+];
+
+// Hosts of ~/.ssh/config, or none if there's no such file
+pub fn ssh_config_hosts() -> Result<Vec<SshConfigHost>> {
+    let path = dirs::home_dir()
+        .context("Failed to find home directory")?
+        .join(".ssh")
+        .join("config");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let config = fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+    Ok(parse_ssh_config(&config))
+}
+
+#[derive(Default)]
+struct HostBlock {
+    aliases: Vec<String>,
+    host_name: Option<String>,
+    user: Option<String>,
+    ssh_options: Vec<String>,
+}
+
+// Hosts with plain names, in the order of the file. Patterns like `*` or
+// `*.internal` only hold defaults for other hosts and aren't offered, and
+// `Match` blocks are skipped, as are `Include`d files.
+fn parse_ssh_config(config: &str) -> Vec<SshConfigHost> {
+    let mut blocks: Vec<HostBlock> = Vec::new();
+    let mut in_host = false;
+
+    for line in config.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Keywords are case-insensitive and may be followed by `=`
+        let Some((key, value)) = line.split_once(|c: char| c.is_whitespace() || c == '=') else {
+            continue;
+        };
+        let value = value
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+            .trim()
+            .trim_matches('"')
+            .to_string();
+
+        if key.eq_ignore_ascii_case("Host") {
+            in_host = true;
+            blocks.push(HostBlock {
+                aliases: value
+                    .split_whitespace()
+                    .filter(|alias| !alias.contains(['*', '?', '!']))
+                    .map(str::to_string)
+                    .collect(),
+                ..Default::default()
+            });
+        } else if key.eq_ignore_ascii_case("Match") {
+            in_host = false;
+        } else if let Some(block) = blocks.last_mut().filter(|_| in_host) {
+            // The first value given for a keyword is the one ssh uses
+            if key.eq_ignore_ascii_case("HostName") {
+                block.host_name.get_or_insert(value);
+            } else if key.eq_ignore_ascii_case("User") {
+                block.user.get_or_insert(value);
+            } else if let Some(option) = KEPT_SSH_OPTIONS
+                .iter()
+                .find(|option| option.eq_ignore_ascii_case(key))
+            {
+                block.ssh_options.push(format!("{}={}", option, value));
+            }
+        }
+    }
+
+    let mut hosts = Vec::new();
+    for block in blocks {
+        for alias in &block.aliases {
+            let host_name = block.host_name.as_deref().unwrap_or(alias);
+            hosts.push(SshConfigHost {
+                alias: alias.clone(),
+                remote_host: match &block.user {
+                    Some(user) => format!("{}@{}", user, host_name),
+                    None => host_name.to_string(),
+                },
+                ssh_options: block.ssh_options.clone(),
+            });
+        }
+    }
+    hosts
+}
+
+// An ssh_config `Host` block for the machine `entry` syncs to, named after
+// the remote and its aliases, so that `ssh NAME` gets there too. None for
+// remotes plain ssh can't reach: containers, pods, plugin targets, and
+// hosts looked up or connected to by a command.
+pub fn host_block(entry: &RemoteEntry) -> Option<String> {
+    if entry.host_command.is_some() || entry.connect_command.is_some() {
+        return None;
+    }
+    let RemoteTarget::Ssh { host, .. } = RemoteTarget::parse(&entry.remote_host) else {
+        return None;
+    };
+    let host = SshHost::parse(&host);
+
+    let mut names = vec![entry.name.as_str()];
+    names.extend(entry.aliases.iter().map(String::as_str));
+    let mut lines = vec![
+        format!("Host {}", names.join(" ")),
+        format!("    HostName {}", host.host),
+    ];
+    if let Some(user) = &host.user {
+        lines.push(format!("    User {}", user));
+    }
+    if let Some(port) = host.port {
+        lines.push(format!("    Port {}", port));
+    }
+    if let Some(relay) = &entry.relay {
+        lines.push(format!("    ProxyJump {}", relay));
+    }
+    // Options are kept as for `ssh -o`, `KEY=VALUE` or `KEY VALUE`
+    for option in &entry.ssh_options {
+        let Some((key, value)) = option.split_once(|c: char| c.is_whitespace() || c == '=') else {
+            continue;
+        };
+        let value = value.trim_start_matches(|c: char| c.is_whitespace() || c == '=');
+        if value.contains(char::is_whitespace) {
+            lines.push(format!("    {} \"{}\"", key, value));
+        } else {
+            lines.push(format!("    {} {}", key, value));
+        }
+    }
+    Some(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remotes_become_host_blocks() {
+        let entry = RemoteEntry {
+            name: "gpu-box".to_string(),
+            aliases: vec!["g".to_string()],
+            remote_host: "alice@10.0.0.5:2222".to_string(),
+            relay: Some("bastion".to_string()),
+            ssh_options: vec![
+                "IdentityFile=~/.ssh/gpu key".to_string(),
+                "IdentitiesOnly yes".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            host_block(&entry).unwrap(),
+            "Host gpu-box g
+    HostName 10.0.0.5
+    User alice
+    Port 2222
+    ProxyJump bastion
+    IdentityFile \"~/.ssh/gpu key\"
+    IdentitiesOnly yes"
+        );
+
+        let container = RemoteEntry {
+            remote_host: "docker:trainer".to_string(),
+            ..entry
+        };
+        assert_eq!(host_block(&container), None);
+    }
+
+    #[test]
+    fn plain_hosts_become_candidates() {
+        let config = "
+# Defaults
+Host *
+    ServerAliveInterval 30
+
+Host gpu gpu-box
+    HostName 10.0.0.5
+    user alice
+    Port=2222
+    IdentityFile \"~/.ssh/gpu key\"
+    ForwardAgent yes
+
+Host *.internal !bastion
+    ProxyJump bastion
+
+Match host build
+    User ci
+
+Host web
+    ProxyJump bastion
+";
+        let gpu = SshConfigHost {
+            alias: "gpu".to_string(),
+            remote_host: "alice@10.0.0.5".to_string(),
+            ssh_options: vec![
+                "Port=2222".to_string(),
+                "IdentityFile=~/.ssh/gpu key".to_string(),
+            ],
+        };
+        assert_eq!(
+            parse_ssh_config(config),
+            [
+                gpu.clone(),
+                SshConfigHost {
+                    alias: "gpu-box".to_string(),
+                    ..gpu
+                },
+                SshConfigHost {
+                    alias: "web".to_string(),
+                    remote_host: "web".to_string(),
+                    ssh_options: vec!["ProxyJump=bastion".to_string()],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::cache::{get_config_dir, FileLock};
+use crate::config::RemoteEntry;
+use crate::remote::RemoteTarget;
+use crate::sync::{listing_time, RsyncVersion};
+use crate::term::warn;
+use crate::timings::TimedRun;
+
+// Runtime state recorded for a remote after syncing to it. Kept apart from
+// the cache so that configuration stays free of machine-generated data.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RemoteState {
+    #[serde(default)]
+    pub remote_fingerprint: Option<String>,
+    // Unix time the last sync finished, and whether it succeeded
+    #[serde(default)]
+    pub last_sync_at: Option<u64>,
+    #[serde(default)]
+    pub last_sync_ok: Option<bool>,
+    // Unix time the last completed push started sending files. Remote files
+    // modified since were changed there, e.g. outputs of the post-sync command.
+    #[serde(default)]
+    pub last_push_at: Option<u64>,
+    // Files sent and deleted by the last sync, when rsync logged them
+    #[serde(default)]
+    pub last_transferred: Option<usize>,
+    #[serde(default)]
+    pub last_deleted: Option<usize>,
+    // Unix time the post-sync command last ran
+    #[serde(default)]
+    pub last_post_command_at: Option<u64>,
+    // Token of the held-back post-sync command waiting for a quiet period.
+    // A newer sync replaces it, so only the last waiter runs the command.
+    #[serde(default)]
+    pub pending_post_command: Option<u64>,
+    // Phase timings of the last TIMING_HISTORY syncs, oldest first
+    #[serde(default)]
+    pub timings: Vec<TimedRun>,
+    // Bytes moved by each sync and pull this month, for the monthly budget
+    #[serde(default)]
+    pub transfers: Vec<TransferRecord>,
+    // Results of the checks made before syncing, reused for a short while
+    #[serde(default)]
+    pub preflight: Option<Preflight>,
+}
+
+// Bytes rsync sent or received in one transfer
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferRecord {
+    // Unix time the transfer finished
+    pub at: u64,
+    pub bytes: u64,
+}
+
+impl RemoteState {
+    // Add a transfer, dropping those of earlier months
+    pub fn record_transfer(&mut self, at: u64, bytes: u64) {
+        let month = month_of(at);
+        self.transfers
+            .retain(|transfer| month_of(transfer.at) == month);
+        self.transfers.push(TransferRecord { at, bytes });
+    }
+
+    // Bytes moved in the calendar month (local time) of `now`
+    pub fn transferred_in_month(&self, now: u64) -> u64 {
+        let month = month_of(now);
+        self.transfers
+            .iter()
+            .filter(|transfer| month_of(transfer.at) == month)
+            .map(|transfer| transfer.bytes)
+            .sum()
+    }
+}
+
+// `YYYY/MM` of a Unix time in local time
+fn month_of(unix: u64) -> String {
+    listing_time(unix)[..7].to_string()
+}
+
+// Seconds the preflight results stay valid
+pub const PREFLIGHT_TTL: u64 = 60;
+
+// What was found out about a remote before syncing to it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Preflight {
+    // Host and SSH options of the entry at the time, so edits invalidate it
+    pub remote_host: String,
+    pub ssh_options: Vec<String>,
+    // The target after resolving hosts and pod selectors
+    pub target: RemoteTarget,
+    pub remote_home: String,
+    pub has_rsync: bool,
+    // Version of the remote rsync, if it could be found out
+    #[serde(default)]
+    pub rsync_version: Option<RsyncVersion>,
+    // Unix time of the checks
+    pub checked_at: u64,
+}
+
+impl Preflight {
+    pub fn is_fresh(&self, entry: &RemoteEntry, now: u64) -> bool {
+        self.remote_host == entry.remote_host
+            && self.ssh_options == entry.ssh_options
+            && now.saturating_sub(self.checked_at) < PREFLIGHT_TTL
+    }
+}
+
+// Directory -> remote name -> state
+pub type StateMap = HashMap<String, HashMap<String, RemoteState>>;
+
+pub fn get_state_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("state.json"))
+}
+
+pub fn read_state(state_path: &Path) -> Result<StateMap> {
+    if !state_path.exists() {
+        return Ok(StateMap::new());
+    }
+
+    let data = fs::read(state_path).context("Failed to read state file")?;
+
+    // State is only a convenience, so a corrupt file is discarded rather than fatal
+    Ok(serde_json::from_slice(&data).unwrap_or_else(|_| {
+        warn(format_args!("Could not parse state file, starting fresh"));
+        StateMap::new()
+    }))
+}
+
+pub fn save_state(state_path: &Path, state: &StateMap) -> Result<()> {
+    let file = File::create(state_path).context("Failed to create state file")?;
+    serde_json::to_writer_pretty(file, state).context("Failed to write state file")
+}
+
+// The state of a single remote, empty if it has none yet
+pub fn read_remote_state(state_path: &Path, dir: &str, name: &str) -> Result<RemoteState> {
+    Ok(read_state(state_path)?
+        .remove(dir)
+        .and_then(|mut states| states.remove(name))
+        .unwrap_or_default())
+}
+
+// Save the state of a single remote, keeping whatever other processes saved
+// for the other remotes since `read_state`, e.g. syncs running in parallel
+pub fn save_remote_state(
+    state_path: &Path,
+    dir: &str,
+    name: &str,
+    remote_state: &RemoteState,
+) -> Result<()> {
+    let _lock = FileLock::acquire(state_path)?;
+    let mut state = read_state(state_path)?;
+    state
+        .entry(dir.to_string())
+        .or_default()
+        .insert(name.to_string(), remote_state.clone());
+    save_state(state_path, &state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budgets_count_this_month_only() {
+        // Mid-month, so that no time zone moves these into another month
+        let march = 1_710_460_800; // 2024-03-15
+        let april = 1_713_139_200; // 2024-04-15
+        let day = 24 * 60 * 60;
+
+        let mut state = RemoteState::default();
+        state.record_transfer(march, 100);
+        state.record_transfer(march + day, 50);
+        assert_eq!(state.transferred_in_month(march + 2 * day), 150);
+        assert_eq!(state.transferred_in_month(april), 0);
+
+        state.record_transfer(april, 10);
+        assert_eq!(state.transfers.len(), 1);
+        assert_eq!(state.transferred_in_month(april + day), 10);
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::i18n::tr;
+use crate::state::{RemoteState, StateMap};
+
+// One compact segment for tmux status bars and shell prompts, such as
+// `⇅ gpu-box 2m ago ✓`, for the remote of `dir` synced most recently
+pub fn statusline(state: &StateMap, dir: &str) -> Option<String> {
+    let (name, remote_state) = state
+        .get(dir)?
+        .iter()
+        .filter(|(_, remote_state)| remote_state.last_sync_at.is_some())
+        .max_by_key(|(_, remote_state)| remote_state.last_sync_at)?;
+
+    Some(format!("⇅ {} {}", name, last_sync(remote_state)?))
+}
+
+// When the remote was last synced and whether it worked, e.g. `2m ago ✓`
+pub fn last_sync(remote_state: &RemoteState) -> Option<String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let ago = format_ago(now.saturating_sub(remote_state.last_sync_at?));
+    let mark = if remote_state.last_sync_ok.unwrap_or(true) {
+        "✓"
+    } else {
+        "✗"
+    };
+
+    Some(format!("{} {}", ago, mark))
+}
+
+// How long ago something happened, e.g. `2m ago`
+pub fn format_ago(secs: u64) -> String {
+    match secs {
+        0..60 => tr("status.just_now", &[]),
+        60..3600 => tr("status.minutes_ago", &[&(secs / 60)]),
+        3600..86400 => tr("status.hours_ago", &[&(secs / 3600)]),
+        _ => tr("status.days_ago", &[&(secs / 86400)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synced(secs_ago: u64, ok: bool) -> RemoteState {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        RemoteState {
+            last_sync_at: Some(now - secs_ago),
+            last_sync_ok: Some(ok),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn the_most_recent_sync_is_shown() {
+        let mut state = StateMap::new();
+        let remotes = state.entry("/home/me/app".to_string()).or_default();
+        remotes.insert("old-box".to_string(), synced(3 * 86400, true));
+        remotes.insert("gpu-box".to_string(), synced(150, false));
+        remotes.insert("never".to_string(), RemoteState::default());
+
+        assert_eq!(
+            statusline(&state, "/home/me/app").as_deref(),
+            Some("⇅ gpu-box 2m ago ✗")
+        );
+        assert_eq!(statusline(&state, "/home/me/other"), None);
+
+        // Remotes that never synced have nothing to show
+        let mut state = StateMap::new();
+        state
+            .entry("/home/me/app".to_string())
+            .or_default()
+            .insert("never".to_string(), RemoteState::default());
+        assert_eq!(statusline(&state, "/home/me/app"), None);
+    }
+
+    #[test]
+    fn the_mark_tells_whether_the_sync_worked() {
+        assert_eq!(last_sync(&synced(7200, true)).as_deref(), Some("2h ago ✓"));
+        assert_eq!(last_sync(&synced(7200, false)).as_deref(), Some("2h ago ✗"));
+        // States from before the result was recorded count as having worked
+        let unknown = RemoteState {
+            last_sync_ok: None,
+            ..synced(7200, true)
+        };
+        assert_eq!(last_sync(&unknown).as_deref(), Some("2h ago ✓"));
+        assert_eq!(last_sync(&RemoteState::default()), None);
+    }
+
+    #[test]
+    fn times_round_down_to_the_largest_unit() {
+        let cases = [
+            (0, "just now"),
+            (59, "just now"),
+            (60, "1m ago"),
+            (3599, "59m ago"),
+            (3600, "1h ago"),
+            (86399, "23h ago"),
+            (86400, "1d ago"),
+            (10 * 86400, "10d ago"),
+        ];
+        for (secs, expected) in cases {
+            assert_eq!(format_ago(secs), expected, "{} seconds", secs);
+        }
+    }
+}
@@ -1,127 +1,1536 @@
 use anyhow::{Context, Result};
-use std::process::Command;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsString};
+use std::io::{self, IsTerminal, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::chunked::format_size;
+use crate::error::{SyncError, SyncResult};
+use crate::keys::{read_key, RawMode};
+use crate::remote::{shell_quote, RemoteTarget};
+use crate::runner;
+use crate::task::Task;
+use crate::term::dim;
 
 fn check_rsync_version() -> Result<()> {
-    let output = Command::new("rsync")
-        .arg("--version")
-        .output()
-        .context("Failed to execute rsync --version")?;
+    let output = match runner::output(Command::new("rsync").arg("--version")) {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(SyncError::RsyncMissing { host: None }.into())
+        }
+        Err(e) => return Err(e).context("Failed to execute rsync --version"),
+    };
 
     if !output.status.success() {
         anyhow::bail!("Failed to get rsync version");
     }
 
     let version_output = String::from_utf8_lossy(&output.stdout);
-    
+
     // Parse version from output like "rsync  version 3.2.7  protocol version 31"
     let version_line = version_output
         .lines()
         .next()
         .context("No version information found")?;
-    
+
     let version_str = version_line
         .split_whitespace()
         .nth(2)
         .context("Could not parse rsync version")?;
-    
+
     let major_version = version_str
         .split('.')
         .next()
         .and_then(|v| v.parse::<u32>().ok())
         .context("Could not parse major version number")?;
-    
+
     if major_version < 3 {
         anyhow::bail!(
             "rsync version {} is not supported. Please upgrade to version > 3.0",
             version_str
         );
     }
-    
+
     Ok(())
 }
 
-pub fn get_remote_home(remote_host: &str) -> Result<String> {
-    let output = Command::new("ssh")
-        .arg(remote_host)
-        .arg("echo $HOME")
-        .output()
+// rsync version as (major, minor, patch)
+pub type RsyncVersion = (u32, u32, u32);
+
+// Options the remote rsync has to understand, the version that introduced
+// them and what to use instead with an older remote (if anything)
+const REMOTE_OPTION_VERSIONS: &[(&str, RsyncVersion, Option<&str>)] = &[
+    ("--info=progress2", (3, 1, 0), Some("--progress")),
+    ("--info", (3, 1, 0), None),
+    ("--debug", (3, 1, 0), None),
+    ("--chown", (3, 1, 0), None),
+    ("--usermap", (3, 1, 0), None),
+    ("--groupmap", (3, 1, 0), None),
+    ("--delete-missing-args", (3, 1, 0), None),
+    ("--ignore-missing-args", (3, 1, 0), None),
+    ("--compress-choice", (3, 2, 0), None),
+    ("--zc", (3, 2, 0), None),
+    ("--checksum-choice", (3, 2, 0), None),
+    ("--cc", (3, 2, 0), None),
+    ("--copy-as", (3, 2, 0), None),
+    ("--write-devices", (3, 2, 0), None),
+    ("--mkpath", (3, 2, 3), None),
+    ("--open-noatime", (3, 2, 3), None),
+];
+
+// Parse the first line of `rsync --version`, e.g.
+// "rsync  version 3.2.7  protocol version 31" (some builds write "v3.2.7")
+fn parse_rsync_version(output: &str) -> Option<RsyncVersion> {
+    let version = output
+        .lines()
+        .next()?
+        .split_whitespace()
+        .nth(2)?
+        .trim_start_matches('v');
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().ok());
+    Some((
+        parts.next()??,
+        parts.next().flatten().unwrap_or(0),
+        parts.next().flatten().unwrap_or(0),
+    ))
+}
+
+// Whether rsync can create missing parents of the destination itself
+// (`--mkpath`), which needs 3.2.3 on both ends
+pub fn mkpath_supported(remote_version: Option<RsyncVersion>) -> bool {
+    const MKPATH_VERSION: RsyncVersion = (3, 2, 3);
+    let local_version = runner::output(Command::new("rsync").arg("--version"))
+        .ok()
+        .and_then(|output| parse_rsync_version(&String::from_utf8_lossy(&output.stdout)));
+    remote_version.is_some_and(|version| version >= MKPATH_VERSION)
+        && local_version.is_some_and(|version| version >= MKPATH_VERSION)
+}
+
+// Version of rsync on the remote, or None if it's missing or unrecognized
+pub fn remote_rsync_version(target: &RemoteTarget) -> Result<Option<RsyncVersion>> {
+    let output = runner::output(&mut target.command("rsync --version 2>/dev/null"))
+        .context("Failed to check the remote rsync version")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(parse_rsync_version(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+// Options controlling a single rsync invocation
+#[derive(Debug, Clone, Default)]
+pub struct RsyncOptions {
+    // Comma-separated rsync filter rules
+    pub filter: Option<String>,
+    pub delete: bool,
+    // Remote shell used to reach the remote (rsync's `-e`)
+    pub rsh: Option<String>,
+    pub extra_args: Vec<String>,
+    // Version of rsync on the remote, when known. Options it doesn't support
+    // are dropped or replaced rather than failing with a protocol error.
+    pub remote_version: Option<RsyncVersion>,
+}
+
+impl RsyncOptions {
+    // Extra arguments adjusted to what the remote supports
+    fn compatible_args(&self) -> Vec<String> {
+        let Some(version) = self.remote_version else {
+            return self.extra_args.clone();
+        };
+
+        self.extra_args
+            .iter()
+            .filter_map(|arg| {
+                let rule = REMOTE_OPTION_VERSIONS.iter().find(|(option, _, _)| {
+                    arg == option || arg.starts_with(&format!("{}=", option))
+                });
+                match rule {
+                    Some((_, since, replacement)) if version < *since => {
+                        replacement.map(str::to_string)
+                    }
+                    _ => Some(arg.clone()),
+                }
+            })
+            .collect()
+    }
+
+    // Arguments that will be dropped or replaced for an older remote
+    pub fn incompatible_args(&self) -> Vec<String> {
+        let compatible = self.compatible_args();
+        self.extra_args
+            .iter()
+            .filter(|arg| !compatible.contains(arg))
+            .cloned()
+            .collect()
+    }
+
+    fn apply(&self, cmd: &mut Command) {
+        if self.delete {
+            cmd.args(["--delete"]);
+        }
+
+        if let Some(rsh) = &self.rsh {
+            cmd.args(["-e", rsh]);
+        }
+
+        if let Some(f) = &self.filter {
+            // Handle multiple filters separated by commas
+            for filter_rule in f.split(',') {
+                cmd.args(["--filter", filter_rule.trim()]);
+            }
+        }
+
+        cmd.args(self.compatible_args());
+    }
+}
+
+// The first command run on a remote, so its failure says whether the
+// remote can be reached at all
+pub fn get_remote_home(target: &RemoteTarget) -> SyncResult<String> {
+    let output = runner::output(&mut target.command("echo $HOME"))
         .context("Failed to get remote home directory")?;
 
     if !output.status.success() {
-        anyhow::bail!(
-            "SSH command failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        // ssh's own failures exit with 255, those of the command with its code
+        return Err(match output.status.code() {
+            Some(255) if stderr.contains("Permission denied") => SyncError::SshAuthFailed {
+                host: target.host().to_string(),
+            },
+            Some(255) => SyncError::SshConnectionFailed {
+                host: target.host().to_string(),
+                message: stderr,
+            },
+            _ => anyhow::anyhow!("SSH command failed: {}", stderr).into(),
+        });
     }
 
-    let home = String::from_utf8(output.stdout)?.trim().to_string();
+    let home = String::from_utf8(output.stdout)
+        .context("Remote home directory is not valid UTF-8")?
+        .trim()
+        .to_string();
 
     if home.is_empty() {
-        anyhow::bail!("Remote home directory is empty");
+        return Err(anyhow::anyhow!("Remote home directory is empty").into());
     }
 
     Ok(home)
 }
 
+// Bytes available to this user on the filesystem holding `path`
+pub fn free_space(path: &Path) -> Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("Invalid path")?;
+    // SAFETY: c_path is a valid NUL-terminated string and stat is written by statvfs
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error())
+            .context(format!("Failed to check free space on {}", path.display()));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// rsync source for the current directory. The trailing slash makes it copy the
+// contents into remote_dir rather than a nested directory of the same name.
+pub const LOCAL_SOURCE: &str = "./";
+
+// Make sure a pull from `source` into `destination` fits on the local disk,
+// estimating its size with a dry run, so it fails up front instead of midway
+// with ENOSPC
+pub fn check_free_space(source: &str, destination: &str, options: &RsyncOptions) -> Result<()> {
+    let needed: u64 = pending_files(source, destination, options)?
+        .iter()
+        .map(|(_, size)| size)
+        .sum();
+    let available = free_space(Path::new(destination))?;
+    if needed > available {
+        anyhow::bail!(
+            "Not enough disk space to pull: {} needed, {} free",
+            format_size(needed),
+            format_size(available)
+        );
+    }
+    Ok(())
+}
+
+// rsync copies a source ending in `/` as its contents, and the directory itself
+// (so into a nested `dest/name`) otherwise. Syncs always mean the contents.
+pub fn dir_contents(path: &str) -> String {
+    format!("{}/", path.trim_end_matches('/'))
+}
+
+// rsync prints bytes it can't show, like those of names that aren't valid
+// UTF-8, as `\#ooo` (octal). This turns a printed name back into the path.
+pub fn rsync_path(name: &str) -> PathBuf {
+    let mut bytes = Vec::new();
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = rest
+            .strip_prefix(b"\\#")
+            .and_then(|tail| tail.get(..3))
+            .filter(|digits| digits.iter().all(|d| (b'0'..=b'7').contains(d)))
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match escaped {
+            Some(escaped) => {
+                bytes.push(escaped);
+                rest = &rest[5..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+// Contents of a `--files-from` file for names as rsync printed them, with
+// the original bytes of each name
+pub fn files_from_list(names: &[String]) -> Vec<u8> {
+    let mut list = Vec::new();
+    for name in names {
+        list.extend_from_slice(rsync_path(name).as_os_str().as_bytes());
+        list.push(b'\n');
+    }
+    list
+}
+
+fn rsync_command(source: &str, destination: &str, options: &RsyncOptions, stats: bool) -> Command {
+    let mut cmd = Command::new("rsync");
+    cmd.args(["-az", "--partial"]);
+    if stats {
+        cmd.arg("--stats");
+    }
+    // Progress output is only useful to someone watching
+    if io::stdout().is_terminal() {
+        cmd.arg("--progress");
+    }
+    options.apply(&mut cmd);
+    cmd.args([source, destination]);
+    cmd
+}
+
+// What a sync did, from rsync's `--stats`, for scripts and programs driving
+// sync-rs to use instead of its console output
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SyncReport {
+    pub files_transferred: u64,
+    pub bytes_sent: u64,
+    pub deleted: u64,
+    pub duration_ms: u64,
+    // One for each rsync run: the project, then any override paths
+    pub paths: Vec<PathReport>,
+}
+
+impl SyncReport {
+    pub fn push(&mut self, path: PathReport) {
+        self.files_transferred += path.files_transferred;
+        self.bytes_sent += path.bytes_sent;
+        self.deleted += path.deleted;
+        self.paths.push(path);
+    }
+
+    // Add the paths of another report, e.g. an override path's transfer
+    pub fn append(&mut self, other: SyncReport) {
+        for path in other.paths {
+            self.push(path);
+        }
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.paths
+            .iter()
+            .any(|path| path.result == PathResult::Stopped)
+    }
+
+    // Paths sent or deleted, if every transfer could tell
+    pub fn changes(&self) -> Option<Vec<String>> {
+        self.paths
+            .iter()
+            .map(|path| path.changed.clone())
+            .collect::<Option<Vec<_>>>()
+            .map(|changed| changed.concat())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PathReport {
+    pub path: String,
+    pub files_transferred: u64,
+    pub bytes_sent: u64,
+    pub deleted: u64,
+    pub result: PathResult,
+    // Paths sent or deleted, when the transport can tell
+    #[serde(skip)]
+    pub changed: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathResult {
+    #[default]
+    Synced,
+    // `s` was pressed during a controlled transfer: abandoned, later ones
+    // went ahead
+    Skipped,
+    // `q` was pressed: stopped once the file being sent was complete
+    Stopped,
+}
+
+// The block `--stats` adds to the end of rsync's output starts with this
+const STATS_START: &[u8] = b"Number of files:";
+
+// Counts from rsync's `--stats` block in `output`. Numbers are grouped with
+// commas or, depending on the locale, dots.
+fn parse_stats(path: &str, output: &[u8]) -> PathReport {
+    let mut report = PathReport {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let output = String::from_utf8_lossy(output);
+    let Some(start) = output.rfind("Number of files:") else {
+        return report;
+    };
+    for line in output[start..].lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value
+            .trim()
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || matches!(c, ',' | '.' | '\''))
+            .filter(char::is_ascii_digit)
+            .collect::<String>()
+            .parse()
+            .unwrap_or(0);
+        match name {
+            // Before rsync 3.1 directories and links were counted too
+            "Number of regular files transferred" | "Number of files transferred" => {
+                report.files_transferred = value
+            }
+            "Number of deleted files" => report.deleted = value,
+            "Total bytes sent" => report.bytes_sent = value,
+            _ => {}
+        }
+    }
+    report
+}
+
 pub fn sync_directory(
     source: &str,
     destination: &str,
-    filter: Option<&str>,
-    delete: bool,
-) -> Result<()> {
+    options: &RsyncOptions,
+) -> SyncResult<SyncReport> {
     // Ensure rsync version is greater than 3
     check_rsync_version()?;
-    
+
+    let started = Instant::now();
+    let mut cmd = rsync_command(source, destination, options, true);
+    // The counts go into the report rather than on screen
+    let mut in_stats = false;
+    let output = runner::output_shown(&mut cmd, &mut |line| {
+        in_stats |= line.starts_with(STATS_START);
+        !in_stats
+    })
+    .context("Failed to execute rsync command")?;
+
+    if !output.status.success() {
+        return Err(SyncError::command_failed("rsync", output.status.code()));
+    }
+
+    let mut report = SyncReport {
+        duration_ms: started.elapsed().as_millis() as u64,
+        ..Default::default()
+    };
+    report.push(parse_stats(source, &output.stdout));
+    Ok(report)
+}
+
+// Like `sync_directory`, but returns once rsync has started. Several can run
+// at the same time, and each can be awaited or cancelled.
+pub fn sync_directory_async(
+    source: &str,
+    destination: &str,
+    options: &RsyncOptions,
+) -> SyncResult<Task> {
+    check_rsync_version()?;
+    Ok(Task::spawn(
+        rsync_command(source, destination, options, false),
+        "rsync",
+    )?)
+}
+
+// What a transfer started with `sync_directory_with_progress` reports
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncEvent {
+    Phase(SyncPhase),
+    // rsync got to this file (relative to the source), which may or may not
+    // need sending
+    File(String),
+    // Progress of the whole transfer, as rsync's `--info=progress2` shows it
+    Progress {
+        bytes: u64,
+        percent: u8,
+        // e.g. `12.34MB/s`
+        rate: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPhase {
+    // Comparing file lists, before anything is sent
+    Scanning,
+    Transferring,
+    Finished,
+}
+
+// Like `sync_directory`, but with rsync's output turned into events for
+// `on_event` instead of being passed through
+pub fn sync_directory_with_progress(
+    source: &str,
+    destination: &str,
+    options: &RsyncOptions,
+    mut on_event: impl FnMut(SyncEvent),
+) -> SyncResult<SyncReport> {
+    check_rsync_version()?;
+
+    let started = Instant::now();
     let mut cmd = Command::new("rsync");
-    cmd.args(["-azP"]);
+    cmd.args(["-az", "--partial", "--stats", "--info=progress2,name1"]);
+    options.apply(&mut cmd);
+    cmd.args([source, destination]);
+    cmd.stdout(Stdio::piped());
+
+    on_event(SyncEvent::Phase(SyncPhase::Scanning));
+    let mut child = cmd.spawn().context("Failed to execute rsync command")?;
+    let mut output = child
+        .stdout
+        .take()
+        .context("Failed to capture rsync output")?;
+
+    // Progress is redrawn with `\r`, file names end with `\n`
+    let mut transferring = false;
+    let mut stats = Vec::new();
+    let mut line = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = output
+            .read(&mut buf)
+            .context("Failed to read rsync output")?;
+        for &byte in &buf[..n] {
+            if byte != b'\r' && byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+            if !stats.is_empty() || line.starts_with(STATS_START) {
+                stats.extend_from_slice(&line);
+                stats.push(b'\n');
+            } else if let Some(event) = parse_progress_line(&String::from_utf8_lossy(&line)) {
+                if !transferring {
+                    transferring = true;
+                    on_event(SyncEvent::Phase(SyncPhase::Transferring));
+                }
+                on_event(event);
+            }
+            line.clear();
+        }
+        if n == 0 {
+            break;
+        }
+    }
+
+    let status = child.wait().context("Failed to wait for rsync")?;
+    if !status.success() {
+        return Err(SyncError::command_failed("rsync", status.code()));
+    }
+    on_event(SyncEvent::Phase(SyncPhase::Finished));
+    let mut report = SyncReport {
+        duration_ms: started.elapsed().as_millis() as u64,
+        ..Default::default()
+    };
+    report.push(parse_stats(source, &stats));
+    Ok(report)
+}
+
+// A line of rsync output with `--info=progress2,name1`: either the overall
+// progress, like `  1,234,567  45%   12.34MB/s    0:00:01 (xfr#3, to-chk=10/20)`,
+// or the name of a file
+fn parse_progress_line(line: &str) -> Option<SyncEvent> {
+    let trimmed = line.trim();
+    if trimmed.is_empty()
+        || trimmed == "sending incremental file list"
+        || trimmed.starts_with("created directory ")
+    {
+        return None;
+    }
+
+    let mut fields = trimmed.split_whitespace();
+    let progress = (|| {
+        let bytes = fields.next()?.replace(',', "").parse().ok()?;
+        let percent = fields.next()?.strip_suffix('%')?.parse().ok()?;
+        let rate = fields.next()?.to_string();
+        Some(SyncEvent::Progress {
+            bytes,
+            percent,
+            rate,
+        })
+    })();
+    // File names keep their spaces, leading ones included
+    progress
+        .filter(|_| line.starts_with(' '))
+        .or_else(|| Some(SyncEvent::File(line.to_string())))
+}
 
-    if delete {
-        cmd.args(["--delete"]);
+// Like `sync_directory`, but on a terminal pressing `q` stops the transfer
+// once the file being sent is complete, and `s` (if `skippable`) abandons it
+// right away. rsync prints each file's name before its progress, so a new
+// name after `q` means the previous file is done.
+pub fn sync_directory_controlled(
+    source: &str,
+    destination: &str,
+    options: &RsyncOptions,
+    skippable: bool,
+) -> Result<PathReport> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        let report = sync_directory(source, destination, options)?;
+        return Ok(report.paths.into_iter().next().unwrap_or_default());
     }
+    check_rsync_version()?;
+
+    let mut cmd = Command::new("rsync");
+    cmd.args(["-az", "--partial", "--stats", "--progress"]);
+    options.apply(&mut cmd);
+    cmd.args([source, destination]);
+    cmd.stdout(Stdio::piped());
+
+    let raw_mode = RawMode::enable()?;
+    let mut child = cmd.spawn().context("Failed to execute rsync command")?;
+    let mut output = child
+        .stdout
+        .take()
+        .context("Failed to capture rsync output")?;
+    println!(
+        "{}",
+        dim(if skippable {
+            "Press s to skip this path, or q to stop after the current file"
+        } else {
+            "Press q to stop after the current file"
+        })
+    );
 
-    if let Some(f) = filter {
-        // Handle multiple filters separated by commas
-        for filter_rule in f.split(',') {
-            cmd.args(["--filter", filter_rule.trim()]);
+    // Pass the output through up to the stats, counting lines that start a
+    // new file
+    let files_started = Arc::new(AtomicUsize::new(0));
+    let forwarder = {
+        let files_started = Arc::clone(&files_started);
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut line = Vec::new();
+            let mut stats = Vec::new();
+            while let Ok(n) = output.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                for &byte in &buf[..n] {
+                    line.push(byte);
+                    if byte != b'\r' && byte != b'\n' {
+                        continue;
+                    }
+                    if !stats.is_empty() || line.starts_with(STATS_START) {
+                        stats.append(&mut line);
+                        continue;
+                    }
+                    if line.first().is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                        files_started.fetch_add(1, Ordering::SeqCst);
+                    }
+                    let mut stdout = io::stdout();
+                    let _ = stdout.write_all(&line);
+                    let _ = stdout.flush();
+                    line.clear();
+                }
+            }
+            stats
+        })
+    };
+
+    let terminate = |child: &Child| {
+        // SAFETY: signals our own child; rsync keeps the partial file on SIGTERM
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    };
+    let mut stop = PathResult::Synced;
+    let mut quit_after = None;
+    let status = loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for rsync")? {
+            break status;
+        }
+        if quit_after.is_some_and(|files| files_started.load(Ordering::SeqCst) > files) {
+            terminate(&child);
+            quit_after = None;
         }
+        match read_key(Duration::from_millis(100)) {
+            Some(b's' | b'S') if skippable && stop == PathResult::Synced => {
+                println!("\nSkipping {}", source);
+                stop = PathResult::Skipped;
+                terminate(&child);
+            }
+            Some(b'q' | b'Q') if stop == PathResult::Synced => {
+                println!("\nStopping after the current file...");
+                stop = PathResult::Stopped;
+                quit_after = Some(files_started.load(Ordering::SeqCst));
+            }
+            _ => {}
+        }
+    };
+    let stats = forwarder.join().unwrap_or_default();
+    drop(raw_mode);
+
+    // Being stopped makes rsync fail, which is expected
+    if stop == PathResult::Synced && !status.success() {
+        return Err(SyncError::command_failed("rsync", status.code()).into());
     }
+    Ok(PathReport {
+        result: stop,
+        ..parse_stats(source, &stats)
+    })
+}
+
+// Run rsync in dry-run mode and return the itemized list of changes it would make
+pub fn itemize_changes(
+    source: &str,
+    destination: &str,
+    options: &RsyncOptions,
+) -> Result<Vec<String>> {
+    check_rsync_version()?;
 
+    let mut cmd = Command::new("rsync");
+    cmd.args(["-az", "--dry-run", "--itemize-changes"]);
+    options.apply(&mut cmd);
     cmd.args([source, destination]);
 
-    let status = cmd.status().context("Failed to execute rsync command")?;
+    let output = runner::output(&mut cmd).context("Failed to execute rsync dry run")?;
 
-    if !status.success() {
-        anyhow::bail!("rsync failed with exit code: {:?}", status.code());
+    if !output.status.success() {
+        anyhow::bail!(
+            "rsync dry run failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.to_string())
+        .collect())
+}
+
+// Files (with sizes) a transfer would send, from an rsync dry run
+pub fn pending_files(
+    source: &str,
+    destination: &str,
+    options: &RsyncOptions,
+) -> Result<Vec<(String, u64)>> {
+    check_rsync_version()?;
+
+    let mut cmd = Command::new("rsync");
+    cmd.args(["-az", "--dry-run", "--out-format=%l %n"]);
+    options.apply(&mut cmd);
+    cmd.args([source, destination]);
+
+    let output = runner::output(&mut cmd).context("Failed to execute rsync dry run")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "rsync dry run failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (size, name) = line.split_once(' ')?;
+            // Directories are created as needed by the file transfers
+            (!name.ends_with('/')).then(|| (name.to_string(), size.parse().unwrap_or(0)))
+        })
+        .collect())
+}
+
+// Regular files under `source` matching the filter, with their sizes
+pub fn list_files(source: &str, options: &RsyncOptions) -> Result<Vec<(String, u64)>> {
+    let mut cmd = Command::new("rsync");
+    cmd.args(["-r", "--list-only", "--no-human-readable"]);
+    options.apply(&mut cmd);
+    cmd.arg(source);
+
+    let output = runner::output(&mut cmd).context("Failed to list files")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to list files: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // Lines look like `-rw-r--r--  1234 2024/01/01 12:00:00 path`
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with('-'))
+        .filter_map(|line| {
+            let mut rest = line;
+            let mut fields = Vec::new();
+            for _ in 0..4 {
+                let (field, tail) = rest.split_once(' ')?;
+                fields.push(field);
+                rest = tail.trim_start();
+            }
+            Some((rest.to_string(), fields[1].parse().ok()?))
+        })
+        .collect())
+}
+
+// Extract the path from a line of rsync's itemized output
+pub fn itemized_path(line: &str) -> Option<&str> {
+    let (_, path) = line.split_once(' ')?;
+    let path = path.trim_start();
+    (!path.is_empty()).then_some(path)
+}
+
+// Compute a fingerprint of the remote directory from rsync's file listing.
+// Only regular files matching the filter are considered, so generated files
+// excluded by .gitignore don't make the remote look modified.
+// Returns None if the remote directory doesn't exist yet.
+pub fn remote_fingerprint(remote: &str, options: &RsyncOptions) -> Result<Option<String>> {
+    let Some(listing) = list_remote(remote, options)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(listing_fingerprint(&listing)))
+}
+
+// Fingerprint of an `rsync --list-only` listing: FNV-1a over the file lines
+// (permissions, size, mtime and path)
+pub fn listing_fingerprint(listing: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for line in listing.lines() {
+        if !line.starts_with('-') {
+            continue;
+        }
+        for byte in line.bytes().chain(std::iter::once(b'\n')) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    format!("{:016x}", hash)
+}
+
+// rsync's recursive listing of a remote directory, or None if it doesn't exist
+fn list_remote(remote: &str, options: &RsyncOptions) -> Result<Option<String>> {
+    let mut cmd = Command::new("rsync");
+    cmd.args(["-r", "--list-only"]);
+    options.apply(&mut cmd);
+    cmd.arg(dir_contents(remote));
+
+    let output = runner::output(&mut cmd).context("Failed to list remote directory")?;
+
+    if !output.status.success() {
+        // rsync reports a missing source directory as a partial transfer (23)
+        if output.status.code() == Some(23) {
+            return Ok(None);
+        }
+        anyhow::bail!(
+            "Failed to list remote directory: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(Some(String::from_utf8_lossy(&output.stdout).to_string()))
+}
+
+// Size and modification time (as rsync prints it) of a remote file
+#[derive(Debug, PartialEq)]
+pub struct ListedFile {
+    pub size: u64,
+    pub mtime: String,
+}
+
+// The regular files under a remote directory by relative path, or None if the
+// directory doesn't exist
+pub fn list_remote_files(
+    remote: &str,
+    options: &RsyncOptions,
+) -> Result<Option<BTreeMap<String, ListedFile>>> {
+    Ok(list_remote(remote, options)?.map(|listing| {
+        listing
+            .lines()
+            .filter(|line| line.starts_with('-'))
+            .filter_map(parse_listing_line)
+            .collect()
+    }))
+}
+
+// Parse a line of `rsync --list-only` output, e.g.
+// "-rw-r--r--          1,234 2024/05/01 12:00:00 src/main.rs"
+fn parse_listing_line(line: &str) -> Option<(String, ListedFile)> {
+    let mut rest = line;
+    let mut fields = Vec::new();
+    for _ in 0..4 {
+        let field = rest.trim_start();
+        let end = field.find(' ')?;
+        fields.push(&field[..end]);
+        rest = &field[end..];
+    }
+    let path = rest.strip_prefix(' ')?;
+    // Sizes are grouped with commas or dots depending on the locale
+    let size = fields[1]
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>()
+        .parse()
+        .ok()?;
+
+    Some((
+        path.to_string(),
+        ListedFile {
+            size,
+            mtime: format!("{} {}", fields[2], fields[3]),
+        },
+    ))
+}
+
+// rsync command line for a shell on another machine, e.g. to forward files
+// from a relay
+pub fn rsync_command_line(source: &str, destination: &str, options: &RsyncOptions) -> String {
+    command_line(&["-az", "--partial"], options, &[source, destination])
+}
+
+// Like `rsync_command_line`, for the dry run `itemize_changes` does
+pub fn itemize_command_line(source: &str, destination: &str, options: &RsyncOptions) -> String {
+    command_line(
+        &["-az", "--dry-run", "--itemize-changes"],
+        options,
+        &[source, destination],
+    )
+}
+
+// Like `rsync_command_line`, for the listing `remote_fingerprint` hashes
+pub fn list_command_line(remote: &str, options: &RsyncOptions) -> String {
+    command_line(&["-r", "--list-only"], options, &[&dir_contents(remote)])
+}
+
+fn command_line(flags: &[&str], options: &RsyncOptions, args: &[&str]) -> String {
+    let mut cmd = Command::new("rsync");
+    cmd.args(flags);
+    options.apply(&mut cmd);
+    cmd.args(args);
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// A Unix time as rsync lists modification times (in local time), so that it
+// can be compared with `ListedFile::mtime`: the format sorts chronologically
+pub fn listing_time(unix: u64) -> String {
+    let time = unix as libc::time_t;
+    // SAFETY: tm is plain data, filled in by localtime_r
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&time, &mut tm) };
+    format!(
+        "{:04}/{:02}/{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+// Like `execute_ssh_command`, but returns once the command has started
+pub fn execute_ssh_command_async(target: &RemoteTarget, command: &str) -> SyncResult<Task> {
+    Ok(Task::spawn(target.command(command), "SSH command")?)
+}
+
+pub fn execute_ssh_command(target: &RemoteTarget, command: &str) -> SyncResult<()> {
+    let status =
+        runner::status(&mut target.command(command)).context("Failed to execute SSH command")?;
+
+    match status.code() {
+        _ if status.success() => {}
+        Some(255) => {
+            return Err(SyncError::SshConnectionFailed {
+                host: target.host().to_string(),
+                message: "ssh exited with code 255".to_string(),
+            })
+        }
+        code => return Err(SyncError::command_failed("SSH command", code)),
     }
 
     Ok(())
 }
 
-pub fn execute_ssh_command(host: &str, command: &str) -> Result<()> {
-    let status = Command::new("ssh")
-        .arg(host)
-        .arg(command)
-        .status()
-        .context("Failed to execute SSH command")?;
+// Run a shell script on the remote, passed on stdin so it can be arbitrarily
+// long, and return its output
+pub fn run_remote_script(target: &RemoteTarget, script: &str) -> Result<String> {
+    let mut child = target
+        .command("sh")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to execute remote shell")?;
+
+    child
+        .stdin
+        .take()
+        .context("Failed to open remote shell input")?
+        .write_all(script.as_bytes())
+        .context("Failed to send script to the remote")?;
+
+    let output = child
+        .wait_with_output()
+        .context("Failed to wait for remote shell")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Remote script failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub fn open_remote_shell(target: &RemoteTarget, directory: &str) -> Result<()> {
+    let status = runner::status(
+        &mut target
+            .interactive_command(&format!("cd {} && exec ${{SHELL:-/bin/sh}} -l", directory)),
+    )
+    .context("Failed to open remote shell")?;
 
     if !status.success() {
-        anyhow::bail!("SSH command failed with exit code: {:?}", status.code());
+        anyhow::bail!("Remote shell exited with code: {:?}", status.code());
     }
 
     Ok(())
 }
 
-pub fn open_remote_shell(host: &str, directory: &str) -> Result<()> {
-    let status = Command::new("ssh")
-        .arg("-t") // Force pseudo-terminal allocation for interactive shell
-        .arg(host)
-        .arg(format!("cd {} && exec $SHELL -l", directory))
+// Whether rsync can be executed on the remote
+pub fn remote_has_rsync(target: &RemoteTarget) -> Result<bool> {
+    remote_has_command(target, "rsync")
+}
+
+// Whether a command is available on the remote
+pub fn remote_has_command(target: &RemoteTarget, command: &str) -> Result<bool> {
+    let output = runner::output(&mut target.command(&format!("command -v {}", command)))
+        .context(format!("Failed to check for {} on the remote", command))?;
+    Ok(output.status.success())
+}
+
+// Copy `source` into `remote_dir` as a tar stream over the remote's exec channel.
+// Like rsync, a source ending in `/` (or `.`) copies its contents, otherwise the
+// directory itself. This is a full copy every time and never deletes anything.
+pub fn tar_upload(
+    target: &RemoteTarget,
+    source: &str,
+    remote_dir: &str,
+    respect_gitignore: bool,
+    excludes: &[String],
+) -> Result<()> {
+    let path = Path::new(source);
+    let (base, name) = if source == "." || source.ends_with('/') {
+        (path.to_path_buf(), ".".to_string())
+    } else {
+        let name = path
+            .file_name()
+            .context(format!("Invalid source path '{}'", source))?;
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+        (
+            parent.unwrap_or(Path::new(".")).to_path_buf(),
+            name.to_string_lossy().to_string(),
+        )
+    };
+
+    // Let git decide what's ignored when possible, so .gitignore is honoured
+    let file_list = if respect_gitignore {
+        git_listed_files(&base)
+    } else {
+        None
+    };
+
+    let mut tar = Command::new("tar");
+    tar.arg("-C").arg(&base).args(["-cf", "-"]);
+    for pattern in excludes {
+        tar.arg(format!("--exclude={}", pattern));
+    }
+    if file_list.is_some() {
+        tar.args(["--null", "-T", "-"]);
+        tar.stdin(Stdio::piped());
+    } else {
+        tar.arg(&name);
+    }
+
+    pipe_tar(target, tar, file_list, remote_dir, false)
+}
+
+// Below this many changed files, rsync's per-file overhead isn't worth avoiding
+pub const TAR_STREAM_MIN_FILES: usize = 100;
+
+// Stream exactly `files` (relative to `base`, with everything in those that
+// are directories but `excludes`) into `remote_dir` as a gzipped tar
+// archive. Much faster than rsync for many small files on slow links, as
+// there's no per-file round trip.
+pub fn tar_upload_files(
+    target: &RemoteTarget,
+    base: &Path,
+    files: &[String],
+    excludes: &[String],
+    remote_dir: &str,
+) -> Result<()> {
+    let mut list = Vec::new();
+    for file in files {
+        list.extend_from_slice(file.as_bytes());
+        list.push(0);
+    }
+
+    let mut tar = Command::new("tar");
+    tar.arg("-C").arg(base).args(["-czf", "-"]);
+    for pattern in excludes {
+        tar.arg(format!("--exclude={}", pattern));
+    }
+    tar.args(["--null", "-T", "-"]).stdin(Stdio::piped());
+
+    pipe_tar(target, tar, Some(list), remote_dir, true)
+}
+
+// Run `tar`, feeding it `file_list` on stdin if given, and extract its output
+// into `remote_dir` on the remote
+fn pipe_tar(
+    target: &RemoteTarget,
+    mut tar: Command,
+    file_list: Option<Vec<u8>>,
+    remote_dir: &str,
+    gzip: bool,
+) -> Result<()> {
+    tar.stdout(Stdio::piped());
+
+    let mut tar_child = tar.spawn().context("Failed to execute tar")?;
+
+    // Feed the list from another thread, as tar starts writing the archive
+    // (which nobody reads yet) before it has read a long list completely
+    let writer = match (file_list, tar_child.stdin.take()) {
+        (Some(list), Some(mut stdin)) => Some(std::thread::spawn(move || stdin.write_all(&list))),
+        _ => None,
+    };
+
+    let quoted_dir = shell_quote(remote_dir);
+    let extract = if gzip { "-xzf" } else { "-xf" };
+    let status = target
+        .command(&format!(
+            "mkdir -p {0} && tar -C {0} {1} -",
+            quoted_dir, extract
+        ))
+        .stdin(
+            tar_child
+                .stdout
+                .take()
+                .context("Failed to capture tar output")?,
+        )
         .status()
-        .context("Failed to open remote shell")?;
+        .context("Failed to execute remote tar")?;
 
+    let tar_status = tar_child.wait().context("Failed to wait for tar")?;
     if !status.success() {
-        anyhow::bail!("Remote shell exited with code: {:?}", status.code());
+        anyhow::bail!("Remote tar failed with exit code: {:?}", status.code());
+    }
+    if !tar_status.success() {
+        anyhow::bail!("tar failed with exit code: {:?}", tar_status.code());
+    }
+    if let Some(writer) = writer {
+        writer
+            .join()
+            .map_err(|_| anyhow::anyhow!("File list writer panicked"))?
+            .context("Failed to pass file list to tar")?;
     }
 
     Ok(())
 }
+
+// NUL-separated list of files git considers part of the work tree (tracked or
+// untracked but not ignored), or None if `dir` isn't inside a git repository
+pub(crate) fn git_listed_files(dir: &Path) -> Option<Vec<u8>> {
+    let output = runner::output(
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args([
+                "ls-files",
+                "-z",
+                "--cached",
+                "--others",
+                "--exclude-standard",
+            ])
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{with_runner, MockRunner};
+    use std::rc::Rc;
+
+    const RSYNC_VERSION: &str = "rsync  version 3.2.7  protocol version 31\n";
+
+    #[test]
+    fn commands_run_through_the_runner() {
+        let target = RemoteTarget::parse("me@box");
+
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, "/home/me\n", "")
+                .reply(255, "", "me@box: Permission denied (publickey).")
+                .reply(
+                    255,
+                    "",
+                    "ssh: connect to host box port 22: Connection refused",
+                ),
+        );
+        with_runner(runner.clone(), || {
+            assert_eq!(get_remote_home(&target).unwrap(), "/home/me");
+            assert!(matches!(
+                get_remote_home(&target),
+                Err(SyncError::SshAuthFailed { host }) if host == "me@box"
+            ));
+            assert!(get_remote_home(&target).unwrap_err().is_transient());
+        });
+        assert!(runner.commands()[0].ends_with("me@box echo $HOME"));
+
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, RSYNC_VERSION, "")
+                .reply(23, "", ""),
+        );
+        let options = RsyncOptions {
+            delete: true,
+            ..Default::default()
+        };
+        let result = with_runner(runner.clone(), || {
+            sync_directory("src/", "me@box:app", &options)
+        });
+        assert!(matches!(
+            result,
+            Err(SyncError::CommandFailed { code: Some(23), .. })
+        ));
+        assert_eq!(runner.commands()[0], "rsync --version");
+        assert!(runner.commands()[1].ends_with("--delete src/ me@box:app"));
+
+        let runner = Rc::new(MockRunner::default().reply(3, "", ""));
+        let result = with_runner(runner, || execute_ssh_command(&target, "make"));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "SSH command failed with exit code: Some(3)"
+        );
+    }
+
+    #[test]
+    fn stats_become_the_report() {
+        let stats = "sending incremental file list\n\
+            Number of files: 1,204 (reg: 1,100, dir: 104)\n\
+            Number of created files: 2\n\
+            Number of deleted files: 3\n\
+            Number of regular files transferred: 1,010\n\
+            Total file size: 9,876,543 bytes\n\
+            Total bytes sent: 1.234.567\n\
+            Total bytes received: 4,321\n\n\
+            sent 1,234,567 bytes  received 4,321 bytes  825,925.33 bytes/sec\n";
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, RSYNC_VERSION, "")
+                .reply(0, stats, ""),
+        );
+        let report = with_runner(runner.clone(), || {
+            sync_directory("src/", "me@box:app", &RsyncOptions::default())
+        })
+        .unwrap();
+        assert!(runner.commands()[1].contains(" --stats "));
+        assert_eq!(
+            (report.files_transferred, report.bytes_sent, report.deleted),
+            (1010, 1234567, 3)
+        );
+        assert_eq!(
+            report.paths,
+            vec![PathReport {
+                path: "src/".to_string(),
+                files_transferred: 1010,
+                bytes_sent: 1234567,
+                deleted: 3,
+                result: PathResult::Synced,
+                changed: None,
+            }]
+        );
+
+        // rsync before 3.1 names the count differently and has no deletions
+        let old = parse_stats(
+            "src/",
+            b"Number of files: 7\nNumber of files transferred: 2\n",
+        );
+        assert_eq!((old.files_transferred, old.deleted), (2, 0));
+    }
+
+    #[test]
+    fn progress_lines_become_events() {
+        assert_eq!(
+            parse_progress_line(
+                "      1,234,567  45%   12.34MB/s    0:00:01 (xfr#3, to-chk=10/20)"
+            ),
+            Some(SyncEvent::Progress {
+                bytes: 1234567,
+                percent: 45,
+                rate: "12.34MB/s".to_string()
+            })
+        );
+        assert_eq!(
+            parse_progress_line("src/main.rs"),
+            Some(SyncEvent::File("src/main.rs".to_string()))
+        );
+        // A file whose name looks like progress is still a file
+        assert_eq!(
+            parse_progress_line("100 50% done.txt"),
+            Some(SyncEvent::File("100 50% done.txt".to_string()))
+        );
+        assert_eq!(parse_progress_line("sending incremental file list"), None);
+        assert_eq!(parse_progress_line(""), None);
+    }
+
+    #[test]
+    fn dir_contents_has_one_trailing_slash() {
+        assert_eq!(dir_contents("."), "./");
+        assert_eq!(dir_contents("./"), "./");
+        assert_eq!(dir_contents("host:/srv/app"), "host:/srv/app/");
+        assert_eq!(dir_contents("host:/srv/app//"), "host:/srv/app/");
+    }
+
+    #[test]
+    fn escaped_names_get_their_bytes_back() {
+        assert_eq!(rsync_path("src/main.rs"), Path::new("src/main.rs"));
+        assert_eq!(
+            rsync_path("data/caf\\#351.csv").as_os_str().as_bytes(),
+            b"data/caf\xe9.csv"
+        );
+        // Only complete octal escapes count
+        assert_eq!(rsync_path("a\\#9b\\#12"), Path::new("a\\#9b\\#12"));
+        assert_eq!(
+            files_from_list(&["a".to_string(), "b\\#377".to_string()]),
+            b"a\nb\xff\n"
+        );
+    }
+
+    #[test]
+    fn listing_lines_give_path_size_and_mtime() {
+        assert_eq!(
+            parse_listing_line("-rw-r--r--      1,234,567 2024/05/01 12:00:00 data/a b.csv"),
+            Some((
+                "data/a b.csv".to_string(),
+                ListedFile {
+                    size: 1234567,
+                    mtime: "2024/05/01 12:00:00".to_string(),
+                }
+            ))
+        );
+        assert_eq!(parse_listing_line("drwxr-xr-x"), None);
+    }
+
+    #[test]
+    fn command_lines_quote_what_the_shell_would_split() {
+        let options = RsyncOptions {
+            filter: Some(":- .gitignore,- *.log".to_string()),
+            delete: true,
+            rsh: Some("ssh -p 2222".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            rsync_command_line("stage/", "box:/srv/app", &options),
+            "rsync -az --partial --delete -e 'ssh -p 2222' --filter ':- .gitignore' \
+             --filter '- *.log' stage/ box:/srv/app"
+        );
+    }
+
+    #[test]
+    fn listing_times_sort_like_unix_times() {
+        let day = 86_400;
+        let times: Vec<String> = [0, day, 40 * day, 400 * day, 20_000 * day]
+            .into_iter()
+            .map(listing_time)
+            .collect();
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(times.iter().all(|time| time.len() == 19));
+    }
+
+    #[test]
+    fn rsync_versions_parse() {
+        let cases = [
+            (
+                "rsync  version 3.2.7  protocol version 31\n",
+                Some((3, 2, 7)),
+            ),
+            (
+                "rsync  version v3.2.3  protocol version 31",
+                Some((3, 2, 3)),
+            ),
+            ("rsync  version 3.1.2  protocol version 31", Some((3, 1, 2))),
+            ("rsync  version 3.0.9  protocol version 30", Some((3, 0, 9))),
+            ("rsync  version 3.4  protocol version 32", Some((3, 4, 0))),
+            (
+                "rsync  version 3.2.7dev  protocol version 31",
+                Some((3, 2, 7)),
+            ),
+            ("openrsync: protocol version 29", None),
+            ("rsync  version", None),
+            ("", None),
+        ];
+        for (output, version) in cases {
+            assert_eq!(parse_rsync_version(output), version, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn remote_versions_come_from_the_remote() {
+        let target = RemoteTarget::parse("me@box");
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, "rsync  version 3.1.3  protocol version 31\n", "")
+                .reply(127, "", "")
+                .reply(0, "something else\n", ""),
+        );
+        let versions = with_runner(runner.clone(), || {
+            [
+                remote_rsync_version(&target).unwrap(),
+                remote_rsync_version(&target).unwrap(),
+                remote_rsync_version(&target).unwrap(),
+            ]
+        });
+        assert_eq!(versions, [Some((3, 1, 3)), None, None]);
+        assert!(runner.commands()[0].ends_with("me@box rsync --version 2>/dev/null"));
+    }
+
+    #[test]
+    fn old_local_rsync_is_refused() {
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, "rsync  version 2.6.9  protocol version 29\n", "")
+                .reply(0, "garbage\n", "")
+                .reply(1, "", ""),
+        );
+        let results = with_runner(runner, || {
+            [
+                check_rsync_version().unwrap_err().to_string(),
+                check_rsync_version().unwrap_err().to_string(),
+                check_rsync_version().unwrap_err().to_string(),
+            ]
+        });
+        assert!(
+            results[0].contains("2.6.9 is not supported"),
+            "{}",
+            results[0]
+        );
+        assert!(results[1].contains("Could not parse"), "{}", results[1]);
+        assert_eq!(results[2], "Failed to get rsync version");
+    }
+
+    #[test]
+    fn options_are_adjusted_to_the_remote_version() {
+        let args = [
+            "--info=progress2",
+            "--usermap=*:www",
+            "--zc=zstd",
+            "--mkpath",
+            "--bwlimit=2m",
+        ];
+        let cases: [(Option<RsyncVersion>, &[&str], &[&str]); 5] = [
+            (None, &args, &[]),
+            (Some((3, 2, 7)), &args, &[]),
+            (
+                Some((3, 2, 0)),
+                &[
+                    "--info=progress2",
+                    "--usermap=*:www",
+                    "--zc=zstd",
+                    "--bwlimit=2m",
+                ],
+                &["--mkpath"],
+            ),
+            (
+                Some((3, 1, 0)),
+                &["--info=progress2", "--usermap=*:www", "--bwlimit=2m"],
+                &["--zc=zstd", "--mkpath"],
+            ),
+            (
+                Some((3, 0, 9)),
+                &["--progress", "--bwlimit=2m"],
+                &[
+                    "--info=progress2",
+                    "--usermap=*:www",
+                    "--zc=zstd",
+                    "--mkpath",
+                ],
+            ),
+        ];
+        for (version, compatible, incompatible) in cases {
+            let options = RsyncOptions {
+                extra_args: args.map(String::from).to_vec(),
+                remote_version: version,
+                ..Default::default()
+            };
+            assert_eq!(options.compatible_args(), compatible, "{:?}", version);
+            assert_eq!(options.incompatible_args(), incompatible, "{:?}", version);
+        }
+
+        // Only whole options match, not ones sharing a prefix
+        let options = RsyncOptions {
+            extra_args: vec!["--information".to_string(), "--ccx".to_string()],
+            remote_version: Some((3, 0, 0)),
+            ..Default::default()
+        };
+        assert!(options.incompatible_args().is_empty());
+    }
+
+    #[test]
+    fn mkpath_needs_both_ends() {
+        let cases = [
+            (
+                "rsync  version 3.2.7  protocol version 31\n",
+                Some((3, 2, 3)),
+                true,
+            ),
+            (
+                "rsync  version 3.2.7  protocol version 31\n",
+                Some((3, 2, 2)),
+                false,
+            ),
+            (
+                "rsync  version 3.2.2  protocol version 31\n",
+                Some((3, 2, 7)),
+                false,
+            ),
+            ("rsync  version 3.2.7  protocol version 31\n", None, false),
+        ];
+        for (local, remote, supported) in cases {
+            let runner = Rc::new(MockRunner::default().reply(0, local, ""));
+            let result = with_runner(runner, || mkpath_supported(remote));
+            assert_eq!(result, supported, "{} {:?}", local, remote);
+        }
+    }
+}
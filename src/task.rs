@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::thread;
+
+use crate::error::{SyncError, SyncResult};
+
+// A command running in the background, like the transfer started by
+// `sync_directory_async`. Async code can await it on any runtime, as it
+// doesn't need one of its own; other code can `wait` for it. Cancelling
+// terminates the process, and the task then fails.
+pub struct Task {
+    what: &'static str,
+    pid: u32,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<TaskState>,
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct TaskState {
+    status: Option<io::Result<ExitStatus>>,
+    cancelled: bool,
+    waker: Option<Waker>,
+}
+
+impl Task {
+    // Start `cmd`, calling it `what` in errors. A thread waits for it, so
+    // that waiting never blocks an async executor.
+    pub fn spawn(mut cmd: Command, what: &'static str) -> Result<Self> {
+        let mut child = cmd.spawn().context(format!("Failed to execute {}", what))?;
+        let pid = child.id();
+        let shared = Arc::new(Shared::default());
+
+        let waiter = Arc::clone(&shared);
+        thread::spawn(move || {
+            let status = child.wait();
+            let mut state = waiter.state.lock().unwrap();
+            state.status = Some(status);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            waiter.finished.notify_all();
+        });
+
+        Ok(Self { what, pid, shared })
+    }
+
+    // Stop the command. It may still finish on its own first.
+    pub fn cancel(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.status.is_none() {
+            state.cancelled = true;
+            // SAFETY: signals our own child. Its status is recorded right
+            // after it's reaped, leaving only a moment for the pid to be
+            // reused before this can tell.
+            unsafe { libc::kill(self.pid as libc::pid_t, libc::SIGTERM) };
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.shared.state.lock().unwrap().status.is_some()
+    }
+
+    // Block until the command is done
+    pub fn wait(self) -> SyncResult<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.status.is_none() {
+            state = self.shared.finished.wait(state).unwrap();
+        }
+        self.outcome(&mut state)
+    }
+
+    fn outcome(&self, state: &mut TaskState) -> SyncResult<()> {
+        let status = state
+            .status
+            .take()
+            .context(format!("{} was already waited for", self.what))?
+            .context(format!("Failed to wait for {}", self.what))?;
+        if state.cancelled {
+            return Err(SyncError::Cancelled {
+                what: self.what.to_string(),
+            });
+        }
+        if !status.success() {
+            return Err(SyncError::command_failed(self.what, status.code()));
+        }
+        Ok(())
+    }
+}
+
+impl Future for Task {
+    type Output = SyncResult<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.status.is_none() {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(self.outcome(&mut state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Wake;
+
+    // Just enough of an executor to drive one future on this thread
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = TaskContext::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[test]
+    fn tasks_can_be_awaited_waited_for_and_cancelled() {
+        assert!(block_on(Task::spawn(sh("sleep 0.1"), "sleep").unwrap()).is_ok());
+        let error = block_on(Task::spawn(sh("exit 3"), "sh").unwrap()).unwrap_err();
+        assert_eq!(error.to_string(), "sh failed with exit code: Some(3)");
+
+        let tasks: Vec<Task> = (0..3)
+            .map(|_| Task::spawn(sh("exec sleep 30"), "sleep").unwrap())
+            .collect();
+        for task in &tasks {
+            task.cancel();
+        }
+        for task in tasks {
+            assert_eq!(task.wait().unwrap_err().to_string(), "sleep was cancelled");
+        }
+    }
+}
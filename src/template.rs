@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// A local template rendered with per-remote variables and synced to `target`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TemplateSpec {
+    pub source: String,
+    pub target: String,
+}
+
+impl TemplateSpec {
+    // Parse `SOURCE[:TARGET]`; without a target the template extension is dropped
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (source, target) = match spec.split_once(':') {
+            Some((source, target)) => (source.to_string(), target.to_string()),
+            None => {
+                let target = strip_template_extension(spec).context(format!(
+                    "Template '{}' needs an explicit target (SOURCE:TARGET)",
+                    spec
+                ))?;
+                (spec.to_string(), target.to_string())
+            }
+        };
+
+        if source.is_empty() || target.is_empty() {
+            anyhow::bail!("Invalid template specification '{}'", spec);
+        }
+
+        Ok(Self { source, target })
+    }
+}
+
+// The path a template renders to, if it has a template extension
+pub fn strip_template_extension(path: &str) -> Option<&str> {
+    [".tera", ".tmpl", ".template"]
+        .iter()
+        .find_map(|ext| path.strip_suffix(ext))
+        .filter(|target| !target.is_empty() && !target.ends_with('/'))
+}
+
+// An `{{ if }}` block being rendered
+struct Block {
+    // Whether the text around the block is kept
+    outer: bool,
+    condition: bool,
+    in_else: bool,
+}
+
+impl Block {
+    fn shown(&self) -> bool {
+        self.outer && self.condition != self.in_else
+    }
+}
+
+// Replace `{{ name }}` placeholders with their values, failing on unknown
+// names. `{{ if name }}`, `{{ if name == "value" }}` or `!=`, `{{ else }}`
+// and `{{ end }}` keep or drop the text between them, so that one template
+// can serve several hosts. Undefined names are false there, and only fail
+// in text that is kept. A line holding nothing but one of these tags is
+// left out entirely.
+pub fn render(template: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut rest = template;
+    let mut at_line_start = true;
+
+    while let Some(start) = rest.find("{{") {
+        let shown = blocks.last().is_none_or(Block::shown);
+        let end = rest[start..]
+            .find("}}")
+            .context("Unterminated '{{' in template")?;
+        let tag = rest[start + 2..start + end].trim();
+        let mut before = &rest[..start];
+        let mut after = &rest[start + end + 2..];
+        let mut standalone = false;
+
+        let keyword = tag.split_whitespace().next().unwrap_or_default();
+        if matches!(keyword, "if" | "else" | "end") {
+            let line_before = match before.rfind('\n') {
+                Some(newline) => Some(&before[newline + 1..]),
+                None => Some(before).filter(|_| at_line_start),
+            };
+            let line_after = after.split_once('\n').map_or(after, |(line, _)| line);
+            if let Some(indent) =
+                line_before.filter(|line| line.trim().is_empty() && line_after.trim().is_empty())
+            {
+                before = &before[..before.len() - indent.len()];
+                after = after.split_once('\n').map_or("", |(_, rest)| rest);
+                standalone = true;
+            }
+        }
+        if shown {
+            rendered.push_str(before);
+        }
+
+        match keyword {
+            "if" => blocks.push(Block {
+                outer: shown,
+                condition: condition(tag["if".len()..].trim(), vars)?,
+                in_else: false,
+            }),
+            "else" if tag == "else" => {
+                let block = blocks
+                    .last_mut()
+                    .context("'else' without 'if' in template")?;
+                if block.in_else {
+                    anyhow::bail!("Second 'else' for one 'if' in template");
+                }
+                block.in_else = true;
+            }
+            "end" if tag == "end" => {
+                blocks.pop().context("'end' without 'if' in template")?;
+            }
+            _ if shown => {
+                let value = vars
+                    .get(tag)
+                    .context(format!("Undefined template variable '{}'", tag))?;
+                rendered.push_str(value);
+            }
+            _ => {}
+        }
+        at_line_start = standalone;
+        rest = after;
+    }
+    if !blocks.is_empty() {
+        anyhow::bail!("'if' without 'end' in template");
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+// Whether `name`, `name == "value"` or `name != "value"` holds. A bare name
+// holds when it's defined and not empty.
+fn condition(expression: &str, vars: &BTreeMap<String, String>) -> Result<bool> {
+    let unquote = |value: &str| {
+        let value = value.trim();
+        value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value)
+            .to_string()
+    };
+    let (name, equal, value) = if let Some((name, value)) = expression.split_once("==") {
+        (name.trim(), true, unquote(value))
+    } else if let Some((name, value)) = expression.split_once("!=") {
+        (name.trim(), false, unquote(value))
+    } else {
+        let name = expression.trim();
+        if name.is_empty() {
+            anyhow::bail!("'if' without a condition in template");
+        }
+        return Ok(vars.get(name).is_some_and(|value| !value.is_empty()));
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        anyhow::bail!("Invalid condition '{}' in template", expression);
+    }
+    Ok((vars.get(name) == Some(&value)) == equal)
+}
+
+// Render every template into the staging directory under its target path
+pub fn render_templates(
+    base: &Path,
+    templates: &[TemplateSpec],
+    vars: &BTreeMap<String, String>,
+    staging_dir: &Path,
+) -> Result<()> {
+    for template in templates {
+        let contents = fs::read_to_string(base.join(&template.source))
+            .context(format!("Failed to read template '{}'", template.source))?;
+        let rendered =
+            render(&contents, vars).context(format!("Failed to render '{}'", template.source))?;
+
+        let target = staging_dir.join(template.target.trim_start_matches('/'));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).context("Failed to create staging directory")?;
+        }
+        fs::write(&target, rendered).context("Failed to write rendered template")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_pick_text_per_host() {
+        let vars = BTreeMap::from([
+            ("name".to_string(), "gpu-box".to_string()),
+            ("editor".to_string(), "nvim".to_string()),
+        ]);
+        let template = "export EDITOR={{ editor }}
+{{ if name == \"gpu-box\" }}
+export CUDA_VISIBLE_DEVICES=0,1
+  {{ if proxy }}
+export HTTPS_PROXY={{ proxy }}
+  {{ else }}
+unset HTTPS_PROXY
+  {{ end }}
+{{ else }}
+export PATH={{ undefined_here }}
+{{ end }}
+alias gs='git status'{{ if name != \"laptop\" }} # remote{{ end }}
+";
+        assert_eq!(
+            render(template, &vars).unwrap(),
+            "export EDITOR=nvim
+export CUDA_VISIBLE_DEVICES=0,1
+unset HTTPS_PROXY
+alias gs='git status' # remote
+"
+        );
+
+        assert!(render("{{ if name }}open", &vars).is_err());
+        assert!(render("{{ end }}", &vars).is_err());
+        assert!(render("{{ missing }}", &vars).is_err());
+        assert_eq!(
+            strip_template_extension(".gitconfig.tmpl"),
+            Some(".gitconfig")
+        );
+        assert_eq!(strip_template_extension(".tmpl"), None);
+    }
+}
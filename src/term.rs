@@ -0,0 +1,136 @@
+use anyhow::Result;
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::os::fd::AsRawFd;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set while stdout goes to a pager, which shows colors like the terminal would
+static PAGING: AtomicBool = AtomicBool::new(false);
+
+// Whether output to a stream should be colored, following the NO_COLOR and
+// CLICOLOR/CLICOLOR_FORCE conventions and falling back to tty detection
+fn color_enabled(is_terminal: bool) -> bool {
+    let set = |name: &str| env::var_os(name).is_some_and(|value| !value.is_empty());
+
+    if set("NO_COLOR") {
+        return false;
+    }
+    if set("CLICOLOR_FORCE") && env::var_os("CLICOLOR_FORCE").is_some_and(|value| value != "0") {
+        return true;
+    }
+    if env::var_os("CLICOLOR").is_some_and(|value| value == "0") {
+        return false;
+    }
+    is_terminal && env::var_os("TERM").is_none_or(|term| term != "dumb")
+}
+
+pub fn stdout_color() -> bool {
+    color_enabled(io::stdout().is_terminal() || PAGING.load(Ordering::Relaxed))
+}
+
+pub fn stderr_color() -> bool {
+    color_enabled(io::stderr().is_terminal())
+}
+
+fn paint(code: &str, text: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn bold(text: &str) -> String {
+    paint("1", text, stdout_color())
+}
+
+pub fn green(text: &str) -> String {
+    paint("32", text, stdout_color())
+}
+
+pub fn yellow(text: &str) -> String {
+    paint("33", text, stdout_color())
+}
+
+pub fn dim(text: &str) -> String {
+    paint("2", text, stdout_color())
+}
+
+// Print a warning to stderr
+pub fn warn(message: fmt::Arguments) {
+    eprintln!("{} {}", paint("33", "Warning:", stderr_color()), message);
+}
+
+// Fail instead of waiting for input that will never come
+pub fn require_interactive(what: &str) -> Result<()> {
+    if !io::stdin().is_terminal() {
+        anyhow::bail!(
+            "{} needs an interactive terminal (stdin is not a tty)",
+            what
+        );
+    }
+    Ok(())
+}
+
+// Sends stdout through a pager until dropped, like git does for long output.
+// The pager is SYNC_RS_PAGER, PAGER or `less`; with LESS unset it quits right
+// away when everything fits on one screen, so short output looks the same.
+pub struct Pager {
+    child: Child,
+    stdout: libc::c_int,
+}
+
+impl Pager {
+    // Only starts one when stdout is a terminal, paging isn't turned off and
+    // the pager can be run
+    pub fn start(disabled: bool) -> Option<Self> {
+        if disabled || !io::stdout().is_terminal() {
+            return None;
+        }
+        let pager = env::var("SYNC_RS_PAGER")
+            .or_else(|_| env::var("PAGER"))
+            .unwrap_or_else(|_| "less".to_string());
+        if pager.is_empty() || pager == "cat" {
+            return None;
+        }
+
+        let mut child = Command::new("sh")
+            .args(["-c", &pager])
+            .env(
+                "LESS",
+                env::var("LESS").unwrap_or_else(|_| "FRX".to_string()),
+            )
+            .stdin(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let input = child.stdin.take()?;
+        io::stdout().flush().ok();
+        // SAFETY: duplicates descriptors we own; the original stdout is kept
+        // to be put back when the pager is done
+        let stdout = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if stdout < 0 || unsafe { libc::dup2(input.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+            return None;
+        }
+        // Quitting the pager early ends the output quietly instead of failing
+        // on the closed pipe
+        unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+        PAGING.store(true, Ordering::Relaxed);
+        Some(Self { child, stdout })
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        io::stdout().flush().ok();
+        // SAFETY: puts back the stdout saved in `start`, which closes the
+        // last write end of the pipe so that the pager sees the end of input
+        unsafe {
+            libc::dup2(self.stdout, libc::STDOUT_FILENO);
+            libc::close(self.stdout);
+        }
+        PAGING.store(false, Ordering::Relaxed);
+        self.child.wait().ok();
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::term::dim;
+
+// Runs kept per remote in the state for performance tracking
+pub const TIMING_HISTORY: usize = 20;
+
+// How long each named step of a sync took, in the order they first ran
+#[derive(Debug, Default)]
+pub struct Timings {
+    pub phases: Vec<PhaseTiming>,
+    current: Option<(String, Instant)>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PhaseTiming {
+    pub name: String,
+    pub ms: u64,
+}
+
+// One past sync, as recorded in the state
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimedRun {
+    // Unix time the sync finished
+    pub at: u64,
+    pub ok: bool,
+    pub total_ms: u64,
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl Timings {
+    // End the current phase and start `name`. Time spent in a phase that is
+    // entered again adds up.
+    pub fn phase(&mut self, name: &str) {
+        self.finish();
+        self.current = Some((name.to_string(), Instant::now()));
+    }
+
+    pub fn finish(&mut self) {
+        let Some((name, started)) = self.current.take() else {
+            return;
+        };
+        let ms = started.elapsed().as_millis() as u64;
+        match self.phases.iter_mut().find(|phase| phase.name == name) {
+            Some(phase) => phase.ms += ms,
+            None => self.phases.push(PhaseTiming { name, ms }),
+        }
+    }
+
+    pub fn print(&self, total: Duration) {
+        println!("Timings:");
+        let width = self
+            .phases
+            .iter()
+            .map(|phase| phase.name.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+        let total_ms = total.as_millis() as u64;
+        for phase in &self.phases {
+            println!(
+                "  {:width$}  {:>7}  {}",
+                phase.name,
+                format_ms(phase.ms),
+                dim(&format!("{:>3}%", phase.ms * 100 / total_ms.max(1))),
+            );
+        }
+        println!("  {:width$}  {:>7}", "total", format_ms(total_ms));
+    }
+}
+
+// Milliseconds as seconds with one decimal, e.g. `12.3s`
+pub fn format_ms(ms: u64) -> String {
+    format!("{:.1}s", ms as f64 / 1000.0)
+}
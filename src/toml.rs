@@ -0,0 +1,678 @@
+use anyhow::{Context, Result};
+use serde_json::{Map, Number, Value};
+
+// A small TOML reader covering what configuration files need: tables, arrays
+// of tables, dotted keys, strings, integers, floats, booleans, arrays and
+// inline tables (dates are not supported). The result is a JSON value, so
+// it can be deserialized with serde_json.
+pub fn parse(input: &str) -> Result<Value> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    parser
+        .document()
+        .with_context(|| format!("Invalid TOML at line {}", parser.line))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => anyhow::bail!("Expected '{}', found '{}'", expected, c),
+            None => anyhow::bail!("Expected '{}', found end of input", expected),
+        }
+    }
+
+    // Skip spaces and tabs, and a comment up to the end of the line
+    fn skip_inline_space(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // Skip whitespace including newlines, and comments
+    fn skip_space(&mut self) {
+        loop {
+            self.skip_inline_space();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.next();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<()> {
+        self.skip_inline_space();
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') => {
+                self.next();
+                Ok(())
+            }
+            Some(c) => anyhow::bail!("Unexpected '{}' after value", c),
+        }
+    }
+
+    fn document(&mut self) -> Result<Value> {
+        let mut root = Map::new();
+        // Path of the table that key/value pairs currently go into
+        let mut current: Vec<String> = Vec::new();
+
+        loop {
+            self.skip_space();
+            let Some(c) = self.peek() else {
+                break;
+            };
+
+            if c == '[' {
+                self.next();
+                let array = self.peek() == Some('[');
+                if array {
+                    self.next();
+                }
+                self.skip_inline_space();
+                let keys = self.key()?;
+                self.skip_inline_space();
+                self.expect(']')?;
+                if array {
+                    self.expect(']')?;
+                }
+                self.end_of_line()?;
+
+                current = keys;
+                if array {
+                    let (last, parents) = current.split_last().context("Empty key")?;
+                    let parent = table_at(&mut root, parents)?;
+                    let entry = parent
+                        .entry(last.clone())
+                        .or_insert_with(|| Value::Array(Vec::new()));
+                    let Value::Array(items) = entry else {
+                        anyhow::bail!("'{}' is not an array of tables", last);
+                    };
+                    items.push(Value::Object(Map::new()));
+                } else {
+                    table_at(&mut root, &current)?;
+                }
+            } else {
+                let keys = self.key()?;
+                self.skip_inline_space();
+                self.expect('=')?;
+                self.skip_inline_space();
+                let value = self.value()?;
+                self.end_of_line()?;
+
+                let table = table_at(&mut root, &current)?;
+                insert(table, &keys, value)?;
+            }
+        }
+
+        Ok(Value::Object(root))
+    }
+
+    // A possibly dotted key
+    fn key(&mut self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        loop {
+            self.skip_inline_space();
+            let key = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        anyhow::bail!("Expected a key");
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            keys.push(key);
+            self.skip_inline_space();
+            if self.peek() == Some('.') {
+                self.next();
+            } else {
+                return Ok(keys);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.basic_string()?)),
+            Some('\'') => Ok(Value::String(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(_) => self.scalar(),
+            None => anyhow::bail!("Expected a value"),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        if self.starts_with("\"\"\"") {
+            self.pos += 3;
+            // A newline right after the opening quotes is not part of the string
+            if self.peek() == Some('\n') {
+                self.next();
+            }
+            let mut s = String::new();
+            loop {
+                if self.starts_with("\"\"\"") {
+                    self.pos += 3;
+                    return Ok(s);
+                }
+                match self.next() {
+                    Some('\\') => {
+                        // Line-ending backslash trims the following whitespace
+                        if self.peek().is_some_and(|c| c == '\n' || c == ' ') {
+                            while self.peek().is_some_and(char::is_whitespace) {
+                                self.next();
+                            }
+                        } else {
+                            s.push(self.escape()?);
+                        }
+                    }
+                    Some(c) => s.push(c),
+                    None => anyhow::bail!("Unterminated string"),
+                }
+            }
+        }
+
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.escape()?),
+                Some('\n') | None => anyhow::bail!("Unterminated string"),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char> {
+        Ok(match self.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(c @ ('u' | 'U')) => {
+                let len = if c == 'u' { 4 } else { 8 };
+                let hex: String = (0..len).filter_map(|_| self.next()).collect();
+                u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .context(format!("Invalid unicode escape '\\{}{}'", c, hex))?
+            }
+            Some(c) => anyhow::bail!("Invalid escape '\\{}'", c),
+            None => anyhow::bail!("Unterminated string"),
+        })
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        if self.starts_with("'''") {
+            self.pos += 3;
+            if self.peek() == Some('\n') {
+                self.next();
+            }
+            let mut s = String::new();
+            loop {
+                if self.starts_with("'''") {
+                    self.pos += 3;
+                    return Ok(s);
+                }
+                s.push(self.next().context("Unterminated string")?);
+            }
+        }
+
+        self.expect('\'')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(s),
+                Some('\n') | None => anyhow::bail!("Unterminated string"),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_space();
+            if self.peek() == Some(']') {
+                self.next();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_space();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => anyhow::bail!("Expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value> {
+        self.expect('{')?;
+        let mut table = Map::new();
+        self.skip_inline_space();
+        if self.peek() == Some('}') {
+            self.next();
+            return Ok(Value::Object(table));
+        }
+        loop {
+            let keys = self.key()?;
+            self.skip_inline_space();
+            self.expect('=')?;
+            self.skip_inline_space();
+            let value = self.value()?;
+            insert(&mut table, &keys, value)?;
+            self.skip_inline_space();
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Object(table)),
+                _ => anyhow::bail!("Expected ',' or '}}' in inline table"),
+            }
+        }
+    }
+
+    // Booleans and numbers
+    fn scalar(&mut self) -> Result<Value> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| !matches!(c, ',' | ']' | '}' | '#' | ' ' | '\t' | '\r' | '\n'))
+        {
+            self.pos += 1;
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+
+        match token.as_str() {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => {}
+        }
+
+        let digits = token.replace('_', "");
+        let integer = if let Some(hex) = digits.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(octal) = digits.strip_prefix("0o") {
+            i64::from_str_radix(octal, 8).ok()
+        } else if let Some(binary) = digits.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2).ok()
+        } else {
+            digits.parse::<i64>().ok()
+        };
+        if let Some(integer) = integer {
+            return Ok(Value::Number(integer.into()));
+        }
+
+        digits
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number)
+            .context(format!("Invalid value '{}'", token))
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        let mut chars = s.chars();
+        self.chars[self.pos..]
+            .iter()
+            .take(s.len())
+            .all(|c| Some(*c) == chars.next())
+            && self.chars.len() - self.pos >= s.len()
+    }
+}
+
+// The table at `path`, creating missing tables on the way
+fn table_at<'a>(
+    root: &'a mut Map<String, Value>,
+    path: &[String],
+) -> Result<&'a mut Map<String, Value>> {
+    let mut table = root;
+    for key in path {
+        let value = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        table = match value {
+            Value::Object(map) => map,
+            // Continue into the most recent element of an array of tables
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Object(map)) => map,
+                _ => anyhow::bail!("'{}' is not a table", key),
+            },
+            _ => anyhow::bail!("'{}' is not a table", key),
+        };
+    }
+    Ok(table)
+}
+
+// Set a dotted key in a table
+fn insert(table: &mut Map<String, Value>, keys: &[String], value: Value) -> Result<()> {
+    let (last, parents) = keys.split_last().context("Empty key")?;
+    let table = table_at(table, parents)?;
+    if table.contains_key(last) {
+        anyhow::bail!("Duplicate key '{}'", last);
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+// Write a JSON object as TOML. Nulls are left out, since TOML has no null.
+pub fn to_string(value: &Value) -> Result<String> {
+    let Value::Object(root) = value else {
+        anyhow::bail!("Only tables can be written as TOML");
+    };
+    let mut out = String::new();
+    write_table(&mut out, &[], root)?;
+    Ok(out.trim_start().to_string())
+}
+
+fn write_table(out: &mut String, path: &[String], table: &Map<String, Value>) -> Result<()> {
+    let is_table_array = |value: &Value| matches!(value, Value::Array(items) if !items.is_empty() && items.iter().all(Value::is_object));
+
+    // Plain values come before any sub-table, or they'd end up inside it
+    for (key, value) in table {
+        if !value.is_null() && !value.is_object() && !is_table_array(value) {
+            out.push_str(&format!("{} = {}\n", format_key(key), inline_value(value)?));
+        }
+    }
+
+    for (key, value) in table {
+        let mut child = path.to_vec();
+        child.push(key.clone());
+        let header = child
+            .iter()
+            .map(|key| format_key(key))
+            .collect::<Vec<_>>()
+            .join(".");
+
+        match value {
+            Value::Object(map) => {
+                out.push_str(&format!("\n[{}]\n", header));
+                write_table(out, &child, map)?;
+            }
+            Value::Array(items) if is_table_array(value) => {
+                for item in items {
+                    out.push_str(&format!("\n[[{}]]\n", header));
+                    if let Value::Object(map) = item {
+                        write_table(out, &child, map)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn inline_value(value: &Value) -> Result<String> {
+    Ok(match value {
+        Value::Null => anyhow::bail!("TOML can't represent null"),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => quote(s),
+        Value::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(inline_value)
+                .collect::<Result<Vec<_>>>()?
+                .join(", ")
+        ),
+        Value::Object(map) => format!(
+            "{{ {} }}",
+            map.iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| Ok(format!("{} = {}", format_key(key), inline_value(value)?)))
+                .collect::<Result<Vec<_>>>()?
+                .join(", ")
+        ),
+    })
+}
+
+// Bare keys where possible, quoted otherwise
+fn format_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_string()
+    } else {
+        quote(key)
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn strings_and_escapes() {
+        let value = parse(
+            r#"
+basic = "tab\there \"quoted\" \\ \u00e9\U0001F600"
+literal = 'C:\path\no escapes'
+multi = """
+first
+second \
+    joined"""
+multi_literal = '''
+raw \n'''
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "basic": "tab\there \"quoted\" \\ \u{e9}\u{1F600}",
+                "literal": r"C:\path\no escapes",
+                "multi": "first\nsecond joined",
+                "multi_literal": "raw \\n",
+            })
+        );
+    }
+
+    #[test]
+    fn numbers_booleans_and_arrays() {
+        let value = parse(
+            r#"
+count = 1_000
+negative = -3
+hex = 0xff
+octal = 0o17
+binary = 0b101
+ratio = 2.5
+exponent = 1e3
+on = true
+off = false
+empty = []
+nested = [[1, 2], ["a"]]
+trailing = [
+    "a",  # comment inside
+    "b",
+]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "count": 1000,
+                "negative": -3,
+                "hex": 255,
+                "octal": 15,
+                "binary": 5,
+                "ratio": 2.5,
+                "exponent": 1000.0,
+                "on": true,
+                "off": false,
+                "empty": [],
+                "nested": [[1, 2], ["a"]],
+                "trailing": ["a", "b"],
+            })
+        );
+    }
+
+    #[test]
+    fn tables_dotted_keys_and_comments() {
+        let value = parse(
+            r#"
+# A comment line
+top = 1 # and a trailing one
+ssh.binary = "ssh"
+
+[defaults]
+max_deletes = 10
+"quoted key" = { a = 1, b.c = "x" }
+
+[defaults.nested]
+deep = true
+
+[[protected]]
+hosts = ["gpu-*"]
+
+[[protected]]
+hosts = ["cluster-*"]
+paths = []
+
+[[protected.extra]]
+name = "inside the second rule"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "top": 1,
+                "ssh": { "binary": "ssh" },
+                "defaults": {
+                    "max_deletes": 10,
+                    "quoted key": { "a": 1, "b": { "c": "x" } },
+                    "nested": { "deep": true },
+                },
+                "protected": [
+                    { "hosts": ["gpu-*"] },
+                    {
+                        "hosts": ["cluster-*"],
+                        "paths": [],
+                        "extra": [{ "name": "inside the second rule" }],
+                    },
+                ],
+            })
+        );
+        assert_eq!(parse("").unwrap(), json!({}));
+        assert_eq!(
+            parse("a = 1\r\nb = 2\r\n").unwrap(),
+            json!({ "a": 1, "b": 2 })
+        );
+    }
+
+    #[test]
+    fn malformed_input_is_refused_with_its_line() {
+        for input in [
+            "key",
+            "key =",
+            "= 1",
+            "a = 1 2",
+            "a = \"unterminated",
+            "a = \"line\nbreak\"",
+            "a = '''never closed",
+            "a = \"bad \\q escape\"",
+            "a = \"\\u12\"",
+            "a = [1, 2",
+            "a = [1 2]",
+            "a = { b = 1",
+            "a = nope",
+            "a = 1\na = 2",
+            "a = 1\n[a]",
+            "[table",
+            "[[array]",
+            "[]",
+            "a = 1\n[[a]]",
+        ] {
+            assert!(parse(input).is_err(), "{:?}", input);
+        }
+        let err = format!("{:#}", parse("a = 1\nb = 2\nc = oops").unwrap_err());
+        assert!(err.contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn written_toml_reads_back() {
+        let value = json!({
+            "name": "box \"one\"",
+            "list": [1, 2.5, "three"],
+            "skipped": null,
+            "inline": { "a": true },
+            "table": { "key with space": "\u{1}\ttab", "deep": { "x": 1 } },
+            "rules": [{ "hosts": ["a"] }, { "hosts": ["b"] }],
+        });
+        let written = to_string(&value).unwrap();
+        let mut expected = value.clone();
+        expected.as_object_mut().unwrap().remove("skipped");
+        assert_eq!(parse(&written).unwrap(), expected, "{}", written);
+        assert!(to_string(&json!([1])).is_err());
+    }
+}
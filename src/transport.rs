@@ -0,0 +1,1177 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::chunked::chunked_sync;
+use crate::config::slugify;
+use crate::dedup::dedup_large_files;
+use crate::error::{SyncError, SyncResult};
+use crate::events::{read_rsync_changes, RSYNC_LOG_FORMAT};
+use crate::home::{filter_args, HomePlan};
+use crate::remote::{shell_quote, RemoteTarget};
+use crate::runner;
+use crate::sftp::{local_file_sizes, sftp_available, sftp_upload};
+use crate::sync::{
+    check_free_space, dir_contents, files_from_list, itemize_changes, itemize_command_line,
+    list_command_line, list_remote_files, listing_fingerprint, listing_time, pending_files,
+    remote_fingerprint, remote_has_command, rsync_command_line, sync_directory,
+    sync_directory_controlled, sync_directory_with_progress, tar_upload, tar_upload_files,
+    PathReport, PathResult, RsyncOptions, SyncEvent, SyncReport, LOCAL_SOURCE,
+};
+use crate::term::warn;
+
+// How files get to the remote, as saved for each remote
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransportKind {
+    // rsync if the remote has it, otherwise SFTP for SSH hosts (with a local
+    // `sftp`) and tar for the others
+    #[default]
+    Auto,
+    Rsync,
+    // Changed files only, but nothing is deleted
+    Sftp,
+    // With rsync on the remote, the first upload is streamed with tar and
+    // rsync does the rest. Without, every file on every sync, and nothing is
+    // deleted.
+    Tar,
+}
+
+impl TransportKind {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "rsync" => Ok(Self::Rsync),
+            "sftp" => Ok(Self::Sftp),
+            "tar" => Ok(Self::Tar),
+            _ => anyhow::bail!("Expected auto, rsync, sftp or tar, got '{}'", s),
+        }
+    }
+
+    // What a sync to `target` uses: `Auto` becomes the best the remote
+    // allows, and `Tar` becomes rsync when the remote has it
+    pub fn resolve(self, target: &RemoteTarget, has_rsync: bool) -> SyncResult<Self> {
+        Ok(match self {
+            Self::Auto | Self::Rsync | Self::Tar if has_rsync => Self::Rsync,
+            Self::Rsync => {
+                return Err(SyncError::RsyncMissing {
+                    host: Some(target.host().to_string()),
+                })
+            }
+            Self::Auto if target.sftp_command().is_some() && sftp_available() => Self::Sftp,
+            Self::Auto => Self::Tar,
+            kind => kind,
+        })
+    }
+
+    // The backend sending files to `target` this way. `Auto` is taken as
+    // rsync, so resolve it first. rsync to a host behind a relay goes
+    // through the relay.
+    pub fn backend(self, target: &RemoteTarget, options: TransferOptions) -> Box<dyn Transport> {
+        let target = target.clone();
+        match (self, &target) {
+            (Self::Auto | Self::Rsync, RemoteTarget::Relay { relay, .. }) => Box::new(Relay {
+                relay: RemoteTarget::parse(relay),
+                target,
+                options,
+            }),
+            (Self::Auto | Self::Rsync, _) => Box::new(Rsync { target, options }),
+            (Self::Sftp, _) => Box::new(Sftp { target, options }),
+            (Self::Tar, _) => Box::new(Tar { target, options }),
+        }
+    }
+}
+
+// The itemized changes of each step of a transfer, named for the step
+pub type DryRunSteps = Vec<(String, Vec<String>)>;
+
+// What one transfer of a sync sends, from the project directory
+#[derive(Debug, Clone, Copy)]
+pub enum Upload<'a> {
+    // The project, without what's ignored or protected. Remote files that
+    // are gone here are deleted where the transport can.
+    Project,
+    // An override path, kept away from protected paths. As with rsync,
+    // `dir/` goes into the remote directory itself and anything else is
+    // copied as itself.
+    Override(&'a str),
+    // Everything in a local directory, e.g. rendered templates
+    Contents(&'a str),
+    // The paths home mode lists. Nothing is deleted.
+    Home(&'a HomePlan),
+}
+
+// What `Transport::pull` copies back. Nothing local is deleted either way.
+#[derive(Debug, Clone, Copy)]
+pub enum Pull {
+    // Every file that differs from the remote copy
+    All,
+    // Files created or changed on the remote since this Unix time (all of
+    // them without one), unless the local copy is newer
+    ChangedSince(Option<u64>),
+}
+
+// How a sync goes, for each transport to use what applies to it
+#[derive(Clone, Default)]
+pub struct TransferOptions {
+    // The project, which local paths are relative to. Empty for the current
+    // directory.
+    pub local_dir: PathBuf,
+    // rsync options every transfer shares
+    pub base: RsyncOptions,
+    // For the project itself, with its filters and --delete
+    pub main: RsyncOptions,
+    // For override paths
+    pub overrides: RsyncOptions,
+    // For copying back: ignore patterns apply, protected paths don't
+    pub pull: RsyncOptions,
+    // What transports without rsync leave out of the project, and rsync out
+    // of home mode's paths
+    pub excludes: Vec<String>,
+    // What transports without rsync leave out of override paths
+    pub protected: Vec<String>,
+    // Files of at least this size are linked from the shared store in this
+    // remote home directory rather than sent again
+    pub dedup: Option<(String, u64)>,
+    // Push the project through a checkpointed manifest in chunks of this
+    // size first
+    pub chunk_size: Option<u64>,
+    // Send the project's changes as one tar stream first when there are at
+    // least this many
+    pub tar_stream_min: Option<usize>,
+    // Remove what a relay staged once the sync is done
+    pub relay_cleanup: bool,
+    // Told what rsync is doing, instead of showing its output
+    pub progress: Option<Rc<dyn Fn(SyncEvent)>>,
+}
+
+impl TransferOptions {
+    // `path` of the project as the transfers name it. The project itself
+    // (`.` or `./`) is named as its contents.
+    pub fn local(&self, path: &str) -> String {
+        let dir = self.local_dir.to_string_lossy();
+        if dir.is_empty() || dir == "." || Path::new(path).is_absolute() {
+            return path.to_string();
+        }
+        match path.trim_start_matches("./") {
+            "" | "." => dir_contents(&dir),
+            path => format!("{}/{}", dir.trim_end_matches('/'), path),
+        }
+    }
+
+    // The project directory in full
+    fn project_dir(&self) -> Result<PathBuf> {
+        if self.local_dir.as_os_str().is_empty() {
+            return Ok(env::current_dir()?);
+        }
+        Ok(std::path::absolute(&self.local_dir)?)
+    }
+
+    // rsync from `source` to `destination`, telling `progress` how it goes
+    // when there is one
+    fn send(
+        &self,
+        source: &str,
+        destination: &str,
+        options: &RsyncOptions,
+    ) -> SyncResult<SyncReport> {
+        match &self.progress {
+            Some(on_event) => {
+                sync_directory_with_progress(source, destination, options, |event| on_event(event))
+            }
+            None => sync_directory(source, destination, options),
+        }
+    }
+}
+
+// A way of copying local files into a directory on a remote. Every sync goes
+// through one, and the checks around it (remote changes, protected paths,
+// the delete limit) only use what the trait offers, so they apply whichever
+// backend is in use. A backend that can't tell what a sync would change
+// must not delete anything, or those checks refuse to go ahead.
+pub trait Transport {
+    // Fails if the remote can't receive files this way
+    fn check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // What a sync this way does, e.g. for the warning when it's used
+    // because the remote has no rsync
+    fn describe(&self) -> &'static str;
+
+    // Whether syncing the project deletes remote files that are gone here
+    fn deletes(&self) -> bool {
+        false
+    }
+
+    // Fingerprint of the files in `remote_dir`, to tell at the next sync
+    // whether someone else changed them. None if the directory doesn't
+    // exist yet, or this transport can't tell.
+    fn fingerprint(&self, _remote_dir: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    // What sending `upload` would change, itemized like rsync does
+    // (`*deleting path` for deletions), or None if this transport can't
+    // tell beforehand. Nothing is sent to the remote.
+    fn changes(&self, _upload: Upload, _remote_dir: &str) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+
+    // What `--dry-run` shows for `upload`: the changes of each step on the
+    // way to the remote, named unless there's only the one, or None if this
+    // transport can't tell. Unlike `changes`, nothing is written anywhere.
+    fn preview(&self, upload: Upload, remote_dir: &str) -> Result<Option<DryRunSteps>> {
+        Ok(self
+            .changes(upload, remote_dir)?
+            .map(|lines| vec![(String::new(), lines)]))
+    }
+
+    // The files sending `upload` would send, with their sizes, or None if
+    // this transport can't tell beforehand
+    fn pending(&self, _upload: Upload, _remote_dir: &str) -> Result<Option<Vec<(String, u64)>>> {
+        Ok(None)
+    }
+
+    // Copy files from `remote_dir` back into the project directory
+    fn pull(&self, _remote_dir: &str, _pull: Pull) -> Result<()> {
+        anyhow::bail!(
+            "Copying remote files back needs rsync on the remote, and doesn't work through a relay"
+        )
+    }
+
+    // Send `upload` into `remote_dir`. Paths the user skipped or stopped
+    // from the keyboard are marked as such in the report.
+    fn sync(&self, upload: Upload, remote_dir: &str) -> Result<SyncReport>;
+
+    // Called once the last transfer of a sync went through
+    fn finish(&self, _remote_dir: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Rsync {
+    pub target: RemoteTarget,
+    pub options: TransferOptions,
+}
+
+impl Rsync {
+    // The rsync source and options for `upload`
+    fn transfer(&self, upload: Upload) -> (String, RsyncOptions) {
+        match upload {
+            Upload::Project => {
+                let mut options = self.options.main.clone();
+                // Large files are linked from the shared store, so the
+                // regular sync must leave them alone
+                if let Some((_, min_size)) = &self.options.dedup {
+                    options
+                        .extra_args
+                        .push(format!("--max-size={}", min_size.saturating_sub(1)));
+                }
+                (self.options.local(LOCAL_SOURCE), options)
+            }
+            Upload::Override(path) => (self.options.local(path), self.options.overrides.clone()),
+            Upload::Contents(dir) => (
+                dir_contents(&self.options.local(dir)),
+                self.options.base.clone(),
+            ),
+            Upload::Home(plan) => {
+                let mut options = self.options.base.clone();
+                options
+                    .extra_args
+                    .extend(filter_args(plan, &self.options.excludes));
+                (self.options.local(LOCAL_SOURCE), options)
+            }
+        }
+    }
+
+    // What goes ahead of the project's regular transfer: large files into
+    // the shared store, the chunked push, and the tar stream. Returns the
+    // files streamed, with their sizes.
+    fn prepare(
+        &self,
+        remote_dir: &str,
+        source: &str,
+        destination: &str,
+        options: &RsyncOptions,
+    ) -> Result<Vec<(String, u64)>> {
+        let project_dir = self.options.project_dir()?;
+        if let Some((remote_home, min_size)) = &self.options.dedup {
+            dedup_large_files(
+                &project_dir,
+                &self.target,
+                remote_home,
+                remote_dir,
+                &self.options.main,
+                *min_size,
+            )?;
+        }
+
+        // Large initial pushes go through a checkpointed manifest first, so
+        // the regular sync only has to handle what changed since and deletions
+        if let Some(chunk_size) = self.options.chunk_size {
+            chunked_sync(&project_dir, destination, options, chunk_size)?;
+        }
+
+        // With lots of changes, sending them as one tar stream first leaves
+        // rsync with little more than deletions and metadata
+        let Some(min_files) = self.options.tar_stream_min else {
+            return Ok(Vec::new());
+        };
+        let changed = pending_files(source, destination, options)?;
+        if changed.len() < min_files {
+            return Ok(Vec::new());
+        }
+        println!("Streaming {} changed files with tar", changed.len());
+        let files: Vec<String> = changed.iter().map(|(name, _)| name.clone()).collect();
+        tar_upload_files(&self.target, &project_dir, &files, &[], remote_dir)?;
+        Ok(changed)
+    }
+}
+
+impl Transport for Rsync {
+    // Old remotes get a reduced set of options instead of a protocol error
+    fn check(&self) -> Result<()> {
+        let incompatible = self.options.main.incompatible_args();
+        if let Some((major, minor, patch)) = self
+            .options
+            .main
+            .remote_version
+            .filter(|_| !incompatible.is_empty())
+        {
+            warn(format_args!(
+                "rsync {}.{}.{} on the remote doesn't support {}; using older equivalents or leaving them out",
+                major,
+                minor,
+                patch,
+                incompatible.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> &'static str {
+        "Sending changes with rsync"
+    }
+
+    fn deletes(&self) -> bool {
+        true
+    }
+
+    fn fingerprint(&self, remote_dir: &str) -> Result<Option<String>> {
+        remote_fingerprint(&self.target.rsync_location(remote_dir), &self.options.main)
+    }
+
+    fn changes(&self, upload: Upload, remote_dir: &str) -> Result<Option<Vec<String>>> {
+        let (source, options) = self.transfer(upload);
+        itemize_changes(&source, &self.target.rsync_location(remote_dir), &options).map(Some)
+    }
+
+    fn pending(&self, upload: Upload, remote_dir: &str) -> Result<Option<Vec<(String, u64)>>> {
+        let (source, options) = self.transfer(upload);
+        pending_files(&source, &self.target.rsync_location(remote_dir), &options).map(Some)
+    }
+
+    fn pull(&self, remote_dir: &str, pull: Pull) -> Result<()> {
+        let source = dir_contents(&self.target.rsync_location(remote_dir));
+        let local = self.options.local(LOCAL_SOURCE);
+        let since = match pull {
+            Pull::All => {
+                let options = RsyncOptions {
+                    delete: false,
+                    ..self.options.main.clone()
+                };
+                check_free_space(&source, &local, &options)?;
+                sync_directory(&source, &local, &options)?;
+                return Ok(());
+            }
+            Pull::ChangedSince(since) => since.map(listing_time),
+        };
+
+        // Files only on the remote but older than `since` were deleted here,
+        // so they're left for the push to delete
+        let mut options = self.options.pull.clone();
+        options.extra_args.push("--update".to_string());
+        let Some(listing) = list_remote_files(&source, &options)? else {
+            return Ok(());
+        };
+        let changed: Vec<String> = pending_files(&source, &local, &options)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| {
+                listing
+                    .get(name)
+                    .is_some_and(|file| since.as_ref().is_none_or(|since| file.mtime >= *since))
+            })
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        println!("Pulling {} files changed on the remote", changed.len());
+        let files_from = env::temp_dir().join(format!("sync-rs-pull-{}", process::id()));
+        fs::write(&files_from, files_from_list(&changed))?;
+        options
+            .extra_args
+            .push(format!("--files-from={}", files_from.display()));
+        let result = check_free_space(&source, &local, &options)
+            .and_then(|()| Ok(sync_directory(&source, &local, &options).map(drop)?));
+        let _ = fs::remove_file(&files_from);
+        result
+    }
+
+    fn sync(&self, upload: Upload, remote_dir: &str) -> Result<SyncReport> {
+        let destination = self.target.rsync_location(remote_dir);
+        let (source, options) = self.transfer(upload);
+        let mut report = SyncReport::default();
+        match upload {
+            Upload::Project => {
+                let streamed = self.prepare(remote_dir, &source, &destination, &options)?;
+                let mut path = logged_sync(&self.options, &source, &destination, &options, false)?;
+                path.files_transferred += streamed.len() as u64;
+                path.bytes_sent += streamed.iter().map(|(_, size)| size).sum::<u64>();
+                path.changed = path.changed.map(|changed| {
+                    streamed
+                        .into_iter()
+                        .map(|(name, _)| name)
+                        .chain(changed)
+                        .collect()
+                });
+                report.push(path);
+            }
+            Upload::Override(_) => {
+                report.push(logged_sync(
+                    &self.options,
+                    &source,
+                    &destination,
+                    &options,
+                    true,
+                )?);
+            }
+            Upload::Home(plan) if plan.paths.is_empty() => {}
+            Upload::Contents(_) | Upload::Home(_) => {
+                report = self.options.send(&source, &destination, &options)?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+// A controlled rsync transfer with rsync logging what it sent and deleted,
+// for the report to list the changed paths. With a progress callback, it's
+// told how the transfer goes instead of the keyboard controlling it.
+fn logged_sync(
+    transfer: &TransferOptions,
+    source: &str,
+    destination: &str,
+    options: &RsyncOptions,
+    skippable: bool,
+) -> Result<PathReport> {
+    static LOGS: AtomicUsize = AtomicUsize::new(0);
+    let log = env::temp_dir().join(format!(
+        "sync-rs-rsync-{}-{}.log",
+        process::id(),
+        LOGS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut options = options.clone();
+    options
+        .extra_args
+        .push(format!("--log-file={}", log.display()));
+    options
+        .extra_args
+        .push(format!("--log-file-format={}", RSYNC_LOG_FORMAT));
+
+    let result = match transfer.progress {
+        Some(_) => transfer
+            .send(source, destination, &options)
+            .map(|report| report.paths.into_iter().next().unwrap_or_default())
+            .map_err(anyhow::Error::from),
+        None => sync_directory_controlled(source, destination, &options, skippable),
+    };
+    let (transferred, deleted, _) = read_rsync_changes(&log);
+    let _ = fs::remove_file(&log);
+    Ok(PathReport {
+        changed: Some(transferred.into_iter().chain(deleted).collect()),
+        ..result?
+    })
+}
+
+// For SSH hosts only reachable through a relay: files are staged in a
+// directory on the relay, then forwarded from there with the same options
+// as a direct sync. The staged copy is kept for quicker syncs unless the
+// remote is set to clean it up.
+pub struct Relay {
+    pub relay: RemoteTarget,
+    pub target: RemoteTarget,
+    pub options: TransferOptions,
+}
+
+// Added to a relay's staging directory for the one other contents go to
+const CONTENTS_SUFFIX: &str = "-contents";
+
+// Where one upload goes through a relay: from `source` into `staging`, then
+// on from `staged`
+struct Staged<'a> {
+    source: String,
+    staging: String,
+    staged: String,
+    options: &'a RsyncOptions,
+    skippable: bool,
+}
+
+impl Relay {
+    // Where the relay keeps its copy for `remote_dir`
+    fn staging(&self, remote_dir: &str) -> String {
+        format!(
+            ".sync-rs-relay/{}",
+            slugify(&format!("{}-{}", self.target.host(), remote_dir))
+        )
+    }
+
+    // `options` for one hop. Neither can count on --mkpath, as the relay's
+    // rsync is unknown. Owners, permissions and the trash are for the
+    // remote's files, not the staged copy.
+    fn hop(options: &RsyncOptions, rsh: Option<String>, last: bool) -> RsyncOptions {
+        RsyncOptions {
+            rsh,
+            remote_version: None,
+            extra_args: options
+                .extra_args
+                .iter()
+                .filter(|arg| *arg != "--mkpath")
+                .filter(|arg| {
+                    last || ![
+                        "--rsync-path",
+                        "--usermap",
+                        "--groupmap",
+                        "--chown",
+                        "--chmod",
+                        "--backup",
+                    ]
+                    .iter()
+                    .any(|option| arg.starts_with(option))
+                })
+                .cloned()
+                .collect(),
+            ..options.clone()
+        }
+    }
+
+    // Override paths go to the staging directory as they'd go to the
+    // remote, and are forwarded from there. Other contents get a directory
+    // of their own, or the project would go along.
+    fn route<'a>(&'a self, upload: Upload, remote_dir: &str) -> Result<Staged<'a>> {
+        let mut staging = self.staging(remote_dir);
+        let (source, staged, options, skippable) = match upload {
+            Upload::Project => (
+                self.options.local(LOCAL_SOURCE),
+                dir_contents(&staging),
+                &self.options.main,
+                false,
+            ),
+            Upload::Override(path) => {
+                let staged = match Path::new(path).file_name() {
+                    Some(name) if !path.ends_with('/') => {
+                        format!("{}/{}", staging, name.to_string_lossy())
+                    }
+                    _ => dir_contents(&staging),
+                };
+                (
+                    self.options.local(path),
+                    staged,
+                    &self.options.overrides,
+                    true,
+                )
+            }
+            Upload::Contents(dir) => {
+                staging.push_str(CONTENTS_SUFFIX);
+                let options = &self.options.base;
+                let source = dir_contents(&self.options.local(dir));
+                (source, dir_contents(&staging), options, false)
+            }
+            Upload::Home(_) => {
+                anyhow::bail!("Home mode doesn't work through a relay")
+            }
+        };
+        Ok(Staged {
+            source,
+            staging,
+            staged,
+            options,
+            skippable,
+        })
+    }
+
+    // Options for the hop to the relay
+    fn first_hop(&self, options: &RsyncOptions) -> RsyncOptions {
+        Self::hop(options, self.relay.rsync_options().rsh, false)
+    }
+
+    // Options for the hop from the relay to the remote
+    fn last_hop(&self, options: &RsyncOptions) -> RsyncOptions {
+        Self::hop(options, self.target.rsync_options().rsh, true)
+    }
+
+    // What forwarding the staged copy would change on the remote, from a dry
+    // run on the relay, or None if nothing is staged
+    fn forwarded_changes(&self, route: &Staged, remote_dir: &str) -> Result<Option<Vec<String>>> {
+        let line = itemize_command_line(
+            &route.staged,
+            &self.target.rsync_location(remote_dir),
+            &self.last_hop(route.options),
+        );
+        let output =
+            runner::output(&mut self.relay.command(&line)).context("Failed to reach the relay")?;
+        if !output.status.success() {
+            // rsync reports a missing source directory as a partial transfer (23)
+            if output.status.code() == Some(23) {
+                return Ok(None);
+            }
+            anyhow::bail!(
+                "rsync dry run on {} failed: {}",
+                self.relay.host(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(Some(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(|line| line.to_string())
+                .collect(),
+        ))
+    }
+
+    // Run a script on the relay
+    fn run(&self, script: &str) -> Result<()> {
+        let status =
+            runner::status(&mut self.relay.command(script)).context("Failed to reach the relay")?;
+        if !status.success() {
+            anyhow::bail!(
+                "Running on the relay {} failed with exit code: {:?}",
+                self.relay.host(),
+                status.code()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Relay {
+    fn describe(&self) -> &'static str {
+        "Staging files on the relay and forwarding them with rsync"
+    }
+
+    fn deletes(&self) -> bool {
+        true
+    }
+
+    // The remote is listed from the relay, as it is only reachable from there
+    fn fingerprint(&self, remote_dir: &str) -> Result<Option<String>> {
+        let line = list_command_line(
+            &self.target.rsync_location(remote_dir),
+            &self.last_hop(&self.options.main),
+        );
+        let output =
+            runner::output(&mut self.relay.command(&line)).context("Failed to reach the relay")?;
+        if !output.status.success() {
+            // rsync reports a missing source directory as a partial transfer (23)
+            if output.status.code() == Some(23) {
+                return Ok(None);
+            }
+            anyhow::bail!(
+                "Failed to list remote directory through {}: {}",
+                self.relay.host(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(Some(listing_fingerprint(&String::from_utf8_lossy(
+            &output.stdout,
+        ))))
+    }
+
+    // Nothing reaches the remote, but the relay's staged copy is brought up
+    // to date first, as the sync these checks are for would, so the dry run
+    // from there sees what it would forward
+    fn changes(&self, upload: Upload, remote_dir: &str) -> Result<Option<Vec<String>>> {
+        let route = self.route(upload, remote_dir)?;
+        println!(
+            "Updating the staged copy on {} to check it",
+            self.relay.host()
+        );
+        self.run(&format!("mkdir -p {}", shell_quote(&route.staging)))?;
+        self.options.send(
+            &route.source,
+            &self.relay.rsync_location(&route.staging),
+            &self.first_hop(route.options),
+        )?;
+
+        match self.forwarded_changes(&route, remote_dir)? {
+            Some(lines) => Ok(Some(lines)),
+            None => anyhow::bail!("The staged copy on {} is missing", self.relay.host()),
+        }
+    }
+
+    // Both hops as dry runs: what the staged copy would get, and what the
+    // remote would get from the staged copy as it is now, before that
+    fn preview(&self, upload: Upload, remote_dir: &str) -> Result<Option<DryRunSteps>> {
+        let route = self.route(upload, remote_dir)?;
+        let host = self.relay.host();
+        let staged = itemize_changes(
+            &route.source,
+            &self.relay.rsync_location(&route.staging),
+            &self.first_hop(route.options),
+        )?;
+        let mut steps = vec![(format!("staging on {}", host), staged)];
+        match self.forwarded_changes(&route, remote_dir)? {
+            Some(forwarded) => steps.push((
+                format!("forwarding what is staged on {} now", host),
+                forwarded,
+            )),
+            None => warn(format_args!(
+                "Nothing is staged on {} yet, so what the remote would get can't be listed",
+                host
+            )),
+        }
+        Ok(Some(steps))
+    }
+
+    fn sync(&self, upload: Upload, remote_dir: &str) -> Result<SyncReport> {
+        let route = self.route(upload, remote_dir)?;
+        println!("Staging on {}", self.relay.host());
+        self.run(&format!("mkdir -p {}", shell_quote(&route.staging)))?;
+        let path = logged_sync(
+            &self.options,
+            &route.source,
+            &self.relay.rsync_location(&route.staging),
+            &self.first_hop(route.options),
+            route.skippable,
+        )?;
+        let mut report = SyncReport::default();
+        if path.result == PathResult::Synced {
+            println!("Forwarding from {}", self.relay.host());
+            self.run(&rsync_command_line(
+                &route.staged,
+                &self.target.rsync_location(remote_dir),
+                &self.last_hop(route.options),
+            ))?;
+        }
+        report.push(path);
+        Ok(report)
+    }
+
+    fn finish(&self, remote_dir: &str) -> Result<()> {
+        if self.options.relay_cleanup {
+            let staging = self.staging(remote_dir);
+            self.run(&format!(
+                "rm -rf {} {}",
+                shell_quote(&staging),
+                shell_quote(&format!("{}{}", staging, CONTENTS_SUFFIX))
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+// One copy by a transport without rsync: the source, the remote directory
+// it goes into, and what it leaves out
+struct LocalCopy<'a> {
+    source: String,
+    remote_dir: String,
+    gitignore: bool,
+    excludes: &'a [String],
+}
+
+// The copies that send `upload`. Home mode's paths go below their parent,
+// as with rsync.
+fn local_copies<'a>(
+    upload: Upload,
+    remote_dir: &str,
+    options: &'a TransferOptions,
+) -> Vec<LocalCopy<'a>> {
+    let copy = |source: String, remote_dir: String, gitignore, excludes| LocalCopy {
+        source,
+        remote_dir,
+        gitignore,
+        excludes,
+    };
+    match upload {
+        Upload::Project => vec![copy(
+            options.local("."),
+            remote_dir.to_string(),
+            true,
+            &options.excludes,
+        )],
+        Upload::Override(path) => vec![copy(
+            options.local(path),
+            remote_dir.to_string(),
+            false,
+            &options.protected,
+        )],
+        Upload::Contents(dir) => vec![copy(
+            dir_contents(&options.local(dir)),
+            remote_dir.to_string(),
+            false,
+            &[],
+        )],
+        Upload::Home(plan) => plan
+            .paths
+            .iter()
+            .map(|path| {
+                let parent = Path::new(path)
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty());
+                let remote_dir = match parent {
+                    Some(parent) => {
+                        format!("{}/{}", remote_dir.trim_end_matches('/'), parent.display())
+                    }
+                    None => remote_dir.to_string(),
+                };
+                copy(options.local(path), remote_dir, false, &options.excludes)
+            })
+            .collect(),
+    }
+}
+
+pub struct Sftp {
+    pub target: RemoteTarget,
+    pub options: TransferOptions,
+}
+
+impl Transport for Sftp {
+    fn describe(&self) -> &'static str {
+        "Sending changed files over SFTP, and nothing is deleted on the remote"
+    }
+
+    fn sync(&self, upload: Upload, remote_dir: &str) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        for copy in local_copies(upload, remote_dir, &self.options) {
+            let (files, bytes) = sftp_upload(
+                &self.target,
+                &copy.source,
+                &copy.remote_dir,
+                copy.gitignore,
+                copy.excludes,
+            )?;
+            report.push(PathReport {
+                path: copy.source,
+                files_transferred: files as u64,
+                bytes_sent: bytes,
+                ..Default::default()
+            });
+        }
+        Ok(report)
+    }
+}
+
+pub struct Tar {
+    pub target: RemoteTarget,
+    pub options: TransferOptions,
+}
+
+impl Transport for Tar {
+    fn check(&self) -> Result<()> {
+        if !remote_has_command(&self.target, "tar")? {
+            anyhow::bail!(
+                "Neither rsync nor tar is available on {}, install one of them to sync",
+                self.target.host()
+            );
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> &'static str {
+        "Copying with tar, which is NOT incremental: every file is sent on every sync, and nothing is deleted on the remote"
+    }
+
+    // Every file goes, so that's what's counted
+    fn sync(&self, upload: Upload, remote_dir: &str) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        for copy in local_copies(upload, remote_dir, &self.options) {
+            tar_upload(
+                &self.target,
+                &copy.source,
+                &copy.remote_dir,
+                copy.gitignore,
+                copy.excludes,
+            )?;
+            let files = local_file_sizes(&copy.source, copy.gitignore, copy.excludes)?;
+            report.push(PathReport {
+                path: copy.source,
+                files_transferred: files.len() as u64,
+                bytes_sent: files.iter().map(|(_, size)| size).sum(),
+                changed: Some(files.into_iter().map(|(name, _)| name).collect()),
+                ..Default::default()
+            });
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{with_runner, MockRunner};
+    use std::rc::Rc;
+
+    #[test]
+    fn kinds_resolve_and_filter() {
+        let target = RemoteTarget::parse("me@box");
+        for kind in [
+            TransportKind::Auto,
+            TransportKind::Rsync,
+            TransportKind::Tar,
+        ] {
+            assert_eq!(kind.resolve(&target, true).unwrap(), TransportKind::Rsync);
+        }
+        assert_eq!(
+            TransportKind::Sftp.resolve(&target, false).unwrap(),
+            TransportKind::Sftp
+        );
+        assert!(matches!(
+            TransportKind::Rsync.resolve(&target, false),
+            Err(SyncError::RsyncMissing { host: Some(host) }) if host == "me@box"
+        ));
+
+        let main = RsyncOptions {
+            filter: Some("- *.log".to_string()),
+            delete: true,
+            ..Default::default()
+        };
+        let options = TransferOptions {
+            main,
+            ..Default::default()
+        };
+        let transport = TransportKind::Rsync.backend(&target, options);
+        let version = "rsync  version 3.2.7  protocol version 31\n";
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, version, "")
+                .reply(0, "", "")
+                .reply(0, version, ""),
+        );
+        let report = with_runner(runner.clone(), || {
+            let mut report = transport.sync(Upload::Project, "/home/me/app")?;
+            report.append(transport.sync(Upload::Contents("/tmp/rendered"), "/home/me/app")?);
+            Ok::<_, anyhow::Error>(report)
+        })
+        .unwrap();
+        assert_eq!(report.paths[0].path, "./");
+        // The log rsync would have written is what lists the changes, and
+        // the rendered contents aren't logged
+        assert_eq!(report.paths[0].changed, Some(Vec::new()));
+        assert_eq!(report.changes(), None);
+        let commands = runner.commands();
+        assert!(commands[1].contains("--delete --filter - *.log"));
+        assert!(commands[1].contains(" --log-file="));
+        assert!(commands[1].ends_with(" ./ me@box:/home/me/app"));
+        assert!(!commands[3].contains("--delete"));
+        assert!(commands[3].ends_with(" /tmp/rendered/ me@box:/home/me/app"));
+
+        let relayed = RemoteTarget::Relay {
+            relay: "me@gateway".to_string(),
+            host: "inner".to_string(),
+            options: Vec::new(),
+        };
+        let transport = TransportKind::Auto.backend(&relayed, TransferOptions::default());
+        assert!(transport.describe().contains("relay"));
+        assert!(transport.deletes());
+    }
+
+    #[test]
+    fn copies_without_rsync_keep_the_layout() {
+        let options = TransferOptions {
+            excludes: vec!["*.log".to_string()],
+            protected: vec!["data".to_string()],
+            ..Default::default()
+        };
+        let summary = |upload| {
+            local_copies(upload, "/home/me/", &options)
+                .into_iter()
+                .map(|copy| {
+                    (
+                        copy.source,
+                        copy.remote_dir,
+                        copy.gitignore,
+                        copy.excludes.to_vec(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let listed = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+
+        assert_eq!(
+            summary(Upload::Project),
+            vec![(".".into(), "/home/me/".into(), true, listed(&["*.log"]))]
+        );
+        assert_eq!(
+            summary(Upload::Override("shared")),
+            vec![(
+                "shared".into(),
+                "/home/me/".into(),
+                false,
+                listed(&["data"])
+            )]
+        );
+        let plan = HomePlan {
+            paths: listed(&[".bashrc", ".config/nvim"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            summary(Upload::Home(&plan)),
+            vec![
+                (
+                    ".bashrc".into(),
+                    "/home/me/".into(),
+                    false,
+                    listed(&["*.log"])
+                ),
+                (
+                    ".config/nvim".into(),
+                    "/home/me/.config".into(),
+                    false,
+                    listed(&["*.log"])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_are_named_from_the_project() {
+        let here = TransferOptions::default();
+        let project = TransferOptions {
+            local_dir: PathBuf::from("/home/me/app"),
+            ..Default::default()
+        };
+        let named = |options: &TransferOptions| {
+            ["./", ".", "shared/", "data/x.csv", "/tmp/templates"].map(|path| options.local(path))
+        };
+
+        // The current directory's paths stay as they are
+        assert_eq!(
+            named(&here),
+            ["./", ".", "shared/", "data/x.csv", "/tmp/templates"]
+        );
+        assert_eq!(
+            named(&project),
+            [
+                "/home/me/app/",
+                "/home/me/app/",
+                "/home/me/app/shared/",
+                "/home/me/app/data/x.csv",
+                "/tmp/templates"
+            ]
+        );
+        let copies = local_copies(Upload::Project, "/srv/app", &project);
+        assert_eq!(copies[0].source, "/home/me/app/");
+    }
+
+    #[test]
+    fn relays_list_the_remote_from_the_relay() {
+        let relayed = RemoteTarget::Relay {
+            relay: "me@gateway".to_string(),
+            host: "inner".to_string(),
+            options: Vec::new(),
+        };
+        let options = TransferOptions {
+            main: RsyncOptions {
+                delete: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let transport = TransportKind::Auto.backend(&relayed, options);
+        let listing = "-rw-r--r--          1,234 2024/05/01 12:00:00 a.txt\n";
+        let version = "rsync  version 3.2.7  protocol version 31\n";
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, listing, "")
+                .reply(23, "", "")
+                .reply(0, "", "")
+                .reply(0, version, "")
+                .reply(0, "", "")
+                .reply(0, "*deleting old.txt\n>f+++++++++ new.txt\n", ""),
+        );
+        let (fingerprint, missing, changes) = with_runner(runner.clone(), || {
+            Ok::<_, anyhow::Error>((
+                transport.fingerprint("/srv/app")?,
+                transport.fingerprint("/srv/app")?,
+                transport.changes(Upload::Project, "/srv/app")?,
+            ))
+        })
+        .unwrap();
+        assert_eq!(fingerprint, Some(listing_fingerprint(listing)));
+        assert_eq!(missing, None);
+        assert_eq!(
+            changes,
+            Some(vec![
+                "*deleting old.txt".to_string(),
+                ">f+++++++++ new.txt".to_string()
+            ])
+        );
+
+        // The staged copy is updated before the relay's dry run against the
+        // remote, and both run on the relay
+        let commands = runner.commands();
+        assert!(commands[0].starts_with("ssh me@gateway rsync -r --list-only"));
+        assert!(commands[0].ends_with(" inner:/srv/app/"));
+        assert!(commands[2].ends_with("mkdir -p .sync-rs-relay/inner-srv-app"));
+        assert!(commands[4].ends_with(" ./ me@gateway:.sync-rs-relay/inner-srv-app"));
+        assert!(commands[5].starts_with("ssh me@gateway rsync -az --dry-run --itemize-changes"));
+        assert!(commands[5].ends_with(" .sync-rs-relay/inner-srv-app/ inner:/srv/app"));
+    }
+
+    #[test]
+    fn relay_previews_dry_run_both_hops() {
+        let relayed = RemoteTarget::Relay {
+            relay: "me@gateway".to_string(),
+            host: "inner".to_string(),
+            options: Vec::new(),
+        };
+        let transport = TransportKind::Auto.backend(&relayed, TransferOptions::default());
+        let version = "rsync  version 3.2.7  protocol version 31\n";
+        let runner = Rc::new(
+            MockRunner::default()
+                .reply(0, version, "")
+                .reply(0, ">f.st...... a.txt\n", "")
+                .reply(0, "*deleting old.txt\n", "")
+                .reply(0, version, "")
+                .reply(0, ">f+++++++++ a.txt\n", "")
+                .reply(23, "", ""),
+        );
+        let (staged, unstaged) = with_runner(runner.clone(), || {
+            Ok::<_, anyhow::Error>((
+                transport.preview(Upload::Project, "/srv/app")?,
+                transport.preview(Upload::Project, "/srv/app")?,
+            ))
+        })
+        .unwrap();
+        let step = |name: &str, line: &str| (name.to_string(), vec![line.to_string()]);
+        assert_eq!(
+            staged,
+            Some(vec![
+                step("staging on me@gateway", ">f.st...... a.txt"),
+                step(
+                    "forwarding what is staged on me@gateway now",
+                    "*deleting old.txt"
+                ),
+            ])
+        );
+        // Without a staged copy there's only the first hop to show
+        assert_eq!(
+            unstaged,
+            Some(vec![step("staging on me@gateway", ">f+++++++++ a.txt")])
+        );
+
+        // Both hops are dry runs, and nothing else runs on the relay
+        let commands = runner.commands();
+        assert!(commands[1].starts_with("rsync -az --dry-run --itemize-changes"));
+        assert!(commands[1].ends_with(" ./ me@gateway:.sync-rs-relay/inner-srv-app"));
+        assert!(commands[2].starts_with("ssh me@gateway rsync -az --dry-run --itemize-changes"));
+        assert_eq!(commands.len(), 6);
+    }
+}
@@ -0,0 +1,216 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// A local VM that can be turned into a remote entry
+#[derive(Debug, Clone)]
+pub struct VmCandidate {
+    pub name: String,
+    pub remote_host: String,
+    pub ssh_options: Vec<String>,
+}
+
+// ssh_config keywords from `vagrant ssh-config` worth keeping on the entry
+const VAGRANT_SSH_OPTIONS: &[&str] = &[
+    "Port",
+    "IdentityFile",
+    "IdentitiesOnly",
+    "UserKnownHostsFile",
+    "StrictHostKeyChecking",
+];
+
+// Running Vagrant machines of the project in `dir`, if it has a Vagrantfile
+pub fn vagrant_candidates(dir: &Path) -> Result<Vec<VmCandidate>> {
+    if !dir.join("Vagrantfile").exists() {
+        return Ok(Vec::new());
+    }
+
+    let output = Command::new("vagrant")
+        .arg("ssh-config")
+        .current_dir(dir)
+        .stderr(Stdio::null())
+        .output()
+        .context("Failed to execute vagrant ssh-config")?;
+
+    // vagrant exits non-zero when no machine is running
+    if !output.status.success() {
+        return Ok(Vec::new());
+    }
+
+    Ok(parse_vagrant_ssh_config(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+#[derive(Default)]
+struct VagrantHost {
+    name: String,
+    host_name: Option<String>,
+    user: Option<String>,
+    ssh_options: Vec<String>,
+}
+
+impl VagrantHost {
+    fn into_candidate(self) -> Option<VmCandidate> {
+        let host_name = self.host_name?;
+        let remote_host = match self.user {
+            Some(user) => format!("{}@{}", user, host_name),
+            None => host_name,
+        };
+        Some(VmCandidate {
+            name: format!("vagrant-{}", self.name),
+            remote_host,
+            ssh_options: self.ssh_options,
+        })
+    }
+}
+
+fn parse_vagrant_ssh_config(config: &str) -> Vec<VmCandidate> {
+    let mut hosts: Vec<VagrantHost> = Vec::new();
+
+    for line in config.lines() {
+        let Some((key, value)) = line.trim().split_once(char::is_whitespace) else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"').to_string();
+
+        if key == "Host" {
+            hosts.push(VagrantHost {
+                name: value,
+                ..Default::default()
+            });
+        } else if let Some(host) = hosts.last_mut() {
+            match key {
+                "HostName" => host.host_name = Some(value),
+                "User" => host.user = Some(value),
+                _ if VAGRANT_SSH_OPTIONS.contains(&key) => {
+                    host.ssh_options.push(format!("{}={}", key, value))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    hosts
+        .into_iter()
+        .filter_map(VagrantHost::into_candidate)
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct MultipassList {
+    list: Vec<MultipassInstance>,
+}
+
+#[derive(Deserialize)]
+struct MultipassInstance {
+    name: String,
+    state: String,
+    #[serde(default)]
+    ipv4: Vec<String>,
+}
+
+// Running Multipass instances. Multipass images log in as `ubuntu` with keys
+// provided through cloud-init, so only the address is filled in.
+pub fn multipass_candidates() -> Result<Vec<VmCandidate>> {
+    let output = match Command::new("multipass")
+        .args(["list", "--format", "json"])
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        // Not installed or not running
+        _ => return Ok(Vec::new()),
+    };
+
+    let instances: MultipassList =
+        serde_json::from_slice(&output.stdout).context("Failed to parse multipass output")?;
+
+    Ok(instances
+        .list
+        .into_iter()
+        .filter(|instance| instance.state == "Running")
+        .filter_map(|instance| {
+            let address = instance.ipv4.first()?;
+            Some(VmCandidate {
+                name: format!("multipass-{}", instance.name),
+                remote_host: format!("ubuntu@{}", address),
+                ssh_options: Vec::new(),
+            })
+        })
+        .collect())
+}
+
+// All VMs that could be used from `dir`
+pub fn detect_vms(dir: &Path) -> Result<Vec<VmCandidate>> {
+    let mut candidates = vagrant_candidates(dir)?;
+    candidates.extend(multipass_candidates()?);
+    Ok(candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vagrant_ssh_config_becomes_candidates() {
+        // As printed by `vagrant ssh-config` for a multi-machine Vagrantfile,
+        // one machine being on a provider that reports no address yet
+        let config = "\
+Host web
+  HostName 127.0.0.1
+  User vagrant
+  Port 2222
+  UserKnownHostsFile /dev/null
+  StrictHostKeyChecking no
+  PasswordAuthentication no
+  IdentityFile \"/Users/Jane Doe/proj/.vagrant/machines/web/virtualbox/private_key\"
+  IdentitiesOnly yes
+  LogLevel FATAL
+
+Host db
+  HostName 192.168.56.11
+  Port 22
+  IdentityFile /home/me/proj/.vagrant/machines/db/libvirt/private_key
+
+Host pending
+  User vagrant
+  Port 2200
+";
+        let candidates: Vec<_> = parse_vagrant_ssh_config(config)
+            .into_iter()
+            .map(|vm| (vm.name, vm.remote_host, vm.ssh_options))
+            .collect();
+        assert_eq!(
+            candidates,
+            [
+                (
+                    "vagrant-web".to_string(),
+                    "vagrant@127.0.0.1".to_string(),
+                    vec![
+                        "Port=2222".to_string(),
+                        "UserKnownHostsFile=/dev/null".to_string(),
+                        "StrictHostKeyChecking=no".to_string(),
+                        "IdentityFile=/Users/Jane Doe/proj/.vagrant/machines/web/virtualbox/private_key"
+                            .to_string(),
+                        "IdentitiesOnly=yes".to_string(),
+                    ]
+                ),
+                (
+                    "vagrant-db".to_string(),
+                    "192.168.56.11".to_string(),
+                    vec![
+                        "Port=22".to_string(),
+                        "IdentityFile=/home/me/proj/.vagrant/machines/db/libvirt/private_key"
+                            .to_string(),
+                    ]
+                ),
+            ]
+        );
+
+        assert!(parse_vagrant_ssh_config("").is_empty());
+        // Options before any Host line belong to no machine
+        assert!(parse_vagrant_ssh_config("  HostName 10.0.0.5\n").is_empty());
+    }
+}
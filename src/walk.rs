@@ -0,0 +1,143 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+// Most threads reading directories at once. Beyond this the disk, not the
+// number of threads, is what limits a scan.
+const MAX_THREADS: usize = 8;
+
+// A file or directory found by `walk_tree`
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalkEntry {
+    // Relative to the walked root
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+struct Queue {
+    dirs: Vec<PathBuf>,
+    // Threads reading a directory, which may find more
+    busy: usize,
+}
+
+// Everything below `root`, sorted by path, with directories read by several
+// threads at once so that trees of a million files take seconds. Directories
+// are listed but only entered when `descend` returns true for them, which
+// keeps `.git` or ignored build outputs from being read at all. Symlinks are
+// listed, not followed.
+pub fn walk_tree<F>(root: &Path, descend: F) -> Vec<WalkEntry>
+where
+    F: Fn(&Path) -> bool + Sync,
+{
+    let queue = Mutex::new(Queue {
+        dirs: vec![PathBuf::new()],
+        busy: 0,
+    });
+    let changed = Condvar::new();
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_THREADS);
+
+    let mut entries: Vec<WalkEntry> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| scope.spawn(|| walk_worker(root, &descend, &queue, &changed)))
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_default())
+            .collect()
+    });
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
+}
+
+fn walk_worker<F>(
+    root: &Path,
+    descend: &F,
+    queue: &Mutex<Queue>,
+    changed: &Condvar,
+) -> Vec<WalkEntry>
+where
+    F: Fn(&Path) -> bool,
+{
+    let mut found = Vec::new();
+    loop {
+        let dir = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if let Some(dir) = queue.dirs.pop() {
+                    queue.busy += 1;
+                    break dir;
+                }
+                if queue.busy == 0 {
+                    // Nothing left to read and nobody who could find more
+                    changed.notify_all();
+                    return found;
+                }
+                queue = changed.wait(queue).unwrap();
+            }
+        };
+
+        let mut subdirs = Vec::new();
+        if let Ok(read) = fs::read_dir(root.join(&dir)) {
+            for entry in read.flatten() {
+                let path = dir.join(entry.file_name());
+                let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+                if is_dir && descend(&path) {
+                    subdirs.push(path.clone());
+                }
+                found.push(WalkEntry { path, is_dir });
+            }
+        }
+
+        let mut queue = queue.lock().unwrap();
+        queue.dirs.extend(subdirs);
+        queue.busy -= 1;
+        changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_entry_is_found_once_without_entering_skipped_dirs() {
+        let root = std::env::temp_dir().join(format!("sync-rs-walk-test-{}", std::process::id()));
+        for i in 0..20 {
+            let dir = root.join(format!("d{:02}/sub", i));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("file"), "").unwrap();
+        }
+        fs::create_dir_all(root.join(".git/objects")).unwrap();
+        fs::write(root.join("top"), "").unwrap();
+
+        let entries = walk_tree(&root, |path| path != Path::new(".git"));
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(entries.len(), 2 + 20 * 3);
+        assert_eq!(
+            entries[..4],
+            [
+                WalkEntry {
+                    path: ".git".into(),
+                    is_dir: true
+                },
+                WalkEntry {
+                    path: "d00".into(),
+                    is_dir: true
+                },
+                WalkEntry {
+                    path: "d00/sub".into(),
+                    is_dir: true
+                },
+                WalkEntry {
+                    path: "d00/sub/file".into(),
+                    is_dir: false
+                },
+            ]
+        );
+        assert_eq!(entries.last().unwrap().path, Path::new("top"));
+    }
+}
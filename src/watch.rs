@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::glob::path_matches;
+use crate::notify::Notifier;
+use crate::walk::walk_tree;
+
+// How often the tree is scanned for changes without change notifications,
+// and the most often it's scanned while they keep coming
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// With change notifications, the tree is still scanned this often for file
+// systems that don't send them, like network mounts
+pub const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+// Changes are synced once the tree stayed the same for this long, so that a
+// save touching several files or a `git checkout` ends up as one sync
+pub const DEBOUNCE: Duration = Duration::from_millis(800);
+
+// Keyed by path relative to the root, kept as bytes so that names that
+// aren't valid UTF-8 are watched too
+type Snapshot = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+// Waits for changes to the files of a local directory and its override paths
+// by comparing sizes and modification times. Change notifications say when to
+// look where the system has them; otherwise the tree is polled. In a git
+// repository only the files git would see, and the override paths, are
+// watched, so build outputs don't trigger syncs.
+pub struct Watcher {
+    root: PathBuf,
+    ignore_patterns: Vec<String>,
+    override_paths: Vec<PathBuf>,
+    notifier: Option<Notifier>,
+    last: Snapshot,
+}
+
+impl Watcher {
+    pub fn new(root: &Path, ignore_patterns: &[String], override_paths: &[String]) -> Self {
+        let override_paths: Vec<PathBuf> = override_paths
+            .iter()
+            .map(|path| PathBuf::from(path.trim_end_matches('/')))
+            .collect();
+        let mut watcher = Self {
+            root: root.to_path_buf(),
+            ignore_patterns: ignore_patterns.to_vec(),
+            notifier: notifier(root, ignore_patterns, &override_paths),
+            override_paths,
+            last: Snapshot::new(),
+        };
+        watcher.last = watcher.scan();
+        watcher
+    }
+
+    // Block until files changed and then stayed quiet for `DEBOUNCE`, and
+    // return the paths that changed
+    pub fn wait(&mut self) -> Vec<String> {
+        let mut current = loop {
+            self.pause();
+            let current = self.scan();
+            if current != self.last {
+                break current;
+            }
+        };
+        loop {
+            thread::sleep(DEBOUNCE);
+            let next = self.scan();
+            if next == current {
+                break;
+            }
+            current = next;
+        }
+
+        let changed = changed_paths(&self.last, &current);
+        self.last = current;
+        changed
+    }
+
+    // Wait for a change notification, or for the next poll without them
+    fn pause(&mut self) {
+        let Some(notifier) = &mut self.notifier else {
+            thread::sleep(POLL_INTERVAL);
+            return;
+        };
+        if notifier.wait(RESCAN_INTERVAL) {
+            // Let a burst of changes, like a build writing files, settle
+            // into a single scan
+            thread::sleep(POLL_INTERVAL);
+            while notifier.wait(Duration::ZERO) {}
+        }
+    }
+
+    fn scan(&self) -> Snapshot {
+        let mut files =
+            git_files(&self.root).unwrap_or_else(|| walk_files(&self.root, &self.ignore_patterns));
+        for path in &self.override_paths {
+            let full = self.root.join(path);
+            if full.is_dir() {
+                files.extend(
+                    walk_files(&full, &self.ignore_patterns)
+                        .into_iter()
+                        .map(|file| path.join(file)),
+                );
+            } else {
+                files.push(path.clone());
+            }
+        }
+        files
+            .into_iter()
+            .filter(|path| {
+                !self
+                    .ignore_patterns
+                    .iter()
+                    .any(|pattern| path_matches(pattern, &path.to_string_lossy()))
+            })
+            .filter_map(|path| {
+                let metadata = fs::metadata(self.root.join(&path)).ok()?;
+                Some((path, (metadata.len(), metadata.modified().ok())))
+            })
+            .collect()
+    }
+}
+
+// Change notifications for the directories whose files are watched: all but
+// `.git`, what matches an ignore pattern and what git ignores, plus the
+// override paths. None if the system has none or can't watch that many.
+fn notifier(
+    root: &Path,
+    ignore_patterns: &[String],
+    override_paths: &[PathBuf],
+) -> Option<Notifier> {
+    let git_ignored: HashSet<PathBuf> = git_paths(
+        root,
+        &["--others", "--ignored", "--exclude-standard", "--directory"],
+    )
+    .unwrap_or_default()
+    .into_iter()
+    .collect();
+    let ignore_patterns = ignore_patterns.to_vec();
+    let descend = move |path: &Path| {
+        path.file_name() != Some(OsStr::new(".git"))
+            && !git_ignored.contains(path)
+            && !ignore_patterns
+                .iter()
+                .any(|pattern| path_matches(pattern, &path.to_string_lossy()))
+    };
+
+    let mut notifier = Notifier::new(root, Box::new(descend))?;
+    let mut dirs = vec![PathBuf::new()];
+    dirs.extend(
+        override_paths
+            .iter()
+            .filter(|path| root.join(path).is_dir())
+            .cloned(),
+    );
+    dirs.iter()
+        .all(|dir| notifier.watch_tree(dir))
+        .then_some(notifier)
+}
+
+// Tracked and untracked files that aren't ignored, if `root` is in a git repository
+fn git_files(root: &Path) -> Option<Vec<PathBuf>> {
+    git_paths(root, &["--cached", "--others", "--exclude-standard"])
+}
+
+// What `git ls-files` lists with `args` below `root`, if it's in a git
+// repository. Directories are listed without their trailing slash.
+fn git_paths(root: &Path, args: &[&str]) -> Option<Vec<PathBuf>> {
+    let output = Command::new("git")
+        .args(["ls-files", "-z"])
+        .args(args)
+        .current_dir(root)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        output
+            .stdout
+            .split(|&b| b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| PathBuf::from(OsStr::from_bytes(path.strip_suffix(b"/").unwrap_or(path))))
+            .collect(),
+    )
+}
+
+// Every file below `root`, as paths relative to it, without entering `.git`
+// or directories matching an ignore pattern
+fn walk_files(root: &Path, ignore_patterns: &[String]) -> Vec<PathBuf> {
+    walk_tree(root, |path| {
+        path.file_name() != Some(OsStr::new(".git"))
+            && !ignore_patterns
+                .iter()
+                .any(|pattern| path_matches(pattern, &path.to_string_lossy()))
+    })
+    .into_iter()
+    .filter(|entry| !entry.is_dir)
+    .map(|entry| entry.path)
+    .collect()
+}
+
+// Paths added, removed or modified between two snapshots
+fn changed_paths(before: &Snapshot, after: &Snapshot) -> Vec<String> {
+    let mut changed: Vec<&PathBuf> = after
+        .iter()
+        .filter(|(path, meta)| before.get(*path) != Some(meta))
+        .map(|(path, _)| path)
+        .collect();
+    changed.extend(before.keys().filter(|path| !after.contains_key(*path)));
+    changed.sort();
+    changed
+        .into_iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_added_removed_and_modified_files() {
+        let at = SystemTime::UNIX_EPOCH;
+        let before: Snapshot = [
+            ("a".into(), (1, Some(at))),
+            ("b".into(), (2, Some(at))),
+            ("c".into(), (3, Some(at))),
+        ]
+        .into();
+        let after: Snapshot = [
+            ("a".into(), (1, Some(at))),
+            ("b".into(), (2, Some(at + Duration::from_secs(1)))),
+            ("d".into(), (4, Some(at))),
+        ]
+        .into();
+        assert_eq!(changed_paths(&before, &after), ["b", "c", "d"]);
+        assert!(changed_paths(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn names_that_are_not_utf8_are_watched() {
+        let root = std::env::temp_dir().join(format!("sync-rs-watch-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("data")).unwrap();
+        let name = OsStr::from_bytes(b"caf\xe9.csv");
+        fs::write(root.join("data").join(name), "1").unwrap();
+
+        let files = walk_files(&root, &[]);
+        assert_eq!(files, [Path::new("data").join(name)]);
+        let watcher = Watcher::new(&root, &[], &[]);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(watcher.last.len(), 1);
+    }
+
+    #[test]
+    fn override_paths_are_watched_even_when_ignored() {
+        let root =
+            std::env::temp_dir().join(format!("sync-rs-watch-override-{}", std::process::id()));
+        fs::create_dir_all(root.join("data/raw")).unwrap();
+        fs::write(root.join(".gitignore"), "data/\n.env\n").unwrap();
+        fs::write(root.join(".env"), "A=1").unwrap();
+        fs::write(root.join("data/raw/a.csv"), "1").unwrap();
+        let git_init = Command::new("git")
+            .args(["init", "-q"])
+            .current_dir(&root)
+            .status()
+            .is_ok_and(|status| status.success());
+
+        let overrides = ["data/".to_string(), ".env".to_string()];
+        let watcher = Watcher::new(&root, &["*.tmp".to_string()], &overrides);
+        fs::write(root.join("data/raw/b.tmp"), "").unwrap();
+        let snapshot = watcher.scan();
+        fs::remove_dir_all(&root).unwrap();
+
+        let paths: Vec<&Path> = snapshot.keys().map(PathBuf::as_path).collect();
+        if git_init {
+            assert_eq!(
+                paths,
+                [
+                    Path::new(".env"),
+                    Path::new(".gitignore"),
+                    Path::new("data/raw/a.csv")
+                ]
+            );
+        }
+        assert!(paths.contains(&Path::new("data/raw/a.csv")));
+        assert!(paths.contains(&Path::new(".env")));
+    }
+}